@@ -0,0 +1,508 @@
+//! Keeping every installed interface up to date from a single process.
+//!
+//! Each interface gets its own fetch loop on its own thread, with its own interval and
+//! backoff, so one unreachable server never holds up the others. The config directory is
+//! rescanned periodically to pick up newly installed interfaces, and an interface's loop
+//! ends once it's uninstalled.
+//!
+//! Only `--max-concurrent-fetches` interfaces fetch at a time, so a host in many networks
+//! doesn't reconfigure all of them at once on a cold start. Interfaces take turns in the
+//! order they became due, so the ones at the end of the list still get theirs.
+//!
+//! The daemon keeps a status file in the data directory for `innernet daemon status`.
+
+use crate::{events, fetch, fetch_status, refresh_server_endpoint, util, Backoff, HostsOpt, Opts};
+use anyhow::anyhow;
+use clap::{Args, Subcommand};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use shared::{Error, IoErrorContext, NatOpts};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+use wireguard_control::InterfaceName;
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum DaemonCommand {
+    /// Show the state of every interface the running daemon manages
+    Status {
+        /// Output the status as JSON
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct DaemonOpts {
+    /// Fetch the latest peer list of each interface at this interval in seconds
+    #[clap(long, default_value = "60")]
+    pub interval: u64,
+
+    /// Override the fetch interval of a single interface (ex. "office=300"). Can be
+    /// given multiple times
+    #[clap(long = "interface-interval", value_parser = parse_interface_interval)]
+    pub interface_intervals: Vec<(String, u64)>,
+
+    /// The longest interval in seconds to back off to when an interface's fetches keep
+    /// failing
+    #[clap(long, default_value = "600")]
+    pub max_interval: u64,
+
+    /// Randomly vary backoff intervals by up to this fraction (0.0 - 1.0) so peers don't
+    /// retry in lockstep
+    #[clap(long, default_value = "0.2")]
+    pub jitter: f64,
+
+    /// How often in seconds to look for newly installed interfaces
+    #[clap(long, default_value = "30")]
+    pub rescan_interval: u64,
+
+    /// How many interfaces may fetch and apply their peers at the same time. The rest wait
+    /// for their turn
+    #[clap(long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_fetches: u32,
+
+    #[clap(flatten)]
+    pub hosts: HostsOpt,
+
+    #[clap(flatten)]
+    pub nat: NatOpts,
+
+    /// Also report peer changes, new handshakes, and fetch results as newline-delimited
+    /// JSON on stdout. Log messages go to stderr instead
+    #[clap(long)]
+    pub events_json: bool,
+}
+
+fn parse_interface_interval(s: &str) -> Result<(String, u64), String> {
+    let (interface, secs) = s
+        .split_once('=')
+        .ok_or_else(|| "expected <interface>=<seconds>".to_string())?;
+    let interface: InterfaceName = interface.parse().map_err(|e| format!("{e}"))?;
+    let secs = secs
+        .parse()
+        .map_err(|_| format!("invalid interval '{secs}'"))?;
+    Ok((interface.to_string(), secs))
+}
+
+/// One interface's fetch loop, as last reported by the daemon.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct InterfaceStatus {
+    /// Unix timestamp of the last successful fetch.
+    pub last_success: Option<u64>,
+    /// The error from the last fetch, if it failed.
+    pub last_error: Option<String>,
+    /// How many fetches in a row have failed.
+    pub failures: u32,
+    /// Unix timestamp of when the next fetch is due.
+    pub next_fetch: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: u64,
+    pub interfaces: BTreeMap<String, InterfaceStatus>,
+}
+
+impl DaemonStatus {
+    /// Not a `.json` file, which would clash with an interface's peer data.
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("daemon.status")
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Ok(serde_json::from_str(
+            &fs::read_to_string(path).with_path(path)?,
+        )?)
+    }
+
+    pub fn write_to_path(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, serde_json::to_string_pretty(self)?).with_path(path)?;
+        Ok(())
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The status shared by every interface's thread, written out whenever it changes.
+struct StatusFile {
+    path: PathBuf,
+    status: Mutex<DaemonStatus>,
+}
+
+impl StatusFile {
+    fn update(&self, f: impl FnOnce(&mut BTreeMap<String, InterfaceStatus>)) {
+        let mut status = self.status.lock().unwrap();
+        f(&mut status.interfaces);
+        if let Err(e) = status.write_to_path(&self.path) {
+            log::warn!("failed to write daemon status ({}).", e);
+        }
+    }
+
+    fn is_running(&self, interface: &str) -> bool {
+        self.status
+            .lock()
+            .unwrap()
+            .interfaces
+            .contains_key(interface)
+    }
+}
+
+/// Turns at fetching, handed out first come, first served, with at most `limit` taken at once.
+struct FetchSlots {
+    limit: usize,
+    queue: Mutex<SlotQueue>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct SlotQueue {
+    /// The ticket the next caller of `acquire` gets.
+    next_ticket: u64,
+    /// The ticket whose turn is next.
+    serving: u64,
+    taken: usize,
+}
+
+/// A turn at fetching, which is given back when dropped.
+struct FetchSlot<'a>(&'a FetchSlots);
+
+impl FetchSlots {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            queue: Mutex::new(SlotQueue::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a turn, behind everyone who asked for one earlier.
+    fn acquire(&self) -> FetchSlot<'_> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        let mut queue = self
+            .changed
+            .wait_while(queue, |queue| {
+                queue.serving != ticket || queue.taken >= self.limit
+            })
+            .unwrap();
+        queue.serving += 1;
+        queue.taken += 1;
+        // The next in line may be able to go too.
+        self.changed.notify_all();
+        FetchSlot(self)
+    }
+}
+
+impl Drop for FetchSlot<'_> {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().taken -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+pub fn run(opts: &Opts, daemon_opts: &DaemonOpts) -> Result<(), Error> {
+    if daemon_opts.events_json {
+        events::enable();
+    }
+    shared::ensure_dirs_exist(&[&opts.data_dir])?;
+    let status = StatusFile {
+        path: DaemonStatus::path(&opts.data_dir),
+        status: Mutex::new(DaemonStatus {
+            pid: std::process::id(),
+            started: unix_time(SystemTime::now()),
+            interfaces: BTreeMap::new(),
+        }),
+    };
+    status.update(|_| {});
+    let hosts_path: Option<PathBuf> = daemon_opts.hosts.clone().into();
+    let slots = FetchSlots::new(daemon_opts.max_concurrent_fetches as usize);
+
+    thread::scope(|scope| loop {
+        match util::all_installed(&opts.config_dir) {
+            Ok(interfaces) => {
+                for interface in interfaces {
+                    let name = interface.to_string();
+                    if status.is_running(&name) {
+                        continue;
+                    }
+                    log::info!("managing {}.", name.yellow());
+                    status.update(|interfaces| {
+                        interfaces.insert(name.clone(), InterfaceStatus::default());
+                    });
+
+                    let interval = daemon_opts
+                        .interface_intervals
+                        .iter()
+                        .rev()
+                        .find(|(iface, _)| *iface == name)
+                        .map_or(daemon_opts.interval, |(_, secs)| *secs);
+                    let backoff = Backoff::new(
+                        Duration::from_secs(interval),
+                        Duration::from_secs(daemon_opts.max_interval),
+                        daemon_opts.jitter,
+                    );
+                    let context = LoopContext {
+                        opts,
+                        daemon_opts,
+                        hosts_path: hosts_path.clone(),
+                        status: &status,
+                        slots: &slots,
+                    };
+                    scope.spawn(move || fetch_loop(&interface, context, backoff));
+                }
+            },
+            Err(e) => log::warn!("failed to scan for installed interfaces ({}).", e),
+        }
+        thread::sleep(Duration::from_secs(daemon_opts.rescan_interval));
+    })
+}
+
+/// What every interface's fetch loop shares.
+struct LoopContext<'a> {
+    opts: &'a Opts,
+    daemon_opts: &'a DaemonOpts,
+    hosts_path: Option<PathBuf>,
+    status: &'a StatusFile,
+    slots: &'a FetchSlots,
+}
+
+/// Keep one interface up to date until it's uninstalled. Failures, even ones `up` would
+/// give up on, only back this interface's loop off; they never end it.
+fn fetch_loop(interface: &InterfaceName, context: LoopContext<'_>, mut backoff: Backoff) {
+    let LoopContext {
+        opts,
+        daemon_opts,
+        hosts_path,
+        status,
+        slots,
+    } = context;
+    let name = interface.to_string();
+    let config_path = opts.config_dir.join(&name).with_extension("conf");
+    let mut network_changed = false;
+    loop {
+        if !config_path.exists() {
+            log::info!("{} was uninstalled, no longer managing it.", name.yellow());
+            status.update(|interfaces| {
+                interfaces.remove(&name);
+            });
+            return;
+        }
+
+        let slot = slots.acquire();
+        if network_changed {
+            if let Err(e) = refresh_server_endpoint(interface, opts) {
+                log::warn!("failed to re-resolve server endpoint for {}: {}", name, e);
+            }
+        }
+
+        let result = fetch(
+            interface,
+            opts,
+            true,
+            hosts_path.clone(),
+            &daemon_opts.nat,
+            None,
+        );
+        drop(slot);
+        fetch_status::record(opts.fetch_status_dir(), interface, &result);
+        let result = events::fetched(interface, result);
+        let delay = match &result {
+            Ok(()) => backoff.succeeded(),
+            Err(e) => {
+                let delay = backoff.failed();
+                if util::is_transient_error(e) {
+                    log::warn!(
+                        "{}: fetch failed ({}), retrying in {}s.",
+                        name,
+                        e,
+                        delay.as_secs()
+                    );
+                } else {
+                    log::error!(
+                        "{}: server refused the request ({}), retrying in {}s.",
+                        name,
+                        e,
+                        delay.as_secs()
+                    );
+                }
+                delay
+            },
+        };
+
+        let now = SystemTime::now();
+        status.update(|interfaces| {
+            let entry = interfaces.entry(name.clone()).or_default();
+            match &result {
+                Ok(()) => {
+                    entry.last_success = Some(unix_time(now));
+                    entry.last_error = None;
+                    entry.failures = 0;
+                },
+                Err(e) => {
+                    entry.last_error = Some(format!("{e:#}"));
+                    entry.failures += 1;
+                },
+            }
+            entry.next_fetch = Some(unix_time(now + delay));
+        });
+
+        network_changed =
+            match shared::wait_for_network_change(delay, crate::NETWORK_CHANGE_DEBOUNCE) {
+                Ok(changed) => changed,
+                Err(e) => {
+                    log::warn!("can't watch for network changes ({}), using the timer.", e);
+                    thread::sleep(delay);
+                    false
+                },
+            };
+        if network_changed {
+            log::info!("{}: network change detected, re-fetching early.", name);
+        }
+    }
+}
+
+/// Whether the process that wrote the status file is still around. Without procfs,
+/// there's no cheap way to tell, so it's assumed to be.
+fn is_alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.is_dir() || proc.join(pid.to_string()).exists()
+}
+
+pub fn status(opts: &Opts, json: bool) -> Result<(), Error> {
+    let path = DaemonStatus::path(&opts.data_dir);
+    if !path.exists() {
+        return Err(anyhow!(
+            "no daemon status found, is the daemon running? (start it with 'innernet daemon')"
+        ));
+    }
+    let status = DaemonStatus::from_file(&path)?;
+    if !is_alive(status.pid) {
+        return Err(anyhow!("the daemon (pid {}) isn't running.", status.pid));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    let since = |timestamp: u64| {
+        now.duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp))
+            .unwrap_or_default()
+    };
+    println!(
+        "{} daemon (pid {}) started {}",
+        "[*]".dimmed(),
+        status.pid,
+        util::human_duration(since(status.started))
+    );
+    if status.interfaces.is_empty() {
+        println!("    no interfaces installed.");
+    }
+    for (name, interface) in &status.interfaces {
+        let last_success = match interface.last_success {
+            Some(timestamp) => util::human_duration(since(timestamp)),
+            None => "never".to_string(),
+        };
+        let next_fetch = interface
+            .next_fetch
+            .map(|timestamp| {
+                (SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp))
+                    .duration_since(now)
+                    .unwrap_or_default()
+                    .as_secs()
+            })
+            .map(|secs| format!(", next fetch in {secs}s"))
+            .unwrap_or_default();
+        println!(
+            "    {}: last fetched {}{}",
+            name.yellow(),
+            last_success,
+            next_fetch
+        );
+        if let Some(error) = &interface.last_error {
+            println!(
+                "      {} {} failure(s) in a row, last: {}",
+                "[!]".red(),
+                interface.failures,
+                error
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interface_interval() {
+        assert_eq!(
+            parse_interface_interval("office=300"),
+            Ok(("office".to_string(), 300))
+        );
+        assert!(parse_interface_interval("office").is_err());
+        assert!(parse_interface_interval("office=soon").is_err());
+        assert!(parse_interface_interval("way-too-long-for-an-interface=30").is_err());
+    }
+
+    #[test]
+    fn test_fetch_slots() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let slots = FetchSlots::new(2);
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _slot = slots.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(slots.queue.lock().unwrap().taken, 0);
+    }
+
+    #[test]
+    fn test_status_roundtrip() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = DaemonStatus::path(dir.path());
+        let mut status = DaemonStatus {
+            pid: std::process::id(),
+            started: 1_700_000_000,
+            interfaces: BTreeMap::new(),
+        };
+        status.interfaces.insert(
+            "office".into(),
+            InterfaceStatus {
+                last_success: Some(1_700_000_060),
+                next_fetch: Some(1_700_000_120),
+                ..Default::default()
+            },
+        );
+        status.write_to_path(&path)?;
+        assert_eq!(
+            DaemonStatus::from_file(&path)?.interfaces,
+            status.interfaces
+        );
+        assert!(is_alive(status.pid));
+        Ok(())
+    }
+}
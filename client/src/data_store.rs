@@ -6,6 +6,7 @@ use std::{
     fs::{File, OpenOptions},
     io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 use wireguard_control::InterfaceName;
 
@@ -19,7 +20,17 @@ pub struct DataStore {
 #[serde(tag = "version")]
 pub enum Contents {
     #[serde(rename = "1")]
-    V1 { peers: Vec<Peer>, cidrs: Vec<Cidr> },
+    V1 {
+        peers: Vec<Peer>,
+        cidrs: Vec<Cidr>,
+        /// The server's ETag for the state these peers and CIDRs came from.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        etag: Option<String>,
+        /// Unix timestamp of the last fetch that removed peers the server no longer lists,
+        /// for interfaces that defer removals.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reconciled_at: Option<u64>,
+    },
 }
 
 impl DataStore {
@@ -48,6 +59,8 @@ impl DataStore {
         let contents = serde_json::from_str(&json).unwrap_or_else(|_| Contents::V1 {
             peers: vec![],
             cidrs: vec![],
+            etag: None,
+            reconciled_at: None,
         });
 
         Ok(Self { file, contents })
@@ -91,17 +104,28 @@ impl DataStore {
     ///
     /// Note, however, that this does not prevent a compromised server from adding a new
     /// peer under its control, of course.
-    pub fn update_peers(&mut self, current_peers: &[Peer]) -> Result<(), Error> {
+    ///
+    /// The one exception is a peer whose pinned key the server lists in `revoked_keys`,
+    /// i.e. one that an admin re-keyed, which is re-pinned to its new key. That leaves the
+    /// server able to unpin any peer's key, so it's trusted with that much.
+    pub fn update_peers(
+        &mut self,
+        current_peers: &[Peer],
+        revoked_keys: &[String],
+    ) -> Result<(), Error> {
         let peers = match &mut self.contents {
             Contents::V1 { ref mut peers, .. } => peers,
         };
 
         for new_peer in current_peers.iter() {
             if let Some(existing_peer) = peers.iter_mut().find(|p| p.ip == new_peer.ip) {
-                if existing_peer.public_key != new_peer.public_key {
-                    bail!("PINNING ERROR: New peer has same IP but different public key.");
-                } else {
+                if existing_peer.public_key == new_peer.public_key {
+                    *existing_peer = new_peer.clone();
+                } else if revoked_keys.contains(&existing_peer.public_key) {
+                    log::warn!("peer {} was given a new key by an admin.", new_peer.name);
                     *existing_peer = new_peer.clone();
+                } else {
+                    bail!("PINNING ERROR: New peer has same IP but different public key.");
                 }
             } else {
                 peers.push(new_peer.clone());
@@ -132,6 +156,41 @@ impl DataStore {
         }
     }
 
+    pub fn etag(&self) -> Option<&str> {
+        match &self.contents {
+            Contents::V1 { etag, .. } => etag.as_deref(),
+        }
+    }
+
+    pub fn set_etag(&mut self, new_etag: Option<String>) {
+        match &mut self.contents {
+            Contents::V1 { ref mut etag, .. } => *etag = new_etag,
+        }
+    }
+
+    /// When peers the server no longer lists were last removed from the interface, see
+    /// `InterfaceInfo::removal_interval`.
+    pub fn reconciled_at(&self) -> Option<SystemTime> {
+        match &self.contents {
+            Contents::V1 { reconciled_at, .. } => {
+                reconciled_at.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            },
+        }
+    }
+
+    pub fn set_reconciled_at(&mut self, time: SystemTime) {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut reconciled_at,
+                ..
+            } => *reconciled_at = Some(secs),
+        }
+    }
+
     pub fn write(&mut self) -> Result<(), io::Error> {
         self.file.rewind()?;
         self.file.set_len(0)?;
@@ -161,6 +220,15 @@ mod tests {
                 persistent_keepalive_interval: None,
                 invite_expires: None,
                 candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
             },
         }]
     });
@@ -171,6 +239,10 @@ mod tests {
                 name: "cidr".to_string(),
                 cidr: "10.0.0.0/24".parse().unwrap(),
                 parent: None,
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
         }]
     });
@@ -182,7 +254,7 @@ mod tests {
         assert_eq!(0, store.peers().len());
         assert_eq!(0, store.cidrs().len());
 
-        store.update_peers(&BASE_PEERS).unwrap();
+        store.update_peers(&BASE_PEERS, &[]).unwrap();
         store.set_cidrs(BASE_CIDRS.to_owned());
         store.write().unwrap();
     }
@@ -196,6 +268,22 @@ mod tests {
         assert_eq!(store.cidrs(), &*BASE_CIDRS);
     }
 
+    #[test]
+    fn test_reconciled_at() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let path = dir.path().join("peer_store.json");
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.reconciled_at(), None);
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        store.set_reconciled_at(time + Duration::from_millis(500));
+        store.write().unwrap();
+        let store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.reconciled_at(), Some(time));
+        assert_eq!(store.peers(), &*BASE_PEERS);
+    }
+
     #[test]
     fn test_pinning() {
         let dir = tempfile::tempdir().unwrap();
@@ -204,13 +292,19 @@ mod tests {
             DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
-        store.update_peers(&BASE_PEERS).unwrap();
+        store.update_peers(&BASE_PEERS, &[]).unwrap();
 
         let mut modified = BASE_PEERS.clone();
         modified[0].contents.public_key = "foo".to_string();
 
         // Should NOT work, since peer is unmodified.
-        assert!(store.update_peers(&modified).is_err());
+        assert!(store.update_peers(&modified, &[]).is_err());
+
+        // ...unless the server says the old key was revoked.
+        store
+            .update_peers(&modified, &[BASE_PEERS[0].public_key.clone()])
+            .unwrap();
+        assert_eq!(store.peers()[0].public_key, "foo");
     }
 
     #[test]
@@ -221,7 +315,7 @@ mod tests {
             DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
-        store.update_peers(&[]).unwrap();
+        store.update_peers(&[], &[]).unwrap();
         let new_peers = BASE_PEERS
             .iter()
             .cloned()
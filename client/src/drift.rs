@@ -0,0 +1,357 @@
+//! `innernet diff`, comparing the live WireGuard device against the peers innernet would
+//! configure on it.
+//!
+//! Endpoints are only counted as drift when the device has one that innernet would never
+//! have set. WireGuard learns a peer's endpoint from its handshakes, and NAT traversal
+//! tries each of a peer's candidates, so the device endpoint legitimately wanders.
+
+use colored::*;
+use ipnet::IpNet;
+use serde::Serialize;
+use shared::{wg, Endpoint, Peer};
+use std::net::SocketAddr;
+use wireguard_control::{AllowedIp, PeerInfo};
+
+/// A value that differs between the intended and the live peer config.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct FieldDrift<T> {
+    pub expected: T,
+    pub actual: T,
+}
+
+/// A peer that's only on one side of the comparison.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PeerRef {
+    /// The innernet peer name, if the public key belongs to a known peer.
+    pub name: Option<String>,
+    pub public_key: String,
+}
+
+/// A peer that's on the device, but configured differently than innernet intends.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PeerDrift {
+    pub name: String,
+    pub public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_ips: Option<FieldDrift<Vec<IpNet>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<FieldDrift<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive_interval: Option<FieldDrift<Option<u16>>>,
+}
+
+/// Everything that differs between the intended and the live device, as emitted by
+/// `innernet diff --json`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct DriftReport {
+    /// Peers that should be on the device but aren't.
+    pub missing: Vec<PeerRef>,
+    /// Peers on the device that shouldn't be.
+    pub unexpected: Vec<PeerRef>,
+    pub changed: Vec<PeerDrift>,
+}
+
+impl DriftReport {
+    /// Compare `intended` (the peer list as `fetch` would configure it) with the `live`
+    /// device peers. `own_key` is this interface's public key, which never has a peer.
+    pub fn new(
+        intended: &[Peer],
+        live: &[PeerInfo],
+        own_key: Option<&str>,
+        resolve: impl Fn(&Endpoint) -> Option<SocketAddr>,
+    ) -> Self {
+        let wanted = intended
+            .iter()
+            .filter(|peer| wg::is_wanted(peer, own_key.unwrap_or_default()))
+            .collect::<Vec<_>>();
+        let live_peer = |public_key: &str| {
+            live.iter()
+                .find(|info| info.config.public_key.to_base64() == public_key)
+        };
+
+        let mut report = Self::default();
+        for peer in &wanted {
+            match live_peer(&peer.public_key) {
+                Some(info) => {
+                    if let Some(drift) = peer_drift(peer, info, &resolve) {
+                        report.changed.push(drift);
+                    }
+                },
+                None => report.missing.push(PeerRef {
+                    name: Some(peer.name.to_string()),
+                    public_key: peer.public_key.clone(),
+                }),
+            }
+        }
+        for info in live {
+            let public_key = info.config.public_key.to_base64();
+            if !wanted.iter().any(|peer| peer.public_key == public_key) {
+                report.unexpected.push(PeerRef {
+                    name: intended
+                        .iter()
+                        .find(|peer| peer.public_key == public_key)
+                        .map(|peer| peer.name.to_string()),
+                    public_key,
+                });
+            }
+        }
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!(
+                "{}",
+                "no drift, the device matches innernet's peer list.".green()
+            );
+            return;
+        }
+        let describe = |peer: &PeerRef| {
+            format!(
+                "{} ({}…)",
+                peer.name.as_deref().unwrap_or("(unknown peer)").yellow(),
+                &peer.public_key[..10].dimmed()
+            )
+        };
+        for peer in &self.missing {
+            println!(
+                "  {} missing from the device: {}",
+                "-".red(),
+                describe(peer)
+            );
+        }
+        for peer in &self.unexpected {
+            println!(
+                "  {} not expected on the device: {}",
+                "+".red(),
+                describe(peer)
+            );
+        }
+        for drift in &self.changed {
+            println!(
+                "  {} {} ({}…)",
+                "~".yellow(),
+                drift.name.yellow(),
+                &drift.public_key[..10].dimmed()
+            );
+            if let Some(FieldDrift { expected, actual }) = &drift.allowed_ips {
+                println!(
+                    "    {}: expected {}, actual {}",
+                    "allowed ips".bold(),
+                    join(expected),
+                    join(actual)
+                );
+            }
+            if let Some(FieldDrift { expected, actual }) = &drift.endpoint {
+                println!(
+                    "    {}: expected {}, actual {}",
+                    "endpoint".bold(),
+                    expected.as_deref().unwrap_or("none"),
+                    actual.as_deref().unwrap_or("none")
+                );
+            }
+            if let Some(FieldDrift { expected, actual }) = &drift.persistent_keepalive_interval {
+                let show = |interval: &Option<u16>| match interval {
+                    Some(secs) => format!("{secs}s"),
+                    None => "off".to_string(),
+                };
+                println!(
+                    "    {}: expected {}, actual {}",
+                    "keepalive".bold(),
+                    show(expected),
+                    show(actual)
+                );
+            }
+        }
+    }
+}
+
+fn join(ips: &[IpNet]) -> String {
+    if ips.is_empty() {
+        return "none".to_string();
+    }
+    ips.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn to_ipnets(allowed_ips: &[AllowedIp]) -> Vec<IpNet> {
+    let mut ips = allowed_ips
+        .iter()
+        .filter_map(|ip| IpNet::new(ip.address, ip.cidr).ok())
+        .collect::<Vec<_>>();
+    ips.sort();
+    ips
+}
+
+fn peer_drift(
+    peer: &Peer,
+    info: &PeerInfo,
+    resolve: impl Fn(&Endpoint) -> Option<SocketAddr>,
+) -> Option<PeerDrift> {
+    let expected_ips = to_ipnets(&peer.allowed_ips());
+    let actual_ips = to_ipnets(&info.config.allowed_ips);
+    let allowed_ips = (expected_ips != actual_ips).then_some(FieldDrift {
+        expected: expected_ips,
+        actual: actual_ips,
+    });
+
+    let acceptable = peer
+        .endpoint
+        .iter()
+        .chain(&peer.candidates)
+        .filter_map(&resolve)
+        .collect::<Vec<_>>();
+    let endpoint_drifted = match info.config.endpoint {
+        Some(actual) => !acceptable.is_empty() && !acceptable.contains(&actual),
+        None => !acceptable.is_empty(),
+    };
+    let endpoint = endpoint_drifted.then(|| FieldDrift {
+        expected: peer.endpoint.as_ref().map(ToString::to_string),
+        actual: info.config.endpoint.map(|addr| addr.to_string()),
+    });
+
+    // Devices report a disabled keepalive as an interval of 0.
+    let actual_keepalive = info
+        .config
+        .persistent_keepalive_interval
+        .filter(|&secs| secs != 0);
+    let keepalive =
+        (peer.persistent_keepalive_interval != actual_keepalive).then_some(FieldDrift {
+            expected: peer.persistent_keepalive_interval,
+            actual: actual_keepalive,
+        });
+
+    if allowed_ips.is_none() && endpoint.is_none() && keepalive.is_none() {
+        return None;
+    }
+    Some(PeerDrift {
+        name: peer.name.to_string(),
+        public_key: peer.public_key.clone(),
+        allowed_ips,
+        endpoint,
+        persistent_keepalive_interval: keepalive,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use wireguard_control::{Key, PeerConfigBuilder};
+
+    fn peer(id: i64, public_key: &Key, ip: &str) -> Peer {
+        let mut peer = test::peer(id, &format!("peer{id}"), &public_key.to_base64(), ip);
+        peer.contents.endpoint = Some("203.0.113.1:51820".parse().unwrap());
+        peer.contents.persistent_keepalive_interval = Some(25);
+        peer.contents.candidates = vec!["192.168.1.10:51820".parse().unwrap()];
+        peer
+    }
+
+    fn live(peer: &Peer, endpoint: Option<&str>) -> PeerInfo {
+        let mut builder = PeerConfigBuilder::from(peer);
+        if let Some(endpoint) = endpoint {
+            builder = builder.set_endpoint(endpoint.parse().unwrap());
+        }
+        PeerInfo {
+            config: builder.into_peer_config(),
+            stats: Default::default(),
+        }
+    }
+
+    fn resolve(endpoint: &Endpoint) -> Option<SocketAddr> {
+        endpoint.resolve().ok()
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let key = Key::generate_private().get_public();
+        let peer = peer(1, &key, "10.0.0.1");
+        // A roamed-to candidate isn't drift.
+        let info = live(&peer, Some("192.168.1.10:51820"));
+        let report = DriftReport::new(&[peer], &[info], None, resolve);
+        assert!(report.is_empty(), "{report:?}");
+    }
+
+    #[test]
+    fn test_missing_and_unexpected_peers() {
+        let own = Key::generate_private().get_public();
+        let (a, b, c) = (
+            Key::generate_private().get_public(),
+            Key::generate_private().get_public(),
+            Key::generate_private().get_public(),
+        );
+        let me = peer(1, &own, "10.0.0.1");
+        let wanted = peer(2, &a, "10.0.0.2");
+        let mut disabled = peer(3, &b, "10.0.0.3");
+        disabled.is_disabled = true;
+        let stranger = peer(4, &c, "10.0.0.4");
+
+        let report = DriftReport::new(
+            &[me, wanted.clone(), disabled.clone()],
+            &[live(&disabled, None), live(&stranger, None)],
+            Some(&own.to_base64()),
+            resolve,
+        );
+        assert_eq!(
+            report.missing,
+            vec![PeerRef {
+                name: Some("peer2".into()),
+                public_key: a.to_base64()
+            }]
+        );
+        assert_eq!(
+            report.unexpected,
+            vec![
+                PeerRef {
+                    name: Some("peer3".into()),
+                    public_key: b.to_base64()
+                },
+                PeerRef {
+                    name: None,
+                    public_key: c.to_base64()
+                },
+            ]
+        );
+        assert!(report.changed.is_empty());
+    }
+
+    #[test]
+    fn test_changed_peer() {
+        let key = Key::generate_private().get_public();
+        let peer = peer(1, &key, "10.0.0.1");
+        let mut device_side = peer.clone();
+        device_side.persistent_keepalive_interval = None;
+        device_side.advertised_routes = vec!["10.99.0.0/16".parse().unwrap()];
+        let info = live(&device_side, Some("198.51.100.7:40000"));
+
+        let report = DriftReport::new(&[peer], &[info], None, resolve);
+        assert_eq!(
+            report.changed,
+            vec![PeerDrift {
+                name: "peer1".into(),
+                public_key: key.to_base64(),
+                allowed_ips: Some(FieldDrift {
+                    expected: vec!["10.0.0.1/32".parse().unwrap()],
+                    actual: vec![
+                        "10.0.0.1/32".parse().unwrap(),
+                        "10.99.0.0/16".parse().unwrap()
+                    ],
+                }),
+                endpoint: Some(FieldDrift {
+                    expected: Some("203.0.113.1:51820".into()),
+                    actual: Some("198.51.100.7:40000".into()),
+                }),
+                persistent_keepalive_interval: Some(FieldDrift {
+                    expected: Some(25),
+                    actual: None,
+                }),
+            }]
+        );
+    }
+}
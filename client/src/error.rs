@@ -0,0 +1,99 @@
+//! Errors from fetching and bringing up interfaces, sorted by what a caller can do about
+//! them: fix the config, retry later, get re-invited, or wait for the server to recover.
+//!
+//! They're wrapped in `anyhow::Error` like everything else, so find them with
+//! [`ClientError::find`], which also looks through any context added on the way up.
+
+use shared::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The interface's config (or an invitation) couldn't be read, or isn't valid.
+    Config(Error),
+    /// The server couldn't be reached: its name didn't resolve, the connection failed, or
+    /// it didn't answer in time.
+    Network(Error),
+    /// The server refused this peer (ex. it was disabled, or its key isn't known).
+    Auth(Error),
+    /// The server refused the request itself (any other 4xx, ex. a conflict), which
+    /// sending it again won't change.
+    Request(Error),
+    /// The server answered with any other error, usually a 5xx.
+    Server(Error),
+}
+
+impl ClientError {
+    /// Sort an error from a call to the server's API.
+    pub fn from_http(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(401 | 403, _) => Self::Auth(e.into()),
+            ureq::Error::Status(400..=499, _) => Self::Request(e.into()),
+            ureq::Error::Status(..) => Self::Server(e.into()),
+            ureq::Error::Transport(_) => Self::Network(e.into()),
+        }
+    }
+
+    /// The first `ClientError` in `e`'s chain, if there is one.
+    pub fn find(e: &Error) -> Option<&Self> {
+        e.chain().find_map(|e| e.downcast_ref())
+    }
+
+    pub fn inner(&self) -> &Error {
+        match self {
+            Self::Config(e)
+            | Self::Network(e)
+            | Self::Auth(e)
+            | Self::Request(e)
+            | Self::Server(e) => e,
+        }
+    }
+
+    /// What `innernet` exits with when this stopped it, so wrappers can branch on it.
+    /// Anything else exits with 1 (and clap uses 2 for usage errors).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 3,
+            Self::Network(_) => 4,
+            Self::Auth(_) => 5,
+            Self::Server(_) => 6,
+            Self::Request(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_from_http() {
+        let status = |code| {
+            ClientError::from_http(ureq::Error::Status(
+                code,
+                ureq::Response::new(code, "", "").unwrap(),
+            ))
+        };
+        assert!(matches!(status(401), ClientError::Auth(_)));
+        assert!(matches!(status(409), ClientError::Request(_)));
+        assert!(matches!(status(503), ClientError::Server(_)));
+
+        let e = Error::from(ClientError::Config(anyhow!("bad config"))).context("fetch failed");
+        assert_eq!(ClientError::find(&e).map(ClientError::exit_code), Some(3));
+        assert_eq!(format!("{e:#}"), "fetch failed: bad config");
+        assert!(ClientError::find(&anyhow!("other")).is_none());
+    }
+}
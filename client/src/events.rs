@@ -0,0 +1,205 @@
+//! `--events-json`, which reports what the fetch loop does as newline-delimited JSON on
+//! stdout, for programs (ex. a GUI) that want to follow along without polling `list`.
+//!
+//! Log messages move to stderr while it's on, so that stdout only ever has events. With
+//! the flag off, nothing here does anything.
+
+use crate::data_store::DataStore;
+use innernet_client::fetch::{PeerUpdate, UpdateKind};
+use serde::Serialize;
+use shared::{wg::PeerInfoExt, Error, PeerChange};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+use wireguard_control::{InterfaceName, PeerInfo};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The peers each interface had a live handshake with as of its last fetch.
+static CONNECTED: Mutex<Option<HashMap<String, HashSet<String>>>> = Mutex::new(None);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct PeerId {
+    /// The innernet peer name, if the public key belongs to a known peer.
+    pub name: Option<String>,
+    pub public_key: String,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PeerAdded {
+        peer: PeerId,
+    },
+    PeerRemoved {
+        peer: PeerId,
+    },
+    EndpointChanged {
+        peer: PeerId,
+        old: Option<SocketAddr>,
+        new: Option<SocketAddr>,
+    },
+    HandshakeEstablished {
+        peer: PeerId,
+    },
+    FetchSucceeded,
+    FetchFailed {
+        error: String,
+    },
+}
+
+/// A single line of output.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Milliseconds since the Unix epoch.
+    timestamp_ms: u128,
+    interface: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+fn render(interface: &InterfaceName, event: &Event, time: SystemTime) -> Result<String, Error> {
+    Ok(serde_json::to_string(&Record {
+        timestamp_ms: time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        interface: interface.to_string(),
+        event,
+    })?)
+}
+
+pub fn emit(interface: &InterfaceName, event: Event) {
+    if !is_enabled() {
+        return;
+    }
+    match render(interface, &event, SystemTime::now()) {
+        Ok(line) => {
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "{line}").and_then(|()| stdout.flush());
+        },
+        Err(e) => log::warn!("failed to serialize event ({}).", e),
+    }
+}
+
+/// Report how a fetch went, passing its result through.
+pub fn fetched(interface: &InterfaceName, result: Result<(), Error>) -> Result<(), Error> {
+    let event = match &result {
+        Ok(()) => Event::FetchSucceeded,
+        Err(e) => Event::FetchFailed {
+            error: format!("{e:#}"),
+        },
+    };
+    emit(interface, event);
+    result
+}
+
+/// The events a change to a single peer on the interface amounts to.
+pub fn from_peer_update(store: &DataStore, update: &PeerUpdate) -> Vec<Event> {
+    let public_key = update.public_key.clone();
+    let name = match &update.peer {
+        Some(peer) => Some(peer.name.to_string()),
+        None => store
+            .peers()
+            .iter()
+            .find(|p| p.public_key == public_key)
+            .map(|p| p.name.to_string()),
+    };
+    let peer = PeerId { name, public_key };
+
+    match (update.kind, &update.peer) {
+        (UpdateKind::Added, _) => vec![Event::PeerAdded { peer }],
+        (UpdateKind::Removed, _) => vec![Event::PeerRemoved { peer }],
+        (_, Some(new)) if new.is_disabled => vec![Event::PeerRemoved { peer }],
+        _ => update
+            .changes
+            .iter()
+            .filter_map(|change| match change {
+                PeerChange::Endpoint { old, new } => Some(Event::EndpointChanged {
+                    peer: peer.clone(),
+                    old: *old,
+                    new: *new,
+                }),
+                _ => None,
+            })
+            .collect(),
+    }
+}
+
+/// Report the peers that have a live handshake now, but didn't as of the last call for
+/// this interface.
+pub fn handshakes(interface: &InterfaceName, store: &DataStore, peers: &[PeerInfo]) {
+    if !is_enabled() {
+        return;
+    }
+    let connected = peers
+        .iter()
+        .filter(|info| info.is_recently_connected())
+        .map(|info| info.config.public_key.to_base64())
+        .collect::<HashSet<_>>();
+    let previous = CONNECTED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(interface.to_string(), connected.clone())
+        .unwrap_or_default();
+    for public_key in connected.difference(&previous) {
+        let name = store
+            .peers()
+            .iter()
+            .find(|p| &p.public_key == public_key)
+            .map(|p| p.name.to_string());
+        emit(
+            interface,
+            Event::HandshakeEstablished {
+                peer: PeerId {
+                    name,
+                    public_key: public_key.clone(),
+                },
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() -> Result<(), Error> {
+        let interface: InterfaceName = "office".parse()?;
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+        let event = Event::EndpointChanged {
+            peer: PeerId {
+                name: Some("laptop".into()),
+                public_key: "key".into(),
+            },
+            old: None,
+            new: Some("203.0.113.1:51820".parse()?),
+        };
+        assert_eq!(
+            render(&interface, &event, time)?,
+            r#"{"timestamp_ms":1700000000123,"interface":"office","event":"endpoint_changed","peer":{"name":"laptop","public_key":"key"},"old":null,"new":"203.0.113.1:51820"}"#
+        );
+        assert_eq!(
+            render(&interface, &Event::FetchSucceeded, time)?,
+            r#"{"timestamp_ms":1700000000123,"interface":"office","event":"fetch_succeeded"}"#
+        );
+        Ok(())
+    }
+}
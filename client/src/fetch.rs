@@ -0,0 +1,543 @@
+//! Fetching an interface's peers from the server and applying them, for programs that embed
+//! the client in an async runtime.
+//!
+//! [`fetch_and_apply`] is cancel-safe: the only await points are the request to the server,
+//! and the interface is changed afterwards in a single [`DeviceUpdate`], so dropping the
+//! future before it completes leaves the interface as it was. [`fetch_and_apply_blocking`]
+//! runs it for callers without a runtime of their own.
+
+use crate::error::ClientError;
+use anyhow::anyhow;
+use flate2::read::GzDecoder;
+use hyper::{
+    body::{self, Buf},
+    client::HttpConnector,
+    header, Body, Client, Request, StatusCode,
+};
+use ipnet::IpNet;
+use shared::{
+    interface_config::{InterfaceConfig, ServerInfo},
+    wg::{self, DeviceExt},
+    Error, Peer, PeerChange, PeerDiff, State, INNERNET_PUBKEY_HEADER,
+};
+use std::{
+    future::Future,
+    io,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, PeerConfigBuilder};
+
+/// How long to wait before each retry, multiplied by the attempt number.
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// After a read-only request failed with `error` on its `attempt`th retry (0 for the first
+/// try), count another attempt and return how long to wait before it, or `None` if all of
+/// its `retries` are used up.
+pub fn next_retry(
+    request: &str,
+    error: &dyn std::fmt::Display,
+    attempt: &mut u32,
+    retries: u32,
+) -> Option<Duration> {
+    if *attempt >= retries {
+        return None;
+    }
+    *attempt += 1;
+    log::warn!(
+        "{} failed ({}), retrying ({}/{}).",
+        request,
+        error,
+        attempt,
+        retries
+    );
+    Some(RETRY_DELAY * *attempt)
+}
+
+/// What happened to a peer on the interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// A change made to one of the interface's peers.
+#[derive(Debug, Clone)]
+pub struct PeerUpdate {
+    pub kind: UpdateKind,
+    pub public_key: String,
+    /// The peer as the server reported it, or `None` if it was removed.
+    pub peer: Option<Peer>,
+    pub changes: Vec<PeerChange>,
+}
+
+/// The outcome of [`fetch_and_apply`].
+#[derive(Debug, Clone)]
+pub struct FetchSummary {
+    /// What the server reported, or `None` if nothing changed since the ETag passed in.
+    pub state: Option<State>,
+    /// The ETag to pass to the next fetch.
+    pub etag: Option<String>,
+    /// The changes made to the interface, empty if it was already up to date.
+    pub updates: Vec<PeerUpdate>,
+}
+
+/// Fetch the state from the interface's server, and bring its peers up to date with it.
+///
+/// `etag` is the one returned by the last fetch, if the interface still has the peers it
+/// brought.
+pub async fn fetch_and_apply(
+    interface: &InterfaceName,
+    backend: Backend,
+    config: &InterfaceConfig,
+    etag: Option<&str>,
+) -> Result<FetchSummary, Error> {
+    let Some((state, etag)) = get_state(&config.server, etag).await? else {
+        return Ok(FetchSummary {
+            state: None,
+            etag: etag.map(String::from),
+            updates: vec![],
+        });
+    };
+
+    // Nothing is awaited from here on, so the interface is either updated or left alone.
+    let updates = apply(interface, backend, config, &state.peers)?;
+    Ok(FetchSummary {
+        state: Some(state),
+        etag,
+        updates,
+    })
+}
+
+/// [`fetch_and_apply`], blocking on a runtime of its own.
+pub fn fetch_and_apply_blocking(
+    interface: &InterfaceName,
+    backend: Backend,
+    config: &InterfaceConfig,
+    etag: Option<&str>,
+) -> Result<FetchSummary, Error> {
+    block_on(fetch_and_apply(interface, backend, config, etag))?
+}
+
+/// Run a future to completion on a single-threaded runtime.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output, io::Error> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future))
+}
+
+/// GET `/user/state`, or `None` if the server says it hasn't changed since `etag`.
+/// Like every read-only request, it's retried if the server can't be reached in time.
+pub async fn get_state(
+    server: &ServerInfo,
+    etag: Option<&str>,
+) -> Result<Option<(State, Option<String>)>, ClientError> {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(server.api_connect_timeout()));
+    let client = Client::builder().build::<_, Body>(connector);
+
+    let retries = server.api_retries();
+    let mut attempt = 0;
+    loop {
+        let request = Request::get(format!("http://{}/v1/user/state", server.internal_endpoint))
+            .header(INNERNET_PUBKEY_HEADER, &server.public_key)
+            .header(header::ACCEPT_ENCODING, "gzip");
+        let request = match etag {
+            Some(etag) => request.header(header::IF_NONE_MATCH, etag),
+            None => request,
+        }
+        .body(Body::empty())
+        .map_err(|e| ClientError::Config(e.into()))?;
+
+        let error = match tokio::time::timeout(server.api_read_timeout(), async {
+            let response = client.request(request).await?;
+            let status = response.status();
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(String::from);
+            let gzipped = response
+                .headers()
+                .get(header::CONTENT_ENCODING)
+                .is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"gzip"));
+            let body = body::aggregate(response.into_body()).await?;
+            Ok::<_, hyper::Error>((status, etag, gzipped, body))
+        })
+        .await
+        {
+            Ok(Ok((StatusCode::NOT_MODIFIED, ..))) => return Ok(None),
+            Ok(Ok((status, etag, gzipped, body))) if status.is_success() => {
+                let state = if gzipped {
+                    serde_json::from_reader(GzDecoder::new(body.reader()))
+                } else {
+                    serde_json::from_reader(body.reader())
+                }
+                .map_err(|e| {
+                    ClientError::Server(anyhow!(
+                        "failed to deserialize JSON response from the server: {}",
+                        e
+                    ))
+                })?;
+                return Ok(Some((state, etag)));
+            },
+            Ok(Ok((status, ..))) => {
+                let e = anyhow!("the server answered {}", status);
+                return Err(match status.as_u16() {
+                    401 | 403 => ClientError::Auth(e),
+                    400..=499 => ClientError::Request(e),
+                    _ => ClientError::Server(e),
+                });
+            },
+            Ok(Err(e)) => io::Error::other(e),
+            Err(_) => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "timed out waiting for the server at {}",
+                    server.internal_endpoint
+                ),
+            ),
+        };
+        match next_retry("GET /user/state", &error, &mut attempt, retries) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return Err(ClientError::Network(error.into())),
+        }
+    }
+}
+
+/// Bring the interface's peers up to date with `peers` (as the server reported them), in a
+/// single update.
+pub fn apply(
+    interface: &InterfaceName,
+    backend: Backend,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+) -> Result<Vec<PeerUpdate>, Error> {
+    apply_diffs(interface, backend, config, peers, true)
+}
+
+/// [`apply`], but only adding and updating peers, and leaving any that aren't in `peers` on
+/// the interface, for interfaces that defer removals.
+pub fn apply_additive(
+    interface: &InterfaceName,
+    backend: Backend,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+) -> Result<Vec<PeerUpdate>, Error> {
+    apply_diffs(interface, backend, config, peers, false)
+}
+
+fn apply_diffs(
+    interface: &InterfaceName,
+    backend: Backend,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    remove: bool,
+) -> Result<Vec<PeerUpdate>, Error> {
+    let device = Device::get(interface, backend)?;
+    let public_key = device.public_key.as_ref().map(|key| key.to_base64());
+    let device_peers = device_peers(config, peers, public_key.as_deref());
+    let diffs = without_deferred_removals(device.diff(&device_peers), remove);
+
+    let updates = diffs
+        .iter()
+        .map(|diff| PeerUpdate {
+            kind: match (diff.old, diff.new) {
+                (None, _) => UpdateKind::Added,
+                (Some(_), Some(_)) => UpdateKind::Modified,
+                (Some(_), None) => UpdateKind::Removed,
+            },
+            public_key: diff.public_key().to_base64(),
+            peer: diff.new.cloned(),
+            changes: diff.changes().to_vec(),
+        })
+        .collect();
+    let builders: Vec<_> = diffs.into_iter().map(PeerConfigBuilder::from).collect();
+    if !builders.is_empty() {
+        DeviceUpdate::new()
+            .add_peers(&builders)
+            .apply(interface, backend)
+            .map_err(|e| anyhow!("failed to update {}: {}", interface, e))?;
+    }
+    Ok(updates)
+}
+
+/// `diffs`, minus the ones removing peers unless `remove` is set. Peers the server stopped
+/// listing (ex. because it disabled them) stay on the interface until removals are due.
+fn without_deferred_removals(mut diffs: Vec<PeerDiff>, remove: bool) -> Vec<PeerDiff> {
+    if !remove {
+        let count = diffs.len();
+        diffs.retain(|diff| diff.new.is_some());
+        if diffs.len() < count {
+            log::info!(
+                "leaving {} peer(s) the server no longer lists until removals are due.",
+                count - diffs.len()
+            );
+        }
+    }
+    diffs
+}
+
+/// With removals deferred to every `removal_interval` seconds, whether a fetch at `now` is
+/// due to remove the peers the server no longer lists, given when that was last done
+/// (`reconciled_at`). `None` if removals aren't deferred, so every fetch makes them.
+pub fn removals_due(
+    removal_interval: Option<u64>,
+    reconciled_at: Option<SystemTime>,
+    now: SystemTime,
+) -> Option<bool> {
+    removal_interval.map(|interval| {
+        reconciled_at.is_none_or(|at| {
+            now.duration_since(at).unwrap_or_default() >= Duration::from_secs(interval)
+        })
+    })
+}
+
+/// The peers (as the server reported them) that should be configured on the interface,
+/// given the local config.
+pub fn device_peers(
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    public_key: Option<&str>,
+) -> Vec<Peer> {
+    // Observers only keep their tunnel to the server, which is all they need to reach the API.
+    let is_observer = peers
+        .iter()
+        .any(|peer| Some(peer.public_key.as_str()) == public_key && peer.is_observer);
+    let mut device_peers = if is_observer {
+        peers
+            .iter()
+            .filter(|peer| peer.ip == config.server.internal_endpoint.ip())
+            .cloned()
+            .collect()
+    } else {
+        peers.to_vec()
+    };
+    if config.interface.metered {
+        suppress_keepalives(&mut device_peers, config.server.internal_endpoint.ip());
+    }
+    if config.interface.full_tunnel {
+        route_everything_through(&mut device_peers, config.server.internal_endpoint.ip());
+    }
+    device_peers
+}
+
+/// Add routes through the interface for the subnets its peers advertise (see
+/// [`advertised_routes`]), in the interface's routing table if it has one. Returns the
+/// routes that weren't there yet. Routes a peer stops advertising are left in place, since
+/// WireGuard drops traffic no peer's allowed IPs cover anyway.
+pub fn add_advertised_routes(
+    interface: &InterfaceName,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    public_key: Option<&str>,
+) -> Result<Vec<IpNet>, io::Error> {
+    let device_peers = device_peers(config, peers, public_key);
+    let mut added = vec![];
+    for route in advertised_routes(config.interface.address.trunc(), &device_peers, public_key) {
+        if wg::add_route(interface, route, config.interface.route_table)? {
+            log::info!("added route to {} through {}.", route, interface);
+            added.push(route);
+        }
+    }
+    Ok(added)
+}
+
+/// The subnets `peers` (other than the one with `public_key`) advertise that need a route
+/// of their own, ie. those outside of the `network`, which is already routed through the
+/// interface. Default routes are left to full-tunnel mode.
+fn advertised_routes(network: IpNet, peers: &[Peer], public_key: Option<&str>) -> Vec<IpNet> {
+    let mut routes = vec![];
+    for peer in peers
+        .iter()
+        .filter(|peer| Some(peer.public_key.as_str()) != public_key)
+    {
+        for route in &peer.advertised_routes {
+            if route.prefix_len() > 0 && !network.contains(route) && !routes.contains(route) {
+                routes.push(*route);
+            }
+        }
+    }
+    routes
+}
+
+/// Give the server the default routes, so that a full tunnel keeps them across fetches.
+fn route_everything_through(peers: &mut [Peer], server_ip: IpAddr) {
+    for peer in peers.iter_mut().filter(|peer| peer.ip == server_ip) {
+        // The default networks are 0.0.0.0/0 and ::/0.
+        for route in [IpNet::V4(Default::default()), IpNet::V6(Default::default())] {
+            if !peer.advertised_routes.contains(&route) {
+                peer.advertised_routes.push(route);
+            }
+        }
+    }
+}
+
+/// Turn off keepalives for every peer but the server, so that a metered connection only
+/// sends traffic to peers when there's something to send.
+fn suppress_keepalives(peers: &mut [Peer], server_ip: IpAddr) {
+    for peer in peers.iter_mut().filter(|peer| peer.ip != server_ip) {
+        peer.contents.persistent_keepalive_interval = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+    use wireguard_control::Key;
+
+    #[test]
+    fn test_get_state() {
+        // A server that answers from the request's headers alone.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = |public_key: &str| ServerInfo {
+            public_key: public_key.into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+            preshared_key: None,
+            connect_timeout: Some(1),
+            read_timeout: Some(1),
+            retries: Some(0),
+            allowed_ips: None,
+        };
+        let (good, bad) = (server("key"), server("stale"));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+                let response = if request.contains("x-innernet-server-key: stale") {
+                    b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n".to_vec()
+                } else if request.contains("if-none-match: \"1\"") {
+                    b"HTTP/1.1 304 Not Modified\r\n\r\n".to_vec()
+                } else {
+                    // The state comes gzipped, as the real server sends it when asked to.
+                    assert!(request.contains("accept-encoding: gzip"));
+                    let mut encoder = GzEncoder::new(vec![], Compression::default());
+                    encoder.write_all(br#"{"peers":[],"cidrs":[]}"#).unwrap();
+                    let body = encoder.finish().unwrap();
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\netag: \"1\"\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend(body);
+                    response
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        let (state, etag) = block_on(get_state(&good, None)).unwrap().unwrap().unwrap();
+        assert!(state.peers.is_empty());
+        assert_eq!(etag.as_deref(), Some("\"1\""));
+        assert!(block_on(get_state(&good, etag.as_deref()))
+            .unwrap()
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            block_on(get_state(&bad, None)).unwrap(),
+            Err(ClientError::Auth(_))
+        ));
+    }
+
+    fn peer(name: &str, ip: &str) -> Peer {
+        let mut peer = test::peer(0, name, name, ip);
+        peer.contents.persistent_keepalive_interval = Some(25);
+        peer
+    }
+
+    #[test]
+    fn test_local_overrides() {
+        let mut peers = vec![
+            peer("innernet-server", "10.42.0.1"),
+            peer("laptop", "10.42.1.2"),
+        ];
+        suppress_keepalives(&mut peers, "10.42.0.1".parse().unwrap());
+        assert_eq!(peers[0].persistent_keepalive_interval, Some(25));
+        assert_eq!(peers[1].persistent_keepalive_interval, None);
+
+        route_everything_through(&mut peers, "10.42.0.1".parse().unwrap());
+        route_everything_through(&mut peers, "10.42.0.1".parse().unwrap());
+        assert_eq!(
+            peers[0].advertised_routes,
+            vec![
+                "0.0.0.0/0".parse::<IpNet>().unwrap(),
+                "::/0".parse().unwrap()
+            ]
+        );
+        assert!(peers[1].advertised_routes.is_empty());
+    }
+
+    #[test]
+    fn test_removals_due() {
+        let now = SystemTime::now();
+        let ago = |secs| Some(now - Duration::from_secs(secs));
+        assert_eq!(removals_due(None, ago(0), now), None);
+
+        // Never having reconciled counts as overdue.
+        assert_eq!(removals_due(Some(3600), None, now), Some(true));
+        assert_eq!(removals_due(Some(3600), ago(60), now), Some(false));
+        assert_eq!(removals_due(Some(3600), ago(3600), now), Some(true));
+    }
+
+    #[test]
+    fn test_deferred_removals() {
+        let keys: Vec<_> = (0..2)
+            .map(|_| Key::generate_private().get_public())
+            .collect();
+        let mut added = peer("laptop", "10.42.1.2");
+        added.contents.public_key = keys[0].to_base64();
+        // The server disabled this one, so it no longer lists it.
+        let disabled = test::info(&keys[1].to_base64(), None);
+        let diffs = || {
+            vec![
+                PeerDiff::new(None, Some(&added)).unwrap().unwrap(),
+                PeerDiff::new(Some(&disabled), None).unwrap().unwrap(),
+            ]
+        };
+
+        // Until removals are due, it stays on the interface while new peers still get added.
+        let kept = without_deferred_removals(diffs(), false);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].new, Some(&added));
+        assert_eq!(without_deferred_removals(diffs(), true).len(), 2);
+    }
+
+    #[test]
+    fn test_advertised_routes() {
+        let network: IpNet = "10.42.0.0/16".parse().unwrap();
+        let route = |net: &str| net.parse::<IpNet>().unwrap();
+        let mut peers = vec![
+            peer("innernet-server", "10.42.0.1"),
+            peer("office", "10.42.1.2"),
+            peer("laptop", "10.42.1.3"),
+        ];
+        peers[0].contents.advertised_routes = vec![route("0.0.0.0/0"), route("192.168.1.0/24")];
+        peers[1].contents.advertised_routes = vec![route("192.168.1.0/24"), route("10.42.5.0/24")];
+        peers[2].contents.advertised_routes = vec![route("172.16.0.0/12")];
+
+        // Default routes, routes inside the network, repeats, and our own routes are skipped.
+        assert_eq!(
+            advertised_routes(network, &peers, Some("laptop")),
+            vec![route("192.168.1.0/24")]
+        );
+        assert_eq!(
+            advertised_routes(network, &peers, None),
+            vec![route("192.168.1.0/24"), route("172.16.0.0/12")]
+        );
+    }
+}
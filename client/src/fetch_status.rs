@@ -0,0 +1,217 @@
+//! A small per-interface file that the fetch loops rewrite after every fetch, so that
+//! monitoring can alert on an interface that stopped syncing with a file-age check, and
+//! tell a down server from a revoked peer by the recorded error category.
+
+use innernet_client::error::ClientError;
+use serde::{Deserialize, Serialize};
+use shared::{Error, IoErrorContext, WrappedIoError};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use wireguard_control::InterfaceName;
+
+/// Why a fetch failed, coarsely enough for a monitor to act on.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    /// The server's endpoint couldn't be resolved.
+    Dns,
+    /// The server couldn't be reached, or didn't answer in time.
+    Connect,
+    /// The server refused this peer (ex. it was disabled or its key revoked).
+    Auth,
+    /// The server refused the request itself (a 4xx other than an auth failure).
+    Request,
+    /// The server answered with any other error.
+    ServerError,
+    /// The interface's config couldn't be read, or isn't valid.
+    Config,
+    /// Anything else, usually a local problem.
+    Other,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FetchStatus {
+    /// Unix timestamp of the last fetch.
+    pub last_fetch: u64,
+    /// Unix timestamp of the last successful fetch.
+    pub last_success: Option<u64>,
+    /// How many fetches in a row have failed.
+    pub failures: u32,
+    /// Why the last fetch failed, if it did.
+    pub error: Option<ErrorCategory>,
+    /// The last fetch's error message, if it failed.
+    pub message: Option<String>,
+}
+
+impl FetchStatus {
+    pub fn path(dir: &Path, interface: &InterfaceName) -> PathBuf {
+        dir.join(interface.to_string())
+            .with_extension("fetch-status")
+    }
+
+    /// Fold in the outcome of another fetch.
+    fn record(&mut self, now: u64, result: &Result<(), Error>) {
+        self.last_fetch = now;
+        match result {
+            Ok(()) => {
+                self.last_success = Some(now);
+                self.failures = 0;
+                self.error = None;
+                self.message = None;
+            },
+            Err(e) => {
+                self.failures += 1;
+                self.error = Some(categorize(e));
+                self.message = Some(format!("{e:#}"));
+            },
+        }
+    }
+
+    /// Replace the file by renaming a fully written one over it, so a reader never sees it
+    /// half-written.
+    fn write_to_path(&self, path: &Path) -> Result<(), Error> {
+        let tmp = path.with_extension("fetch-status.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?).with_path(&tmp)?;
+        fs::rename(&tmp, path).with_path(path)?;
+        Ok(())
+    }
+}
+
+/// Record how an interface's fetch went in its status file under `dir`. Failing to write it
+/// is only logged, since it mustn't stop the fetch loop.
+pub fn record(dir: &Path, interface: &InterfaceName, result: &Result<(), Error>) {
+    let path = FetchStatus::path(dir, interface);
+    let mut status = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<FetchStatus>(&json).ok())
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    status.record(now, result);
+    if let Err(e) = status.write_to_path(&path) {
+        log::warn!("failed to write fetch status for {}: {}", interface, e);
+    }
+}
+
+pub fn categorize(e: &Error) -> ErrorCategory {
+    match ClientError::find(e) {
+        Some(ClientError::Config(_)) => return ErrorCategory::Config,
+        Some(ClientError::Auth(_)) => return ErrorCategory::Auth,
+        Some(ClientError::Request(_)) => return ErrorCategory::Request,
+        Some(ClientError::Server(_)) => return ErrorCategory::ServerError,
+        // Tell a name that didn't resolve from a server that didn't answer.
+        Some(ClientError::Network(inner)) => {
+            return match categorize(inner) {
+                ErrorCategory::Dns => ErrorCategory::Dns,
+                _ => ErrorCategory::Connect,
+            }
+        },
+        None => {},
+    }
+    if let Some(e) = e.downcast_ref::<ureq::Error>() {
+        return match e {
+            ureq::Error::Status(401 | 403, _) => ErrorCategory::Auth,
+            ureq::Error::Status(400..=499, _) => ErrorCategory::Request,
+            ureq::Error::Status(..) => ErrorCategory::ServerError,
+            ureq::Error::Transport(t) if t.kind() == ureq::ErrorKind::Dns => ErrorCategory::Dns,
+            ureq::Error::Transport(_) => ErrorCategory::Connect,
+        };
+    }
+    let io_error = e
+        .downcast_ref::<WrappedIoError>()
+        .map(|e| &**e)
+        .or_else(|| e.downcast_ref::<io::Error>());
+    match io_error {
+        // Name lookups don't get an ErrorKind of their own, only a message.
+        Some(e)
+            if e.to_string().contains("failed to lookup address")
+                || e.to_string().contains("failed to resolve address") =>
+        {
+            ErrorCategory::Dns
+        },
+        Some(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::TimedOut
+            ) =>
+        {
+            ErrorCategory::Connect
+        },
+        _ => ErrorCategory::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_categorize() {
+        let status = |code| {
+            Error::from(ureq::Error::Status(
+                code,
+                ureq::Response::new(code, "", "").unwrap(),
+            ))
+        };
+        assert_eq!(categorize(&status(401)), ErrorCategory::Auth);
+        assert_eq!(categorize(&status(500)), ErrorCategory::ServerError);
+
+        let io = |kind, msg: &str| Error::from(io::Error::new(kind, msg.to_string()));
+        assert_eq!(
+            categorize(&io(io::ErrorKind::ConnectionRefused, "refused")),
+            ErrorCategory::Connect
+        );
+        assert_eq!(
+            categorize(&io(
+                io::ErrorKind::AddrNotAvailable,
+                "failed to resolve address"
+            )),
+            ErrorCategory::Dns
+        );
+        assert_eq!(categorize(&anyhow!("bad config")), ErrorCategory::Other);
+        assert_eq!(
+            categorize(&ClientError::Config(anyhow!("bad config")).into()),
+            ErrorCategory::Config
+        );
+        assert_eq!(
+            categorize(&ClientError::Network(io(io::ErrorKind::TimedOut, "timed out")).into()),
+            ErrorCategory::Connect
+        );
+    }
+
+    #[test]
+    fn test_record() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let interface: InterfaceName = "office".parse()?;
+        let path = FetchStatus::path(dir.path(), &interface);
+        let read = || -> Result<FetchStatus, Error> {
+            Ok(serde_json::from_str(&fs::read_to_string(&path)?)?)
+        };
+
+        record(dir.path(), &interface, &Ok(()));
+        let success = read()?.last_success.expect("a successful fetch");
+        record(dir.path(), &interface, &Err(anyhow!("bad config")));
+        record(dir.path(), &interface, &Err(anyhow!("bad config")));
+        let status = read()?;
+        assert_eq!(status.last_success, Some(success));
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.error, Some(ErrorCategory::Other));
+
+        record(dir.path(), &interface, &Ok(()));
+        let status = read()?;
+        assert_eq!((status.failures, status.error), (0, None));
+        assert!(!path.with_extension("fetch-status.tmp").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+//! The parts of the innernet client that other programs can embed.
+
+pub mod error;
+pub mod fetch;
+#[cfg(test)]
+mod test;
@@ -4,39 +4,160 @@ use colored::*;
 use dialoguer::{Confirm, Input};
 use hostsfile::HostsBuilder;
 use indoc::eprintdoc;
+use ipnet::IpNet;
+use serde::Serialize;
 use shared::{
-    get_local_addrs,
+    get_local_addrs, human_size, inherited_cidr_default,
     interface_config::InterfaceConfig,
-    prompts,
-    wg::{DeviceExt, PeerInfoExt},
+    prompts::{self, Prefill},
+    reachability,
+    vanilla::VanillaConfig,
+    wg::{HandshakeHealth, PeerInfoExt},
     AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, AssociationContents, Cidr,
-    CidrTree, DeleteCidrOpts, Endpoint, EndpointContents, InstallOpts, Interface, IoErrorContext,
-    ListenPortOpts, NatOpts, NetworkOpts, OverrideEndpointOpts, Peer, RedeemContents,
-    RenamePeerOpts, State, WrappedIoError, REDEEM_TRANSITION_WAIT,
+    CidrTree, DeleteCidrOpts, Endpoint, EndpointContents, Hostname, InstallOpts, Interface,
+    IoErrorContext, KeepaliveContents, ListenPortOpts, NatOpts, NetworkOpts, OverrideEndpointOpts,
+    Peer, PeerDescription, RedeemContents, RenameCidrOpts, RenamePeerOpts, SetKeepaliveOpts, State,
+    WrappedIoError, REDEEM_TRANSITION_WAIT,
 };
 use std::{
-    fmt, io,
-    net::SocketAddr,
+    collections::{BTreeSet, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
+};
+use wireguard_control::{
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
 };
-use wireguard_control::{Device, DeviceUpdate, InterfaceName, PeerConfigBuilder, PeerInfo};
 
+mod daemon;
 mod data_store;
+mod drift;
+mod events;
+mod fetch_status;
+mod mtu_probe;
 mod nat;
+mod resolved;
+mod self_test;
+mod systemd;
+#[cfg(test)]
+mod test;
 mod util;
+mod watch;
 
+use daemon::{DaemonCommand, DaemonOpts};
 use data_store::DataStore;
+use drift::DriftReport;
+use innernet_client::{
+    error::ClientError,
+    fetch::{self as fetcher, device_peers},
+};
 use nat::NatTraverse;
-use shared::{wg, Error};
-use util::{human_duration, human_size, Api};
+use shared::{
+    wg::{self, DeviceExt},
+    Error,
+};
+use util::{human_duration, Api, Backoff};
+use watch::HandshakeTracker;
 
 use crate::util::all_installed;
 
+/// How long the network needs to be quiet after a change before re-fetching, so that a
+/// burst of interface events only causes a single re-report.
+const NETWORK_CHANGE_DEBOUNCE: Duration = Duration::from_secs(2);
+
 struct PeerState<'a> {
     peer: &'a Peer,
     info: Option<&'a PeerInfo>,
+    /// Whether the peer's handshake changed since the last `--watch` redraw.
+    handshake_changed: bool,
+}
+
+/// A single peer as emitted by `innernet list --json`.
+///
+/// Scripts depend on this shape, so fields should only ever be added, never renamed or removed.
+#[derive(Debug, Serialize)]
+struct PeerListEntry {
+    /// The innernet network (interface) the peer belongs to.
+    network: String,
+    name: String,
+    public_key: String,
+    ip: IpAddr,
+    /// The allowed IPs currently configured on the WireGuard interface for this peer.
+    /// Empty for yourself and for peers that aren't on the interface (ex. disabled peers).
+    allowed_ips: Vec<IpNet>,
+    endpoint: Option<String>,
+    /// Seconds since the last WireGuard handshake, if there's been one.
+    last_handshake_secs_ago: Option<u64>,
+    is_disabled: bool,
+    /// Whether the peer is active, staged (never activated yet), or disabled.
+    status: shared::PeerStatus,
+    /// Whether this peer is the local machine.
+    is_you: bool,
+    /// The UNIX time the peer was created, or null if the server didn't record it.
+    created_at: Option<u64>,
+    /// The admin peer that created the peer, or null if the server didn't record it.
+    created_by: Option<String>,
+    /// What the peer reports about itself (hostname, OS, innernet version), if it does.
+    /// Advisory only.
+    description: Option<PeerDescription>,
+}
+
+/// The live WireGuard status of a single peer as emitted by `innernet status --json`.
+#[derive(Debug, Serialize)]
+struct PeerStatus {
+    /// The innernet peer name, if the public key is known in the local peer cache.
+    name: Option<String>,
+    public_key: String,
+    ip: Option<IpAddr>,
+    endpoint: Option<SocketAddr>,
+    /// Seconds since the last WireGuard handshake, if there's been one.
+    last_handshake_secs_ago: Option<u64>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    health: HandshakeHealth,
+}
+
+impl PeerListEntry {
+    fn new(network: &InterfaceName, peer: &Peer, info: Option<&PeerInfo>, is_you: bool) -> Self {
+        let allowed_ips = info
+            .map(|info| {
+                info.config
+                    .allowed_ips
+                    .iter()
+                    .filter_map(|ip| IpNet::new(ip.address, ip.cidr).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let endpoint = info
+            .and_then(|info| info.config.endpoint)
+            .map(|endpoint| endpoint.to_string())
+            .or_else(|| peer.endpoint.as_ref().map(|endpoint| endpoint.to_string()));
+        let last_handshake_secs_ago = info
+            .and_then(|info| info.handshake_age())
+            .map(|age| age.as_secs());
+
+        Self {
+            network: network.to_string(),
+            name: peer.name.to_string(),
+            public_key: peer.public_key.clone(),
+            ip: peer.ip,
+            allowed_ips,
+            endpoint,
+            last_handshake_secs_ago,
+            is_disabled: peer.is_disabled,
+            status: peer.status(),
+            is_you,
+            created_at: peer
+                .created_at
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+            created_by: peer.created_by.clone(),
+            description: peer.description.clone(),
+        }
+    }
 }
 
 macro_rules! println_pad {
@@ -56,16 +177,80 @@ struct Opts {
     #[clap(short, long, action = ArgAction::Count)]
     verbose: u8,
 
-    #[clap(short, long, default_value = "/etc/innernet")]
+    /// Directory holding interface configs. `--config-dir` takes precedence over
+    /// $INNERNET_CONFIG_DIR, which takes precedence over the default
+    #[clap(
+        short,
+        long,
+        env = "INNERNET_CONFIG_DIR",
+        default_value = "/etc/innernet"
+    )]
     config_dir: PathBuf,
 
-    #[clap(short, long, default_value = "/var/lib/innernet")]
+    /// Directory holding peer data. `--data-dir` takes precedence over
+    /// $INNERNET_DATA_DIR, which takes precedence over the default
+    #[clap(
+        short,
+        long,
+        env = "INNERNET_DATA_DIR",
+        default_value = "/var/lib/innernet"
+    )]
     data_dir: PathBuf,
 
+    /// Directory for the `<interface>.fetch-status` files that the fetch loops of
+    /// `up --daemon` and `daemon` rewrite after every fetch, for monitoring. Defaults to the
+    /// data directory
+    #[clap(long, env = "INNERNET_FETCH_STATUS_DIR")]
+    fetch_status_dir: Option<PathBuf>,
+
+    /// Run inside this network namespace (see 'ip netns'), which has to exist already. The
+    /// WireGuard interface is moved there with its routes, and the server's API is reached
+    /// from there, while its encrypted traffic still leaves from the host. Linux only
+    #[clap(long, value_name = "NAME", global = true)]
+    netns: Option<String>,
+
+    /// The namespace `--netns` entered, which interfaces are moved into once created.
+    #[clap(skip)]
+    entered_netns: Option<Arc<wg::Netns>>,
+
     #[clap(flatten)]
     network: NetworkOpts,
 }
 
+impl Opts {
+    fn fetch_status_dir(&self) -> &Path {
+        self.fetch_status_dir.as_deref().unwrap_or(&self.data_dir)
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+struct ShowOpts {
+    /// One-line peer list
+    #[clap(short, long)]
+    short: bool,
+
+    /// Display peers in a tree based on the CIDRs
+    #[clap(short, long)]
+    tree: bool,
+
+    /// Display peers grouped by their tags
+    #[clap(long, conflicts_with = "tree")]
+    by_tag: bool,
+
+    /// Output a JSON array of all peers, including disabled ones
+    #[clap(long, conflicts_with_all = ["short", "tree", "by_tag"])]
+    json: bool,
+
+    /// Only show peers whose name contains this text (case-insensitive)
+    #[clap(long, value_name = "TEXT")]
+    grep: Option<String>,
+
+    /// Redraw the list every few seconds (2 by default), highlighting peers whose
+    /// handshake changed since the last redraw
+    #[clap(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "2", conflicts_with = "json")]
+    watch: Option<u64>,
+}
+
 #[derive(Clone, Debug, Args)]
 struct HostsOpt {
     /// The path to write hosts to
@@ -83,13 +268,28 @@ impl From<HostsOpt> for Option<PathBuf> {
     }
 }
 
+#[derive(Clone, Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the configuration in effect, after the command line and the CIDR's defaults
+    /// are applied. Secrets are redacted
+    Show {
+        interface: Interface,
+
+        /// Output JSON instead of TOML
+        #[clap(long)]
+        json: bool,
+    },
+}
+
 #[derive(Clone, Debug, Subcommand)]
 enum Command {
     /// Install a new innernet config
     #[clap(alias = "redeem")]
     Install {
-        /// Path to the invitation file
-        invite: PathBuf,
+        /// Paths to invitation files, or directories of them (their .toml and .conf files).
+        /// Each one is installed in turn, even if an earlier one fails
+        #[clap(required = true)]
+        invites: Vec<PathBuf>,
 
         #[clap(flatten)]
         hosts: HostsOpt,
@@ -101,20 +301,113 @@ enum Command {
         nat: NatOpts,
     },
 
+    /// Check that an invitation is valid, without installing it
+    ///
+    /// Runs the same checks as install does before changing anything, and exits with 3 if
+    /// the invitation is invalid.
+    Check {
+        /// Path to the invitation file
+        invite: PathBuf,
+    },
+
+    /// Adopt an existing wg-quick config as an innernet interface
+    ///
+    /// The config must have a single peer, the innernet server. If it doesn't have the
+    /// network name and internal endpoint comments innernet writes, you'll be asked for them.
+    Import {
+        /// Path to the wg-quick config
+        config: PathBuf,
+
+        /// The name of the network
+        #[clap(long)]
+        network_name: Option<String>,
+
+        /// The server's internal endpoint for the innernet API (ex. '10.42.0.1:51820')
+        #[clap(long)]
+        internal_endpoint: Option<SocketAddr>,
+    },
+
+    /// Print an interface's config in wg-quick format, for use without innernet
+    ///
+    /// Only the link to the innernet server is included, since other peers come and go.
+    Export {
+        interface: Interface,
+
+        /// Leave out innernet's metadata comments so that stock wg-quick accepts the output
+        /// verbatim. Configs exported this way can't be imported again
+        #[clap(long)]
+        strict: bool,
+
+        /// Only route the parts of these CIDRs that this peer can reach, as of the last fetch
+        #[clap(long, num_args = 1.., value_name = "NAME")]
+        only_cidr: Vec<String>,
+
+        /// Leave the private key out (ex. when it's kept in a hardware token or agent), with
+        /// a commented-out PrivateKey line in its place
+        #[clap(long)]
+        no_private_key: bool,
+
+        /// Route all traffic through the server, with a kill-switch that blocks anything
+        /// that would leave through another interface. Implied by the interface's
+        /// full-tunnel setting
+        #[clap(long)]
+        full_tunnel: bool,
+    },
+
+    /// Inspect an interface's configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+
     /// Enumerate all innernet connections
     #[clap(alias = "list")]
     Show {
-        /// One-line peer list
-        #[clap(short, long)]
-        short: bool,
-
-        /// Display peers in a tree based on the CIDRs
-        #[clap(short, long)]
-        tree: bool,
+        #[clap(flatten)]
+        show_opts: ShowOpts,
 
         interface: Option<Interface>,
     },
 
+    /// Show the handshake health and transfer stats of each peer on a live interface
+    Status {
+        interface: Interface,
+
+        /// Output a JSON array of peer statuses
+        #[clap(long)]
+        json: bool,
+
+        /// Redraw the status every few seconds (2 by default), highlighting peers whose
+        /// handshake changed since the last redraw
+        #[clap(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "2", conflicts_with = "json")]
+        watch: Option<u64>,
+    },
+
+    /// Check whether an interface is working: that it's up and its handshake with the
+    /// server is fresh, and optionally that the server and a couple of peers answer pings.
+    ///
+    /// Prints a pass, warning, or failure for each check, and exits with an error if any
+    /// check failed.
+    Test {
+        interface: Interface,
+
+        /// Also ping the server and the peers with the freshest handshakes. Peers that
+        /// don't answer only warn, since they may just drop pings
+        #[clap(long)]
+        ping: bool,
+    },
+
+    /// Show how the live WireGuard interface differs from the server's peer list.
+    ///
+    /// Exits with an error if there's any drift, for use as a monitoring probe.
+    Diff {
+        interface: Interface,
+
+        /// Output the differences as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Bring up your local interface, and update it with latest peer list
     Up {
         /// Enable daemon mode i.e. keep the process running, while fetching
@@ -127,15 +420,55 @@ enum Command {
         #[clap(long, default_value = "60")]
         interval: u64,
 
+        /// The longest interval in seconds to back off to when fetches keep failing.
+        /// Valid only in daemon mode
+        #[clap(long, default_value = "600")]
+        max_interval: u64,
+
+        /// Randomly vary backoff intervals by up to this fraction (0.0 - 1.0) so peers
+        /// don't retry in lockstep. Valid only in daemon mode
+        #[clap(long, default_value = "0.2")]
+        jitter: f64,
+
         #[clap(flatten)]
         hosts: HostsOpt,
 
         #[clap(flatten)]
         nat: NatOpts,
 
+        /// Register this DNS resolver for the network's domain ('<interface>.wg') with
+        /// systemd-resolved when bringing up the interface. No-op without systemd-resolved
+        #[clap(long)]
+        resolved_dns: Option<IpAddr>,
+
+        /// Also report peer changes, new handshakes, and fetch results as newline-delimited
+        /// JSON on stdout. Log messages go to stderr instead
+        #[clap(long)]
+        events_json: bool,
+
+        /// Print the configuration the interface would be brought up with (using the peers
+        /// from the last fetch) without changing anything
+        #[clap(long, conflicts_with = "daemon")]
+        dry_run: bool,
+
+        /// Once the interface is up, check that packets of its full MTU reach the server,
+        /// and warn with the largest size that does if they don't
+        #[clap(long)]
+        check_mtu: bool,
+
         interface: Option<Interface>,
     },
 
+    /// Keep every installed interface up and updated from a single process, picking up
+    /// newly installed interfaces as they appear
+    Daemon {
+        #[clap(subcommand)]
+        command: Option<DaemonCommand>,
+
+        #[clap(flatten)]
+        daemon_opts: DaemonOpts,
+    },
+
     /// Fetch and update your local interface with the latest peer list
     Fetch {
         interface: Interface,
@@ -147,6 +480,21 @@ enum Command {
         nat: NatOpts,
     },
 
+    /// Rename an installed network's interface, keeping its keys and IP.
+    ///
+    /// If the interface is up, it's brought back up under the new name.
+    RenameInterface {
+        interface: Interface,
+
+        new_name: Interface,
+
+        #[clap(flatten)]
+        hosts: HostsOpt,
+
+        #[clap(flatten)]
+        nat: NatOpts,
+    },
+
     /// Uninstall an innernet network.
     Uninstall {
         interface: Interface,
@@ -185,6 +533,20 @@ enum Command {
         sub_opts: RenamePeerOpts,
     },
 
+    /// Set how often peers send keepalives to a peer
+    ///
+    /// Peers follow the network's default interval unless they're given their own, eg:
+    ///
+    /// --name 'laptop' --interval 10
+    ///
+    /// An interval of 0 disables keepalive for the peer.
+    SetKeepalive {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: SetKeepaliveOpts,
+    },
+
     /// Add a new CIDR
     AddCidr {
         interface: Interface,
@@ -193,6 +555,19 @@ enum Command {
         sub_opts: AddCidrOpts,
     },
 
+    /// Rename a CIDR
+    ///
+    /// By default, you'll be prompted interactively to select a CIDR, but you can
+    /// also specify all the options in the command, eg:
+    ///
+    /// 'old-name' 'new-name' --yes
+    RenameCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: RenameCidrOpts,
+    },
+
     /// Delete a CIDR
     DeleteCidr {
         interface: Interface,
@@ -216,6 +591,21 @@ enum Command {
     /// Enable a disabled peer
     EnablePeer { interface: Interface },
 
+    /// Immediately remove a peer from your local interface, by name or public key.
+    ///
+    /// Other peers' sessions are left intact. The peer comes back on the next fetch
+    /// unless it's also disabled on the server (see 'disable-peer').
+    EvictPeer {
+        interface: Interface,
+
+        /// The name or base64 public key of the peer to remove
+        peer: String,
+    },
+
+    /// Show which peer this interface is as far as the server is concerned: its name, IP,
+    /// CIDR, and the CIDRs it can reach
+    Whoami { interface: Interface },
+
     /// Add an association between CIDRs
     AddAssociation {
         interface: Interface,
@@ -235,6 +625,17 @@ enum Command {
     /// List existing assocations between CIDRs
     ListAssociations { interface: Interface },
 
+    /// Check whether two peers can reach each other, and which association allows it
+    CanReach {
+        interface: Interface,
+
+        /// Name of the first peer
+        peer_a: Hostname,
+
+        /// Name of the second peer
+        peer_b: Hostname,
+    },
+
     /// Set the local listen port.
     SetListenPort {
         interface: Interface,
@@ -243,6 +644,46 @@ enum Command {
         sub_opts: ListenPortOpts,
     },
 
+    /// Only keep the link to the server alive, for metered connections
+    ///
+    /// Other peers stop getting keepalives and handshake on demand instead, which saves
+    /// data but makes NAT traversal less reliable.
+    SetMetered {
+        interface: Interface,
+
+        /// Go back to keeping every peer alive
+        #[clap(long)]
+        off: bool,
+    },
+
+    /// Route all traffic through the server, not just the network's
+    ///
+    /// The server has to forward (and usually NAT) that traffic for it to go anywhere.
+    /// Takes effect the next time the interface is brought up.
+    SetFullTunnel {
+        interface: Interface,
+
+        /// Go back to only routing the network's traffic through innernet
+        #[clap(long)]
+        off: bool,
+    },
+
+    /// Defer removing peers the server no longer lists, for unreliable links
+    ///
+    /// Fetches then only add and update peers, and peers are removed at most this many
+    /// seconds apart, so a glitch in the peer list doesn't tear down working tunnels.
+    SetRemovalInterval {
+        interface: Interface,
+
+        /// Seconds between fetches that remove peers
+        #[clap(required_unless_present = "off", value_parser = clap::value_parser!(u64).range(1..))]
+        seconds: Option<u64>,
+
+        /// Go back to removing peers on every fetch
+        #[clap(long, conflicts_with = "seconds")]
+        off: bool,
+    },
+
     /// Override your external endpoint that the server sends to other peers
     OverrideEndpoint {
         interface: Interface,
@@ -251,6 +692,32 @@ enum Command {
         sub_opts: OverrideEndpointOpts,
     },
 
+    /// Pin an external endpoint you control (ex. a static IP with a port forward).
+    ///
+    /// Unlike override-endpoint, the pinned endpoint is saved in your config and re-reported
+    /// on every fetch, and observed NAT reflections are never reported in its place.
+    PinEndpoint {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: OverrideEndpointOpts,
+    },
+
+    /// Replace all of your preshared keys with freshly generated ones.
+    ///
+    /// Only available on networks with preshared keys enabled. Peers that joined with a
+    /// client that didn't support preshared keys yet start using them this way. Other peers
+    /// pick up their side of the new keys on their next fetch.
+    RotatePresharedKey {
+        interface: Interface,
+
+        #[clap(flatten)]
+        hosts: HostsOpt,
+
+        #[clap(flatten)]
+        nat: NatOpts,
+    },
+
     /// Generate shell completion scripts
     Completions {
         #[clap(value_enum)]
@@ -258,32 +725,20 @@ enum Command {
     },
 }
 
-/// Application-level error.
-#[derive(Debug, Clone)]
-pub(crate) struct ClientError(String);
-
-impl fmt::Display for ClientError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl std::error::Error for ClientError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
-}
-
 fn update_hosts_file(
     interface: &InterfaceName,
     hosts_path: PathBuf,
     peers: &[Peer],
 ) -> Result<(), WrappedIoError> {
+    // The daemon fetches interfaces concurrently, and they all share the hosts file.
+    static HOSTS_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = HOSTS_LOCK.lock().unwrap();
+
     let mut hosts_builder = HostsBuilder::new(format!("innernet {interface}"));
     for peer in peers {
         hosts_builder.add_hostname(
             peer.contents.ip,
-            &format!("{}.{}.wg", peer.contents.name, interface),
+            format!("{}.{}.wg", peer.contents.name, interface),
         );
     }
     match hosts_builder.write_to(&hosts_path).with_path(&hosts_path) {
@@ -300,15 +755,148 @@ fn update_hosts_file(
     Ok(())
 }
 
+/// The invitation files to install from `paths`, with directories replaced by the
+/// invitations in them.
+fn invitation_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut invites = vec![];
+        for entry in std::fs::read_dir(path).with_path(path)? {
+            let invite = entry.with_path(path)?.path();
+            let extension = invite.extension().and_then(|ext| ext.to_str());
+            if invite.is_file() && matches!(extension, Some("toml" | "conf")) {
+                invites.push(invite);
+            }
+        }
+        if invites.is_empty() {
+            bail!(
+                "no invitations (.toml or .conf files) in {}.",
+                path.display()
+            );
+        }
+        invites.sort();
+        files.extend(invites);
+    }
+    Ok(files)
+}
+
+/// Install each invitation in `paths` in turn, carrying on past failures, and sum up how
+/// it went at the end.
+fn install_all(
+    opts: &Opts,
+    paths: &[PathBuf],
+    hosts_file: Option<PathBuf>,
+    install_opts: InstallOpts,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    let invites = invitation_files(paths)?;
+    if let [invite] = &invites[..] {
+        return install(opts, invite, hosts_file, install_opts, nat).map(|_| ());
+    }
+    if install_opts.name.is_some() {
+        bail!("--name can only be used when installing a single invitation.");
+    }
+
+    let results: Vec<_> = invites
+        .iter()
+        .map(|invite| {
+            eprintln!(
+                "{} installing {}.",
+                "[*]".dimmed(),
+                invite.display().to_string().yellow()
+            );
+            let result = install(opts, invite, hosts_file.clone(), install_opts.clone(), nat);
+            if let Err(e) = &result {
+                log::error!("failed to install {}: {:#}", invite.display(), e);
+            }
+            result
+        })
+        .collect();
+
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    eprintln!(
+        "\n{} installed {} of {} invitations:",
+        "[*]".dimmed(),
+        invites.len() - failed,
+        invites.len()
+    );
+    for (invite, result) in invites.iter().zip(&results) {
+        match result {
+            Ok(iface) => eprintln!(
+                "    {} {} as {}",
+                "ok".green(),
+                invite.display(),
+                iface.to_string().yellow()
+            ),
+            Err(e) => eprintln!("    {} {}: {}", "failed".red(), invite.display(), e),
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} invitations failed to install.",
+            failed,
+            invites.len()
+        );
+    }
+    Ok(())
+}
+
+/// Read and validate an invitation, see [`InterfaceConfig::validate`].
+fn load_invitation(invite: &Path) -> Result<InterfaceConfig, Error> {
+    InterfaceConfig::from_file(invite)
+        .and_then(|config| config.validate().map(|_| config))
+        .map_err(|e| {
+            ClientError::Config(anyhow!(
+                "{} isn't a valid invitation: {:#}",
+                invite.display(),
+                e
+            ))
+            .into()
+        })
+}
+
+fn check_invitation(invite: &Path) -> Result<(), Error> {
+    let config = load_invitation(invite)?;
+    let (interface, server) = (&config.interface, &config.server);
+    println!(
+        "{}: {}",
+        "invitation".yellow().bold(),
+        invite.display().to_string().yellow()
+    );
+    println!("  {}: {}", "network".bold(), interface.network_name);
+    println!("  {}: {}", "address".bold(), interface.address);
+    println!("  {}: {}", "public key".bold(), interface.public_key()?);
+    println!("  {}: {}", "server".bold(), server.external_endpoint);
+    println!("  {}: {}", "api".bold(), server.internal_endpoint);
+    if server.preshared_key.is_some() {
+        println!("  {}: yes", "preshared key".bold());
+    }
+    if let Some(steps) = &config.post_install {
+        println!(
+            "  {}: {}",
+            "post-install steps".bold(),
+            steps.lines().count()
+        );
+    }
+    println!("{}", "valid, nothing was installed.".green());
+    Ok(())
+}
+
 fn install(
     opts: &Opts,
     invite: &Path,
     hosts_file: Option<PathBuf>,
     install_opts: InstallOpts,
     nat: &NatOpts,
-) -> Result<(), Error> {
+) -> Result<InterfaceName, Error> {
     shared::ensure_dirs_exist(&[&opts.config_dir])?;
-    let config = InterfaceConfig::from_file(invite)?;
+    let mut config = load_invitation(invite)?;
+    config.interface.private_key_keyring = install_opts.keyring.clone();
+    let post_install = config.post_install.clone();
 
     let iface = if install_opts.default_name {
         config.interface.network_name.clone()
@@ -340,7 +928,7 @@ fn install(
         );
     }
 
-    redeem_invite(&iface, config, target_conf, opts.network).map_err(|e| {
+    redeem_invite(&iface, config, target_conf, opts).map_err(|e| {
         log::error!("failed to start the interface: {}.", e);
         log::info!("bringing down the interface.");
         if let Err(e) = wg::down(&iface, opts.network.backend) {
@@ -350,24 +938,31 @@ fn install(
         e
     })?;
 
-    let mut fetch_success = false;
-    for _ in 0..3 {
-        if fetch(&iface, opts, true, hosts_file.clone(), nat).is_ok() {
-            fetch_success = true;
-            break;
+    if install_opts.no_up {
+        // Redeeming needed the interface, but everything else `up` does on its own, exactly
+        // like the first fetch below would have.
+        log::info!("bringing down the interface until it's brought up.");
+        wg::down(&iface, opts.network.backend)?;
+    } else {
+        let mut fetch_success = false;
+        for _ in 0..3 {
+            if fetch(&iface, opts, true, hosts_file.clone(), nat, None).is_ok() {
+                fetch_success = true;
+                break;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        if !fetch_success {
+            log::warn!(
+                "Failed to fetch peers from server, you will need to manually run the 'up' command.",
+            );
         }
-        thread::sleep(Duration::from_secs(1));
-    }
-    if !fetch_success {
-        log::warn!(
-            "Failed to fetch peers from server, you will need to manually run the 'up' command.",
-        );
     }
 
     if install_opts.delete_invite
         || Confirm::with_theme(&*prompts::THEME)
             .wait_for_newline(true)
-            .with_prompt(&format!(
+            .with_prompt(format!(
                 "Delete invitation file \"{}\" now? (It's no longer needed)",
                 invite.to_string_lossy().yellow()
             ))
@@ -377,6 +972,27 @@ fn install(
         std::fs::remove_file(invite).with_path(invite)?;
     }
 
+    if install_opts.systemd {
+        let resolve = |dir: &Path| std::fs::canonicalize(dir).with_path(dir);
+        let (executable, config_dir, data_dir) = (
+            std::env::current_exe()?,
+            resolve(&opts.config_dir)?,
+            resolve(&opts.data_dir)?,
+        );
+        systemd::install(
+            &iface,
+            &systemd::UnitOpts {
+                executable: &executable,
+                config_dir: &config_dir,
+                data_dir: &data_dir,
+                hosts_path: hosts_file.as_deref(),
+            },
+            !install_opts.no_up,
+        )
+        .map_err(|e| log::warn!("failed to install a systemd service: {}", e))
+        .ok();
+    }
+
     eprintdoc!(
         "
         {star} Done!
@@ -395,6 +1011,20 @@ fn install(
         interface = iface.to_string().yellow(),
         installed = "installed".green(),
     );
+    if install_opts.no_up {
+        eprintln!(
+            "    It stays down until it's brought up with {}{}.\n",
+            "innernet up ".yellow(),
+            iface.to_string().yellow()
+        );
+    }
+    if let Some(steps) = &post_install {
+        eprint!("{}", post_install_message(steps));
+    }
+    if install_opts.systemd {
+        // The generated service already keeps the interface refreshing.
+        return Ok(iface);
+    }
     if cfg!(target_os = "linux") {
         eprintdoc!(
             "
@@ -430,34 +1060,109 @@ fn install(
             daemon_mode = "innernet up -d --interval 60".yellow()
         );
     }
-    Ok(())
+    Ok(iface)
 }
 
-fn redeem_invite(
-    iface: &InterfaceName,
-    mut config: InterfaceConfig,
-    target_conf: PathBuf,
-    network: NetworkOpts,
+fn import(
+    opts: &Opts,
+    path: &Path,
+    network_name: Option<String>,
+    internal_endpoint: Option<SocketAddr>,
 ) -> Result<(), Error> {
-    log::info!("bringing up interface {}.", iface.as_str_lossy().yellow());
-    let resolved_endpoint = config
-        .server
-        .external_endpoint
-        .resolve()
-        .with_str(config.server.external_endpoint.to_string())?;
-    wg::up(
-        iface,
-        &config.interface.private_key,
-        config.interface.address,
+    shared::ensure_dirs_exist(&[&opts.config_dir])?;
+    let mut config = VanillaConfig::from_file(path)?;
+
+    if let Some(network_name) = network_name {
+        config.set_network_name(network_name);
+    } else if config.network_name().is_none() {
+        config.set_network_name(prompts::input("Network name", Prefill::None)?);
+    }
+    if let Some(internal_endpoint) = internal_endpoint {
+        config.set_internal_endpoint(internal_endpoint);
+    } else if config.internal_endpoint().is_none() {
+        config.set_internal_endpoint(prompts::input(
+            "Server's internal endpoint (ex. 10.42.0.1:51820)",
+            Prefill::None,
+        )?);
+    }
+    if config.private_key().is_none() {
+        let private_key = prompts::password("The config has no private key, enter it (base64)")?;
+        Key::from_base64(private_key.trim()).map_err(|_| anyhow!("invalid private key"))?;
+        config.set_private_key(private_key.trim().to_string());
+    }
+    let config = config.to_interface_config()?;
+
+    let iface = config.interface.network_name.clone();
+    let target_conf = opts.config_dir.join(&iface).with_extension("conf");
+    if target_conf.exists() {
+        bail!(
+            "An existing innernet network with the name \"{}\" already exists.",
+            iface
+        );
+    }
+    let iface: InterfaceName = iface.parse()?;
+    config.write_to_path(&target_conf, false, Some(0o600))?;
+
+    eprintdoc!(
+        "
+        {star} {interface} has been {imported}.
+
+            If wg-quick still manages it, bring that down first, then start innernet with:
+
+                {innernet_up}{interface}
+
+        ",
+        star = "[*]".dimmed(),
+        interface = iface.to_string().yellow(),
+        imported = "imported".green(),
+        innernet_up = "innernet up ".yellow(),
+    );
+    Ok(())
+}
+
+/// The post-install steps from an invitation, as shown once the install is done.
+fn post_install_message(steps: &str) -> String {
+    let mut message = format!(
+        "    {} The network's admin asks that you also do the following:\n\n",
+        "[!]".yellow()
+    );
+    for line in steps.lines() {
+        message.push_str(&format!("        {line}\n"));
+    }
+    message.push('\n');
+    message
+}
+
+fn redeem_invite(
+    iface: &InterfaceName,
+    mut config: InterfaceConfig,
+    target_conf: PathBuf,
+    opts: &Opts,
+) -> Result<(), Error> {
+    let network = opts.network;
+    log::info!("bringing up interface {}.", iface.as_str_lossy().yellow());
+    let resolved_endpoint = config
+        .server
+        .external_endpoint
+        .resolve()
+        .with_str(config.server.external_endpoint.to_string())
+        .map_err(|e| ClientError::Network(e.into()))?;
+    let mut plan = wg::UpPlan::new(
+        &config.interface.private_key,
+        config.interface.address,
         None,
         Some((
             &config.server.public_key,
             config.server.internal_endpoint.ip(),
             resolved_endpoint,
+            config.server.preshared_key.as_deref(),
         )),
         network,
     )
     .with_str(iface.to_string())?;
+    plan.netns = opts.entered_netns.clone();
+    plan.apply(iface, network.backend)
+        .with_str(iface.to_string())?;
 
     log::info!("Generating new keypair.");
     let keypair = wireguard_control::KeyPair::generate();
@@ -466,15 +1171,22 @@ fn redeem_invite(
         "Registering keypair with server (at {}).",
         &config.server.internal_endpoint
     );
-    Api::new(&config.server).http_form(
-        "POST",
-        "/user/redeem",
-        RedeemContents {
-            public_key: keypair.public.to_base64(),
-        },
-    )?;
+    // Servers that don't use preshared keys (or predate them) answer with nothing.
+    let preshared_key: Option<String> = Api::new(&config.server)
+        .http_form(
+            "POST",
+            "/user/redeem",
+            RedeemContents {
+                public_key: keypair.public.to_base64(),
+                preshared_keys: true,
+            },
+        )
+        .map_err(ClientError::from_http)?;
 
     config.interface.private_key = keypair.private.to_base64();
+    if preshared_key.is_some() {
+        config.server.preshared_key = preshared_key;
+    }
     config.write_to_path(&target_conf, false, Some(0o600))?;
     log::info!(
         "New keypair registered. Copied config to {}.\n",
@@ -482,8 +1194,14 @@ fn redeem_invite(
     );
 
     log::info!("Changing keys and waiting 5s for server's WireGuard interface to transition.",);
-    DeviceUpdate::new()
-        .set_private_key(keypair.private)
+    let mut update = DeviceUpdate::new().set_private_key(keypair.private);
+    if let Some(preshared_key) = &config.server.preshared_key {
+        let server_key = Key::from_base64(&config.server.public_key)?;
+        update = update.add_peer(
+            PeerConfigBuilder::new(&server_key).set_preshared_key(Key::from_base64(preshared_key)?),
+        );
+    }
+    update
         .apply(iface, network.backend)
         .with_str(iface.to_string())?;
     thread::sleep(REDEEM_TRANSITION_WAIT);
@@ -494,26 +1212,117 @@ fn redeem_invite(
 fn up(
     interface: Option<Interface>,
     opts: &Opts,
-    loop_interval: Option<Duration>,
+    mut backoff: Option<Backoff>,
     hosts_path: Option<PathBuf>,
     nat: &NatOpts,
+    resolved_dns: Option<IpAddr>,
+    check_mtu: bool,
 ) -> Result<(), Error> {
+    let mut network_changed = false;
+    let mut mtu_checked = HashSet::new();
     loop {
         let interfaces = match &interface {
             Some(iface) => vec![iface.clone()],
             None => all_installed(&opts.config_dir)?,
         };
 
-        for iface in interfaces {
-            fetch(&iface, opts, true, hosts_path.clone(), nat)?;
+        if network_changed {
+            for iface in &interfaces {
+                if let Err(e) = refresh_server_endpoint(iface, opts) {
+                    log::warn!("failed to re-resolve server endpoint for {}: {}", iface, e);
+                }
+            }
         }
 
-        match loop_interval {
-            Some(interval) => thread::sleep(interval),
-            None => break,
+        let result = interfaces.iter().try_for_each(|iface| {
+            let result = fetch(iface, opts, true, hosts_path.clone(), nat, resolved_dns);
+            if backoff.is_some() {
+                fetch_status::record(opts.fetch_status_dir(), iface, &result);
+            }
+            if check_mtu && result.is_ok() && mtu_checked.insert(iface.to_string()) {
+                probe_mtu(iface, opts);
+            }
+            events::fetched(iface, result)
+        });
+
+        let backoff = match backoff.as_mut() {
+            Some(backoff) => backoff,
+            None => return result,
+        };
+        let delay = match result {
+            Ok(()) => backoff.succeeded(),
+            Err(e) if util::is_transient_error(&e) => {
+                let delay = backoff.failed();
+                log::warn!("fetch failed ({}), retrying in {}s.", e, delay.as_secs());
+                delay
+            },
+            Err(e) => return Err(e.context("fetch failed in a way retrying won't fix")),
+        };
+
+        network_changed = match shared::wait_for_network_change(delay, NETWORK_CHANGE_DEBOUNCE) {
+            Ok(changed) => changed,
+            Err(e) => {
+                log::warn!("can't watch for network changes ({}), using the timer.", e);
+                thread::sleep(delay);
+                false
+            },
+        };
+        if network_changed {
+            log::info!("network change detected, re-fetching early.");
         }
     }
+}
+
+/// Warn if full-size packets don't make it to the server through the interface. Only ever
+/// logs, since the interface is already up and usable for smaller packets.
+fn probe_mtu(interface: &InterfaceName, opts: &Opts) {
+    let config = match InterfaceConfig::from_interface(&opts.config_dir, interface) {
+        Ok(config) => config,
+        Err(e) => {
+            log::warn!("skipping the MTU check for {}: {}", interface, e);
+            return;
+        },
+    };
+    let server = config.server.internal_endpoint.ip();
+    let store = DataStore::open(&opts.data_dir, interface).ok();
+    let mtu = effective_config(config, store.as_ref(), opts.network.mtu).mtu;
+    match mtu_probe::check(server, mtu) {
+        mtu_probe::Outcome::Fits => {
+            log::info!("{}-byte packets reach the server over {}.", mtu, interface)
+        },
+        mtu_probe::Outcome::TooBig { largest } => log::warn!(
+            "{}-byte packets don't reach the server over {}, but packets of up to {} bytes do. \
+             Try a lower MTU (ex. --mtu {}).",
+            mtu,
+            interface,
+            largest,
+            largest
+        ),
+        mtu_probe::Outcome::Unreachable => log::warn!(
+            "the MTU check for {} got no ping replies from the server at {}.",
+            interface,
+            server
+        ),
+    }
+}
 
+/// Re-resolve the server's external endpoint and point the interface at it, in case
+/// moving networks changed what it resolves to (ex. split-horizon DNS).
+fn refresh_server_endpoint(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let endpoint = config
+        .server
+        .external_endpoint
+        .resolve()
+        .with_str(config.server.external_endpoint.to_string())?;
+    DeviceUpdate::new()
+        .add_peer(
+            PeerConfigBuilder::new(&Key::from_base64(&config.server.public_key)?)
+                .set_endpoint(endpoint),
+        )
+        .apply(interface, opts.network.backend)
+        .with_str(interface.to_string())?;
+    log::debug!("server endpoint for {} set to {}", interface, endpoint);
     Ok(())
 }
 
@@ -523,13 +1332,19 @@ fn fetch(
     bring_up_interface: bool,
     hosts_path: Option<PathBuf>,
     nat: &NatOpts,
+    resolved_dns: Option<IpAddr>,
 ) -> Result<(), Error> {
-    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let mut config = InterfaceConfig::from_interface(&opts.config_dir, interface)
+        .map_err(ClientError::Config)?;
     let interface_up = match Device::list(opts.network.backend) {
         Ok(interfaces) => interfaces.iter().any(|name| name == interface),
         _ => false,
     };
 
+    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
+    // Until we've fetched, the MTU our CIDR wants is only known from the last fetch.
+    let cached_mtu = cidr_mtu(&config, store.peers(), store.cidrs());
+
     if !interface_up {
         if !bring_up_interface {
             bail!(
@@ -538,54 +1353,141 @@ fn fetch(
             );
         }
 
+        if wg::ensure_listen_port_available(&mut config.interface)? {
+            config.write_to_interface(&opts.config_dir, interface)?;
+        }
+
         log::info!(
             "bringing up interface {}.",
             interface.as_str_lossy().yellow()
         );
-        let resolved_endpoint = config
-            .server
-            .external_endpoint
-            .resolve()
-            .with_str(config.server.external_endpoint.to_string())?;
-        wg::up(
-            interface,
-            &config.interface.private_key,
-            config.interface.address,
-            config.interface.listen_port,
-            Some((
-                &config.server.public_key,
-                config.server.internal_endpoint.ip(),
-                resolved_endpoint,
-            )),
-            opts.network,
-        )
-        .with_str(interface.to_string())?;
+        let mut plan = up_plan(
+            &config,
+            NetworkOpts {
+                mtu: opts.network.mtu.or(cached_mtu),
+                ..opts.network
+            },
+        )?;
+        plan.netns = opts.entered_netns.clone();
+        plan.apply(interface, opts.network.backend)
+            .with_str(interface.to_string())?;
+
+        if let Some(dns) = resolved_dns {
+            resolved::register(interface, dns)?;
+        }
     }
 
     log::info!(
         "fetching state for {} from server...",
         interface.as_str_lossy().yellow()
     );
-    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
+    // With removals deferred, whether this fetch removes peers the server no longer lists.
+    // Those fetches skip the cached ETag, since the server's list not changing since the
+    // last fetch doesn't mean the interface matches it.
+    let now = SystemTime::now();
+    let removals_due = fetcher::removals_due(
+        config.interface.removal_interval,
+        store.reconciled_at(),
+        now,
+    );
+    // Only an interface that's up already has the peers the cached ETag stands for.
+    let cached_etag = store
+        .etag()
+        .filter(|_| interface_up && removals_due != Some(true))
+        .map(String::from);
+    let (
+        State {
+            peers,
+            cidrs,
+            revoked_keys,
+            ..
+        },
+        etag,
+    ) = match fetcher::block_on(fetcher::get_state(&config.server, cached_etag.as_deref()))?
+        .inspect_err(|_| warn_if_server_silent(interface, opts.network.backend, &config))?
+    {
+        Some(fetched) => fetched,
+        None => {
+            log::info!("{}", "peers are already up to date".green());
+            let device = Device::get(interface, opts.network.backend)?;
+            events::handshakes(interface, &store, &device.peers);
+            let public_key = device.public_key.as_ref().map(|key| key.to_base64());
+            report_description(
+                &Api::new(&config.server),
+                &config,
+                store.peers(),
+                public_key.as_deref(),
+            );
+            return report_candidates(
+                &Api::new(&config.server),
+                &config,
+                store.peers(),
+                public_key.as_deref(),
+                device.listen_port,
+                nat,
+            );
+        },
+    };
+
     let api = Api::new(&config.server);
-    let State { peers, cidrs } = api.http("GET", "/user/state")?;
+
+    if !interface_up && opts.network.mtu.is_none() {
+        let mtu = cidr_mtu(&config, &peers, &cidrs);
+        if mtu != cached_mtu {
+            wg::set_up(interface, mtu.unwrap_or(wg::DEFAULT_MTU))
+                .with_str(interface.to_string())?;
+        }
+    }
 
     let device = Device::get(interface, opts.network.backend)?;
-    let modifications = device.diff(&peers);
+    let public_key = device.public_key.as_ref().map(|key| key.to_base64());
+    events::handshakes(interface, &store, &device.peers);
 
-    let updates = modifications
-        .iter()
-        .inspect(|diff| util::print_peer_diff(&store, diff))
-        .cloned()
-        .map(PeerConfigBuilder::from)
-        .collect::<Vec<_>>();
+    // Re-report a pinned endpoint whenever the server has anything else on record for us,
+    // ex. after `override-endpoint -u`.
+    if let Some(pinned) = &config.interface.pinned_endpoint {
+        let advertised = peers
+            .iter()
+            .find(|peer| Some(&peer.public_key) == public_key.as_ref())
+            .and_then(|peer| peer.endpoint.as_ref());
+        if advertised != Some(pinned) {
+            log::info!("reporting pinned endpoint {} to the server.", pinned);
+            match api.http_form::<_, ()>(
+                "PUT",
+                "/user/endpoint",
+                EndpointContents::Set(pinned.clone()),
+            ) {
+                Err(ureq::Error::Status(503, _)) => {
+                    log::warn!("the server is read-only for maintenance, the pinned endpoint will be reported later.")
+                },
+                // The server's endpoint-check policy doesn't believe it, which retrying
+                // won't change.
+                Err(ureq::Error::Status(422, response)) => log::warn!(
+                    "the server refused the pinned endpoint: {}",
+                    response.into_string().unwrap_or_default()
+                ),
+                result => result.map_err(ClientError::from_http)?,
+            }
+        }
+    }
 
-    if !updates.is_empty() || !interface_up {
-        DeviceUpdate::new()
-            .add_peers(&updates)
-            .apply(interface, opts.network.backend)
+    let updates = if removals_due == Some(false) {
+        fetcher::apply_additive(interface, opts.network.backend, &config, &peers)?
+    } else {
+        fetcher::apply(interface, opts.network.backend, &config, &peers)?
+    };
+    for update in &updates {
+        util::print_peer_update(&store, update);
+        for event in events::from_peer_update(&store, update) {
+            events::emit(interface, event);
+        }
+    }
+    if !opts.network.no_routing {
+        fetcher::add_advertised_routes(interface, &config, &peers, public_key.as_deref())
             .with_str(interface.to_string())?;
+    }
 
+    if !updates.is_empty() || !interface_up {
         if let Some(path) = hosts_path {
             update_hosts_file(interface, path, &peers)?;
         }
@@ -598,34 +1500,32 @@ fn fetch(
     let interface_updated_time = Instant::now();
 
     store.set_cidrs(cidrs);
-    store.update_peers(&peers)?;
+    store.update_peers(&peers, &revoked_keys)?;
+    store.set_etag(etag);
+    if removals_due == Some(true) {
+        store.set_reconciled_at(now);
+    }
     store.write().with_str(interface.to_string())?;
 
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .filter(|ip| !nat.is_excluded(*ip))
-        .map(|addr| SocketAddr::from((addr, device.listen_port.unwrap_or(51820))).into())
-        .collect::<Vec<Endpoint>>();
-    log::info!(
-        "reporting {} interface address{} as NAT traversal candidates",
-        candidates.len(),
-        if candidates.len() == 1 { "" } else { "es" },
-    );
-    for candidate in &candidates {
-        log::debug!("  candidate: {}", candidate);
-    }
-    match api.http_form::<_, ()>("PUT", "/user/candidates", &candidates) {
-        Err(ureq::Error::Status(404, _)) => {
-            log::warn!("your network is using an old version of innernet-server that doesn't support NAT traversal candidate reporting.")
-        },
-        Err(e) => return Err(e.into()),
-        _ => {},
-    }
-    log::debug!("candidates successfully reported");
+    report_description(&api, &config, &peers, public_key.as_deref());
+    report_candidates(
+        &api,
+        &config,
+        &peers,
+        public_key.as_deref(),
+        device.listen_port,
+        nat,
+    )?;
 
     if nat.no_nat_traversal {
         log::debug!("NAT traversal explicitly disabled, not attempting.");
     } else {
-        let mut nat_traverse = NatTraverse::new(interface, opts.network.backend, &modifications)?;
+        let mut nat_traverse = NatTraverse::new(
+            interface,
+            opts.network.backend,
+            &updates,
+            config.interface.prefer_family,
+        )?;
 
         // Give time for handshakes with recently changed endpoints to complete before attempting traversal.
         if !nat_traverse.is_finished() {
@@ -646,6 +1546,194 @@ fn fetch(
     Ok(())
 }
 
+/// How an interface is brought up, before anything is fetched from the server: with only
+/// the server as a peer.
+fn up_plan(config: &InterfaceConfig, network: NetworkOpts) -> Result<wg::UpPlan, Error> {
+    let resolved_endpoint = config
+        .server
+        .external_endpoint
+        .resolve()
+        .with_str(config.server.external_endpoint.to_string())
+        .map_err(|e| ClientError::Network(e.into()))?;
+    let mut plan = wg::UpPlan::new(
+        &config.interface.private_key,
+        config.interface.address,
+        config.interface.listen_port,
+        Some((
+            &config.server.public_key,
+            config.server.internal_endpoint.ip(),
+            resolved_endpoint,
+            config.server.preshared_key.as_deref(),
+        )),
+        network,
+    )
+    .with_str(config.interface.network_name.clone())
+    .map_err(|e| ClientError::Config(e.into()))?;
+    if config.interface.full_tunnel {
+        // Refused before anything's applied, rather than leaving a default route half set up.
+        if !cfg!(target_os = "linux") {
+            return Err(ClientError::Config(anyhow!(
+                "full-tunnel mode is only supported on Linux, turn it off with `innernet set-full-tunnel --off`"
+            ))
+            .into());
+        }
+        plan.set_full_tunnel();
+    }
+    let (route_table, fwmark) = (config.interface.route_table, config.interface.fwmark);
+    if route_table.is_some() || fwmark.is_some() {
+        if !cfg!(target_os = "linux") {
+            log::warn!("route-table and fwmark are only supported on Linux, ignoring them.");
+        } else if config.interface.full_tunnel {
+            return Err(ClientError::Config(anyhow!(
+                "full-tunnel mode uses its own routing table and fwmark, so it can't be combined with route-table or fwmark"
+            ))
+            .into());
+        } else {
+            plan.route_table = route_table;
+            plan.fwmark = fwmark;
+        }
+    }
+    Ok(plan)
+}
+
+/// Print what `innernet up` would configure the interface with, using the peers from the
+/// last fetch, without changing anything.
+fn up_dry_run(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let store = DataStore::open(&opts.data_dir, interface).ok();
+    let (peers, cidrs) = store
+        .as_ref()
+        .map(|store| (store.peers(), store.cidrs()))
+        .unwrap_or_default();
+    let mut plan = up_plan(
+        &config,
+        NetworkOpts {
+            mtu: opts.network.mtu.or(cidr_mtu(&config, peers, cidrs)),
+            ..opts.network
+        },
+    )?;
+    let public_key = config.interface.public_key()?;
+    let peers = peers
+        .iter()
+        .filter(|peer| wg::is_wanted(peer, &public_key))
+        .cloned()
+        .collect::<Vec<_>>();
+    plan.add_peers(
+        device_peers(&config, &peers, Some(&public_key))
+            .iter()
+            .map(PeerConfigBuilder::from),
+    );
+
+    println!("# {} (peers from the last fetch)", interface);
+    println!("{plan}");
+    Ok(())
+}
+
+/// Report the endpoints other peers might reach us at to the server, for NAT traversal.
+fn report_candidates(
+    api: &Api,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    public_key: Option<&str>,
+    listen_port: Option<u16>,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    // The endpoint the server observes us at is the most likely to work from outside our
+    // network, so it goes first, followed by our local interface addresses.
+    let observed_endpoint = peers
+        .iter()
+        .find(|peer| Some(peer.public_key.as_str()) == public_key)
+        .and_then(|peer| peer.endpoint.clone())
+        .filter(|endpoint| matches!(endpoint.resolve(), Ok(addr) if !nat.is_excluded(addr.ip())));
+    let mut candidates: Vec<Endpoint> = observed_endpoint.into_iter().collect();
+    for candidate in get_local_addrs()?
+        .filter(|ip| !nat.is_excluded(*ip))
+        .map(|addr| Endpoint::from(SocketAddr::from((addr, listen_port.unwrap_or(51820)))))
+    {
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    config.interface.prefer_family.sort(&mut candidates);
+    // The server rejects reports of more than 10 candidates.
+    candidates.truncate(10);
+    // Other peers should only ever try a pinned endpoint.
+    if let Some(pinned) = &config.interface.pinned_endpoint {
+        candidates = vec![pinned.clone()];
+    }
+    log::info!(
+        "reporting {} interface address{} as NAT traversal candidates",
+        candidates.len(),
+        if candidates.len() == 1 { "" } else { "es" },
+    );
+    for candidate in &candidates {
+        log::debug!("  candidate: {}", candidate);
+    }
+    match api.http_form::<_, ()>("PUT", "/user/candidates", &candidates) {
+        Err(ureq::Error::Status(404, _)) => {
+            log::warn!("your network is using an old version of innernet-server that doesn't support NAT traversal candidate reporting.")
+        },
+        Err(ureq::Error::Status(503, _)) => {
+            log::warn!("the server is read-only for maintenance, skipping candidate reporting.");
+            return Ok(());
+        },
+        Err(e) => return Err(ClientError::from_http(e).into()),
+        _ => {},
+    }
+    log::debug!("candidates successfully reported");
+    Ok(())
+}
+
+/// Report this machine's description to the server if it's opted in and the server has
+/// anything else on record. It's only informational, so failing to is just warned about
+/// rather than failing the fetch.
+fn report_description(
+    api: &Api,
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    public_key: Option<&str>,
+) {
+    if !config.interface.report_description {
+        return;
+    }
+    let description = util::local_description();
+    let reported = peers
+        .iter()
+        .find(|peer| Some(peer.public_key.as_str()) == public_key)
+        .and_then(|peer| peer.description.as_ref());
+    if reported == Some(&description) {
+        return;
+    }
+    log::info!("reporting this machine's description ({}).", description);
+    match api.http_form::<_, ()>("PUT", "/user/description", &description) {
+        Err(ureq::Error::Status(404, _)) => {
+            log::warn!("your network is using an old version of innernet-server that doesn't support peer descriptions.")
+        },
+        Err(ureq::Error::Status(503, _)) => {
+            log::warn!(
+                "the server is read-only for maintenance, the description will be reported later."
+            )
+        },
+        Err(e) => log::warn!("failed to report this machine's description: {}", e),
+        Ok(()) => {},
+    }
+}
+
+fn down(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    if let Err(e) = resolved::unregister(interface) {
+        log::warn!("failed to remove systemd-resolved registration: {}", e);
+    }
+    // The default routes go with the interface, but the rules pointing at them don't.
+    let full_tunnel = InterfaceConfig::from_interface(&opts.config_dir, interface)
+        .is_ok_and(|config| config.interface.full_tunnel);
+    if full_tunnel {
+        if let Err(e) = wg::remove_full_tunnel_routes(wg::FULL_TUNNEL_TABLE) {
+            log::warn!("failed to remove full-tunnel routing rules: {}", e);
+        }
+    }
+    wg::down(interface, opts.network.backend)
+}
+
 fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Error> {
     let config = InterfaceConfig::get_path(&opts.config_dir, interface);
     let data = DataStore::get_path(&opts.data_dir, interface);
@@ -659,7 +1747,7 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
 
     if yes
         || Confirm::with_theme(&*prompts::THEME)
-            .with_prompt(&format!(
+            .with_prompt(format!(
                 "Permanently delete network \"{}\"?",
                 interface.as_str_lossy().yellow()
             ))
@@ -667,8 +1755,11 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
             .wait_for_newline(true)
             .interact()?
     {
+        if let Err(e) = systemd::uninstall(interface) {
+            log::warn!("failed to remove the systemd service: {}", e);
+        }
         log::info!("bringing down interface (if up).");
-        wg::down(interface, opts.network.backend).ok();
+        down(interface, opts).ok();
         std::fs::remove_file(&config)
             .with_path(&config)
             .map_err(|e| log::warn!("{}", e.to_string().yellow()))
@@ -685,6 +1776,94 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
     Ok(())
 }
 
+/// Move an interface's config (renaming the network inside it) and peer data over to a
+/// new name.
+fn move_interface_files(
+    config_dir: &Path,
+    data_dir: &Path,
+    old: &InterfaceName,
+    new: &InterfaceName,
+) -> Result<(), Error> {
+    let old_config = InterfaceConfig::get_path(config_dir, old);
+    let new_config = InterfaceConfig::get_path(config_dir, new);
+    let old_data = DataStore::get_path(data_dir, old);
+    let new_data = DataStore::get_path(data_dir, new);
+    if new_config.exists() || new_data.exists() {
+        bail!(
+            "An existing innernet network with the name \"{}\" already exists.",
+            new
+        );
+    }
+
+    let mut config = InterfaceConfig::from_file(&old_config)?;
+    config.interface.network_name = new.to_string();
+    config.write_to_interface(config_dir, new)?;
+    if old_data.exists() {
+        std::fs::rename(&old_data, &new_data).with_path(&old_data)?;
+    }
+    std::fs::remove_file(&old_config).with_path(&old_config)?;
+    Ok(())
+}
+
+fn rename_interface(
+    interface: &InterfaceName,
+    new_name: &InterfaceName,
+    opts: &Opts,
+    hosts_path: Option<PathBuf>,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    if !InterfaceConfig::get_path(&opts.config_dir, interface).exists() {
+        bail!(
+            "No network named \"{}\" exists.",
+            interface.as_str_lossy().yellow()
+        );
+    }
+    let devices = Device::list(opts.network.backend).unwrap_or_default();
+    if devices.contains(new_name) {
+        bail!(
+            "An existing WireGuard interface with the name \"{}\" already exists.",
+            new_name
+        );
+    }
+    let was_up = devices.contains(interface);
+
+    move_interface_files(&opts.config_dir, &opts.data_dir, interface, new_name)?;
+    let resolve = |dir: &Path| std::fs::canonicalize(dir).with_path(dir);
+    let (executable, config_dir, data_dir) = (
+        std::env::current_exe()?,
+        resolve(&opts.config_dir)?,
+        resolve(&opts.data_dir)?,
+    );
+    let unit_opts = systemd::UnitOpts {
+        executable: &executable,
+        config_dir: &config_dir,
+        data_dir: &data_dir,
+        hosts_path: hosts_path.as_deref(),
+    };
+    // A generated service moves over to the new name, and brings it up itself.
+    let has_service = systemd::rename(interface, new_name, &unit_opts, was_up)
+        .map_err(|e| log::warn!("failed to move the systemd service: {}", e))
+        .unwrap_or(false);
+    if was_up {
+        log::info!("bringing down {}.", interface.as_str_lossy().yellow());
+        if Device::get(interface, opts.network.backend).is_ok() {
+            down(interface, opts)?;
+        }
+        if let Some(path) = hosts_path.clone() {
+            update_hosts_file(interface, path, &[])?;
+        }
+        if !has_service {
+            fetch(new_name, opts, true, hosts_path, nat, None)?;
+        }
+    }
+    log::info!(
+        "renamed {} to {}.",
+        interface.as_str_lossy().yellow(),
+        new_name.as_str_lossy().yellow()
+    );
+    Ok(())
+}
+
 fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
@@ -694,7 +1873,18 @@ fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Re
 
     if let Some(cidr_request) = prompts::add_cidr(&cidrs, &sub_opts)? {
         log::info!("Creating CIDR...");
-        let cidr: Cidr = api.http_form("POST", "/admin/cidrs", cidr_request)?;
+        let cidr: Cidr = match api.http_form("POST", "/admin/cidrs", cidr_request) {
+            // Nested too deep, which the server explains.
+            Err(ureq::Error::Status(400, response)) => bail!(
+                "{}",
+                response
+                    .into_string()
+                    .ok()
+                    .filter(|body| !body.is_empty())
+                    .unwrap_or_else(|| "the server refused the CIDR".into())
+            ),
+            result => result?,
+        };
 
         eprintdoc!(
             "
@@ -714,6 +1904,33 @@ fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Re
     Ok(())
 }
 
+fn rename_cidr(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: RenameCidrOpts,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+
+    if let Some((cidr_request, _old_name)) = prompts::rename_cidr(&cidrs, &sub_opts)? {
+        log::info!("Renaming CIDR...");
+        api.http_form::<_, ()>(
+            "PUT",
+            &format!("/admin/cidrs/{}", cidr_request.id),
+            cidr_request.contents,
+        )?;
+        log::info!("CIDR renamed.");
+    } else {
+        log::info!("exited without renaming CIDR.");
+    }
+
+    Ok(())
+}
+
 fn delete_cidr(
     interface: &InterfaceName,
     opts: &Opts,
@@ -729,7 +1946,7 @@ fn delete_cidr(
     let cidr_id = prompts::delete_cidr(&cidrs, &peers, &sub_opts)?;
 
     println!("Deleting CIDR...");
-    api.http("DELETE", &format!("/admin/cidrs/{cidr_id}"))?;
+    api.http::<()>("DELETE", &format!("/admin/cidrs/{cidr_id}"))?;
 
     println!("CIDR deleted.");
 
@@ -751,11 +1968,21 @@ fn list_cidrs(interface: &InterfaceName, opts: &Opts, tree: bool) -> Result<(),
     Ok(())
 }
 
-fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Result<(), Error> {
+fn add_peer(
+    interface: &InterfaceName,
+    opts: &Opts,
+    mut sub_opts: AddPeerOpts,
+) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
     let api = Api::new(&server);
 
+    if sub_opts.ip.is_none() && sub_opts.ip_allocation.is_none() {
+        // Pick the IP the way the server would have. Older servers don't say how.
+        let State { ip_allocation, .. } = api.http("GET", "/user/state")?;
+        sub_opts.ip_allocation = ip_allocation;
+    }
+
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
     log::info!("Fetching peers");
@@ -765,7 +1992,21 @@ fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Re
     if let Some(result) = prompts::add_peer(&peers, &cidr_tree, &sub_opts)? {
         let (peer_request, keypair, target_path, mut target_file) = result;
         log::info!("Creating peer...");
-        let peer: Peer = api.http_form("POST", "/admin/peers", peer_request)?;
+        let path = if sub_opts.allow_unsafe_name {
+            "/admin/peers?allow-unsafe-name"
+        } else {
+            "/admin/peers"
+        };
+        let peer: Peer = match api.http_form("POST", path, peer_request) {
+            // The server says which limit, as opposed to the CIDR running out of IPs.
+            Err(ureq::Error::Status(409, response)) => bail!(
+                "{}",
+                response
+                    .into_string()
+                    .unwrap_or_else(|_| "peer limit reached".into())
+            ),
+            result => result?,
+        };
         let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
         prompts::write_peer_invitation(
             (&mut target_file, &target_path),
@@ -805,7 +2046,7 @@ fn rename_peer(
             .next()
             .ok_or_else(|| anyhow!("Peer not found."))?;
 
-        api.http_form("PUT", &format!("/admin/peers/{id}"), peer_request)?;
+        api.http_form::<_, ()>("PUT", &format!("/admin/peers/{id}"), peer_request)?;
         log::info!("Peer renamed.");
     } else {
         log::info!("exited without renaming peer.");
@@ -814,6 +2055,36 @@ fn rename_peer(
     Ok(())
 }
 
+fn set_keepalive(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: SetKeepaliveOpts,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+
+    if let Some((peer, persistent_keepalive_interval)) = prompts::set_keepalive(&peers, &sub_opts)?
+    {
+        log::info!("Setting keepalive...");
+        api.http_form::<_, ()>(
+            "PUT",
+            &format!("/admin/peers/{}/keepalive", peer.id),
+            KeepaliveContents {
+                persistent_keepalive_interval,
+            },
+        )?;
+        log::info!("Keepalive for peer {} updated.", peer.name);
+    } else {
+        log::info!("exited without setting keepalive.");
+    }
+
+    Ok(())
+}
+
 fn enable_or_disable_peer(
     interface: &InterfaceName,
     opts: &Opts,
@@ -829,7 +2100,7 @@ fn enable_or_disable_peer(
     if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], enable)? {
         let Peer { id, mut contents } = peer;
         contents.is_disabled = !enable;
-        api.http_form("PUT", &format!("/admin/peers/{id}"), contents)?;
+        api.http_form::<_, ()>("PUT", &format!("/admin/peers/{id}"), contents)?;
     } else {
         log::info!("exiting without enabling or disabling peer.");
     }
@@ -837,6 +2108,77 @@ fn enable_or_disable_peer(
     Ok(())
 }
 
+fn whoami(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let public_key = config.interface.public_key()?;
+    let api = Api::new(&config.server);
+    let State { peers, cidrs, .. } = match api.http("GET", "/user/state") {
+        Err(ureq::Error::Status(401, _)) => bail!(
+            "the server doesn't recognize this interface's key ({}). The peer may have been \
+             disabled, or its key revoked.",
+            public_key
+        ),
+        result => result?,
+    };
+    let me = peers
+        .iter()
+        .find(|peer| peer.public_key == public_key)
+        .ok_or_else(|| {
+            anyhow!(
+                "the server doesn't list this interface's key ({}) as one of its peers.",
+                public_key
+            )
+        })?;
+    let cidr_name = |id| {
+        cidrs
+            .iter()
+            .find(|cidr| cidr.id == id)
+            .map(|cidr| cidr.to_string())
+            .unwrap_or_else(|| "unknown".into())
+    };
+    // The server only lists the peers this one may reach, so their CIDRs are what the
+    // associations amount to.
+    let reachable = peers
+        .iter()
+        .filter(|peer| peer.id != me.id)
+        .map(|peer| cidr_name(peer.cidr_id))
+        .collect::<BTreeSet<_>>();
+
+    println!(
+        "{}: {} ({}...)",
+        "peer".yellow().bold(),
+        me.name.yellow(),
+        &me.public_key[..10].yellow()
+    );
+    println!("  {}: {}", "ip".bold(), me.ip);
+    println!("  {}: {}", "cidr".bold(), cidr_name(me.cidr_id));
+    if me.is_admin {
+        println!("  {}: yes", "admin".bold());
+    }
+    for cidr in reachable {
+        println!("  {}: {}", "reaches".bold(), cidr);
+    }
+    Ok(())
+}
+
+fn evict_peer(interface: &InterfaceName, opts: &Opts, peer: &str) -> Result<(), Error> {
+    let store = DataStore::open(&opts.data_dir, interface)?;
+    let public_key = match store.peers().iter().find(|p| &*p.name == peer) {
+        Some(found) => found.public_key.clone(),
+        None => peer.to_string(),
+    };
+    let key = Key::from_base64(&public_key)
+        .map_err(|_| anyhow!("'{}' isn't a known peer name or a valid public key", peer))?;
+
+    Device::remove_peer(interface, opts.network.backend, &key).with_str(interface.to_string())?;
+    log::info!(
+        "removed {} from {}. It will be re-added on the next fetch unless it's disabled.",
+        peer.yellow(),
+        interface.as_str_lossy().yellow()
+    );
+    Ok(())
+}
+
 fn add_association(
     interface: &InterfaceName,
     opts: &Opts,
@@ -867,14 +2209,25 @@ fn add_association(
         return Ok(());
     };
 
-    api.http_form(
+    match api.http_form::<_, ()>(
         "POST",
         "/admin/associations",
         AssociationContents {
             cidr_id_1: association.0.id,
             cidr_id_2: association.1.id,
         },
-    )?;
+    ) {
+        // The server says which loop it would close, if that's the problem.
+        Err(ureq::Error::Status(400, response)) => bail!(
+            "{}",
+            response
+                .into_string()
+                .ok()
+                .filter(|body| !body.is_empty())
+                .unwrap_or_else(|| "the server refused the association".into())
+        ),
+        result => result?,
+    }
 
     Ok(())
 }
@@ -896,7 +2249,7 @@ fn delete_association(
     if let Some(association) =
         prompts::delete_association(&associations[..], &cidrs[..], &sub_opts)?
     {
-        api.http("DELETE", &format!("/admin/associations/{}", association.id))?;
+        api.http::<()>("DELETE", &format!("/admin/associations/{}", association.id))?;
     } else {
         log::info!("exiting without adding association.");
     }
@@ -936,6 +2289,33 @@ fn list_associations(interface: &InterfaceName, opts: &Opts) -> Result<(), Error
     Ok(())
 }
 
+fn can_reach(
+    interface: &InterfaceName,
+    opts: &Opts,
+    peer_a: &Hostname,
+    peer_b: &Hostname,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    log::info!("Fetching associations");
+    let associations: Vec<Association> = api.http("GET", "/admin/associations")?;
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    let find = |name: &Hostname| {
+        peers
+            .iter()
+            .find(|peer| &peer.name == name)
+            .ok_or_else(|| anyhow!("No peer named {} exists.", name))
+    };
+
+    reachability::report(&cidrs, &associations, find(peer_a)?, find(peer_b)?);
+    Ok(())
+}
+
 fn set_listen_port(
     interface: &InterfaceName,
     opts: &Opts,
@@ -949,6 +2329,7 @@ fn set_listen_port(
         log::info!("the interface is updated");
 
         config.interface.listen_port = listen_port;
+        config.interface.listen_port_auto = false;
         config.write_to_interface(&opts.config_dir, interface)?;
         log::info!("the config file is updated");
     } else {
@@ -958,6 +2339,110 @@ fn set_listen_port(
     Ok(listen_port.flatten())
 }
 
+/// The MTU our CIDR (or its nearest ancestor that sets one) wants the interface to have.
+fn cidr_mtu(config: &InterfaceConfig, peers: &[Peer], cidrs: &[Cidr]) -> Option<u32> {
+    let public_key = Key::from_base64(&config.interface.private_key)
+        .ok()?
+        .get_public()
+        .to_base64();
+    let me = peers.iter().find(|peer| peer.public_key == public_key)?;
+    inherited_cidr_default(cidrs, me.cidr_id, |cidr| cidr.default_mtu)
+}
+
+/// Warn about an interface that has been trying to reach the server without ever completing
+/// a handshake. A server that was unreachable all along looks the same, but it's the only way
+/// a server whose key no longer matches the config (ex. regenerated after the invitation)
+/// shows, since its API is only reachable through the tunnel.
+fn warn_if_server_silent(interface: &InterfaceName, backend: Backend, config: &InterfaceConfig) {
+    let Ok(device) = Device::get(interface, backend) else {
+        return;
+    };
+    let silent = device
+        .get_peer(&config.server.public_key)
+        .is_some_and(|info| info.stats.last_handshake_time.is_none() && info.stats.tx_bytes > 0);
+    if silent {
+        log::warn!(
+            "{} has never completed a handshake with the server. Check that it's reachable, \
+             and if its key was regenerated, ask an admin for a new invitation.",
+            interface
+        );
+    }
+}
+
+fn set_metered(interface: &InterfaceName, opts: &Opts, metered: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    if config.interface.metered == metered {
+        log::info!("nothing to change.");
+        return Ok(());
+    }
+
+    InterfaceConfig::edit_field(
+        InterfaceConfig::get_path(&opts.config_dir, interface),
+        "interface",
+        "metered",
+        metered,
+    )?;
+    log::info!(
+        "metered mode {} for {}, which takes effect on the next fetch.",
+        if metered { "enabled" } else { "disabled" },
+        interface.as_str_lossy().yellow()
+    );
+    Ok(())
+}
+
+fn set_full_tunnel(interface: &InterfaceName, opts: &Opts, full_tunnel: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    if config.interface.full_tunnel == full_tunnel {
+        log::info!("nothing to change.");
+        return Ok(());
+    }
+
+    InterfaceConfig::edit_field(
+        InterfaceConfig::get_path(&opts.config_dir, interface),
+        "interface",
+        "full-tunnel",
+        full_tunnel,
+    )?;
+    log::info!(
+        "full-tunnel mode {} for {}, which takes effect the next time it's brought up.",
+        if full_tunnel { "enabled" } else { "disabled" },
+        interface.as_str_lossy().yellow()
+    );
+    Ok(())
+}
+
+fn set_removal_interval(
+    interface: &InterfaceName,
+    opts: &Opts,
+    interval: Option<u64>,
+) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    if config.interface.removal_interval == interval {
+        log::info!("nothing to change.");
+        return Ok(());
+    }
+
+    let path = InterfaceConfig::get_path(&opts.config_dir, interface);
+    match interval {
+        Some(secs) => {
+            InterfaceConfig::edit_field(path, "interface", "removal-interval", secs as i64)?
+        },
+        None => InterfaceConfig::remove_field(path, "interface", "removal-interval")?,
+    }
+    match interval {
+        Some(secs) => log::info!(
+            "{} now only removes peers every {}s, starting with the next fetch.",
+            interface.as_str_lossy().yellow(),
+            secs
+        ),
+        None => log::info!(
+            "{} removes peers on every fetch again.",
+            interface.as_str_lossy().yellow()
+        ),
+    }
+    Ok(())
+}
+
 fn override_endpoint(
     interface: &InterfaceName,
     opts: &Opts,
@@ -978,7 +2463,7 @@ fn override_endpoint(
 
     if let Some(contents) = endpoint_contents {
         log::info!("requesting endpoint update...");
-        Api::new(&config.server).http_form("PUT", "/user/endpoint", contents)?;
+        put_endpoint(&config.server, contents)?;
         log::info!(
             "endpoint override {}",
             if sub_opts.unset { "unset" } else { "set" }
@@ -990,7 +2475,107 @@ fn override_endpoint(
     Ok(())
 }
 
-fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> Result<(), Error> {
+/// Report our endpoint to the server, explaining why if it's refused.
+fn put_endpoint(
+    server: &shared::interface_config::ServerInfo,
+    contents: EndpointContents,
+) -> Result<(), Error> {
+    match Api::new(server).http_form::<_, ()>("PUT", "/user/endpoint", contents) {
+        Err(ureq::Error::Status(422, response)) => bail!(
+            "{}",
+            response
+                .into_string()
+                .ok()
+                .filter(|body| !body.is_empty())
+                .unwrap_or_else(|| "the server refused the endpoint".into())
+        ),
+        result => Ok(result?),
+    }
+}
+
+fn pin_endpoint(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: OverrideEndpointOpts,
+) -> Result<(), Error> {
+    let mut config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let endpoint_contents = if sub_opts.unset {
+        if config.interface.pinned_endpoint.is_none() {
+            log::info!("no endpoint is pinned.");
+            return Ok(());
+        }
+        prompts::unset_override_endpoint(&sub_opts)?.then_some(EndpointContents::Unset)
+    } else {
+        let port = config.interface.listen_port.unwrap_or(51820);
+        prompts::override_endpoint(&sub_opts, port)?.map(EndpointContents::Set)
+    };
+
+    let Some(contents) = endpoint_contents else {
+        log::info!("exiting without pinning endpoint");
+        return Ok(());
+    };
+    config.interface.pinned_endpoint = match &contents {
+        EndpointContents::Set(endpoint) => Some(endpoint.clone()),
+        EndpointContents::Unset => None,
+    };
+    log::info!("requesting endpoint update...");
+    put_endpoint(&config.server, contents)?;
+    config.write_to_interface(&opts.config_dir, interface)?;
+    log::info!(
+        "endpoint {}",
+        if sub_opts.unset { "unpinned" } else { "pinned" }
+    );
+    Ok(())
+}
+
+fn rotate_preshared_key(
+    interface: &InterfaceName,
+    opts: &Opts,
+    hosts_path: Option<PathBuf>,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    let mut config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+
+    log::info!("requesting new preshared keys...");
+    let preshared_key: String =
+        Api::new(&config.server).http("POST", "/user/rotate-preshared-key")?;
+    let key = Key::from_base64(&preshared_key)?;
+
+    config.server.preshared_key = Some(preshared_key);
+    config.write_to_interface(&opts.config_dir, interface)?;
+
+    log::info!("Changing keys and waiting 5s for server's WireGuard interface to transition.");
+    thread::sleep(REDEEM_TRANSITION_WAIT);
+    let server_key = Key::from_base64(&config.server.public_key)?;
+    DeviceUpdate::new()
+        .add_peer(PeerConfigBuilder::new(&server_key).set_preshared_key(key))
+        .apply(interface, opts.network.backend)
+        .with_str(interface.to_string())?;
+
+    fetch(interface, opts, false, hosts_path, nat, None)?;
+    log::info!("preshared keys rotated.");
+    Ok(())
+}
+
+fn show(
+    opts: &Opts,
+    show_opts: &ShowOpts,
+    interface: Option<Interface>,
+    tracker: Option<&mut HandshakeTracker>,
+) -> Result<(), Error> {
+    let ShowOpts {
+        short,
+        tree,
+        by_tag,
+        json,
+        ..
+    } = *show_opts;
+    let grep = show_opts.grep.as_deref().map(str::to_lowercase);
+    let matches = |peer: &Peer| match &grep {
+        Some(text) => peer.name.to_lowercase().contains(text),
+        None => true,
+    };
+
     let interfaces = interface.map_or_else(
         || Device::list(opts.network.backend),
         |interface| Ok(vec![*interface]),
@@ -1013,10 +2598,20 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         })
         .collect::<Result<Vec<_>, _>>()?;
 
+    if json {
+        let matched = print_json(&devices, matches)?;
+        return check_grep_matched(grep.as_deref(), matched);
+    }
+
     if devices.is_empty() {
         log::info!("No innernet networks currently running.");
         return Ok(());
     }
+    let changed = tracker
+        .map(|tracker| tracker.update(devices.iter().flat_map(|(device, _)| &device.peers)))
+        .unwrap_or_default();
+
+    let mut matched = 0;
 
     for (device_info, store) in devices {
         let public_key = match &device_info.public_key {
@@ -1046,6 +2641,7 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
                     Some(peer) => Ok(PeerState {
                         peer,
                         info: Some(info),
+                        handshake_changed: changed.contains(&public_key),
                     }),
                     None => Err(anyhow!("peer {} isn't an innernet peer.", public_key)),
                 }
@@ -1054,23 +2650,319 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         peer_states.push(PeerState {
             peer: me,
             info: None,
+            handshake_changed: false,
         });
+        peer_states.retain(|state| matches(state.peer));
+        if peer_states.is_empty() {
+            continue;
+        }
+        matched += peer_states.len();
 
-        print_interface(&device_info, short || tree)?;
+        print_interface(&device_info, short || tree || by_tag)?;
         peer_states.sort_by_key(|peer| peer.peer.ip);
 
         if tree {
             let cidr_tree = CidrTree::new(cidrs);
             print_tree(&cidr_tree, &peer_states, 1);
+        } else if by_tag {
+            print_tag_groups(&peer_states, 1);
         } else {
             for peer_state in peer_states {
                 print_peer(&peer_state, short, 1);
             }
         }
     }
+    check_grep_matched(grep.as_deref(), matched)
+}
+
+/// Fail with a friendly message when a `--grep` filter left nothing to show, so scripts
+/// can tell.
+fn check_grep_matched(grep: Option<&str>, matched: usize) -> Result<(), Error> {
+    match grep {
+        Some(text) if matched == 0 => bail!("no peers with a name containing '{}'.", text),
+        _ => Ok(()),
+    }
+}
+
+fn status(
+    interface: &InterfaceName,
+    opts: &Opts,
+    json: bool,
+    tracker: Option<&mut HandshakeTracker>,
+) -> Result<(), Error> {
+    let store = DataStore::open(&opts.data_dir, interface)?;
+    let device = Device::get(interface, opts.network.backend).with_str(interface.as_str_lossy())?;
+    let changed = tracker
+        .map(|tracker| tracker.update(&device.peers))
+        .unwrap_or_default();
+
+    let mut statuses = device
+        .peers
+        .iter()
+        .map(|info| {
+            let public_key = info.config.public_key.to_base64();
+            let peer = store.peers().iter().find(|p| p.public_key == public_key);
+            PeerStatus {
+                name: peer.map(|p| p.name.to_string()),
+                public_key,
+                ip: peer.map(|p| p.ip),
+                endpoint: info.config.endpoint,
+                last_handshake_secs_ago: info.handshake_age().map(|age| age.as_secs()),
+                rx_bytes: info.stats.rx_bytes,
+                tx_bytes: info.stats.tx_bytes,
+                health: info.handshake_health(),
+            }
+        })
+        .collect::<Vec<_>>();
+    statuses.sort_by_key(|status| status.ip);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    print_interface(&device, true)?;
+    for status in statuses {
+        let indicator = match status.health {
+            HandshakeHealth::Healthy => "●".green(),
+            HandshakeHealth::Stale => "●".yellow(),
+            HandshakeHealth::Down => "●".red(),
+        };
+        println!(
+            "  {} {} ({}…){}",
+            indicator,
+            status.name.as_deref().unwrap_or("(unknown peer)").yellow(),
+            &status.public_key[..10].dimmed(),
+            handshake_marker(changed.contains(&status.public_key)),
+        );
+        if let Some(ip) = status.ip {
+            println!("    {}: {}", "ip".bold(), ip);
+        }
+        if let Some(endpoint) = status.endpoint {
+            println!("    {}: {}", "endpoint".bold(), endpoint);
+        }
+        println!(
+            "    {}: {}",
+            "last handshake".bold(),
+            status
+                .last_handshake_secs_ago
+                .map(|secs| human_duration(Duration::from_secs(secs)))
+                .unwrap_or_else(|| "never".to_string()),
+        );
+        println!(
+            "    {}: {} received, {} sent",
+            "transfer".bold(),
+            human_size(status.rx_bytes),
+            human_size(status.tx_bytes),
+        );
+    }
+
+    Ok(())
+}
+
+fn export(
+    interface: &InterfaceName,
+    opts: &Opts,
+    strict: bool,
+    only_cidrs: &[String],
+    no_private_key: bool,
+    full_tunnel: bool,
+) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let mut vanilla = VanillaConfig::try_from(&config)?;
+    let store = DataStore::open(&opts.data_dir, interface).ok();
+    // Include whatever the server advertises, as of the last fetch.
+    if let Some(server) = store
+        .iter()
+        .flat_map(|store| store.peers())
+        .find(|peer| peer.public_key == config.server.public_key)
+    {
+        vanilla.add_server_routes(&server.advertised_routes);
+    }
+
+    if !only_cidrs.is_empty() {
+        let store = store.ok_or_else(|| {
+            anyhow!(
+                "no peer list for {} yet, run 'innernet fetch' first.",
+                interface
+            )
+        })?;
+        let cidrs = only_cidrs
+            .iter()
+            .map(|name| {
+                store
+                    .cidrs()
+                    .iter()
+                    .find(|cidr| &cidr.name == name)
+                    .ok_or_else(|| anyhow!("no CIDR named {} exists.", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // What this peer is permitted to reach is exactly what innernet would configure.
+        let own_key = Key::from_base64(&config.interface.private_key)?
+            .get_public()
+            .to_base64();
+        let permitted = device_peers(&config, store.peers(), Some(&own_key))
+            .iter()
+            .filter(|peer| wg::is_wanted(peer, &own_key))
+            .flat_map(|peer| peer.allowed_ips())
+            .filter_map(|ip| IpNet::new(ip.address, ip.cidr).ok())
+            .collect::<Vec<_>>();
+        vanilla.restrict_server_routes(&cidrs, &permitted)?;
+    }
+    if no_private_key {
+        vanilla.remove_private_key();
+    }
+    if full_tunnel {
+        vanilla.set_full_tunnel();
+    }
+
+    if strict {
+        print!("{}", vanilla.to_strict_string()?);
+    } else {
+        print!("{vanilla}");
+    }
+    Ok(())
+}
+
+/// Stands in for secrets in `config show`.
+const REDACTED: &str = "(redacted)";
+
+/// An interface's configuration as innernet applies it, for `config show`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct EffectiveConfig {
+    /// The MTU the interface is brought up with.
+    mtu: u32,
+
+    /// This peer's keepalive interval as of the last fetch, which other peers use to keep
+    /// their link to it alive. `None` if it's disabled or unknown.
+    keepalive: Option<u16>,
+
+    interface: shared::interface_config::InterfaceInfo,
+
+    server: shared::interface_config::ServerInfo,
+}
+
+/// Resolve an interface's configuration the same way bringing it up and talking to the
+/// server do, given the peer list from the last fetch (if any) and the `--mtu` flag.
+fn effective_config(
+    mut config: InterfaceConfig,
+    store: Option<&DataStore>,
+    cli_mtu: Option<u32>,
+) -> EffectiveConfig {
+    let (peers, cidrs) = store
+        .map(|store| (store.peers(), store.cidrs()))
+        .unwrap_or_default();
+    let public_key = Key::from_base64(&config.interface.private_key)
+        .map(|key| key.get_public().to_base64())
+        .ok();
+    let keepalive = peers
+        .iter()
+        .find(|peer| Some(&peer.public_key) == public_key.as_ref())
+        .and_then(|peer| peer.persistent_keepalive_interval);
+    let mtu = cli_mtu
+        .or(cidr_mtu(&config, peers, cidrs))
+        .unwrap_or(wg::DEFAULT_MTU);
+
+    config.interface.private_key = REDACTED.into();
+    let server = &mut config.server;
+    server.preshared_key = server.preshared_key.as_ref().map(|_| REDACTED.into());
+    server.connect_timeout = Some(server.api_connect_timeout().as_secs());
+    server.read_timeout = Some(server.api_read_timeout().as_secs());
+    server.retries = Some(server.api_retries());
+
+    EffectiveConfig {
+        mtu,
+        keepalive,
+        interface: config.interface,
+        server: config.server,
+    }
+}
+
+fn show_config(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let store = DataStore::open(&opts.data_dir, interface).ok();
+    let effective = effective_config(config, store.as_ref(), opts.network.mtu);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+    } else {
+        print!("{}", toml::to_string(&effective)?);
+    }
+    Ok(())
+}
+
+fn self_test(interface: &InterfaceName, opts: &Opts, ping: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let device = Device::get(interface, opts.network.backend).ok();
+    // Only used to name peers to ping, so a missing store isn't worth failing over.
+    let store = DataStore::open(&opts.data_dir, interface).ok();
+    let peers = store
+        .as_ref()
+        .map(|store| store.peers())
+        .unwrap_or_default();
+    let ping: Option<&dyn Fn(IpAddr) -> bool> = ping.then_some(&mtu_probe::answers);
+
+    let checks = self_test::run(
+        interface,
+        &config.server,
+        device.as_ref().map(|device| &device.peers[..]),
+        peers,
+        ping,
+    );
+    self_test::print(&checks);
+    if !self_test::passed(&checks) {
+        bail!("{} isn't working, see the failed checks above.", interface);
+    }
+    Ok(())
+}
+
+fn diff(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let device = Device::get(interface, opts.network.backend).with_str(interface.as_str_lossy())?;
+    let State { peers, .. } = Api::new(&config.server).http("GET", "/user/state")?;
+
+    let public_key = device.public_key.as_ref().map(|key| key.to_base64());
+    let intended = device_peers(&config, &peers, public_key.as_deref());
+    let report = DriftReport::new(
+        &intended,
+        &device.peers,
+        public_key.as_deref(),
+        |endpoint| endpoint.resolve().ok(),
+    );
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+    if !report.is_empty() {
+        bail!("{} has drifted from the server's peer list.", interface);
+    }
     Ok(())
 }
 
+/// Print the peers passing `filter` as JSON, returning how many there were.
+fn print_json(
+    devices: &[(Device, DataStore)],
+    filter: impl Fn(&Peer) -> bool,
+) -> Result<usize, Error> {
+    let mut entries = vec![];
+    for (device_info, store) in devices {
+        let public_key = device_info.public_key.as_ref().map(|key| key.to_base64());
+        for peer in store.peers().iter().filter(|peer| filter(peer)) {
+            let info = device_info
+                .peers
+                .iter()
+                .find(|info| info.config.public_key.to_base64() == peer.public_key);
+            let is_you = public_key.as_ref() == Some(&peer.public_key);
+            entries.push(PeerListEntry::new(&device_info.name, peer, info, is_you));
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(entries.len())
+}
+
 fn print_tree(cidr: &CidrTree, peers: &[PeerState], level: usize) {
     println_pad!(
         level * 2,
@@ -1090,6 +2982,25 @@ fn print_tree(cidr: &CidrTree, peers: &[PeerState], level: usize) {
     }
 }
 
+fn print_tag_groups(peers: &[PeerState], level: usize) {
+    let tags: BTreeSet<_> = peers.iter().flat_map(|p| p.peer.tags.iter()).collect();
+
+    for tag in tags {
+        println_pad!(level * 2, "{}", tag.bold().blue());
+        for peer in peers.iter().filter(|p| p.peer.tags.contains(tag)) {
+            print_peer(peer, true, level + 1);
+        }
+    }
+
+    let untagged: Vec<_> = peers.iter().filter(|p| p.peer.tags.is_empty()).collect();
+    if !untagged.is_empty() {
+        println_pad!(level * 2, "{}", "(untagged)".dimmed());
+        for peer in untagged {
+            print_peer(peer, true, level + 1);
+        }
+    }
+}
+
 fn print_interface(device_info: &Device, short: bool) -> Result<(), Error> {
     if short {
         let listen_port_str = device_info
@@ -1114,9 +3025,22 @@ fn print_interface(device_info: &Device, short: bool) -> Result<(), Error> {
     Ok(())
 }
 
+/// Flags a peer whose handshake changed since the last `--watch` redraw.
+fn handshake_marker(changed: bool) -> ColoredString {
+    if changed {
+        " ✱ new handshake".green().bold()
+    } else {
+        "".normal()
+    }
+}
+
 fn print_peer(peer: &PeerState, short: bool, level: usize) {
     let pad = level * 2;
-    let PeerState { peer, info } = peer;
+    let PeerState {
+        peer,
+        info,
+        handshake_changed,
+    } = peer;
     if short {
         let connected = info
             .map(|info| info.is_recently_connected())
@@ -1126,7 +3050,7 @@ fn print_peer(peer: &PeerState, short: bool, level: usize) {
 
         println_pad!(
             pad,
-            "| {} {}: {} ({}{}…)",
+            "| {} {}: {} ({}{}…){}",
             if connected || is_you {
                 "◉".bold()
             } else {
@@ -1136,27 +3060,54 @@ fn print_peer(peer: &PeerState, short: bool, level: usize) {
             peer.name.yellow(),
             if is_you { "you, " } else { "" },
             &peer.public_key[..6].dimmed(),
+            handshake_marker(*handshake_changed),
         );
     } else {
         println_pad!(
             pad,
-            "{}: {} ({}...)",
+            "{}: {} ({}...){}",
             "peer".yellow().bold(),
             peer.name.yellow(),
             &peer.public_key[..10].yellow(),
+            handshake_marker(*handshake_changed),
         );
         println_pad!(pad, "  {}: {}", "ip".bold(), peer.ip);
+        if !peer.tags.is_empty() {
+            let tags = peer.tags.iter().map(|t| &**t).collect::<Vec<_>>();
+            println_pad!(pad, "  {}: {}", "tags".bold(), tags.join(", "));
+        }
+        if let Some(description) = &peer.description {
+            println_pad!(
+                pad,
+                "  {}: {}",
+                "reports".bold(),
+                description.to_string().dimmed()
+            );
+        }
+        if let Some(created_at) = peer.created_at {
+            let created_by = peer
+                .created_by
+                .as_deref()
+                .map(|name| format!(" by {}", name.yellow()))
+                .unwrap_or_default();
+            println_pad!(
+                pad,
+                "  {}: {}{}",
+                "created".bold(),
+                human_duration(created_at.elapsed().unwrap_or_default()),
+                created_by,
+            );
+        }
         if let Some(info) = info {
             if let Some(endpoint) = info.config.endpoint {
                 println_pad!(pad, "  {}: {}", "endpoint".bold(), endpoint);
             }
-            if let Some(last_handshake) = info.stats.last_handshake_time {
-                let duration = last_handshake.elapsed().expect("horrible clock problem");
+            if let Some(age) = info.handshake_age() {
                 println_pad!(
                     pad,
                     "  {}: {}",
                     "last handshake".bold(),
-                    human_duration(duration),
+                    human_duration(age),
                 );
             }
             if info.stats.tx_bytes > 0 || info.stats.rx_bytes > 0 {
@@ -1185,49 +3136,142 @@ fn main() {
         if let Some(e) = e.downcast_ref::<io::Error>() {
             util::permissions_helptext(&opts.config_dir, &opts.data_dir, e);
         }
-        std::process::exit(1);
+        std::process::exit(ClientError::find(&e).map_or(1, ClientError::exit_code));
     }
 }
 
 fn run(opts: &Opts) -> Result<(), Error> {
+    let opts = &Opts {
+        entered_netns: opts
+            .netns
+            .as_deref()
+            .map(wg::Netns::enter)
+            .transpose()?
+            .map(Arc::new),
+        ..opts.clone()
+    };
+
     let command = opts.command.clone().unwrap_or(Command::Show {
-        short: false,
-        tree: false,
+        show_opts: ShowOpts::default(),
         interface: None,
     });
 
     match command {
         Command::Install {
-            invite,
+            invites,
             hosts,
             install_opts,
             nat,
-        } => install(opts, &invite, hosts.into(), install_opts, &nat)?,
+        } => install_all(opts, &invites, hosts.into(), install_opts, &nat)?,
+        Command::Check { invite } => check_invitation(&invite)?,
+        Command::Import {
+            config,
+            network_name,
+            internal_endpoint,
+        } => import(opts, &config, network_name, internal_endpoint)?,
+        Command::Export {
+            interface,
+            strict,
+            only_cidr,
+            no_private_key,
+            full_tunnel,
+        } => export(
+            &interface,
+            opts,
+            strict,
+            &only_cidr,
+            no_private_key,
+            full_tunnel,
+        )?,
+        Command::Config {
+            command: ConfigCommand::Show { interface, json },
+        } => show_config(&interface, opts, json)?,
         Command::Show {
-            short,
-            tree,
+            show_opts,
+            interface,
+        } => match show_opts.watch {
+            Some(secs) => watch::run(Duration::from_secs(secs), "innernet list", |tracker| {
+                show(opts, &show_opts, interface.clone(), Some(tracker))
+            })?,
+            None => show(opts, &show_opts, interface, None)?,
+        },
+        Command::Status {
+            interface,
+            json,
+            watch: Some(secs),
+        } => watch::run(Duration::from_secs(secs), "innernet status", |tracker| {
+            status(&interface, opts, json, Some(tracker))
+        })?,
+        Command::Status {
             interface,
-        } => show(opts, short, tree, interface)?,
+            json,
+            watch: None,
+        } => status(&interface, opts, json, None)?,
+        Command::Test { interface, ping } => self_test(&interface, opts, ping)?,
+        Command::Diff { interface, json } => diff(&interface, opts, json)?,
         Command::Fetch {
             interface,
             hosts,
             nat,
-        } => fetch(&interface, opts, false, hosts.into(), &nat)?,
+        } => fetch(&interface, opts, false, hosts.into(), &nat, None)?,
+        Command::Daemon {
+            command: Some(DaemonCommand::Status { json }),
+            ..
+        } => daemon::status(opts, json)?,
+        Command::Daemon {
+            command: None,
+            daemon_opts,
+        } => daemon::run(opts, &daemon_opts)?,
         Command::Up {
             interface,
             daemon,
             hosts,
             nat,
             interval,
-        } => up(
-            interface,
-            opts,
-            daemon.then(|| Duration::from_secs(interval)),
-            hosts.into(),
-            &nat,
-        )?,
-        Command::Down { interface } => wg::down(&interface, opts.network.backend)?,
+            max_interval,
+            jitter,
+            resolved_dns,
+            events_json,
+            check_mtu,
+            dry_run,
+        } => {
+            if dry_run {
+                let interfaces = match interface {
+                    Some(iface) => vec![iface],
+                    None => all_installed(&opts.config_dir)?,
+                };
+                for iface in &interfaces {
+                    up_dry_run(iface, opts)?;
+                }
+                return Ok(());
+            }
+            if events_json {
+                events::enable();
+            }
+            up(
+                interface,
+                opts,
+                daemon.then(|| {
+                    Backoff::new(
+                        Duration::from_secs(interval),
+                        Duration::from_secs(max_interval),
+                        jitter,
+                    )
+                }),
+                hosts.into(),
+                &nat,
+                resolved_dns,
+                check_mtu,
+            )?
+        },
+        Command::Down { interface } => down(&interface, opts)?,
         Command::Uninstall { interface, yes } => uninstall(&interface, opts, yes)?,
+        Command::RenameInterface {
+            interface,
+            new_name,
+            hosts,
+            nat,
+        } => rename_interface(&interface, &new_name, opts, hosts.into(), &nat)?,
         Command::AddPeer {
             interface,
             sub_opts,
@@ -1236,10 +3280,18 @@ fn run(opts: &Opts) -> Result<(), Error> {
             interface,
             sub_opts,
         } => rename_peer(&interface, opts, sub_opts)?,
+        Command::SetKeepalive {
+            interface,
+            sub_opts,
+        } => set_keepalive(&interface, opts, sub_opts)?,
         Command::AddCidr {
             interface,
             sub_opts,
         } => add_cidr(&interface, opts, sub_opts)?,
+        Command::RenameCidr {
+            interface,
+            sub_opts,
+        } => rename_cidr(&interface, opts, sub_opts)?,
         Command::DeleteCidr {
             interface,
             sub_opts,
@@ -1247,6 +3299,8 @@ fn run(opts: &Opts) -> Result<(), Error> {
         Command::ListCidrs { interface, tree } => list_cidrs(&interface, opts, tree)?,
         Command::DisablePeer { interface } => enable_or_disable_peer(&interface, opts, false)?,
         Command::EnablePeer { interface } => enable_or_disable_peer(&interface, opts, true)?,
+        Command::EvictPeer { interface, peer } => evict_peer(&interface, opts, &peer)?,
+        Command::Whoami { interface } => whoami(&interface, opts)?,
         Command::AddAssociation {
             interface,
             sub_opts,
@@ -1256,18 +3310,39 @@ fn run(opts: &Opts) -> Result<(), Error> {
             sub_opts,
         } => delete_association(&interface, opts, sub_opts)?,
         Command::ListAssociations { interface } => list_associations(&interface, opts)?,
+        Command::CanReach {
+            interface,
+            peer_a,
+            peer_b,
+        } => can_reach(&interface, opts, &peer_a, &peer_b)?,
         Command::SetListenPort {
             interface,
             sub_opts,
         } => {
             set_listen_port(&interface, opts, sub_opts)?;
         },
+        Command::SetMetered { interface, off } => set_metered(&interface, opts, !off)?,
+        Command::SetRemovalInterval {
+            interface,
+            seconds,
+            off,
+        } => set_removal_interval(&interface, opts, seconds.filter(|_| !off))?,
+        Command::SetFullTunnel { interface, off } => set_full_tunnel(&interface, opts, !off)?,
         Command::OverrideEndpoint {
             interface,
             sub_opts,
         } => {
             override_endpoint(&interface, opts, sub_opts)?;
         },
+        Command::PinEndpoint {
+            interface,
+            sub_opts,
+        } => pin_endpoint(&interface, opts, sub_opts)?,
+        Command::RotatePresharedKey {
+            interface,
+            hosts,
+            nat,
+        } => rotate_preshared_key(&interface, opts, hosts.into(), &nat)?,
         Command::Completions { shell } => {
             use clap::CommandFactory;
             let mut app = Opts::command();
@@ -1279,3 +3354,142 @@ fn run(opts: &Opts) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An invitation to the "office" network, with `keypair` standing in for both the
+    /// interface's and the server's keys.
+    fn invitation_toml(keypair: &wireguard_control::KeyPair) -> String {
+        format!(
+            "[interface]\nnetwork-name = \"office\"\naddress = \"10.66.0.2/16\"\n\
+             private-key = \"{}\"\n\n[server]\npublic-key = \"{}\"\n\
+             external-endpoint = \"203.0.113.1:51820\"\ninternal-endpoint = \"10.66.0.1:51820\"\n",
+            keypair.private.to_base64(),
+            keypair.public.to_base64(),
+        )
+    }
+
+    #[test]
+    fn test_dirs_from_env() {
+        let parse = |args: &[&str]| Opts::try_parse_from(args).unwrap();
+
+        std::env::remove_var("INNERNET_CONFIG_DIR");
+        std::env::remove_var("INNERNET_DATA_DIR");
+        let opts = parse(&["innernet"]);
+        assert_eq!(opts.config_dir, Path::new("/etc/innernet"));
+        assert_eq!(opts.data_dir, Path::new("/var/lib/innernet"));
+
+        std::env::set_var("INNERNET_CONFIG_DIR", "/tmp/innernet-env/config");
+        std::env::set_var("INNERNET_DATA_DIR", "/tmp/innernet-env/data");
+        let opts = parse(&["innernet"]);
+        assert_eq!(opts.config_dir, Path::new("/tmp/innernet-env/config"));
+        assert_eq!(opts.data_dir, Path::new("/tmp/innernet-env/data"));
+        let interface: InterfaceName = "wg0".parse().unwrap();
+        assert_eq!(
+            InterfaceConfig::get_path(&opts.config_dir, &interface),
+            Path::new("/tmp/innernet-env/config/wg0.conf")
+        );
+
+        let opts = parse(&["innernet", "-c", "/tmp/flag/config", "-d", "/tmp/flag/data"]);
+        assert_eq!(opts.config_dir, Path::new("/tmp/flag/config"));
+        assert_eq!(opts.data_dir, Path::new("/tmp/flag/data"));
+
+        std::env::remove_var("INNERNET_CONFIG_DIR");
+        std::env::remove_var("INNERNET_DATA_DIR");
+    }
+
+    #[test]
+    fn test_post_install_surfaced() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("invitation.toml");
+        let keypair = wireguard_control::KeyPair::generate();
+        let toml = invitation_toml(&keypair);
+        std::fs::write(
+            &path,
+            format!("# post_install:\n#   sudo ip route add 10.99.0.0/16 dev office\n\n{toml}"),
+        )?;
+
+        let config = InterfaceConfig::from_file(&path)?;
+        let steps = config.post_install.expect("post-install steps");
+        assert!(post_install_message(&steps)
+            .contains("        sudo ip route add 10.99.0.0/16 dev office\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_effective_config() -> Result<(), Error> {
+        let keypair = wireguard_control::KeyPair::generate();
+        let config: InterfaceConfig = toml::from_str(&format!(
+            "{}preshared-key = \"{}\"\nread-timeout = 10\n",
+            invitation_toml(&keypair),
+            keypair.public.to_base64(),
+        ))?;
+
+        let effective = effective_config(config.clone(), None, None);
+        assert_eq!(effective.mtu, wg::DEFAULT_MTU);
+        assert_eq!(effective.keepalive, None);
+        assert_eq!(effective.server.connect_timeout, Some(5));
+        assert_eq!(effective.server.read_timeout, Some(10));
+        assert_eq!(effective.server.retries, Some(2));
+        let shown = toml::to_string(&effective)?;
+        assert!(!shown.contains(&keypair.private.to_base64()));
+        assert!(shown.contains("preshared-key = \"(redacted)\""));
+
+        assert_eq!(effective_config(config, None, Some(1420)).mtu, 1420);
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_interface_files() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let (config_dir, data_dir) = (dir.path().join("config"), dir.path().join("data"));
+        let (old, new): (InterfaceName, InterfaceName) = ("office".parse()?, "work".parse()?);
+        let keypair = wireguard_control::KeyPair::generate();
+        let toml = invitation_toml(&keypair);
+        std::fs::create_dir_all(&config_dir)?;
+        std::fs::write(InterfaceConfig::get_path(&config_dir, &old), toml)?;
+        DataStore::open_or_create(&data_dir, &old)?.write()?;
+
+        move_interface_files(&config_dir, &data_dir, &old, &new)?;
+        assert!(!InterfaceConfig::get_path(&config_dir, &old).exists());
+        assert!(!DataStore::get_path(&data_dir, &old).exists());
+        let config = InterfaceConfig::from_interface(&config_dir, &new)?;
+        assert_eq!(config.interface.network_name, "work");
+        assert_eq!(config.interface.private_key, keypair.private.to_base64());
+        assert_eq!(config.interface.address, "10.66.0.2/16".parse()?);
+        assert!(DataStore::open(&data_dir, &new).is_ok());
+
+        // Renaming onto an existing network is refused, leaving both alone.
+        std::fs::copy(
+            InterfaceConfig::get_path(&config_dir, &new),
+            InterfaceConfig::get_path(&config_dir, &old),
+        )?;
+        assert!(move_interface_files(&config_dir, &data_dir, &old, &new).is_err());
+        assert!(InterfaceConfig::get_path(&config_dir, &old).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invitation_files() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let bundle = dir.path().join("bundle");
+        std::fs::create_dir_all(bundle.join("nested.toml"))?;
+        for name in ["b.conf", "a.toml", "README.txt"] {
+            std::fs::write(bundle.join(name), "")?;
+        }
+        let single = dir.path().join("home.toml");
+
+        // Directories expand to their invitations, in order, next to files given directly.
+        assert_eq!(
+            invitation_files(&[single.clone(), bundle.clone()])?,
+            vec![single, bundle.join("a.toml"), bundle.join("b.conf")]
+        );
+
+        let empty = dir.path().join("empty");
+        std::fs::create_dir(&empty)?;
+        assert!(invitation_files(&[empty]).is_err());
+        Ok(())
+    }
+}
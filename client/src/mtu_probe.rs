@@ -0,0 +1,103 @@
+//! An optional post-up check for paths that complete handshakes but drop full-size packets
+//! (ex. tunnels inside tunnels), which otherwise shows up as "connected, but nothing works".
+//!
+//! It pings the server's internal address through the tunnel with fragmentation disallowed,
+//! first at the interface's MTU, then searching down for the largest size that gets through.
+
+use std::{net::IpAddr, process::Command};
+
+/// What the probes found out about the path to the server.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Packets of the full MTU got through.
+    Fits,
+    /// Only packets up to `largest` bytes got through.
+    TooBig { largest: u32 },
+    /// Not even small packets got through, so there's nothing to say about the MTU (ex. the
+    /// server doesn't answer pings).
+    Unreachable,
+}
+
+/// The IP and ICMP header bytes that a ping's payload size doesn't include.
+fn header_overhead(target: IpAddr) -> u32 {
+    match target {
+        IpAddr::V4(_) => 20 + 8,
+        IpAddr::V6(_) => 40 + 8,
+    }
+}
+
+/// The smallest packet every path has to carry, used to tell a too-small MTU from no path.
+fn floor(target: IpAddr) -> u32 {
+    match target {
+        IpAddr::V4(_) => 576,
+        IpAddr::V6(_) => 1280,
+    }
+}
+
+/// Send one ping of `size` bytes (headers included) that isn't allowed to be fragmented.
+fn ping(target: IpAddr, size: u32) -> bool {
+    let payload = size.saturating_sub(header_overhead(target)).to_string();
+    let mut command = Command::new("ping");
+    if cfg!(target_os = "macos") {
+        command.args(["-c", "1", "-t", "1", "-D", "-s", &payload]);
+    } else {
+        if target.is_ipv6() {
+            command.arg("-6");
+        }
+        command.args(["-c", "1", "-W", "1", "-M", "do", "-s", &payload]);
+    }
+    command
+        .arg(target.to_string())
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Whether `target` answers a single ping of the smallest size every path has to carry.
+pub fn answers(target: IpAddr) -> bool {
+    ping(target, floor(target))
+}
+
+/// Probe the path to `target` for packets of up to `mtu` bytes.
+pub fn check(target: IpAddr, mtu: u32) -> Outcome {
+    search(mtu, floor(target).min(mtu), |size| ping(target, size))
+}
+
+fn search(mtu: u32, floor: u32, mut probe: impl FnMut(u32) -> bool) -> Outcome {
+    if probe(mtu) {
+        return Outcome::Fits;
+    }
+    if !probe(floor) {
+        return Outcome::Unreachable;
+    }
+    // `low` always gets through and `high` never does.
+    let (mut low, mut high) = (floor, mtu);
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if probe(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Outcome::TooBig { largest: low }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        assert_eq!(search(1420, 576, |_| true), Outcome::Fits);
+        assert_eq!(search(1420, 576, |_| false), Outcome::Unreachable);
+        assert_eq!(
+            search(1420, 576, |size| size <= 1372),
+            Outcome::TooBig { largest: 1372 }
+        );
+        assert_eq!(
+            search(1420, 576, |size| size <= 576),
+            Outcome::TooBig { largest: 576 }
+        );
+    }
+}
@@ -6,9 +6,10 @@
 use std::time::{Duration, Instant};
 
 use anyhow::Error;
+use innernet_client::fetch::PeerUpdate;
 use shared::{
     wg::{DeviceExt, PeerInfoExt},
-    Endpoint, Peer, PeerDiff,
+    Endpoint, FamilyPreference, Peer,
 };
 use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
 
@@ -24,10 +25,14 @@ impl<'a> NatTraverse<'a> {
     pub fn new(
         interface: &'a InterfaceName,
         backend: Backend,
-        diffs: &[PeerDiff],
+        updates: &[PeerUpdate],
+        prefer_family: FamilyPreference,
     ) -> Result<Self, Error> {
-        // Filter out removed peers from diffs list.
-        let mut remaining: Vec<_> = diffs.iter().filter_map(|diff| diff.new).cloned().collect();
+        // Filter out removed peers from updates list.
+        let mut remaining: Vec<_> = updates
+            .iter()
+            .filter_map(|update| update.peer.clone())
+            .collect();
 
         for peer in &mut remaining {
             // Limit reported alternative candidates to 10.
@@ -46,6 +51,11 @@ impl<'a> NatTraverse<'a> {
             if let Some(endpoint) = endpoint {
                 peer.candidates.insert(0, endpoint);
             }
+
+            // For the same reason, endpoints of the preferred family go to the end (and so
+            // are tried first), and the rest are only fallen back to.
+            peer.candidates
+                .sort_by_key(|endpoint| !prefer_family.is_fallback(endpoint));
         }
         let mut nat_traverse = Self {
             interface,
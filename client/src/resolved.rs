@@ -0,0 +1,127 @@
+//! Optional per-interface DNS registration with systemd-resolved.
+//!
+//! Registration is scoped to the innernet interface via resolved's per-link settings, so
+//! only lookups under the network's domain (ex. `peer.network.wg`) are routed to the given
+//! resolver and system-wide DNS is left alone. On systems without resolved, this is a no-op.
+
+use shared::Error;
+use std::net::IpAddr;
+use wireguard_control::InterfaceName;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::*;
+    use anyhow::{anyhow, bail};
+    use std::{fs, path::Path, process::Command};
+
+    const RESOLVED_RUNTIME_DIR: &str = "/run/systemd/resolve";
+    const AF_INET: u8 = 2;
+    const AF_INET6: u8 = 10;
+
+    pub fn is_available() -> bool {
+        Path::new(RESOLVED_RUNTIME_DIR).is_dir()
+    }
+
+    fn link_index(interface: &InterfaceName) -> Result<String, Error> {
+        let path = format!("/sys/class/net/{}/ifindex", interface.as_str_lossy());
+        Ok(fs::read_to_string(&path)
+            .map_err(|e| anyhow!("couldn't read {}: {}", path, e))?
+            .trim()
+            .to_string())
+    }
+
+    /// Call a method on resolved's D-Bus manager interface.
+    fn call(method: &str, signature: &str, args: &[String]) -> Result<(), Error> {
+        let output = Command::new("busctl")
+            .args([
+                "call",
+                "org.freedesktop.resolve1",
+                "/org/freedesktop/resolve1",
+                "org.freedesktop.resolve1.Manager",
+                method,
+                signature,
+            ])
+            .args(args)
+            .output()?;
+        log::debug!("busctl {} {}: {:?}", method, args.join(" "), output.status);
+        if !output.status.success() {
+            bail!(
+                "systemd-resolved {} call failed: {}",
+                method,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn register(interface: &InterfaceName, dns: IpAddr) -> Result<(), Error> {
+        let index = link_index(interface)?;
+
+        let (family, octets) = match dns {
+            IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+        };
+        let mut dns_args = vec![
+            index.clone(),
+            "1".into(),
+            family.to_string(),
+            octets.len().to_string(),
+        ];
+        dns_args.extend(octets.iter().map(u8::to_string));
+        call("SetLinkDNS", "ia(iay)", &dns_args)?;
+
+        // A routing-only domain, so that only lookups for this network go to its resolver.
+        let domain = format!("{}.wg", interface.as_str_lossy());
+        call(
+            "SetLinkDomains",
+            "ia(sb)",
+            &[index, "1".into(), domain, "true".into()],
+        )
+    }
+
+    pub fn unregister(interface: &InterfaceName) -> Result<(), Error> {
+        call("RevertLink", "i", &[link_index(interface)?])
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::*;
+
+    pub fn is_available() -> bool {
+        false
+    }
+
+    pub fn register(_interface: &InterfaceName, _dns: IpAddr) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn unregister(_interface: &InterfaceName) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Register `dns` as the resolver for the interface's domain with systemd-resolved.
+pub fn register(interface: &InterfaceName, dns: IpAddr) -> Result<(), Error> {
+    if !imp::is_available() {
+        log::info!("systemd-resolved isn't running, skipping DNS registration.");
+        return Ok(());
+    }
+
+    imp::register(interface, dns)?;
+    log::info!(
+        "registered {} as the resolver for {}.wg with systemd-resolved.",
+        dns,
+        interface.as_str_lossy()
+    );
+    Ok(())
+}
+
+/// Remove any DNS registration for the interface from systemd-resolved.
+pub fn unregister(interface: &InterfaceName) -> Result<(), Error> {
+    if imp::is_available() {
+        imp::unregister(interface)?;
+        log::debug!("reverted systemd-resolved settings for {}.", interface);
+    }
+    Ok(())
+}
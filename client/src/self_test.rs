@@ -0,0 +1,253 @@
+//! `innernet test`, answering "is it working?" with a pass or fail for each of a few checks
+//! on a live interface.
+//!
+//! Handshake freshness comes from the device's own stats, so nothing is sent unless pings
+//! are asked for. A server or peer that doesn't answer a ping may just be dropping them,
+//! so pings only ever warn.
+
+use colored::*;
+use shared::{
+    interface_config::ServerInfo,
+    wg::{HandshakeHealth, PeerInfoExt},
+    Peer,
+};
+use std::{net::IpAddr, time::Duration};
+use wireguard_control::{InterfaceName, PeerInfo};
+
+use crate::util::human_duration;
+
+/// How many peers besides the server get pinged.
+const PEERS_TO_PING: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    /// What was found, and for anything but a pass, what to do about it.
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// Check `interface`, whose `live` peers are `None` if it isn't up. `peers` are the ones
+/// known from the last fetch, and `ping` is given to also ping the server and a few peers.
+pub fn run(
+    interface: &InterfaceName,
+    server: &ServerInfo,
+    live: Option<&[PeerInfo]>,
+    peers: &[Peer],
+    ping: Option<&dyn Fn(IpAddr) -> bool>,
+) -> Vec<Check> {
+    let mut checks = vec![];
+    let Some(live) = live else {
+        checks.push(Check::new(
+            "interface",
+            Status::Fail,
+            format!("{interface} isn't up, bring it up with `innernet up {interface}`."),
+        ));
+        return checks;
+    };
+    checks.push(Check::new(
+        "interface",
+        Status::Pass,
+        format!("{interface} is up with {} peers.", live.len()),
+    ));
+
+    let Some(server_info) = live
+        .iter()
+        .find(|info| info.config.public_key.to_base64() == server.public_key)
+    else {
+        checks.push(Check::new(
+            "server handshake",
+            Status::Fail,
+            format!(
+                "the server isn't configured on {interface}, run `innernet fetch {interface}`."
+            ),
+        ));
+        return checks;
+    };
+
+    let age = server_info
+        .handshake_age()
+        .map(|age| format!("the last one was {} ago", human_duration(age)))
+        .unwrap_or_else(|| "there's never been one".into());
+    let unreachable = format!(
+        "check that the server is running and that UDP to {} isn't blocked.",
+        server.external_endpoint
+    );
+    checks.push(match server_info.handshake_health() {
+        HandshakeHealth::Healthy => {
+            Check::new("server handshake", Status::Pass, format!("fresh, {age}."))
+        },
+        HandshakeHealth::Stale => Check::new(
+            "server handshake",
+            Status::Warn,
+            format!("overdue ({age}), {unreachable}"),
+        ),
+        HandshakeHealth::Down => Check::new(
+            "server handshake",
+            Status::Fail,
+            format!("no live session ({age}), {unreachable}"),
+        ),
+    });
+
+    if let Some(ping) = ping {
+        let server_ip = server.internal_endpoint.ip();
+        checks.push(ping_check("server", server_ip, ping));
+        for peer in peers_to_ping(live, peers, server) {
+            checks.push(ping_check(&peer.name.to_string(), peer.ip, ping));
+        }
+    }
+    checks
+}
+
+/// The peers with the freshest handshakes (other than the server), which should answer.
+fn peers_to_ping<'a>(live: &[PeerInfo], peers: &'a [Peer], server: &ServerInfo) -> Vec<&'a Peer> {
+    let mut candidates: Vec<_> = live
+        .iter()
+        .filter(|info| info.handshake_health() == HandshakeHealth::Healthy)
+        .filter_map(|info| {
+            let public_key = info.config.public_key.to_base64();
+            let peer = peers.iter().find(|peer| {
+                peer.public_key == public_key && peer.public_key != server.public_key
+            })?;
+            Some((info.handshake_age().unwrap_or(Duration::MAX), peer))
+        })
+        .collect();
+    candidates.sort_by_key(|(age, _)| *age);
+    candidates
+        .into_iter()
+        .take(PEERS_TO_PING)
+        .map(|(_, peer)| peer)
+        .collect()
+}
+
+fn ping_check(name: &str, ip: IpAddr, ping: &dyn Fn(IpAddr) -> bool) -> Check {
+    let name = format!("ping {name}");
+    if ping(ip) {
+        Check::new(name, Status::Pass, format!("{ip} answered."))
+    } else {
+        Check::new(
+            name,
+            Status::Warn,
+            format!("{ip} didn't answer, though it may just not answer pings."),
+        )
+    }
+}
+
+/// Whether none of `checks` failed outright.
+pub fn passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.status != Status::Fail)
+}
+
+pub fn print(checks: &[Check]) {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or_default();
+    for check in checks {
+        let status = match check.status {
+            Status::Pass => "pass".green(),
+            Status::Warn => "warn".yellow(),
+            Status::Fail => "fail".red(),
+        };
+        println!(
+            "  [{}] {:width$}  {}",
+            status,
+            check.name.bold(),
+            check.message
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{info, peer};
+    use std::time::SystemTime;
+    use wireguard_control::Key;
+
+    #[test]
+    fn test_self_test() {
+        let interface: InterfaceName = "test".parse().unwrap();
+        let keys: Vec<_> = (0..4)
+            .map(|_| Key::generate_private().get_public().to_base64())
+            .collect();
+        let server = ServerInfo {
+            public_key: keys[0].clone(),
+            external_endpoint: "203.0.113.1:51820".parse().unwrap(),
+            internal_endpoint: "10.0.0.1:51820".parse().unwrap(),
+            preshared_key: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retries: None,
+            allowed_ips: None,
+        };
+        let now = SystemTime::now();
+        let statuses = |checks: Vec<Check>| {
+            checks
+                .into_iter()
+                .map(|check| (check.name, check.status))
+                .collect::<Vec<_>>()
+        };
+
+        let checks = run(&interface, &server, None, &[], None);
+        assert!(!passed(&checks));
+        assert_eq!(statuses(checks), [("interface".into(), Status::Fail)]);
+
+        // The server missing from the interface, or never handshaking, fails alike.
+        for live in [vec![], vec![info(&keys[0], None)]] {
+            let checks = run(&interface, &server, Some(&live), &[], None);
+            assert_eq!(
+                statuses(checks),
+                [
+                    ("interface".into(), Status::Pass),
+                    ("server handshake".into(), Status::Fail),
+                ]
+            );
+        }
+
+        // Pings go to the server, then the peers with the freshest handshakes.
+        let live = [
+            info(&keys[0], Some(now)),
+            info(&keys[1], Some(now - Duration::from_secs(30))),
+            info(&keys[2], Some(now - Duration::from_secs(60))),
+            info(&keys[3], Some(now - Duration::from_secs(10))),
+        ];
+        let peers = [
+            peer(1, "server", &keys[0], "10.0.0.1"),
+            peer(2, "one", &keys[1], "10.0.0.2"),
+            peer(3, "two", &keys[2], "10.0.0.3"),
+            peer(4, "three", &keys[3], "10.0.0.4"),
+        ];
+        let answers = |ip: IpAddr| ip != "10.0.0.2".parse::<IpAddr>().unwrap();
+        let checks = run(&interface, &server, Some(&live), &peers, Some(&answers));
+        assert!(passed(&checks));
+        assert_eq!(
+            statuses(checks),
+            [
+                ("interface".into(), Status::Pass),
+                ("server handshake".into(), Status::Pass),
+                ("ping server".into(), Status::Pass),
+                ("ping three".into(), Status::Pass),
+                ("ping one".into(), Status::Warn),
+            ]
+        );
+    }
+}
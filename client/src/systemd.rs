@@ -0,0 +1,283 @@
+//! Optional per-interface systemd units, generated by `innernet install --systemd`.
+//!
+//! The generated `innernet@<interface>.service` lives in /etc/systemd/system, where it takes
+//! precedence over the packaged `innernet@.service` template for that one instance. Unlike
+//! the template, it points at the config and data directories the interface was actually
+//! installed with. On systems not running systemd, this is a no-op.
+
+use shared::Error;
+use std::path::{Path, PathBuf};
+use wireguard_control::InterfaceName;
+
+/// Marks a unit as generated by innernet, so that uninstalling never removes one that was
+/// written by hand.
+const GENERATED_HEADER: &str = "# Generated by `innernet install --systemd`.";
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Everything a generated unit needs to run the interface's fetch loop.
+pub struct UnitOpts<'a> {
+    pub executable: &'a Path,
+    pub config_dir: &'a Path,
+    pub data_dir: &'a Path,
+    pub hosts_path: Option<&'a Path>,
+}
+
+fn unit_name(interface: &InterfaceName) -> String {
+    format!("innernet@{}.service", interface.as_str_lossy())
+}
+
+fn unit_path(unit_dir: &Path, interface: &InterfaceName) -> PathBuf {
+    unit_dir.join(unit_name(interface))
+}
+
+/// Quote an argument for an `ExecStart=` line if it needs it.
+fn quote(arg: &Path) -> String {
+    let arg = arg.to_string_lossy();
+    if arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.into_owned()
+    }
+}
+
+fn render(interface: &InterfaceName, opts: &UnitOpts) -> String {
+    let hosts = match opts.hosts_path {
+        Some(path) => format!("--hosts-path {}", quote(path)),
+        None => "--no-write-hosts".to_string(),
+    };
+    format!(
+        "{header}
+[Unit]
+Description=innernet client daemon for {interface}
+After=network-online.target nss-lookup.target
+Wants=network-online.target nss-lookup.target
+PartOf=innernet.target
+
+[Service]
+Type=simple
+ExecStart={executable} --config-dir {config_dir} --data-dir {data_dir} up {interface} --daemon --interval 60 {hosts}
+Restart=always
+RestartSec=10
+
+[Install]
+WantedBy=multi-user.target
+",
+        header = GENERATED_HEADER,
+        interface = interface.as_str_lossy(),
+        executable = quote(opts.executable),
+        config_dir = quote(opts.config_dir),
+        data_dir = quote(opts.data_dir),
+    )
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::*;
+    use anyhow::bail;
+    use std::process::Command;
+
+    pub fn is_available() -> bool {
+        Path::new("/run/systemd/system").is_dir()
+    }
+
+    pub fn systemctl(args: &[&str]) -> Result<(), Error> {
+        let output = Command::new("systemctl").args(args).output()?;
+        log::debug!("systemctl {}: {:?}", args.join(" "), output.status);
+        if !output.status.success() {
+            bail!(
+                "systemctl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::*;
+
+    pub fn is_available() -> bool {
+        false
+    }
+
+    pub fn systemctl(_args: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Write the interface's unit and enable it, starting its fetch loop right away if `start`
+/// is set (otherwise it starts on the next boot).
+pub fn install(interface: &InterfaceName, opts: &UnitOpts, start: bool) -> Result<(), Error> {
+    if !imp::is_available() {
+        log::warn!("systemd isn't running, not installing a service for {interface}.");
+        return Ok(());
+    }
+    install_in(Path::new(UNIT_DIR), &imp::systemctl, interface, opts, start)
+}
+
+/// Disable and remove the interface's unit, if `innernet install --systemd` generated one.
+pub fn uninstall(interface: &InterfaceName) -> Result<(), Error> {
+    if !imp::is_available() {
+        return Ok(());
+    }
+    uninstall_in(Path::new(UNIT_DIR), &imp::systemctl, interface)
+}
+
+/// Move the unit `innernet install --systemd` generated for `old` (if any) over to `new`,
+/// written with `opts` and started right away if `start` is set. Returns whether there was
+/// one to move.
+pub fn rename(
+    old: &InterfaceName,
+    new: &InterfaceName,
+    opts: &UnitOpts,
+    start: bool,
+) -> Result<bool, Error> {
+    if !imp::is_available() {
+        return Ok(false);
+    }
+    rename_in(Path::new(UNIT_DIR), &imp::systemctl, old, new, opts, start)
+}
+
+fn install_in(
+    unit_dir: &Path,
+    systemctl: &dyn Fn(&[&str]) -> Result<(), Error>,
+    interface: &InterfaceName,
+    opts: &UnitOpts,
+    start: bool,
+) -> Result<(), Error> {
+    std::fs::write(unit_path(unit_dir, interface), render(interface, opts))?;
+    systemctl(&["daemon-reload"])?;
+    let unit = unit_name(interface);
+    if start {
+        systemctl(&["enable", "--now", &unit])?;
+    } else {
+        systemctl(&["enable", &unit])?;
+    }
+    log::info!("enabled {}.", unit);
+    Ok(())
+}
+
+fn uninstall_in(
+    unit_dir: &Path,
+    systemctl: &dyn Fn(&[&str]) -> Result<(), Error>,
+    interface: &InterfaceName,
+) -> Result<(), Error> {
+    let path = unit_path(unit_dir, interface);
+    if !is_generated(&path) {
+        return Ok(());
+    }
+
+    systemctl(&["disable", "--now", &unit_name(interface)])?;
+    std::fs::remove_file(&path)?;
+    systemctl(&["daemon-reload"])?;
+    log::info!("disabled and removed {}.", unit_name(interface));
+    Ok(())
+}
+
+fn rename_in(
+    unit_dir: &Path,
+    systemctl: &dyn Fn(&[&str]) -> Result<(), Error>,
+    old: &InterfaceName,
+    new: &InterfaceName,
+    opts: &UnitOpts,
+    start: bool,
+) -> Result<bool, Error> {
+    if !is_generated(&unit_path(unit_dir, old)) {
+        return Ok(false);
+    }
+    uninstall_in(unit_dir, systemctl, old)?;
+    install_in(unit_dir, systemctl, new, opts, start)?;
+    Ok(true)
+}
+
+fn is_generated(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.starts_with(GENERATED_HEADER))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let interface: InterfaceName = "office".parse().unwrap();
+        let unit = render(
+            &interface,
+            &UnitOpts {
+                executable: Path::new("/usr/local/bin/innernet"),
+                config_dir: Path::new("/srv/innernet config"),
+                data_dir: Path::new("/srv/innernet-data"),
+                hosts_path: None,
+            },
+        );
+        assert!(unit.contains(
+            "ExecStart=/usr/local/bin/innernet --config-dir \"/srv/innernet config\" \
+             --data-dir /srv/innernet-data up office --daemon --interval 60 --no-write-hosts\n"
+        ));
+        assert!(unit.contains("After=network-online.target"));
+    }
+
+    #[test]
+    fn test_only_generated_units_are_removed() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let interface: InterfaceName = "office".parse()?;
+        let path = unit_path(dir.path(), &interface);
+        assert!(!is_generated(&path));
+
+        std::fs::write(&path, "[Unit]\nDescription=hand-written\n")?;
+        assert!(!is_generated(&path));
+
+        let opts = UnitOpts {
+            executable: Path::new("/usr/bin/innernet"),
+            config_dir: Path::new("/etc/innernet"),
+            data_dir: Path::new("/var/lib/innernet"),
+            hosts_path: Some(Path::new("/etc/hosts")),
+        };
+        std::fs::write(&path, render(&interface, &opts))?;
+        assert!(is_generated(&path));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let (old, new): (InterfaceName, InterfaceName) = ("office".parse()?, "work".parse()?);
+        let opts = UnitOpts {
+            executable: Path::new("/usr/bin/innernet"),
+            config_dir: Path::new("/etc/innernet"),
+            data_dir: Path::new("/var/lib/innernet"),
+            hosts_path: None,
+        };
+        let calls = std::cell::RefCell::new(vec![]);
+        let systemctl = |args: &[&str]| {
+            calls.borrow_mut().push(args.join(" "));
+            Ok(())
+        };
+
+        // Without a generated unit, there's nothing to move.
+        assert!(!rename_in(dir.path(), &systemctl, &old, &new, &opts, true)?);
+        assert!(calls.borrow().is_empty());
+
+        install_in(dir.path(), &systemctl, &old, &opts, true)?;
+        calls.borrow_mut().clear();
+        assert!(rename_in(dir.path(), &systemctl, &old, &new, &opts, true)?);
+        assert!(!unit_path(dir.path(), &old).exists());
+        let unit = std::fs::read_to_string(unit_path(dir.path(), &new))?;
+        assert!(unit.contains(" up work --daemon "));
+        assert_eq!(
+            *calls.borrow(),
+            [
+                "disable --now innernet@office.service",
+                "daemon-reload",
+                "daemon-reload",
+                "enable --now innernet@work.service",
+            ]
+        );
+        Ok(())
+    }
+}
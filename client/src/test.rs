@@ -0,0 +1,45 @@
+//! Helpers shared by the client's tests, in the library and the binary alike.
+
+use shared::{Peer, PeerContents};
+use std::time::SystemTime;
+use wireguard_control::{Key, PeerConfigBuilder, PeerInfo, PeerStats};
+
+/// A redeemed peer with only what's given set, for tests to adjust as they need.
+pub fn peer(id: i64, name: &str, public_key: &str, ip: &str) -> Peer {
+    Peer {
+        id,
+        contents: PeerContents {
+            name: name.parse().unwrap(),
+            ip: ip.parse().unwrap(),
+            cidr_id: 1,
+            public_key: public_key.into(),
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            is_admin: false,
+            is_disabled: false,
+            is_redeemed: true,
+            invite_expires: None,
+            candidates: vec![],
+            tags: vec![],
+            preshared_key: None,
+            last_handshake: None,
+            advertised_routes: vec![],
+            is_observer: false,
+            is_staged: false,
+            created_at: None,
+            created_by: None,
+            description: None,
+        },
+    }
+}
+
+/// What the interface reports for the peer with `public_key`, with only its last handshake.
+pub fn info(public_key: &str, last_handshake_time: Option<SystemTime>) -> PeerInfo {
+    PeerInfo {
+        config: PeerConfigBuilder::new(&Key::from_base64(public_key).unwrap()).into_peer_config(),
+        stats: PeerStats {
+            last_handshake_time,
+            ..Default::default()
+        },
+    }
+}
@@ -1,12 +1,22 @@
 use crate::data_store::DataStore;
 use colored::*;
 use indoc::eprintdoc;
+use innernet_client::{
+    error::ClientError,
+    fetch::{next_retry, PeerUpdate, UpdateKind},
+};
 use log::{Level, LevelFilter};
 use serde::{de::DeserializeOwned, Serialize};
 use shared::{
-    interface_config::ServerInfo, Interface, PeerChange, PeerDiff, INNERNET_PUBKEY_HEADER,
+    interface_config::ServerInfo, Interface, PeerChange, PeerDescription, WrappedIoError,
+    INNERNET_PUBKEY_HEADER,
+};
+use std::{
+    ffi::{CStr, OsStr},
+    io,
+    path::Path,
+    time::Duration,
 };
-use std::{ffi::OsStr, io, path::Path, time::Duration};
 use ureq::{Agent, AgentBuilder};
 
 static LOGGER: Logger = Logger;
@@ -35,15 +45,21 @@ impl log::Log for Logger {
                 Level::Debug => "[D]".blue(),
                 Level::Trace => "[T]".purple(),
             };
-            if record.level() <= LevelFilter::Debug && !target_is_base(record.target()) {
-                println!(
+            let line = if record.level() <= LevelFilter::Debug && !target_is_base(record.target()) {
+                format!(
                     "{} {} {}",
                     level_str,
                     format!("[{}]", record.target()).dimmed(),
                     record.args()
-                );
+                )
             } else {
-                println!("{} {}", level_str, record.args());
+                format!("{} {}", level_str, record.args())
+            };
+            // stdout is reserved for events when they're on.
+            if crate::events::is_enabled() {
+                eprintln!("{line}");
+            } else {
+                println!("{line}");
             }
         }
     }
@@ -51,6 +67,21 @@ impl log::Log for Logger {
     fn flush(&self) {}
 }
 
+/// What this machine says about itself to the server, with `report-description` on.
+pub fn local_description() -> PeerDescription {
+    let mut buf = [0u8; 256];
+    let hostname = (unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0)
+        .then(|| CStr::from_bytes_until_nul(&buf).ok())
+        .flatten()
+        .map(|name| name.to_string_lossy().into_owned());
+    PeerDescription {
+        hostname,
+        os: Some(std::env::consts::OS.to_string()),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }
+    .sanitized()
+}
+
 pub fn init_logger(verbosity: u8) {
     let level = match verbosity {
         0 => log::LevelFilter::Info,
@@ -90,18 +121,82 @@ pub fn human_duration(duration: Duration) -> String {
     }
 }
 
-pub fn human_size(bytes: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = 1024 * KB;
-    const GB: u64 = 1024 * MB;
-    const TB: u64 = 1024 * GB;
-    match bytes {
-        n if n < 2 * KB => format!("{} {}", n, "B".cyan()),
-        n if n < 2 * MB => format!("{:.2} {}", n as f64 / KB as f64, "KiB".cyan()),
-        n if n < 2 * GB => format!("{:.2} {}", n as f64 / MB as f64, "MiB".cyan()),
-        n if n < 2 * TB => format!("{:.2} {}", n as f64 / GB as f64, "GiB".cyan()),
-        n => format!("{:.2} {}", n as f64 / TB as f64, "TiB".cyan()),
+/// Exponential backoff with jitter for the daemon's fetch loop, so that peers don't all
+/// stampede the server the moment it comes back.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: f64,
+    failures: u32,
+}
+
+impl Backoff {
+    /// `jitter` is the fraction (0.0 to 1.0) by which each backoff delay is randomly
+    /// shortened or lengthened.
+    pub fn new(base: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            jitter: jitter.clamp(0.0, 1.0),
+            failures: 0,
+        }
+    }
+
+    /// The interval to wait after a successful fetch, which also resets the backoff.
+    pub fn succeeded(&mut self) -> Duration {
+        self.failures = 0;
+        self.base
     }
+
+    /// The interval to wait after another consecutive failed fetch.
+    pub fn failed(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max);
+        let factor = 1.0 - self.jitter + 2.0 * self.jitter * random_unit();
+        delay.mul_f64(factor)
+    }
+}
+
+/// A random number in [0, 1], good enough for jitter without pulling in an RNG.
+fn random_unit() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish() as f64 / u64::MAX as f64
+}
+
+/// Whether a failed fetch is worth retrying, i.e. the server or network is (hopefully)
+/// only temporarily unavailable. A 4xx response from the server means it understood and
+/// refused the request (ex. the peer is disabled), so retrying won't help.
+pub fn is_transient_error(e: &anyhow::Error) -> bool {
+    if let Some(e) = ClientError::find(e) {
+        return match e {
+            ClientError::Network(_) | ClientError::Server(_) => true,
+            ClientError::Config(_) | ClientError::Auth(_) | ClientError::Request(_) => false,
+        };
+    }
+    if let Some(e) = e.downcast_ref::<ureq::Error>() {
+        return !matches!(e, ureq::Error::Status(400..=499, _));
+    }
+    let io_error = e
+        .downcast_ref::<WrappedIoError>()
+        .map(|e| &**e)
+        .or_else(|| e.downcast_ref::<io::Error>());
+    matches!(
+        io_error.map(io::Error::kind),
+        Some(
+            io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+        )
+    )
 }
 
 pub fn permissions_helptext(config_dir: &Path, data_dir: &Path, e: &io::Error) {
@@ -139,47 +234,31 @@ pub fn permissions_helptext(config_dir: &Path, data_dir: &Path, e: &io::Error) {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum ChangeAction {
-    Added,
-    Modified,
-    Removed,
-}
-
-impl ChangeAction {
-    fn colored_output(&self) -> ColoredString {
-        match self {
-            Self::Added => "added".green(),
-            Self::Modified => "modified".yellow(),
-            Self::Removed => "removed".red(),
-        }
+fn colored_kind(kind: UpdateKind) -> ColoredString {
+    match kind {
+        UpdateKind::Added => "added".green(),
+        UpdateKind::Modified => "modified".yellow(),
+        UpdateKind::Removed => "removed".red(),
     }
 }
 
-pub fn print_peer_diff(store: &DataStore, diff: &PeerDiff) {
-    let public_key = diff.public_key().to_base64();
-
-    let change_action = match (diff.old, diff.new) {
-        (None, Some(_)) => ChangeAction::Added,
-        (Some(_), Some(_)) => ChangeAction::Modified,
-        (Some(_), None) => ChangeAction::Removed,
-        _ => unreachable!("PeerDiff can't be None -> None"),
-    };
+pub fn print_peer_update(store: &DataStore, update: &PeerUpdate) {
+    let public_key = &update.public_key;
 
     // Grab the peer name from either the new data, or the historical data (if the peer is removed).
-    let peer_hostname = match diff.new {
+    let peer_hostname = match &update.peer {
         Some(peer) => Some(peer.name.clone()),
         None => store
             .peers()
             .iter()
-            .find(|p| p.public_key == public_key)
+            .find(|p| p.public_key == *public_key)
             .map(|p| p.name.clone()),
     };
     let peer_name = peer_hostname.as_deref().unwrap_or("[unknown]");
 
-    if change_action == ChangeAction::Modified
-        && diff
-            .changes()
+    if update.kind == UpdateKind::Modified
+        && update
+            .changes
             .iter()
             .all(|c| *c == PeerChange::NatTraverseReattempt)
     {
@@ -192,10 +271,10 @@ pub fn print_peer_diff(store: &DataStore, diff: &PeerDiff) {
         "  peer {} ({}...) was {}.",
         peer_name.yellow(),
         &public_key[..10].dimmed(),
-        change_action.colored_output(),
+        colored_kind(update.kind),
     );
 
-    for change in diff.changes() {
+    for change in &update.changes {
         if let PeerChange::Endpoint { .. } = change {
             log::info!("    {}", change);
         } else {
@@ -236,8 +315,11 @@ pub struct Api<'a> {
 
 impl<'a> Api<'a> {
     pub fn new(server: &'a ServerInfo) -> Self {
+        let read_timeout = server.api_read_timeout();
         let agent = AgentBuilder::new()
-            .timeout(Duration::from_secs(5))
+            .timeout_connect(server.api_connect_timeout())
+            .timeout_read(read_timeout)
+            .timeout_write(read_timeout)
             .redirects(0)
             .build();
         Self { agent, server }
@@ -258,19 +340,62 @@ impl<'a> Api<'a> {
         self.request(verb, endpoint, Some(form))
     }
 
+    /// Send a request, retrying GETs (which never change anything) a few times if the server
+    /// can't be reached or is too slow to answer.
     #[allow(clippy::result_large_err)]
     fn request<S: Serialize, T: DeserializeOwned>(
         &self,
         verb: &str,
         endpoint: &str,
         form: Option<S>,
+    ) -> Result<T, ureq::Error> {
+        let retries = if verb == "GET" {
+            self.server.api_retries()
+        } else {
+            0
+        };
+        let mut attempt = 0;
+        loop {
+            match self.request_once(verb, endpoint, form.as_ref()) {
+                Err(ureq::Error::Transport(e)) => {
+                    let request = format!("{verb} {endpoint}");
+                    if let Some(delay) = next_retry(&request, &e, &mut attempt, retries) {
+                        std::thread::sleep(delay);
+                        continue;
+                    }
+                    if !is_timeout(&e) {
+                        return Err(e.into());
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "timed out waiting for the server at {} ({} attempt(s))",
+                            self.server.internal_endpoint,
+                            attempt + 1
+                        ),
+                    )
+                    .into());
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// The URL of an API endpoint. `SocketAddr` brackets IPv6 addresses, as URLs need.
+    fn url(&self, endpoint: &str) -> String {
+        format!("http://{}/v1{}", self.server.internal_endpoint, endpoint)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn request_once<S: Serialize, T: DeserializeOwned>(
+        &self,
+        verb: &str,
+        endpoint: &str,
+        form: Option<&S>,
     ) -> Result<T, ureq::Error> {
         let request = self
             .agent
-            .request(
-                verb,
-                &format!("http://{}/v1{}", self.server.internal_endpoint, endpoint),
-            )
+            .request(verb, &self.url(endpoint))
             .set(INNERNET_PUBKEY_HEADER, &self.server.public_key);
 
         let response = if let Some(form) = form {
@@ -283,13 +408,12 @@ impl<'a> Api<'a> {
         } else {
             request.call()?
         };
-
         let mut response = response.into_string()?;
         // A little trick for serde to parse an empty response as `()`.
         if response.is_empty() {
             response = "null".into();
         }
-        Ok(serde_json::from_str(&response).map_err(|e| {
+        let value = serde_json::from_str(&response).map_err(|e| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
@@ -297,6 +421,152 @@ impl<'a> Api<'a> {
                     e, &response
                 ),
             )
-        })?)
+        })?;
+        Ok(value)
+    }
+}
+
+/// Whether a request failed because the server took too long, rather than refusing it.
+fn is_timeout(e: &ureq::Transport) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            );
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(60), Duration::from_secs(600), 0.0);
+        assert_eq!(backoff.failed(), Duration::from_secs(120));
+        assert_eq!(backoff.failed(), Duration::from_secs(240));
+        assert_eq!(backoff.failed(), Duration::from_secs(480));
+        assert_eq!(backoff.failed(), Duration::from_secs(600));
+        assert_eq!(backoff.failed(), Duration::from_secs(600));
+        assert_eq!(backoff.succeeded(), Duration::from_secs(60));
+        assert_eq!(backoff.failed(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_api_timeout_and_retries() {
+        use std::{
+            net::TcpListener,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+        };
+
+        // A server that accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+            preshared_key: None,
+            connect_timeout: Some(1),
+            read_timeout: Some(1),
+            retries: Some(1),
+            allowed_ips: None,
+        };
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            let mut streams = vec![];
+            for stream in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                streams.push(stream);
+            }
+        });
+
+        let api = Api::new(&server);
+        let e = api.http::<()>("GET", "/user/state").unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e}");
+        assert!(is_transient_error(&e.into()));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // Requests that change things are never retried.
+        api.http_form::<_, ()>("PUT", "/user/endpoint", ())
+            .unwrap_err();
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_conflict_not_retried() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+        };
+
+        // A server that refuses every request with a 409.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+            preshared_key: None,
+            connect_timeout: Some(1),
+            read_timeout: Some(1),
+            retries: Some(2),
+            allowed_ips: None,
+        };
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 409 Conflict\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+
+        let e = Api::new(&server)
+            .http::<()>("GET", "/user/state")
+            .unwrap_err();
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert!(!is_transient_error(&ClientError::from_http(e).into()));
+    }
+
+    #[test]
+    fn test_api_url_ipv6() {
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "[2001:db8::1]:51820".parse().unwrap(),
+            internal_endpoint: "[fd00::1]:5555".parse().unwrap(),
+            preshared_key: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retries: None,
+            allowed_ips: None,
+        };
+        assert_eq!(
+            Api::new(&server).url("/user/state"),
+            "http://[fd00::1]:5555/v1/user/state"
+        );
+    }
+
+    #[test]
+    fn test_backoff_jitter_bounds() {
+        let mut backoff = Backoff::new(Duration::from_secs(60), Duration::from_secs(600), 0.5);
+        for _ in 0..10 {
+            let delay = backoff.failed();
+            assert!(delay >= Duration::from_secs(60) && delay <= Duration::from_secs(900));
+        }
     }
 }
@@ -0,0 +1,124 @@
+//! `--watch` for `innernet list` and `innernet status`, which redraws their output on an
+//! interval like watch(1) does.
+//!
+//! Each redraw runs the same code as the one-shot command, with a [`HandshakeTracker`] so
+//! that peers whose handshake changed since the last redraw can be highlighted.
+
+use shared::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use wireguard_control::PeerInfo;
+
+/// How often to check for Ctrl-C while waiting for the next redraw.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Remembers each peer's last handshake between redraws.
+#[derive(Default)]
+pub struct HandshakeTracker {
+    handshakes: HashMap<String, Option<SystemTime>>,
+    primed: bool,
+}
+
+impl HandshakeTracker {
+    /// Record the latest handshakes, returning the public keys of the peers whose handshake
+    /// changed since the last call. Nothing has changed as of the first call.
+    pub fn update<'a>(&mut self, peers: impl IntoIterator<Item = &'a PeerInfo>) -> HashSet<String> {
+        let mut changed = HashSet::new();
+        for info in peers {
+            let public_key = info.config.public_key.to_base64();
+            let handshake = info.stats.last_handshake_time;
+            let previous = self.handshakes.insert(public_key.clone(), handshake);
+            if self.primed && previous.flatten() != handshake {
+                changed.insert(public_key);
+            }
+        }
+        self.primed = true;
+        changed
+    }
+}
+
+/// Redraw `draw`'s output every `interval` on the terminal's alternate screen until Ctrl-C,
+/// then put the terminal back the way it was.
+pub fn run(
+    interval: Duration,
+    title: &str,
+    mut draw: impl FnMut(&mut HandshakeTracker) -> Result<(), Error>,
+) -> Result<(), Error> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+
+    // Enter the alternate screen and hide the cursor.
+    print!("\x1b[?1049h\x1b[?25l");
+    let mut tracker = HandshakeTracker::default();
+    let result = loop {
+        // Clear the screen, then draw from the top.
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "Every {}s: {}  (Ctrl-C to exit)\n",
+            interval.as_secs(),
+            title
+        );
+        if let Err(e) = draw(&mut tracker).and_then(|()| Ok(io::stdout().flush()?)) {
+            break Err(e);
+        }
+
+        let next = Instant::now() + interval;
+        while Instant::now() < next && !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+    };
+
+    // Reset colors, show the cursor, and leave the alternate screen.
+    print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+    io::stdout().flush()?;
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::info;
+    use wireguard_control::Key;
+
+    #[test]
+    fn test_handshake_tracker() {
+        let (a, b) = (
+            Key::generate_private().get_public().to_base64(),
+            Key::generate_private().get_public().to_base64(),
+        );
+        let then = SystemTime::now() - Duration::from_secs(60);
+        let now = SystemTime::now();
+        let mut tracker = HandshakeTracker::default();
+
+        assert!(tracker
+            .update(&[info(&a, None), info(&b, Some(then))])
+            .is_empty());
+        assert!(tracker
+            .update(&[info(&a, None), info(&b, Some(then))])
+            .is_empty());
+        assert_eq!(
+            tracker.update(&[info(&a, Some(now)), info(&b, Some(then))]),
+            HashSet::from([a.clone()])
+        );
+        assert_eq!(
+            tracker.update(&[info(&a, Some(now)), info(&b, Some(now))]),
+            HashSet::from([b.clone()])
+        );
+    }
+}
@@ -97,7 +97,7 @@ impl HostsBuilder {
     /// Adds a mapping of `ip` to `hostname`. If there hostnames associated with the IP already,
     /// the hostname will be appended to the list.
     pub fn add_hostname<S: ToString>(&mut self, ip: IpAddr, hostname: S) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_insert_with(Vec::new);
+        let hostnames_dest = self.hostname_map.entry(ip).or_default();
         hostnames_dest.push(hostname.to_string());
     }
 
@@ -108,7 +108,7 @@ impl HostsBuilder {
         ip: IpAddr,
         hostnames: I,
     ) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_insert_with(Vec::new);
+        let hostnames_dest = self.hostname_map.entry(ip).or_default();
         for hostname in hostnames.into_iter() {
             hostnames_dest.push(hostname.to_string());
         }
@@ -131,17 +131,13 @@ impl HostsBuilder {
                 // the location depends on the environment variable %WinDir%.
                 format!(
                     "{}\\System32\\Drivers\\Etc\\hosts",
-                    std::env::var("WinDir").map_err(|_| io::Error::new(
-                        ErrorKind::Other,
+                    std::env::var("WinDir").map_err(|_| io::Error::other(
                         "WinDir environment variable missing".to_owned()
                     ))?
                 ),
             )
         } else {
-            return Err(io::Error::new(
-                ErrorKind::Other,
-                "unsupported operating system.".to_owned(),
-            ));
+            return Err(io::Error::other("unsupported operating system.".to_owned()));
         };
 
         if !hosts_file.exists() {
@@ -198,6 +194,7 @@ impl HostsBuilder {
 
         let hosts_file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(hosts_path)?;
@@ -340,4 +337,27 @@ mod tests {
         assert!(contents.contains("# DO NOT EDIT foo BEGIN"));
         assert!(contents.contains("1.1.1.1 whatever"));
     }
+
+    #[test]
+    fn test_write_dual_stack() {
+        let (mut temp_file, temp_path) = tempfile::NamedTempFile::new().unwrap().into_parts();
+        temp_file.write_all(b"127.0.0.1 localhost\n").unwrap();
+
+        let mut builder = HostsBuilder::new("foo");
+        builder.add_hostname("10.0.0.1".parse().unwrap(), "both");
+        builder.add_hostname("fd00::1".parse().unwrap(), "both");
+        builder.add_hostname("fd00::2".parse().unwrap(), "v6-only");
+        assert!(builder.write_to(&temp_path).unwrap());
+
+        let mut builder = HostsBuilder::new("foo");
+        builder.add_hostname("10.0.0.1".parse().unwrap(), "both");
+        builder.add_hostname("fd00::1".parse().unwrap(), "both");
+        assert!(builder.write_to(&temp_path).unwrap());
+
+        let contents = std::fs::read_to_string(&temp_path).unwrap();
+        assert_eq!(
+            contents,
+            "127.0.0.1 localhost\n\n# DO NOT EDIT foo BEGIN\n10.0.0.1 both\nfd00::1 both\n# DO NOT EDIT foo END\n"
+        );
+    }
 }
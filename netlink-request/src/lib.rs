@@ -61,7 +61,7 @@ mod linux {
             let responses =
                 netlink_request_genl::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK))?;
 
-            match responses.get(0) {
+            match responses.first() {
                 Some(NetlinkMessage {
                     payload:
                         NetlinkPayload::InnerMessage(GenlMessage {
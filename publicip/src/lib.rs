@@ -1,13 +1,13 @@
 //! Get your public IP address(es) as fast as possible, with no dependencies.
 //!
 //! Currently uses Cloudflare's DNS as it's the simplest, but that could change
-//! in the future.
+//! in the future. A STUN server can be queried instead with [`get_via_stun`].
 
 use std::{
     fs::File,
     io::{Cursor, Error, ErrorKind, Read, Write},
     marker::PhantomData,
-    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
     str::FromStr,
     time::Duration,
 };
@@ -150,6 +150,109 @@ impl<T: Into<IpAddr> + FromStr<Err = AddrParseError>> Request<T> {
     }
 }
 
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Ask a STUN server (RFC 5389) which address our requests come from.
+pub fn get_via_stun<A: ToSocketAddrs>(server: A) -> Result<IpAddr, Error> {
+    let server = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "STUN server didn't resolve"))?;
+    let bind_addr: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    socket.connect(server)?;
+
+    let mut transaction_id = [0u8; 12];
+    File::open("/dev/urandom")?.read_exact(&mut transaction_id)?;
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes()); // No attributes
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    socket.send(&request)?;
+
+    let mut buf = [0u8; 1500];
+    let len = socket.recv(&mut buf)?;
+    parse_stun_response(&buf[..len], &transaction_id)
+}
+
+fn parse_stun_response(response: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr, Error> {
+    let mut buf = Cursor::new(response);
+    ensure!(
+        buf.read_u16()? == STUN_BINDING_RESPONSE,
+        "not a STUN binding success response"
+    );
+    let attributes_len = buf.read_u16()? as usize;
+    ensure!(
+        buf.read_u32()? == STUN_MAGIC_COOKIE,
+        "STUN magic cookie doesn't match"
+    );
+    ensure!(
+        response.get(8..20) == Some(&transaction_id[..]),
+        "STUN transaction IDs don't match"
+    );
+    ensure!(
+        response.len() >= 20 + attributes_len,
+        "truncated STUN response"
+    );
+    buf.set_position(20);
+
+    let mut mapped = None;
+    while (buf.position() as usize) < 20 + attributes_len {
+        let kind = buf.read_u16()?;
+        let len = buf.read_u16()? as usize;
+        let start = buf.position() as usize;
+        let value = response
+            .get(start..start + len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated STUN attribute"))?;
+        match kind {
+            STUN_XOR_MAPPED_ADDRESS => {
+                return parse_stun_address(value, Some(transaction_id));
+            },
+            STUN_MAPPED_ADDRESS => mapped = Some(parse_stun_address(value, None)?),
+            _ => {},
+        }
+        // Attributes are padded to 4-byte boundaries.
+        buf.set_position((start + len.div_ceil(4) * 4) as u64);
+    }
+
+    mapped.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no mapped address in STUN response"))
+}
+
+/// Parse a (XOR-)MAPPED-ADDRESS value, un-XORing it when given the transaction ID.
+fn parse_stun_address(value: &[u8], xor_with: Option<&[u8; 12]>) -> Result<IpAddr, Error> {
+    let mut key = [0u8; 16];
+    if let Some(transaction_id) = xor_with {
+        key[..4].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+    }
+    let unmask =
+        |octets: &[u8]| -> Vec<u8> { octets.iter().zip(key.iter()).map(|(a, b)| a ^ b).collect() };
+
+    match (value.get(1), value.len()) {
+        (Some(0x01), 8) => {
+            let octets: [u8; 4] = unmask(&value[4..8]).try_into().unwrap();
+            Ok(Ipv4Addr::from(octets).into())
+        },
+        (Some(0x02), 20) => {
+            let octets: [u8; 16] = unmask(&value[4..20]).try_into().unwrap();
+            Ok(Ipv6Addr::from(octets).into())
+        },
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "unrecognized STUN address family",
+        )),
+    }
+}
+
 /// DNS wants a random-ish ID to be generated per request.
 fn get_id() -> Result<[u8; 2], Error> {
     let mut id = [0u8; 2];
@@ -158,11 +261,18 @@ fn get_id() -> Result<[u8; 2], Error> {
 }
 
 trait ReadExt {
+    fn read_u32(&mut self) -> Result<u32, std::io::Error>;
     fn read_u16(&mut self) -> Result<u16, std::io::Error>;
     fn read_u8(&mut self) -> Result<u8, std::io::Error>;
 }
 
 impl ReadExt for Cursor<&[u8]> {
+    fn read_u32(&mut self) -> Result<u32, std::io::Error> {
+        let mut u32_buf = [0; 4];
+        self.read_exact(&mut u32_buf)?;
+        Ok(u32::from_be_bytes(u32_buf))
+    }
+
     fn read_u16(&mut self) -> Result<u16, std::io::Error> {
         let mut u16_buf = [0; 2];
         self.read_exact(&mut u16_buf)?;
@@ -193,4 +303,28 @@ mod tests {
         assert!(v6.is_some());
         Ok(())
     }
+
+    #[test]
+    fn test_parse_stun_response() -> Result<(), Error> {
+        let transaction_id = [7u8; 12];
+        let mut response = vec![0x01, 0x01, 0x00, 0x14];
+        response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(&transaction_id);
+        // An unknown attribute with padding, to make sure it's skipped.
+        response.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0x00]);
+        // XOR-MAPPED-ADDRESS for 203.0.113.7:51820.
+        let ip = u32::from(Ipv4Addr::new(203, 0, 113, 7)) ^ STUN_MAGIC_COOKIE;
+        let port = 51820 ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        response.extend_from_slice(&port.to_be_bytes());
+        response.extend_from_slice(&ip.to_be_bytes());
+
+        assert_eq!(
+            parse_stun_response(&response, &transaction_id)?,
+            IpAddr::from(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert!(parse_stun_response(&response, &[8u8; 12]).is_err());
+        assert!(parse_stun_response(&response[..30], &transaction_id).is_err());
+        Ok(())
+    }
 }
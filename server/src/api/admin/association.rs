@@ -40,7 +40,7 @@ mod handlers {
     ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
 
-        DatabaseAssociation::create(&conn, contents)?;
+        DatabaseAssociation::create(&conn, contents, session.context.max_cidr_depth)?;
 
         status_response(StatusCode::CREATED)
     }
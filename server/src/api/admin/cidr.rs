@@ -19,6 +19,11 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::create(form, session).await
         },
+        (&Method::PUT, Some(id)) => {
+            let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+            let form = form_body(req).await?;
+            handlers::update(id, form, session).await
+        },
         (&Method::DELETE, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
             handlers::delete(id, session).await
@@ -38,11 +43,23 @@ mod handlers {
     ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
 
-        let cidr = DatabaseCidr::create(&conn, contents)?;
+        let cidr = DatabaseCidr::create(&conn, contents, session.context.max_cidr_depth)?;
 
         json_status_response(cidr, StatusCode::CREATED)
     }
 
+    pub async fn update(
+        id: i64,
+        form: CidrContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let mut cidr = DatabaseCidr::from(DatabaseCidr::get(&conn, id)?);
+        cidr.update(&conn, form)?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
     pub async fn list(session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let cidrs = DatabaseCidr::list(&conn)?;
@@ -61,10 +78,14 @@ mod handlers {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{test, DatabasePeer};
+    use crate::{
+        db::{cidr::DEFAULT_MAX_CIDR_DEPTH, DatabaseAssociation},
+        test, DatabasePeer,
+    };
     use anyhow::Result;
     use bytes::Buf;
-    use shared::{Cidr, Error};
+    use ipnet::IpNet;
+    use shared::{AssociationContents, Cidr, Error, IpRange};
 
     #[tokio::test]
     async fn test_cidr_add() -> Result<(), Error> {
@@ -76,6 +97,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
 
         let res = server
@@ -94,6 +119,37 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cidr_bad_ancestry() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(1000),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Too deep a CIDR is refused with its name in the reason.
+        let contents = CidrContents {
+            parent: Some(test::ROOT_CIDR_ID),
+            ..contents
+        };
+        match DatabaseCidr::create(&server.db().lock(), contents, 1) {
+            Err(ServerError::CidrGraph(e)) => assert!(e.contains("experimental"), "{e}"),
+            res => panic!("expected a CIDR graph error, got {res:?}"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cidr_name_uniqueness() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -102,6 +158,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
 
         let res = server
@@ -115,6 +175,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(cidr_res.id),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -132,6 +196,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
 
         let res = server
@@ -150,6 +218,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -160,6 +232,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
 
         let res = server
@@ -170,6 +246,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cidr_allocation_range() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let cidr: IpNet = test::EXPERIMENTAL_CIDR.parse()?;
+        let mut hosts = cidr.hosts();
+        let (first, tenth) = (hosts.next().unwrap(), hosts.nth(8).unwrap());
+
+        // A range that starts outside of the CIDR is refused.
+        let mut contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr,
+            parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: Some(IpRange::new(test::ADMIN_PEER_IP.parse()?, tenth).unwrap()),
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), 400);
+
+        contents.allocation_range = Some(IpRange::new(first, tenth).unwrap());
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), 201);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let cidr: Cidr = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(
+            DatabaseCidr::get(&server.db().lock(), cidr.id)?.allocation_range,
+            contents.allocation_range
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cidr_overlap() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -178,6 +292,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: "10.80.1.0/21".parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -197,7 +315,12 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
+            DEFAULT_MAX_CIDR_DEPTH,
         )?;
         let experimental_subcidr = DatabaseCidr::create(
             &server.db().lock(),
@@ -205,7 +328,12 @@ mod tests {
                 name: "experimental subcidr".to_string(),
                 cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                 parent: Some(experimental_cidr.id),
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
+            DEFAULT_MAX_CIDR_DEPTH,
         )?;
 
         let res = server
@@ -250,7 +378,12 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
+            DEFAULT_MAX_CIDR_DEPTH,
         )?;
 
         let _experiment_peer = DatabasePeer::create(
@@ -275,4 +408,102 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_cidr_rename() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let (experimental_cidr, experimental_subcidr) = {
+            let conn = server.db.lock();
+            let cidr = DatabaseCidr::create(
+                &conn,
+                CidrContents {
+                    name: "experimental".to_string(),
+                    cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                    parent: Some(test::ROOT_CIDR_ID),
+                    allocation_range: None,
+                    default_keepalive: None,
+                    default_mtu: None,
+                    max_peers: None,
+                },
+                DEFAULT_MAX_CIDR_DEPTH,
+            )?;
+            let subcidr = DatabaseCidr::create(
+                &conn,
+                CidrContents {
+                    name: "experimental-sub".to_string(),
+                    cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
+                    parent: Some(cidr.id),
+                    allocation_range: None,
+                    default_keepalive: None,
+                    default_mtu: None,
+                    max_peers: None,
+                },
+                DEFAULT_MAX_CIDR_DEPTH,
+            )?;
+            DatabaseAssociation::create(
+                &conn,
+                AssociationContents {
+                    cidr_id_1: test::DEVELOPER_CIDR_ID,
+                    cidr_id_2: cidr.id,
+                },
+                DEFAULT_MAX_CIDR_DEPTH,
+            )?;
+            (cidr, subcidr)
+        };
+
+        let contents = CidrContents {
+            name: "research".to_string(),
+            ..experimental_cidr.contents.clone()
+        };
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/cidrs/{}", experimental_cidr.id),
+                &contents,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let conn = server.db.lock();
+        let renamed = DatabaseCidr::get(&conn, experimental_cidr.id)?;
+        assert_eq!(renamed.name, "research");
+        assert_eq!(renamed.cidr, experimental_cidr.cidr);
+        assert_eq!(renamed.parent, experimental_cidr.parent);
+
+        // Nested CIDRs and associations should still point at the renamed CIDR.
+        let subcidr = DatabaseCidr::get(&conn, experimental_subcidr.id)?;
+        assert_eq!(subcidr.parent, Some(experimental_cidr.id));
+        assert!(DatabaseAssociation::list(&conn)?.iter().any(|a| {
+            a.cidr_id_1 == test::DEVELOPER_CIDR_ID && a.cidr_id_2 == experimental_cidr.id
+        }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cidr_rename_invalid_name() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let developer_cidr = DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?;
+        let contents = CidrContents {
+            name: "not a hostname".to_string(),
+            ..developer_cidr.contents.clone()
+        };
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/cidrs/{}", test::DEVELOPER_CIDR_ID),
+                &contents,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let developer_cidr = DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?;
+        assert_eq!(developer_cidr.name, "developer");
+
+        Ok(())
+    }
 }
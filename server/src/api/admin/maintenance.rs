@@ -0,0 +1,147 @@
+//! Read-only mode, for keeping peers configured while the network can't be changed (ex.
+//! during a migration).
+
+use std::collections::VecDeque;
+
+use crate::{
+    util::{form_body, json_response},
+    ServerError, Session,
+};
+use hyper::{Body, Method, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Maintenance {
+    pub read_only: bool,
+}
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => handlers::get(session).await,
+        (&Method::PUT, None) => {
+            let form = form_body(req).await?;
+            handlers::update(form, session).await
+        },
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    pub async fn get(session: Session) -> Result<Response<Body>, ServerError> {
+        json_response(Maintenance {
+            read_only: session.context.is_read_only(),
+        })
+    }
+
+    pub async fn update(
+        form: Maintenance,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let was_read_only = session.context.read_only.set(form.read_only)?;
+        if was_read_only != form.read_only {
+            log::warn!(
+                "{} read-only mode.",
+                if form.read_only { "entered" } else { "left" }
+            );
+        }
+        json_response(form)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use anyhow::Result;
+    use bytes::Buf;
+    use serde_json::Value;
+    use shared::{EndpointContents, Error};
+
+    #[tokio::test]
+    async fn test_read_only() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let read_only = |read_only| Maintenance { read_only };
+
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "PUT",
+                "/v1/admin/maintenance",
+                read_only(true),
+            )
+            .await;
+        assert_eq!(res.status(), 401);
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                "/v1/admin/maintenance",
+                read_only(true),
+            )
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = server.request(test::ADMIN_PEER_IP, "GET", "/healthz").await;
+        let health: Value = serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+        assert_eq!(health["read-only"], true);
+        // The CLI refuses changes too.
+        let interface = server.context().interface;
+        assert!(server.conf().check_writable(&interface).is_err());
+
+        // Reads keep working, changes are refused.
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers")
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "PUT",
+                "/v1/user/endpoint",
+                EndpointContents::Unset,
+            )
+            .await;
+        assert_eq!(res.status(), 503);
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "DELETE",
+                &format!("/v1/admin/peers/{}", test::USER1_PEER_ID),
+            )
+            .await;
+        assert_eq!(res.status(), 503);
+        let body = hyper::body::to_bytes(res).await?;
+        assert!(String::from_utf8_lossy(&body).contains("read-only"));
+
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                "/v1/admin/maintenance",
+                read_only(false),
+            )
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(server.conf().check_writable(&interface).is_ok());
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "DELETE",
+                &format!("/v1/admin/peers/{}", test::USER1_PEER_ID),
+            )
+            .await;
+        assert!(res.status().is_success());
+        Ok(())
+    }
+}
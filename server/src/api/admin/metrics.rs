@@ -0,0 +1,60 @@
+//! Per-endpoint request latencies, for keeping an eye on how the API is holding up.
+
+use std::collections::VecDeque;
+
+use crate::{util::json_response, ServerError, Session};
+use hyper::{Body, Method, Request, Response};
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => handlers::get(session).await,
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    pub async fn get(session: Session) -> Result<Response<Body>, ServerError> {
+        json_response(session.context.request_log.summary())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test;
+    use anyhow::Result;
+    use bytes::Buf;
+    use serde_json::Value;
+    use shared::Error;
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        for _ in 0..3 {
+            let res = server
+                .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers")
+                .await;
+            assert_eq!(res.status(), 200);
+        }
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/admin/metrics")
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/metrics")
+            .await;
+        assert_eq!(res.status(), 200);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let metrics: Value = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(metrics["GET /v1/admin/peers"]["count"], 3);
+        assert_eq!(metrics["GET /v1/admin/metrics"]["count"], 1);
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ use crate::{ServerError, Session};
 
 pub mod association;
 pub mod cidr;
+pub mod maintenance;
+pub mod metrics;
 pub mod peer;
 
 pub async fn routes(
@@ -17,9 +19,17 @@ pub async fn routes(
         return Err(ServerError::Unauthorized);
     }
 
-    match components.pop_front().as_deref() {
+    let component = components.pop_front();
+    // Maintenance stays writable, or read-only mode could never be turned off.
+    if component.as_deref() != Some("maintenance") {
+        session.context.check_writable(req.method())?;
+    }
+
+    match component.as_deref() {
         Some("associations") => association::routes(req, components, session).await,
         Some("cidrs") => cidr::routes(req, components, session).await,
+        Some("maintenance") => maintenance::routes(req, components, session).await,
+        Some("metrics") => metrics::routes(req, components, session).await,
         Some("peers") => peer::routes(req, components, session).await,
         _ => Err(ServerError::NotFound),
     }
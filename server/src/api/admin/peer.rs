@@ -1,14 +1,15 @@
 use std::collections::VecDeque;
 
 use crate::{
-    api::inject_endpoints,
+    api::{inject_endpoints, inject_last_handshakes},
     db::DatabasePeer,
+    update_peer,
     util::{form_body, json_response, json_status_response, status_response},
     ServerError, Session,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::PeerContents;
-use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
+use shared::{KeepaliveContents, PeerContents, Tag};
+use wireguard_control::{DeviceUpdate, Key, PeerConfigBuilder};
 
 pub async fn routes(
     req: Request<Body>,
@@ -16,15 +17,38 @@ pub async fn routes(
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
-        (&Method::GET, None) => handlers::list(session).await,
+        (&Method::GET, None) => {
+            let tag = req
+                .uri()
+                .query()
+                .and_then(|query| {
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "tag")
+                        .map(|(_, tag)| tag.parse::<Tag>())
+                })
+                .transpose()
+                .map_err(|_| ServerError::InvalidQuery)?;
+            handlers::list(tag, session).await
+        },
         (&Method::POST, None) => {
+            let allow_unsafe_name = allows_unsafe_name(&req);
             let form = form_body(req).await?;
-            handlers::create(form, session).await
+            handlers::create(form, allow_unsafe_name, session).await
         },
         (&Method::PUT, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
-            let form = form_body(req).await?;
-            handlers::update(id, form, session).await
+            match components.pop_front().as_deref() {
+                None => {
+                    let allow_unsafe_name = allows_unsafe_name(&req);
+                    let form = form_body(req).await?;
+                    handlers::update(id, form, allow_unsafe_name, session).await
+                },
+                Some("keepalive") => {
+                    let form = form_body(req).await?;
+                    handlers::set_keepalive(id, form, session).await
+                },
+                Some(_) => Err(ServerError::NotFound),
+            }
         },
         (&Method::DELETE, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
@@ -34,20 +58,61 @@ pub async fn routes(
     }
 }
 
+/// Fields of a peer that older clients don't know to send. Left out of an update, they'd
+/// deserialize as empty and wipe what's stored, so they keep the stored value instead.
+const KEPT_IF_MISSING: &[&str] = &["tags", "advertised_routes", "is_observer"];
+
+/// The contents a PUT `body` asks for, with any of [`KEPT_IF_MISSING`] it leaves out taken
+/// from the `stored` ones.
+fn updated_contents(
+    mut body: serde_json::Value,
+    stored: &PeerContents,
+) -> Result<PeerContents, ServerError> {
+    let stored = serde_json::to_value(stored)?;
+    if let (Some(body), Some(stored)) = (body.as_object_mut(), stored.as_object()) {
+        for &field in KEPT_IF_MISSING {
+            if let (false, Some(value)) = (body.contains_key(field), stored.get(field)) {
+                body.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+    Ok(serde_json::from_value(body)?)
+}
+
+/// Whether the request opted in to a peer name that isn't DNS-safe, with the
+/// `allow-unsafe-name` query parameter.
+fn allows_unsafe_name(req: &Request<Body>) -> bool {
+    req.uri().query().is_some_and(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "allow-unsafe-name" && value != "false")
+    })
+}
+
 mod handlers {
 
     use super::*;
 
     pub async fn create(
         form: PeerContents,
+        allow_unsafe_name: bool,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
+        DatabasePeer::check_requested_name(&form.name, allow_unsafe_name)?;
         let conn = session.context.db.lock();
 
-        let peer = DatabasePeer::create(&conn, form)?;
+        // Provenance is always the server's to record, never the requester's.
+        let form = PeerContents {
+            created_at: None,
+            created_by: Some(session.peer.name.to_string()),
+            description: None,
+            ..form
+        };
+        session.context.peer_limits.check_new(&conn, &form)?;
+        let mut peer = DatabasePeer::create(&conn, form)?;
+        peer.with_default_keepalive(session.context.persistent_keepalive);
         log::info!("adding peer {}", &*peer);
 
-        if cfg!(not(test)) {
+        if cfg!(not(test)) && !peer.is_staged {
             // Update the current WireGuard interface with the new peers.
             DeviceUpdate::new()
                 .add_peer(PeerConfigBuilder::from(&*peer))
@@ -61,30 +126,104 @@ mod handlers {
 
     pub async fn update(
         id: i64,
-        form: PeerContents,
+        body: serde_json::Value,
+        allow_unsafe_name: bool,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let mut peer = DatabasePeer::get(&conn, id)?;
+        let form = updated_contents(body, &peer.contents)?;
+        // A name that isn't changing was already let through.
+        if form.name != peer.name {
+            DatabasePeer::check_requested_name(&form.name, allow_unsafe_name)?;
+        }
+        session
+            .context
+            .peer_limits
+            .check_update(&conn, &peer.contents, &form)?;
+        update_peer(
+            &conn,
+            &mut peer,
+            form,
+            &session.context.interface,
+            session.context.backend,
+            session.context.preshared_keys,
+            session.context.persistent_keepalive,
+        )?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    pub async fn set_keepalive(
+        id: i64,
+        form: KeepaliveContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let mut peer = DatabasePeer::get(&conn, id)?;
-        peer.update(&conn, form)?;
+        peer.set_persistent_keepalive(&conn, form.persistent_keepalive_interval)?;
+        peer.with_default_keepalive(session.context.persistent_keepalive);
+        log::info!(
+            "set keepalive of {} to {:?}",
+            &*peer,
+            peer.persistent_keepalive_interval
+        );
+
+        if cfg!(not(test)) && !peer.is_disabled {
+            // Building from the peer only ever sets an interval, so clear it explicitly.
+            let public_key =
+                Key::from_base64(&peer.public_key).map_err(|_| ServerError::WireGuard)?;
+            let mut builder = PeerConfigBuilder::new(&public_key);
+            builder = match peer.persistent_keepalive_interval {
+                Some(interval) => builder.set_persistent_keepalive_interval(interval),
+                None => builder.unset_persistent_keepalive(),
+            };
+            DeviceUpdate::new()
+                .add_peer(builder)
+                .apply(&session.context.interface, session.context.backend)
+                .map_err(|_| ServerError::WireGuard)?;
+        }
 
         status_response(StatusCode::NO_CONTENT)
     }
 
     /// List all peers, including disabled ones. This is an admin-only endpoint.
-    pub async fn list(session: Session) -> Result<Response<Body>, ServerError> {
+    ///
+    /// If a tag is given, only peers with that tag are returned.
+    pub async fn list(tag: Option<Tag>, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let mut peers = DatabasePeer::list(&conn)?
             .into_iter()
-            .map(|peer| peer.inner)
+            .map(|mut peer| {
+                peer.with_default_keepalive(session.context.persistent_keepalive);
+                peer.inner
+            })
+            .filter(|peer| match &tag {
+                Some(tag) => peer.tags.contains(tag),
+                None => true,
+            })
             .collect::<Vec<_>>();
         inject_endpoints(&session, &mut peers);
+        inject_last_handshakes(&session, &mut peers);
         json_response(&peers)
     }
 
     pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
-        DatabasePeer::disable(&conn, id)?;
+        let mut peer = DatabasePeer::get(&conn, id)?;
+        let contents = PeerContents {
+            is_disabled: true,
+            ..peer.contents.clone()
+        };
+        update_peer(
+            &conn,
+            &mut peer,
+            contents,
+            &session.context.interface,
+            session.context.backend,
+            session.context.preshared_keys,
+            session.context.persistent_keepalive,
+        )?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -93,9 +232,11 @@ mod handlers {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test;
+    use crate::{test, LimitPolicy, PeerLimits};
     use bytes::Buf;
-    use shared::{Error, Peer};
+    use rusqlite::params;
+    use shared::{Error, Hostname, Peer};
+    use std::time::{Duration, SystemTime};
 
     #[tokio::test]
     async fn test_add_peer() -> Result<(), Error> {
@@ -116,8 +257,16 @@ mod tests {
         assert_eq!(res.status(), StatusCode::CREATED);
         // The response contains the new peer information.
         let whole_body = hyper::body::aggregate(res).await?;
-        let peer_res: Peer = serde_json::from_reader(whole_body.reader())?;
+        let mut peer_res: Peer = serde_json::from_reader(whole_body.reader())?;
 
+        // ...without a preshared key, which only comes once its client opts in on redeeming.
+        assert!(peer_res.contents.preshared_key.is_none());
+        // ...and who created it, and when.
+        assert!(peer_res.contents.created_at.take().is_some());
+        assert_eq!(
+            peer_res.contents.created_by.take().as_deref(),
+            Some("admin")
+        );
         assert_eq!(peer, peer_res.contents);
 
         // The number of peer entries in the database increased by 1.
@@ -127,12 +276,89 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_over_limit() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        let peers = DatabasePeer::list(&server.db().lock())?.len() as u32;
+        server.set_peer_limits(PeerLimits {
+            max_peers: Some(peers + 1),
+            policy: LimitPolicy::All,
+        });
+
+        let ips = if cfg!(feature = "v6-test") {
+            ["fd00:1337::2:0:0:3", "fd00:1337::2:0:0:4"]
+        } else {
+            ["10.80.64.4", "10.80.64.5"]
+        };
+        let peer = test::developer_peer_contents("developer3", ips[0])?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let peer = test::developer_peer_contents("developer4", ips[1])?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(res).await?;
+        assert!(String::from_utf8_lossy(&body).starts_with("peer limit reached: the network"));
+        assert_eq!(
+            DatabasePeer::list(&server.db().lock())?.len() as u32,
+            peers + 1
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_invalid_name() -> Result<(), Error> {
         assert!(test::developer_peer_contents("devel oper", "10.80.64.4").is_err());
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_with_unsafe_name() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let peer = PeerContents {
+            name: Hostname::parse_allowed("Dev_Laptop").unwrap(),
+            ..test::developer_peer_contents("developer3", "10.80.64.4")?
+        };
+
+        // Without opting in, the server refuses it like the CLI would.
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                "/v1/admin/peers?allow-unsafe-name",
+                &peer,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let peer: Peer = serde_json::from_reader(whole_body.reader())?;
+
+        // Changing anything else about it doesn't need the opt-in again.
+        let change = PeerContents {
+            tags: vec!["laptops".parse().unwrap()],
+            ..peer.contents.clone()
+        };
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/peers/{}", peer.id),
+                &change,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_duplicate_name() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -254,6 +480,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_update_peer_from_older_client() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let stored = {
+            let db = server.db.lock();
+            let mut peer = DatabasePeer::get(&db, test::DEVELOPER1_PEER_ID)?;
+            let contents = PeerContents {
+                tags: vec!["laptops".parse().unwrap()],
+                advertised_routes: vec!["192.168.1.0/24".parse().unwrap()],
+                is_observer: true,
+                ..peer.contents.clone()
+            };
+            peer.update(&db, contents)?;
+            peer.contents.clone()
+        };
+
+        // A client that predates a field leaves it out of a rename.
+        let mut change = serde_json::to_value(PeerContents {
+            name: "renamed".parse().unwrap(),
+            ..stored.clone()
+        })?;
+        for field in KEPT_IF_MISSING {
+            change.as_object_mut().unwrap().remove(*field);
+        }
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/peers/{}", test::DEVELOPER1_PEER_ID),
+                &change,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(&*peer.name, "renamed");
+        assert_eq!(peer.tags, stored.tags);
+        assert_eq!(peer.advertised_routes, stored.advertised_routes);
+        assert!(peer.is_observer);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_peer_advertised_routes() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let old_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        let path = format!("/v1/admin/peers/{}", test::DEVELOPER1_PEER_ID);
+
+        let change = PeerContents {
+            advertised_routes: vec!["192.168.1.0/24".parse()?],
+            ..old_peer.contents.clone()
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &change)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let new_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(new_peer.advertised_routes, change.advertised_routes);
+
+        // Routes need to be networks, not addresses inside of them.
+        let change = PeerContents {
+            advertised_routes: vec!["192.168.1.1/24".parse()?],
+            ..old_peer.contents.clone()
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &change)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_update_peer_from_non_admin() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -275,6 +572,120 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_set_keepalive() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let path = format!("/v1/admin/peers/{}/keepalive", test::DEVELOPER1_PEER_ID);
+        let keepalive = |interval| KeepaliveContents {
+            persistent_keepalive_interval: interval,
+        };
+
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &keepalive(Some(10)))
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.persistent_keepalive_interval, Some(10));
+
+        // Other updates to the peer leave its keepalive alone.
+        let change = PeerContents {
+            name: "new-peer-name".parse().unwrap(),
+            persistent_keepalive_interval: None,
+            ..peer.contents.clone()
+        };
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/peers/{}", test::DEVELOPER1_PEER_ID),
+                &change,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.persistent_keepalive_interval, Some(10));
+
+        // Zero disables keepalive entirely.
+        server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &keepalive(Some(0)))
+            .await;
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.persistent_keepalive_interval, None);
+
+        // No interval goes back to the default.
+        server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &keepalive(None))
+            .await;
+        let mut peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(
+            peer.persistent_keepalive_interval,
+            Some(shared::PERSISTENT_KEEPALIVE_INTERVAL_SECS)
+        );
+        // ...or the network's own default, when it has one.
+        peer.with_default_keepalive(Some(40));
+        assert_eq!(peer.persistent_keepalive_interval, Some(40));
+        peer.set_persistent_keepalive(&server.db.lock(), Some(10))?;
+        peer.with_default_keepalive(Some(40));
+        assert_eq!(peer.persistent_keepalive_interval, Some(10));
+
+        let res = server
+            .form_request(test::USER1_PEER_IP, "PUT", &path, &keepalive(Some(10)))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_peer_inherits_cidr_keepalive() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let set_default = |cidr_id: i64, keepalive: u16| {
+            server.db().lock().execute(
+                "UPDATE cidrs SET default_keepalive = ?2 WHERE id = ?1",
+                params![cidr_id, keepalive],
+            )
+        };
+        let add_peer = |contents| DatabasePeer::create(&server.db().lock(), contents).map(|p| p.id);
+        set_default(test::ROOT_CIDR_ID, 40)?;
+        set_default(test::DEVELOPER_CIDR_ID, 15)?;
+
+        // The peer's own CIDR wins over its ancestors.
+        let developer = if cfg!(feature = "v6-test") {
+            add_peer(test::developer_peer_contents(
+                "developer3",
+                "fd00:1337::2:0:0:3",
+            )?)?
+        } else {
+            add_peer(test::developer_peer_contents("developer3", "10.80.64.4")?)?
+        };
+        // Without one, the nearest ancestor that has one applies.
+        let user = if cfg!(feature = "v6-test") {
+            add_peer(test::user_peer_contents("user3", "fd00:1337::3:0:0:3")?)?
+        } else {
+            add_peer(test::user_peer_contents("user3", "10.80.128.3")?)?
+        };
+
+        // The default is copied when the peer is created, so changing it later doesn't
+        // affect existing peers.
+        set_default(test::ROOT_CIDR_ID, 50)?;
+        let conn = server.db.lock();
+        assert_eq!(
+            DatabasePeer::get(&conn, developer)?.persistent_keepalive_interval,
+            Some(15)
+        );
+        assert_eq!(
+            DatabasePeer::get(&conn, user)?.persistent_keepalive_interval,
+            Some(40)
+        );
+        // Peers created before any default was set still follow the network's.
+        assert_eq!(
+            DatabasePeer::get(&conn, test::USER1_PEER_ID)?.persistent_keepalive_interval,
+            Some(shared::PERSISTENT_KEEPALIVE_INTERVAL_SECS)
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_all_peers_from_admin() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -303,6 +714,68 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_list_peers_with_last_handshake() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let developer1 = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        let handshake = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        server
+            .handshakes()
+            .write()
+            .insert(developer1.public_key.clone(), handshake);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let whole_body = hyper::body::aggregate(res).await?;
+        let peers: Vec<Peer> = serde_json::from_reader(whole_body.reader())?;
+        for peer in peers {
+            if peer.id == test::DEVELOPER1_PEER_ID {
+                assert_eq!(peer.last_handshake, Some(handshake));
+            } else {
+                // Peers that never shook hands with the server don't have one.
+                assert_eq!(peer.last_handshake, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_peers_by_tag() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        {
+            let conn = server.db.lock();
+            let mut peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+            let contents = PeerContents {
+                tags: vec!["laptops".parse().unwrap()],
+                advertised_routes: vec!["192.168.1.0/24".parse().unwrap()],
+                is_observer: true,
+                ..peer.contents.clone()
+            };
+            peer.update(&conn, contents)?;
+        }
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers?tag=Laptops")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let whole_body = hyper::body::aggregate(res).await?;
+        let peers: Vec<Peer> = serde_json::from_reader(whole_body.reader())?;
+        let peer_names = peers.iter().map(|p| &*p.contents.name).collect::<Vec<_>>();
+        assert_eq!(&["developer1"], &peer_names[..]);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers?tag=a,b")
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_all_peers_from_non_admin() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -16,3 +16,12 @@ pub fn inject_endpoints(session: &Session, peers: &mut Vec<Peer>) {
         }
     }
 }
+
+/// Inject the most recent handshake the WG interface has seen from each peer, so that
+/// clients and monitoring can tell which peers have gone quiet.
+pub fn inject_last_handshakes(session: &Session, peers: &mut [Peer]) {
+    let handshakes = session.context.handshakes.read();
+    for peer in peers {
+        peer.contents.last_handshake = handshakes.get(&peer.public_key).copied();
+    }
+}
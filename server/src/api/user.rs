@@ -1,12 +1,15 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::SystemTime};
 
 use crate::{
-    api::inject_endpoints,
-    db::{DatabaseCidr, DatabasePeer},
-    util::{form_body, json_response, status_response},
+    api::{inject_endpoints, inject_last_handshakes},
+    db::{revoked_key, DatabaseCidr, DatabasePeer, DatabasePresharedKey, DatabaseRevokedKey},
+    util::{
+        accepts_gzip, compressed_json_response, etag, form_body, json_response, matches_etag,
+        status_response,
+    },
     Context, ServerError, Session,
 };
-use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
 use shared::{EndpointContents, PeerContents, RedeemContents, State, REDEEM_TRANSITION_WAIT};
 use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
 
@@ -15,12 +18,14 @@ pub async fn routes(
     mut components: VecDeque<String>,
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
+    session.context.check_writable(req.method())?;
+
     match (req.method(), components.pop_front().as_deref()) {
         (&Method::GET, Some("state")) => {
             if !session.user_capable() {
                 return Err(ServerError::Unauthorized);
             }
-            handlers::state(session).await
+            handlers::state(req, session).await
         },
         (&Method::POST, Some("redeem")) => {
             if !session.redeemable() {
@@ -36,6 +41,12 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::endpoint(form, session).await
         },
+        (&Method::POST, Some("rotate-preshared-key")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            handlers::rotate_preshared_key(session).await
+        },
         (&Method::PUT, Some("candidates")) => {
             if !session.user_capable() {
                 return Err(ServerError::Unauthorized);
@@ -43,20 +54,32 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::candidates(form, session).await
         },
+        (&Method::PUT, Some("description")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            let form = form_body(req).await?;
+            handlers::description(form, session).await
+        },
         _ => Err(ServerError::NotFound),
     }
 }
 
 mod handlers {
-    use shared::Endpoint;
+    use shared::{Endpoint, PeerDescription};
 
     use super::*;
 
     /// Get the current state of the network, in the eyes of the current peer.
     ///
     /// This endpoint returns the visible CIDRs and Peers, providing all the necessary
-    /// information for the peer to create connections to all of them.
-    pub async fn state(session: Session) -> Result<Response<Body>, ServerError> {
+    /// information for the peer to create connections to all of them. The response carries
+    /// an ETag of what's in the database, and is answered with a bodyless 304 if the
+    /// request's `If-None-Match` already has it.
+    pub async fn state(
+        req: Request<Body>,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
 
@@ -65,10 +88,83 @@ mod handlers {
         let mut peers: Vec<_> = selected_peer
             .get_all_allowed_peers(&conn)?
             .into_iter()
-            .map(|p| p.inner)
+            .map(|mut p| {
+                p.with_default_keepalive(session.context.persistent_keepalive);
+                p.inner
+            })
+            .collect();
+        if session.context.preshared_keys {
+            // Only the links where both sides have opted in have keys.
+            let mut keys = DatabasePresharedKey::for_peer(&conn, selected_peer.id)?;
+            for peer in &mut peers {
+                peer.contents.preshared_key = keys.remove(&peer.id);
+            }
+        }
+        let mut revoked =
+            DatabaseRevokedKey::list_since(&conn, SystemTime::now() - revoked_key::ADVERTISED_FOR)?;
+        let revoked_keys = peers
+            .iter()
+            .flat_map(|peer| revoked.remove(&peer.id).unwrap_or_default())
             .collect();
-        inject_endpoints(&session, &mut peers);
-        json_response(State { peers, cidrs })
+        let mut state = State {
+            peers,
+            cidrs,
+            revoked_keys,
+            ip_allocation: selected_peer
+                .is_admin
+                .then_some(session.context.ip_allocation),
+        };
+        // Roamed endpoints change what clients configure, so they're part of the ETag, but
+        // handshake times change every couple of minutes and are left out of it.
+        inject_endpoints(&session, &mut state.peers);
+        let etag = etag(&state)?;
+        inject_last_handshakes(&session, &mut state.peers);
+        let mut response = if matches_etag(&req, &etag) {
+            status_response(StatusCode::NOT_MODIFIED)?
+        } else {
+            compressed_json_response(state, accepts_gzip(&req))?
+        };
+        response.headers_mut().insert(
+            header::ETAG,
+            etag.parse().map_err(hyper::http::Error::from)?,
+        );
+        Ok(response)
+    }
+
+    /// Replace all of the current peer's preshared keys with new ones, returning the new key
+    /// for its link with the server. A peer that hasn't opted in to preshared keys yet is
+    /// opted in.
+    ///
+    /// Like redeeming, the server waits before switching its own interface over to the new key
+    /// so the response can get through, and the client is expected to wait the same amount
+    /// before switching. Other peers pick up their new keys on their next fetch.
+    pub async fn rotate_preshared_key(session: Session) -> Result<Response<Body>, ServerError> {
+        if !session.context.preshared_keys {
+            return Err(ServerError::NotFound);
+        }
+
+        let conn = session.context.db.lock();
+        let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        let preshared_key = DatabasePresharedKey::enable(&conn, selected_peer.id)?;
+        selected_peer.contents.preshared_key = Some(preshared_key.clone());
+        selected_peer.with_default_keepalive(session.context.persistent_keepalive);
+
+        if cfg!(not(test)) {
+            let Context {
+                interface, backend, ..
+            } = session.context;
+            tokio::task::spawn(async move {
+                tokio::time::sleep(REDEEM_TRANSITION_WAIT).await;
+                log::info!("WireGuard: rotating preshared key for {}", &*selected_peer);
+                DeviceUpdate::new()
+                    .add_peer(PeerConfigBuilder::from(&*selected_peer))
+                    .apply(&interface, backend)
+                    .map_err(|e| log::error!("{:?}", e))
+                    .ok();
+            });
+        }
+
+        json_response(preshared_key)
     }
 
     /// Redeems an invitation. An invitation includes a WireGuard keypair generated by either the server
@@ -79,6 +175,9 @@ mod handlers {
     ///
     /// Until this API endpoint is called, the invited peer will not show up to other peers, and once
     /// it is called and succeeds, it cannot be called again.
+    ///
+    /// A client that supports preshared keys says so, and gets back the key for its link with
+    /// the server (or `null` if the network doesn't use them). Older clients get no content.
     pub async fn redeem(
         form: RedeemContents,
         session: Session,
@@ -90,6 +189,12 @@ mod handlers {
             .map_err(|_| ServerError::WireGuard)?;
 
         selected_peer.redeem(&conn, &form.public_key)?;
+        if session.context.preshared_keys && form.preshared_keys {
+            DatabasePresharedKey::enable(&conn, selected_peer.id)?;
+        }
+        selected_peer.with_server_preshared_key(&conn, session.context.preshared_keys)?;
+        selected_peer.with_default_keepalive(session.context.persistent_keepalive);
+        let preshared_key = selected_peer.preshared_key.clone();
 
         if cfg!(not(test)) {
             let Context {
@@ -123,7 +228,11 @@ mod handlers {
                     .ok();
             });
         }
-        status_response(StatusCode::NO_CONTENT)
+        if form.preshared_keys {
+            json_response(preshared_key)
+        } else {
+            status_response(StatusCode::NO_CONTENT)
+        }
     }
 
     /// Report any other endpoint candidates that can be tried by peers to connect.
@@ -137,13 +246,20 @@ mod handlers {
         }
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
-        selected_peer.update(
-            &conn,
-            PeerContents {
-                candidates: contents,
-                ..selected_peer.contents.clone()
-            },
-        )?;
+        selected_peer.set_candidates(&conn, contents)?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// Record what the peer says about itself (ex. its hostname). It's sanitized and cut
+    /// down to size first, since nothing else vouches for it.
+    pub async fn description(
+        contents: PeerDescription,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        selected_peer.set_description(&conn, &contents)?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -153,6 +269,34 @@ mod handlers {
         contents: EndpointContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
+        let check = session.context.endpoint_check;
+        if let (true, EndpointContents::Set(endpoint)) = (check.is_enabled(), &contents) {
+            let reported = endpoint.to_string();
+            // A hostname could be pointed somewhere else right after it's checked.
+            if check.is_enforced() && endpoint.is_hostname() {
+                log::warn!(
+                    "rejected hostname endpoint {} reported by peer {}.",
+                    reported,
+                    session.peer.name
+                );
+                return Err(ServerError::EndpointMismatch(format!(
+                    "{reported} is a hostname, and only IP endpoints can be checked"
+                )));
+            }
+            // A hostname that doesn't resolve can't match anything.
+            let resolved: Vec<_> = tokio::net::lookup_host(&reported)
+                .await
+                .map(|addrs| addrs.collect())
+                .unwrap_or_default();
+            let observed = session
+                .context
+                .endpoints
+                .read()
+                .get(&session.peer.public_key)
+                .copied();
+            check.check(&session.peer.name, &reported, &resolved, observed)?;
+        }
+
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
         selected_peer.update(
@@ -169,12 +313,18 @@ mod handlers {
 
 #[cfg(test)]
 mod tests {
-    use std::time::{Duration, SystemTime};
+    use std::time::Duration;
 
     use super::*;
-    use crate::{db::DatabaseAssociation, test};
+    use crate::{
+        db::{cidr::DEFAULT_MAX_CIDR_DEPTH, peer::SERVER_PEER_ID, DatabaseAssociation},
+        test, EndpointCheck,
+    };
     use bytes::Buf;
-    use shared::{AssociationContents, CidrContents, Endpoint, EndpointContents, Error};
+    use shared::{
+        allocation::IpAllocation, AssociationContents, CidrContents, Endpoint, EndpointContents,
+        Error, Peer, PeerDescription,
+    };
 
     #[tokio::test]
     async fn test_get_state_from_developer1() -> Result<(), Error> {
@@ -198,6 +348,215 @@ mod tests {
         Ok(())
     }
 
+    async fn get_state(server: &test::Server, ip: &str) -> Result<Vec<Peer>, Error> {
+        let res = server.request(ip, "GET", "/v1/user/state").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let State { peers, .. } = serde_json::from_reader(whole_body.reader())?;
+        Ok(peers)
+    }
+
+    #[tokio::test]
+    async fn test_get_state_ip_allocation() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        // Only admins add peers, so only they're told how IPs are picked.
+        for (ip, expected) in [
+            (test::ADMIN_PEER_IP, Some(IpAllocation::LowestFree)),
+            (test::DEVELOPER1_PEER_IP, None),
+        ] {
+            let res = server.request(ip, "GET", "/v1/user/state").await;
+            let whole_body = hyper::body::aggregate(res).await?;
+            let State { ip_allocation, .. } = serde_json::from_reader(whole_body.reader())?;
+            assert_eq!(ip_allocation, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_state_lists_revoked_keys() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let old_key = {
+            let db = server.db();
+            let conn = db.lock();
+            let mut peer = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+            let old_key = peer.public_key.clone();
+            let invite_key = wireguard_control::KeyPair::generate().public.to_base64();
+            peer.rotate_key(
+                &conn,
+                &invite_key,
+                SystemTime::now() + Duration::from_secs(60),
+            )?;
+            peer.redeem(
+                &conn,
+                &wireguard_control::KeyPair::generate().public.to_base64(),
+            )?;
+            old_key
+        };
+
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        let whole_body = hyper::body::aggregate(res).await?;
+        let State { revoked_keys, .. } = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(revoked_keys, vec![old_key.clone()]);
+
+        // Old revocations are no longer told to clients, but still hold.
+        server.db().lock().execute(
+            "UPDATE revoked_keys SET revoked_at = 0 WHERE public_key = ?1",
+            [&old_key],
+        )?;
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        let whole_body = hyper::body::aggregate(res).await?;
+        let State { revoked_keys, .. } = serde_json::from_reader(whole_body.reader())?;
+        assert!(revoked_keys.is_empty());
+        assert!(DatabaseRevokedKey::is_revoked(
+            &server.db().lock(),
+            &old_key
+        )?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_state_not_modified() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[header::ETAG].clone();
+
+        let conditional = |etag| {
+            server
+                .base_request_builder("GET", "/v1/user/state")
+                .header(header::IF_NONE_MATCH, etag)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = server
+            .raw_request(test::DEVELOPER1_PEER_IP, conditional(etag.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
+
+        // New handshakes alone don't.
+        let public_key = DatabasePeer::get(&server.db().lock(), test::DEVELOPER2_PEER_ID)?
+            .public_key
+            .clone();
+        server
+            .handshakes()
+            .write()
+            .insert(public_key.clone(), std::time::SystemTime::now());
+        let res = server
+            .raw_request(test::DEVELOPER1_PEER_IP, conditional(etag.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        // A peer roaming to a new endpoint does, since clients configure it.
+        server
+            .endpoints()
+            .write()
+            .insert(public_key, "100.64.0.7:40000".parse().unwrap());
+        let res = server
+            .raw_request(test::DEVELOPER1_PEER_IP, conditional(etag.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers()[header::ETAG], etag);
+        let etag = res.headers()[header::ETAG].clone();
+
+        // Any change to what the peer can see changes the ETag.
+        {
+            let conn = server.db();
+            let conn = conn.lock();
+            let mut peer = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+            let contents = PeerContents {
+                is_disabled: true,
+                ..peer.contents.clone()
+            };
+            peer.update(&conn, contents)?;
+        }
+        let res = server
+            .raw_request(test::DEVELOPER1_PEER_IP, conditional(etag.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers()[header::ETAG], etag);
+        Ok(())
+    }
+    async fn opt_in_preshared_keys(server: &test::Server, ip: &str) -> Result<String, Error> {
+        let res = server
+            .request(ip, "POST", "/v1/user/rotate-preshared-key")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        Ok(serde_json::from_reader(whole_body.reader())?)
+    }
+
+    fn key_for(peers: &[Peer], id: i64) -> Option<String> {
+        peers
+            .iter()
+            .find(|p| p.id == id)
+            .and_then(|p| p.preshared_key.clone())
+    }
+
+    #[tokio::test]
+    async fn test_state_preshared_keys_opt_in() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        // Peers that haven't opted in (ex. older clients) don't get any keys.
+        let from_developer1 = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert!(from_developer1.iter().all(|p| p.preshared_key.is_none()));
+
+        // Opting in gets a key for the link with the server, but not with peers that haven't.
+        let server_key = opt_in_preshared_keys(&server, test::DEVELOPER1_PEER_IP).await?;
+        let from_developer1 = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert_eq!(key_for(&from_developer1, SERVER_PEER_ID), Some(server_key));
+        assert_eq!(key_for(&from_developer1, test::DEVELOPER2_PEER_ID), None);
+        let from_developer2 = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        assert_eq!(key_for(&from_developer2, test::DEVELOPER1_PEER_ID), None);
+
+        // Once both sides of a pair have opted in, both see the same key.
+        opt_in_preshared_keys(&server, test::DEVELOPER2_PEER_IP).await?;
+        let from_developer1 = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        let from_developer2 = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        let developer1_to_2 = key_for(&from_developer1, test::DEVELOPER2_PEER_ID);
+        assert!(developer1_to_2.is_some());
+        assert_eq!(
+            developer1_to_2,
+            key_for(&from_developer2, test::DEVELOPER1_PEER_ID)
+        );
+        assert_eq!(key_for(&from_developer1, test::DEVELOPER1_PEER_ID), None);
+
+        // Keys are per pair.
+        assert_ne!(
+            key_for(&from_developer1, SERVER_PEER_ID),
+            key_for(&from_developer2, SERVER_PEER_ID)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotate_preshared_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        opt_in_preshared_keys(&server, test::DEVELOPER1_PEER_IP).await?;
+        opt_in_preshared_keys(&server, test::DEVELOPER2_PEER_IP).await?;
+        let before = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+
+        let server_key = opt_in_preshared_keys(&server, test::DEVELOPER1_PEER_IP).await?;
+        let from_developer1 = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert_eq!(key_for(&from_developer1, SERVER_PEER_ID), Some(server_key));
+
+        // Other peers see the new key for their link with developer1.
+        let after = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        let developer1_key = key_for(&after, test::DEVELOPER1_PEER_ID);
+        assert!(developer1_key.is_some());
+        assert_ne!(key_for(&before, test::DEVELOPER1_PEER_ID), developer1_key);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_override_endpoint() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -244,6 +603,122 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_override_endpoint_with_hostname() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        assert_eq!(
+            server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/endpoint",
+                    &EndpointContents::Set("vpn.example.com:51820".parse().unwrap())
+                )
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+
+        // The hostname is stored as-is rather than as a resolved IP.
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(
+            peer.endpoint.as_ref().map(ToString::to_string).as_deref(),
+            Some("vpn.example.com:51820")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_endpoint_preferred_over_observed() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let developer1 = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        server.endpoints().write().insert(
+            developer1.public_key.clone(),
+            "100.64.0.7:40000".parse().unwrap(),
+        );
+        let endpoint_of = |peers: &[Peer]| {
+            peers
+                .iter()
+                .find(|peer| peer.public_key == developer1.public_key)
+                .and_then(|peer| peer.endpoint.clone())
+        };
+
+        let peers = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        assert_eq!(
+            endpoint_of(&peers),
+            Some("100.64.0.7:40000".parse().unwrap())
+        );
+
+        let pinned: Endpoint = "203.0.113.5:51820".parse().unwrap();
+        assert_eq!(
+            server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/endpoint",
+                    &EndpointContents::Set(pinned.clone()),
+                )
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+        let peers = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        assert_eq!(endpoint_of(&peers), Some(pinned));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_check() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        server.set_endpoint_check(EndpointCheck::Ip);
+        let developer1 = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        let set_endpoint = |endpoint: &str| {
+            let contents = EndpointContents::Set(endpoint.parse().unwrap());
+            let server = &server;
+            async move {
+                server
+                    .form_request(
+                        test::DEVELOPER1_PEER_IP,
+                        "PUT",
+                        "/v1/user/endpoint",
+                        &contents,
+                    )
+                    .await
+                    .status()
+            }
+        };
+
+        // Nothing observed yet to compare against.
+        assert_eq!(
+            set_endpoint("203.0.113.5:51820").await,
+            StatusCode::NO_CONTENT
+        );
+        // Hostnames can't be held to what they resolved to when checked.
+        assert_eq!(
+            set_endpoint("localhost:51820").await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        server.endpoints().write().insert(
+            developer1.public_key.clone(),
+            "100.64.0.7:40000".parse().unwrap(),
+        );
+        assert_eq!(
+            set_endpoint("203.0.113.5:51820").await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            set_endpoint("100.64.0.7:51820").await,
+            StatusCode::NO_CONTENT
+        );
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.endpoint, Some("100.64.0.7:51820".parse().unwrap()));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_peers_from_unknown_ip() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -256,6 +731,62 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_observer_state() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let update_developer2 = |change: fn(&mut shared::PeerContents)| -> Result<(), Error> {
+            let db = server.db.lock();
+            let mut peer = DatabasePeer::get(&db, test::DEVELOPER2_PEER_ID)?;
+            let mut contents = peer.contents.clone();
+            change(&mut contents);
+            peer.update(&db, contents)?;
+            Ok(())
+        };
+        let peer_names = |peers: Vec<Peer>| {
+            let mut names = peers
+                .into_iter()
+                .map(|p| p.contents.name.to_string())
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            names
+        };
+
+        update_developer2(|c| c.is_observer = true)?;
+
+        // Other peers don't get the observer at all...
+        let peers = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert_eq!(peer_names(peers), ["developer1", "innernet-server"]);
+
+        // ...but the observer still sees everyone it's allowed to, itself included.
+        let peers = get_state(&server, test::DEVELOPER2_PEER_IP).await?;
+        assert!(peers
+            .iter()
+            .any(|p| p.id == test::DEVELOPER2_PEER_ID && p.is_observer));
+        assert_eq!(
+            peer_names(peers),
+            ["developer1", "developer2", "innernet-server"]
+        );
+
+        // Disabling and re-enabling an observer leaves it an observer.
+        update_developer2(|c| c.is_disabled = true)?;
+        let res = server
+            .request(test::DEVELOPER2_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        update_developer2(|c| c.is_disabled = false)?;
+        let peers = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert_eq!(peer_names(peers), ["developer1", "innernet-server"]);
+
+        update_developer2(|c| c.is_observer = false)?;
+        let peers = get_state(&server, test::DEVELOPER1_PEER_IP).await?;
+        assert_eq!(
+            peer_names(peers),
+            ["developer1", "developer2", "innernet-server"]
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_peers_for_developer_subcidr() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -267,7 +798,12 @@ mod tests {
                     name: "experiment cidr".to_string(),
                     cidr: test::EXPERIMENTAL_CIDR.parse()?,
                     parent: Some(test::ROOT_CIDR_ID),
+                    allocation_range: None,
+                    default_keepalive: None,
+                    default_mtu: None,
+                    max_peers: None,
                 },
+                DEFAULT_MAX_CIDR_DEPTH,
             )?;
             let subcidr = DatabaseCidr::create(
                 &db,
@@ -275,7 +811,12 @@ mod tests {
                     name: "experiment subcidr".to_string(),
                     cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                     parent: Some(cidr.id),
+                    allocation_range: None,
+                    default_keepalive: None,
+                    default_mtu: None,
+                    max_peers: None,
                 },
+                DEFAULT_MAX_CIDR_DEPTH,
             )?;
             DatabasePeer::create(
                 &db,
@@ -294,6 +835,7 @@ mod tests {
                     cidr_id_1: test::DEVELOPER_CIDR_ID,
                     cidr_id_2: cidr.id,
                 },
+                DEFAULT_MAX_CIDR_DEPTH,
             )?;
             DatabaseAssociation::create(
                 &db,
@@ -301,6 +843,7 @@ mod tests {
                     cidr_id_1: test::INFRA_CIDR_ID,
                     cidr_id_2: cidr.id,
                 },
+                DEFAULT_MAX_CIDR_DEPTH,
             )?;
         }
 
@@ -336,7 +879,12 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
+            DEFAULT_MAX_CIDR_DEPTH,
         )?;
 
         let mut peer_contents = test::peer_contents(
@@ -358,6 +906,7 @@ mod tests {
         // Step 2: Ensure that redemption works.
         let body = RedeemContents {
             public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+            preshared_keys: true,
         };
         let res = server
             .form_request(
@@ -368,6 +917,10 @@ mod tests {
             )
             .await;
         assert!(res.status().is_success());
+        // Opting in to preshared keys gets the key for the link with the server.
+        let whole_body = hyper::body::aggregate(res).await?;
+        let server_key: Option<String> = serde_json::from_reader(whole_body.reader())?;
+        assert!(server_key.is_some());
 
         // Step 3: Ensure that a second attempt at redemption DOESN'T work.
         let res = server
@@ -398,7 +951,12 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
             },
+            DEFAULT_MAX_CIDR_DEPTH,
         )?;
 
         let mut peer_contents = test::peer_contents(
@@ -420,6 +978,7 @@ mod tests {
         // Step 2: Ensure that redemption works.
         let body = RedeemContents {
             public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+            preshared_keys: false,
         };
         let res = server
             .form_request(
@@ -464,4 +1023,46 @@ mod tests {
         assert_eq!(peer.candidates, candidates);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_description() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let description = PeerDescription {
+            hostname: Some("  laptop\u{1b}[31m\n".into()),
+            os: Some("x".repeat(1000)),
+            version: Some("".into()),
+        };
+        assert_eq!(
+            server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/description",
+                    &description
+                )
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        let stored = peer.description.clone().unwrap();
+        assert_eq!(stored.hostname.as_deref(), Some("laptop[31m"));
+        assert_eq!(stored.os.unwrap().len(), PeerDescription::MAX_FIELD_LEN);
+        assert_eq!(stored.version, None);
+
+        // Reporting nothing clears it.
+        server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/description",
+                &PeerDescription::default(),
+            )
+            .await;
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.description, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,431 @@
+//! An optional JSON-RPC 2.0 control interface on a Unix socket, for controllers that manage
+//! networks without shelling out to `innernet-server`.
+//!
+//! Each line sent to the socket is one request, and gets one line back (notifications,
+//! i.e. requests without an `id`, get none). Calls are served by the same handlers as the
+//! admin HTTP API, acting as the server's own peer, so they validate and update the
+//! WireGuard interface exactly like an admin peer's requests would. The socket is only
+//! accessible to its owner.
+//!
+//! Peers can't be deleted, the same as through the CLI or the HTTP API, so there's
+//! `peers.disable` rather than a `peers.delete`.
+
+use crate::{
+    api,
+    db::{peer::SERVER_PEER_ID, DatabaseCidr, DatabasePeer},
+    Context, ServerError, Session,
+};
+use anyhow::bail;
+use hyper::{header, Body, Method, Request, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use shared::{CidrContents, Error, Hostname, IoErrorContext, PeerContents};
+use std::{
+    collections::VecDeque,
+    fs, io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A call the server refused or failed, with the HTTP API's status for it as `data`.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<ServerError> for RpcError {
+    fn from(e: ServerError) -> Self {
+        Self {
+            data: Some(json!(StatusCode::from(&e).as_u16())),
+            ..Self::new(SERVER_ERROR, e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(INVALID_PARAMS, e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IdParams {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameParams {
+    id: i64,
+    name: Hostname,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListPeersParams {
+    tag: Option<String>,
+}
+
+/// Listen on `path` until an error stops the listener. A socket left over from an earlier
+/// run is replaced.
+pub async fn serve(path: &Path, context: Context) -> Result<(), Error> {
+    let listener = bind(path)?;
+    log::info!("serving the control interface on {}.", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, context).await {
+                log::warn!("control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Bind the socket at `path`, replacing a socket (but nothing else) that's already there.
+///
+/// It's bound inside a directory only the owner can enter and restricted there, then moved
+/// into place, so there's no moment anyone else could connect to it.
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).with_path(path)?
+        },
+        Ok(_) => bail!(
+            "{} already exists and isn't a socket, refusing to replace it.",
+            path.display()
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => Err(e).with_path(path)?,
+    }
+
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => bail!("{} isn't a valid socket path.", path.display()),
+    };
+    let staging = parent.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_path(&staging)?;
+    let staged = staging.join("control.sock");
+    let result = UnixListener::bind(&staged)
+        .with_path(&staged)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600)).with_path(&staged)?;
+            fs::rename(&staged, path).with_path(path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&staged);
+    fs::remove_dir(&staging).with_path(&staging)?;
+    Ok(result?)
+}
+
+async fn handle_connection(stream: UnixStream, context: Context) -> Result<(), Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(&context, &line).await {
+            let mut json = serde_json::to_string(&response)?;
+            json.push('\n');
+            writer.write_all(json.as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer one line of JSON-RPC, or nothing if it was a notification.
+async fn respond(context: &Context, line: &str) -> Option<RpcResponse> {
+    let (id, outcome) = match serde_json::from_str::<RpcRequest>(line) {
+        Ok(request) => {
+            let outcome = call(context, &request.method, request.params).await;
+            (request.id?, outcome)
+        },
+        Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+    };
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    Some(RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result,
+        error,
+    })
+}
+
+fn params_as<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    Ok(serde_json::from_value(params)?)
+}
+
+async fn call(context: &Context, method: &str, params: Value) -> Result<Value, RpcError> {
+    let (http_method, path, body) = match method {
+        "peers.list" => {
+            let ListPeersParams { tag } = if params.is_null() {
+                ListPeersParams::default()
+            } else {
+                params_as(params)?
+            };
+            let path = match tag {
+                Some(tag) => format!(
+                    "peers?{}",
+                    url::form_urlencoded::Serializer::new(String::new())
+                        .append_pair("tag", &tag)
+                        .finish()
+                ),
+                None => "peers".into(),
+            };
+            (Method::GET, path, None)
+        },
+        "peers.add" => {
+            let contents: PeerContents = params_as(params)?;
+            (Method::POST, "peers".into(), Some(json!(contents)))
+        },
+        "peers.rename" => {
+            let RenameParams { id, name } = params_as(params)?;
+            let peer = DatabasePeer::get(&context.db.lock(), id)?;
+            let contents = PeerContents {
+                name,
+                ..peer.inner.contents
+            };
+            (Method::PUT, format!("peers/{id}"), Some(json!(contents)))
+        },
+        "peers.disable" => {
+            let IdParams { id } = params_as(params)?;
+            (Method::DELETE, format!("peers/{id}"), None)
+        },
+        "peers.enable" => {
+            let IdParams { id } = params_as(params)?;
+            let peer = DatabasePeer::get(&context.db.lock(), id)?;
+            let contents = PeerContents {
+                is_disabled: false,
+                ..peer.inner.contents
+            };
+            (Method::PUT, format!("peers/{id}"), Some(json!(contents)))
+        },
+        "cidrs.list" => (Method::GET, "cidrs".into(), None),
+        "cidrs.add" => {
+            let contents: CidrContents = params_as(params)?;
+            (Method::POST, "cidrs".into(), Some(json!(contents)))
+        },
+        "cidrs.rename" => {
+            let RenameParams { id, name } = params_as(params)?;
+            let cidr = DatabaseCidr::get(&context.db.lock(), id)?;
+            let contents = CidrContents {
+                name: name.to_string(),
+                ..cidr.contents
+            };
+            (Method::PUT, format!("cidrs/{id}"), Some(json!(contents)))
+        },
+        "cidrs.delete" => {
+            let IdParams { id } = params_as(params)?;
+            (Method::DELETE, format!("cidrs/{id}"), None)
+        },
+        "associations.list" => (Method::GET, "associations".into(), None),
+        "associations.add" => (Method::POST, "associations".into(), Some(params)),
+        "associations.delete" => {
+            let IdParams { id } = params_as(params)?;
+            (Method::DELETE, format!("associations/{id}"), None)
+        },
+        "maintenance.get" => (Method::GET, "maintenance".into(), None),
+        "maintenance.set" => (Method::PUT, "maintenance".into(), Some(params)),
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {method}"),
+            ))
+        },
+    };
+
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let req = Request::builder()
+        .method(http_method)
+        .uri(format!("/v1/admin/{path}"))
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .map_err(ServerError::from)?;
+    let components: VecDeque<_> = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .map(String::from)
+        .collect();
+    let session = Session {
+        context: context.clone(),
+        peer: DatabasePeer::get(&context.db.lock(), SERVER_PEER_ID)?,
+    };
+
+    let response = api::admin::routes(req, components, session).await?;
+    let bytes = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(ServerError::from)?;
+    if bytes.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(serde_json::from_slice(&bytes).map_err(ServerError::from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+
+    async fn rpc(context: &Context, request: Value) -> Value {
+        let response = respond(context, &request.to_string()).await.unwrap();
+        serde_json::to_value(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("control.sock");
+
+        // Sockets left over are replaced, and new ones are only for the owner.
+        drop(bind(&path)?);
+        let _listener = bind(&path)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        // Anything else is left alone.
+        let file = dir.path().join("innernet.conf");
+        fs::write(&file, "keep")?;
+        assert!(bind(&file).is_err());
+        assert_eq!(fs::read_to_string(&file)?, "keep");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_control_calls() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let context = server.context();
+
+        let added = rpc(
+            &context,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "cidrs.add",
+                "params": {
+                    "name": "experimental",
+                    "cidr": test::EXPERIMENTAL_CIDR,
+                    "parent": test::ROOT_CIDR_ID,
+                },
+            }),
+        )
+        .await;
+        assert_eq!(added["id"], 1);
+        let id = added["result"]["id"].as_i64().expect("the new CIDR's id");
+
+        let renamed = rpc(
+            &context,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "cidrs.rename",
+                "params": { "id": id, "name": "lab" },
+            }),
+        )
+        .await;
+        assert_eq!(renamed["result"], Value::Null);
+        assert_eq!(DatabaseCidr::get(&server.db().lock(), id)?.name, "lab");
+
+        let disabled = rpc(
+            &context,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": "peers.disable",
+                "params": { "id": test::USER1_PEER_ID },
+            }),
+        )
+        .await;
+        assert!(disabled.get("error").is_none());
+        assert!(DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?.is_disabled);
+
+        // Refusals carry the HTTP API's status.
+        let overlapping = rpc(
+            &context,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 4,
+                "method": "cidrs.add",
+                "params": {
+                    "name": "overlapping",
+                    "cidr": test::EXPERIMENTAL_CIDR,
+                    "parent": test::ROOT_CIDR_ID,
+                },
+            }),
+        )
+        .await;
+        assert_eq!(overlapping["error"]["code"], SERVER_ERROR);
+        assert_eq!(overlapping["error"]["data"], 400);
+
+        let unknown = rpc(
+            &context,
+            json!({ "jsonrpc": "2.0", "id": 5, "method": "peers.delete", "params": { "id": 3 } }),
+        )
+        .await;
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+
+        assert!(
+            respond(&context, r#"{"jsonrpc": "2.0", "method": "cidrs.list"}"#)
+                .await
+                .is_none()
+        );
+        let garbage = serde_json::to_value(respond(&context, "{").await.unwrap())?;
+        assert_eq!(garbage["error"]["code"], PARSE_ERROR);
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! A peer belongs to one parent CIDR, and can by default see all peers within that parent.
 
+use super::DatabaseCidr;
 use crate::ServerError;
 use rusqlite::{params, Connection};
 use shared::{Association, AssociationContents};
@@ -48,15 +49,39 @@ impl DerefMut for DatabaseAssociation {
 }
 
 impl DatabaseAssociation {
+    /// Associate two CIDRs, refusing if either is nested deeper than `max_depth`.
     pub fn create(
         conn: &Connection,
         contents: AssociationContents,
+        max_depth: u8,
     ) -> Result<Association, ServerError> {
         let AssociationContents {
             cidr_id_1,
             cidr_id_2,
         } = &contents;
 
+        // Associations only ever link two CIDRs directly, so the only loops they can make
+        // are a CIDR with itself or the same pair twice. Parent links that loop, or go too
+        // deep, would make every reachability check walk them.
+        let cidrs = DatabaseCidr::list(conn)?;
+        let name = |id| {
+            cidrs
+                .iter()
+                .find(|cidr| cidr.id == id)
+                .map(|cidr| cidr.name.clone())
+                .ok_or(ServerError::InvalidQuery)
+        };
+        let (name_1, name_2) = (name(*cidr_id_1)?, name(*cidr_id_2)?);
+        if cidr_id_1 == cidr_id_2 {
+            log::warn!("tried to associate {} with itself.", name_1);
+            return Err(ServerError::CidrGraph(format!(
+                "CIDR {name_1} can't be associated with itself ({name_1} -> {name_1})"
+            )));
+        }
+        for id in [cidr_id_1, cidr_id_2] {
+            DatabaseCidr::ancestry(&cidrs, *id, max_depth)?;
+        }
+
         // Verify an existing association doesn't currently exist
         let existing_associations: usize = conn.query_row(
             "SELECT COUNT(*)
@@ -66,19 +91,10 @@ impl DatabaseAssociation {
             |r| r.get(0),
         )?;
         if existing_associations > 0 {
-            return Err(ServerError::InvalidQuery);
-        }
-
-        // Verify both provided CIDR IDs exist
-        let existing_cidrs: usize = conn.query_row(
-            "SELECT COUNT(*)
-            FROM cidrs
-            WHERE id = ?1 OR id = ?2",
-            params![cidr_id_1, cidr_id_2],
-            |r| r.get(0),
-        )?;
-        if existing_cidrs != 2 {
-            return Err(ServerError::InvalidQuery);
+            log::warn!("tried to associate {} and {} again.", name_1, name_2);
+            return Err(ServerError::CidrGraph(format!(
+                "{name_1} and {name_2} are already associated"
+            )));
         }
 
         conn.execute(
@@ -117,6 +133,7 @@ impl DatabaseAssociation {
 #[cfg(test)]
 mod tests {
     use crate::test;
+    use hyper::StatusCode;
     use shared::{CidrContents, Error};
 
     use super::*;
@@ -161,7 +178,74 @@ mod tests {
                 &contents_flipped,
             )
             .await;
-        assert!(res.status().is_client_error());
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert!(String::from_utf8_lossy(&body).contains("already associated"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_association() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                "/v1/admin/associations",
+                &AssociationContents {
+                    cidr_id_1: test::DEVELOPER_CIDR_ID,
+                    cidr_id_2: test::DEVELOPER_CIDR_ID,
+                },
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert!(String::from_utf8_lossy(&body).contains("can't be associated with itself"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cidr_ancestry() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let cidrs = DatabaseCidr::list(&conn)?;
+
+        // root -> developer is two deep.
+        let chain = DatabaseCidr::ancestry(&cidrs, test::DEVELOPER_CIDR_ID, 2)?;
+        assert_eq!(chain.len(), 2);
+        assert!(matches!(
+            DatabaseCidr::ancestry(&cidrs, test::DEVELOPER_CIDR_ID, 1),
+            Err(ServerError::CidrGraph(_))
+        ));
+
+        // Creating a CIDR holds it to the depth it's given.
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
+        };
+        assert!(matches!(
+            DatabaseCidr::create(&conn, contents.clone(), 1),
+            Err(ServerError::CidrGraph(_))
+        ));
+        DatabaseCidr::create(&conn, contents, 2)?;
+
+        // Parent links that loop are refused rather than followed forever.
+        conn.execute(
+            "UPDATE cidrs SET parent = ?1 WHERE id = ?2",
+            params![test::DEVELOPER_CIDR_ID, test::ROOT_CIDR_ID],
+        )?;
+        let cidrs = DatabaseCidr::list(&conn)?;
+        assert!(matches!(
+            DatabaseCidr::ancestry(&cidrs, test::DEVELOPER_CIDR_ID, u8::MAX),
+            Err(ServerError::CidrGraph(_))
+        ));
         Ok(())
     }
 
@@ -193,6 +277,10 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         };
 
         let res = server
@@ -1,8 +1,12 @@
 use crate::ServerError;
 use ipnet::IpNet;
 use rusqlite::{params, Connection};
-use shared::{Cidr, CidrContents};
-use std::ops::Deref;
+use shared::{find_conflicting_cidr, Cidr, CidrContents, Hostname};
+use std::{collections::HashSet, ops::Deref};
+
+/// How deep CIDRs may be nested by default, counting the root network as 1. Limiting it keeps
+/// the walks up the tree that reachability checks do short.
+pub const DEFAULT_MAX_CIDR_DEPTH: u8 = 16;
 
 pub static CREATE_TABLE_SQL: &str = "CREATE TABLE cidrs (
       id               INTEGER PRIMARY KEY,
@@ -10,6 +14,10 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE cidrs (
       ip               TEXT NOT NULL,
       prefix           INTEGER NOT NULL,
       parent           INTEGER REFERENCES cidrs,
+      allocation_range TEXT,
+      default_keepalive INTEGER,
+      default_mtu INTEGER,
+      max_peers INTEGER,
       UNIQUE(ip, prefix),
       FOREIGN KEY (parent)
          REFERENCES cidrs (id)
@@ -36,8 +44,55 @@ impl Deref for DatabaseCidr {
 }
 
 impl DatabaseCidr {
-    pub fn create(conn: &Connection, contents: CidrContents) -> Result<Cidr, ServerError> {
-        let CidrContents { name, cidr, parent } = &contents;
+    /// The CIDR with id `cidr_id` followed by its ancestors, up to the root. Refuses parent
+    /// links that loop, and trees deeper than `max_depth`.
+    pub fn ancestry(
+        cidrs: &[Cidr],
+        cidr_id: i64,
+        max_depth: u8,
+    ) -> Result<Vec<&Cidr>, ServerError> {
+        let mut seen = HashSet::new();
+        let mut chain: Vec<&Cidr> = vec![];
+        let mut next = Some(cidr_id);
+        while let Some(id) = next {
+            let cidr = cidrs
+                .iter()
+                .find(|cidr| cidr.id == id)
+                .ok_or(ServerError::NotFound)?;
+            if !seen.insert(id) {
+                return Err(ServerError::CidrGraph(format!(
+                    "CIDR {} is its own ancestor (through {})",
+                    cidr.name,
+                    chain.last().map_or(&cidr.name, |child| &child.name)
+                )));
+            }
+            chain.push(cidr);
+            if chain.len() > max_depth as usize {
+                return Err(ServerError::CidrGraph(format!(
+                    "CIDR {} is nested deeper than the maximum depth of {}",
+                    chain[0].name, max_depth
+                )));
+            }
+            next = cidr.parent;
+        }
+        Ok(chain)
+    }
+
+    /// Create a CIDR, refusing to nest it deeper than `max_depth`.
+    pub fn create(
+        conn: &Connection,
+        contents: CidrContents,
+        max_depth: u8,
+    ) -> Result<Cidr, ServerError> {
+        let CidrContents {
+            name,
+            cidr,
+            parent,
+            allocation_range,
+            default_keepalive,
+            default_mtu,
+            max_peers,
+        } = &contents;
 
         log::debug!("creating {:?}", contents);
 
@@ -53,6 +108,16 @@ impl DatabaseCidr {
 
         if let Some(parent_id) = parent {
             let cidrs = Self::list(conn)?;
+            // One more for the new CIDR itself.
+            Self::ancestry(&cidrs, *parent_id, max_depth.saturating_sub(1)).map_err(
+                |e| match e {
+                    ServerError::CidrGraph(e) => {
+                        log::warn!("tried to add a CIDR under {}: {}.", parent_id, e);
+                        ServerError::CidrGraph(format!("can't add CIDR {name}: {e}"))
+                    },
+                    e => e,
+                },
+            )?;
 
             let closest_parent = cidrs
                 .iter()
@@ -76,36 +141,60 @@ impl DatabaseCidr {
             }
         }
 
-        let overlapping_sibling = Self::list(conn)?
-            .iter()
-            .filter(|current| current.parent == *parent)
-            .map(|sibling| sibling.cidr)
-            .any(|sibling| {
-                cidr.contains(&sibling.network())
-                    || cidr.contains(&sibling.broadcast())
-                    || sibling.contains(&cidr.network())
-                    || sibling.contains(&cidr.broadcast())
-            });
-
-        if overlapping_sibling {
-            log::warn!("tried to add a CIDR that overlaps with a sibling.");
+        if let Some(range) = allocation_range {
+            if !range.is_within(cidr) {
+                log::warn!("tried to add a CIDR with an allocation range outside of it.");
+                return Err(ServerError::InvalidQuery);
+            }
+        }
+
+        if let Some(conflict) = find_conflicting_cidr(cidr, &Self::list(conn)?) {
+            log::warn!("tried to add a CIDR that overlaps with {}.", conflict);
             return Err(ServerError::InvalidQuery);
         }
 
         conn.execute(
-            "INSERT INTO cidrs (name, ip, prefix, parent)
-              VALUES (?1, ?2, ?3, ?4)",
+            "INSERT INTO cidrs (name, ip, prefix, parent, allocation_range, default_keepalive, default_mtu, max_peers)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
                 name,
                 cidr.addr().to_string(),
                 cidr.prefix_len() as i32,
-                parent
+                parent,
+                allocation_range.map(|range| range.to_string()),
+                default_keepalive,
+                default_mtu,
+                max_peers,
             ],
         )?;
         let id = conn.last_insert_rowid();
         Ok(Cidr { id, contents })
     }
 
+    /// Update self with new contents, validating them and updating the backend in the process.
+    ///
+    /// Only the name of a CIDR can be changed; its network range and position in the tree are
+    /// kept as-is so that peers, child CIDRs, and associations remain attached.
+    pub fn update(&mut self, conn: &Connection, contents: CidrContents) -> Result<(), ServerError> {
+        if !Hostname::is_valid(&contents.name) {
+            log::warn!("CIDR name is invalid, must conform to hostname(7) requirements.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let new_contents = CidrContents {
+            name: contents.name,
+            ..self.inner.contents.clone()
+        };
+
+        conn.execute(
+            "UPDATE cidrs SET name = ?2 WHERE id = ?1",
+            params![self.id, &new_contents.name],
+        )?;
+
+        self.inner.contents = new_contents;
+        Ok(())
+    }
+
     pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
         conn.execute("DELETE FROM cidrs WHERE id = ?1", params![id])?;
         Ok(())
@@ -121,22 +210,39 @@ impl DatabaseCidr {
             .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
         let cidr = IpNet::new(ip, prefix).map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
         let parent = row.get(4)?;
+        let allocation_range = row
+            .get::<_, Option<String>>(5)?
+            .map(|range| range.parse())
+            .transpose()
+            .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
+        let default_keepalive = row.get(6)?;
+        let default_mtu = row.get(7)?;
+        let max_peers = row.get(8)?;
         Ok(Cidr {
             id,
-            contents: CidrContents { name, cidr, parent },
+            contents: CidrContents {
+                name,
+                cidr,
+                parent,
+                allocation_range,
+                default_keepalive,
+                default_mtu,
+                max_peers,
+            },
         })
     }
 
     pub fn get(conn: &Connection, id: i64) -> Result<Cidr, ServerError> {
         Ok(conn.query_row(
-            "SELECT id, name, ip, prefix, parent FROM cidrs WHERE id = ?1",
+            "SELECT id, name, ip, prefix, parent, allocation_range, default_keepalive, default_mtu, max_peers FROM cidrs WHERE id = ?1",
             params![id],
             Self::from_row,
         )?)
     }
 
     pub fn list(conn: &Connection) -> Result<Vec<Cidr>, ServerError> {
-        let mut stmt = conn.prepare_cached("SELECT id, name, ip, prefix, parent FROM cidrs")?;
+        let mut stmt = conn
+            .prepare_cached("SELECT id, name, ip, prefix, parent, allocation_range, default_keepalive, default_mtu, max_peers FROM cidrs")?;
         let cidr_iter = stmt.query_map(params![], Self::from_row)?;
 
         Ok(cidr_iter.collect::<Result<Vec<_>, rusqlite::Error>>()?)
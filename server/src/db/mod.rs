@@ -1,16 +1,36 @@
 pub mod association;
 pub mod cidr;
 pub mod peer;
+pub mod preshared_key;
+pub mod revoked_key;
+pub mod usage;
 
 pub use association::DatabaseAssociation;
 pub use cidr::DatabaseCidr;
 pub use peer::DatabasePeer;
+pub use preshared_key::DatabasePresharedKey;
+pub use revoked_key::DatabaseRevokedKey;
 use rusqlite::params;
+pub use usage::DatabaseUsage;
 
 const INVITE_EXPIRATION_VERSION: usize = 1;
 const ENDPOINT_CANDIDATES_VERSION: usize = 2;
+const PEER_TAGS_VERSION: usize = 3;
+const CANDIDATES_UPDATED_VERSION: usize = 4;
+const PRESHARED_KEYS_VERSION: usize = 5;
+const REVOKED_KEYS_VERSION: usize = 6;
+const PERSISTENT_KEEPALIVE_VERSION: usize = 7;
+const ADVERTISED_ROUTES_VERSION: usize = 8;
+const OBSERVER_VERSION: usize = 9;
+const ALLOCATION_RANGE_VERSION: usize = 10;
+const CIDR_DEFAULTS_VERSION: usize = 11;
+const CREATION_METADATA_VERSION: usize = 12;
+const STAGED_PEERS_VERSION: usize = 13;
+const PEER_LIMITS_VERSION: usize = 14;
+const PEER_DESCRIPTIONS_VERSION: usize = 15;
+const USAGE_VERSION: usize = 16;
 
-pub const CURRENT_VERSION: usize = ENDPOINT_CANDIDATES_VERSION;
+pub const CURRENT_VERSION: usize = USAGE_VERSION;
 
 pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
     let old_version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
@@ -27,6 +47,89 @@ pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error>
         conn.execute("ALTER TABLE peers ADD COLUMN candidates TEXT", params![])?;
     }
 
+    if old_version < PEER_TAGS_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN tags TEXT", params![])?;
+    }
+
+    if old_version < CANDIDATES_UPDATED_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN candidates_updated INTEGER",
+            params![],
+        )?;
+    }
+
+    if old_version < PRESHARED_KEYS_VERSION {
+        conn.execute(preshared_key::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < REVOKED_KEYS_VERSION {
+        conn.execute(revoked_key::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < PERSISTENT_KEEPALIVE_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN persistent_keepalive INTEGER",
+            params![],
+        )?;
+    }
+
+    if old_version < ADVERTISED_ROUTES_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN advertised_routes TEXT",
+            params![],
+        )?;
+    }
+
+    if old_version < OBSERVER_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN is_observer INTEGER DEFAULT 0 NOT NULL",
+            params![],
+        )?;
+    }
+
+    if old_version < ALLOCATION_RANGE_VERSION {
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN allocation_range TEXT",
+            params![],
+        )?;
+    }
+
+    if old_version < CIDR_DEFAULTS_VERSION {
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN default_keepalive INTEGER",
+            params![],
+        )?;
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN default_mtu INTEGER",
+            params![],
+        )?;
+    }
+
+    if old_version < CREATION_METADATA_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN created_at INTEGER", params![])?;
+        conn.execute("ALTER TABLE peers ADD COLUMN created_by TEXT", params![])?;
+    }
+
+    if old_version < STAGED_PEERS_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN is_staged INTEGER DEFAULT 0 NOT NULL",
+            params![],
+        )?;
+    }
+
+    if old_version < PEER_LIMITS_VERSION {
+        conn.execute("ALTER TABLE cidrs ADD COLUMN max_peers INTEGER", params![])?;
+    }
+
+    if old_version < PEER_DESCRIPTIONS_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN description TEXT", params![])?;
+    }
+
+    if old_version < USAGE_VERSION {
+        conn.execute(usage::CREATE_TABLE_SQL, params![])?;
+        conn.execute(usage::CREATE_COUNTERS_TABLE_SQL, params![])?;
+    }
+
     if old_version != CURRENT_VERSION {
         conn.pragma_update(None, "user_version", CURRENT_VERSION)?;
         log::info!(
@@ -1,9 +1,10 @@
-use super::DatabaseCidr;
+use super::{DatabaseCidr, DatabasePresharedKey, DatabaseRevokedKey};
 use crate::ServerError;
-use once_cell::sync::Lazy;
-use regex::Regex;
-use rusqlite::{params, types::Type, Connection};
-use shared::{IpNetExt, Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+use shared::{
+    inherited_cidr_default, overlapping_routes, Cidr, Endpoint, Hostname, IpNetExt, Peer,
+    PeerContents, PeerDescription, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+};
 use std::{
     net::IpAddr,
     ops::{Deref, DerefMut},
@@ -22,6 +23,15 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peers (
       is_redeemed     INTEGER DEFAULT 0 NOT NULL,   /* Has the peer redeemed their invite yet?                          */
       invite_expires  INTEGER,                      /* The UNIX time that an invited peer can no longer redeem.         */
       candidates      TEXT,                         /* A list of additional endpoints that peers can use to connect.    */
+      tags            TEXT,                         /* A list of informational tags used to group peers.                */
+      candidates_updated INTEGER,                   /* The UNIX time the peer last reported its candidates.             */
+      persistent_keepalive INTEGER,                 /* Keepalive override in seconds (0 disables, NULL uses the default). */
+      advertised_routes TEXT,                       /* A list of extra subnets routed through the peer.                 */
+      is_observer     INTEGER DEFAULT 0 NOT NULL,   /* Observers can fetch state but aren't given to other peers.       */
+      created_at      INTEGER,                      /* The UNIX time the peer was created (NULL if unknown).            */
+      created_by      TEXT,                         /* The name of the admin peer that created the peer.                */
+      is_staged       INTEGER DEFAULT 0 NOT NULL,   /* Is the peer waiting to be activated? (staged peers are disabled)  */
+      description     TEXT,                         /* What the peer last reported about itself (json, advisory only).  */
       FOREIGN KEY (cidr_id)
          REFERENCES cidrs (id)
             ON UPDATE RESTRICT
@@ -40,20 +50,34 @@ pub static COLUMNS: &[&str] = &[
     "is_redeemed",
     "invite_expires",
     "candidates",
+    "tags",
+    "persistent_keepalive",
+    "advertised_routes",
+    "is_observer",
+    "created_at",
+    "created_by",
+    "is_staged",
+    "description",
 ];
 
-/// Regex to match the requirements of hostname(7), needed to have peers also be reachable hostnames.
-/// Note that the full length also must be maximum 63 characters, which this regex does not check.
-static PEER_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([a-z0-9]-?)*[a-z0-9]$").unwrap());
+/// The server's own peer is always the first one created when initializing a network.
+pub const SERVER_PEER_ID: i64 = 1;
 
 #[derive(Debug)]
 pub struct DatabasePeer {
     pub inner: Peer,
+    /// The peer's own keepalive interval as stored, where `None` follows the network's
+    /// default. The inner peer's interval is resolved against the built-in default until
+    /// `with_default_keepalive` is given the network's.
+    persistent_keepalive: Option<u16>,
 }
 
 impl From<Peer> for DatabasePeer {
     fn from(inner: Peer) -> Self {
-        Self { inner }
+        Self {
+            persistent_keepalive: Some(inner.persistent_keepalive_interval.unwrap_or(0)),
+            inner,
+        }
     }
 }
 
@@ -72,6 +96,19 @@ impl DerefMut for DatabasePeer {
 }
 
 impl DatabasePeer {
+    /// Make sure a peer name an admin asked for over the API is DNS-safe, unless they opted in
+    /// to unsafe names. Storing a name only needs it to be allowed, so names from before the
+    /// rule (or the server's own CLI, which checks for itself) still load and update.
+    pub fn check_requested_name(name: &str, allow_unsafe_name: bool) -> Result<(), ServerError> {
+        if let Some(reason) = Hostname::dns_unsafe_reason(name) {
+            if !allow_unsafe_name {
+                log::warn!("peer name \"{name}\" isn't DNS-safe ({reason}), and unsafe names weren't allowed.");
+                return Err(ServerError::InvalidQuery);
+            }
+        }
+        Ok(())
+    }
+
     pub fn create(conn: &Connection, contents: PeerContents) -> Result<Self, ServerError> {
         let PeerContents {
             name,
@@ -84,12 +121,24 @@ impl DatabasePeer {
             is_redeemed,
             invite_expires,
             candidates,
+            tags,
+            advertised_routes,
+            is_observer,
+            created_at,
+            created_by,
+            is_staged,
+            description,
             ..
         } = &contents;
         log::info!("creating peer {:?}", contents);
 
-        if !Self::is_valid_name(name) {
-            log::warn!("peer name is invalid, must conform to hostname(7) requirements.");
+        if DatabaseRevokedKey::is_revoked(conn, public_key)? {
+            log::warn!("tried to add a peer with a revoked public key.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        if !Hostname::is_allowed(name) {
+            log::warn!("peer name is invalid, it may only have letters, digits, dots, underscores and dashes.");
             return Err(ServerError::InvalidQuery);
         }
 
@@ -107,16 +156,59 @@ impl DatabasePeer {
             return Err(ServerError::InvalidQuery);
         }
 
+        // Staged and disabled peers still hold their IPs.
+        let holder: Option<String> = conn
+            .query_row(
+                "SELECT name FROM peers WHERE ip = ?1",
+                params![ip.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(holder) = holder {
+            log::warn!(
+                "tried to add peer with IP {} already held by {}.",
+                ip,
+                holder
+            );
+            return Err(ServerError::InvalidQuery);
+        }
+
         let invite_expires = invite_expires
             .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
             .flatten()
             .map(|t| t.as_secs());
+        // Kept as given when a peer is recreated (ex. from a network dump).
+        let created_at = created_at
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_secs());
+
+        if *is_staged && !is_disabled {
+            log::warn!("tried to add a staged peer that isn't disabled.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        Self::validate_advertised_routes(conn, &contents)?;
 
         let candidates = serde_json::to_string(candidates)?;
+        let tags = serde_json::to_string(tags)?;
+        let advertised_routes = serde_json::to_string(advertised_routes)?;
+        let description = description
+            .as_ref()
+            .map(|description| serde_json::to_string(&description.sanitized()))
+            .transpose()?;
 
+        // New peers copy their CIDR's default keepalive (or the nearest ancestor's) as their own
+        // override, so changing a CIDR's default later doesn't affect its existing peers. Without
+        // one, they follow the network's default keepalive.
+        let persistent_keepalive =
+            inherited_cidr_default(&DatabaseCidr::list(conn)?, *cidr_id, |c| {
+                c.default_keepalive
+            });
         conn.execute(
             &format!(
-                "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
                 COLUMNS[1..].join(", ")
             ),
             params![
@@ -130,20 +222,41 @@ impl DatabasePeer {
                 is_redeemed,
                 invite_expires,
                 candidates,
+                tags,
+                persistent_keepalive,
+                advertised_routes,
+                is_observer,
+                created_at,
+                created_by,
+                is_staged,
+                description,
             ],
         )?;
         let id = conn.last_insert_rowid();
-        Ok(Peer { id, contents }.into())
-    }
-
-    fn is_valid_name(name: &str) -> bool {
-        name.len() < 64 && PEER_NAME_REGEX.is_match(name)
+        let persistent_keepalive_interval =
+            Self::resolve_persistent_keepalive(persistent_keepalive);
+        Ok(Self {
+            inner: Peer {
+                id,
+                contents: PeerContents {
+                    persistent_keepalive_interval,
+                    created_at: created_at
+                        .map(|unixtime| SystemTime::UNIX_EPOCH + Duration::from_secs(unixtime)),
+                    description: contents
+                        .description
+                        .as_ref()
+                        .map(PeerDescription::sanitized),
+                    ..contents
+                },
+            },
+            persistent_keepalive,
+        })
     }
 
     /// Update self with new contents, validating them and updating the backend in the process.
     pub fn update(&mut self, conn: &Connection, contents: PeerContents) -> Result<(), ServerError> {
-        if !Self::is_valid_name(&contents.name) {
-            log::warn!("peer name is invalid, must conform to hostname(7) requirements.");
+        if !Hostname::is_allowed(&contents.name) {
+            log::warn!("peer name is invalid, it may only have letters, digits, dots, underscores and dashes.");
             return Err(ServerError::InvalidQuery);
         }
 
@@ -158,17 +271,29 @@ impl DatabasePeer {
             is_admin: contents.is_admin,
             is_disabled: contents.is_disabled,
             candidates: contents.candidates,
+            tags: contents.tags,
+            advertised_routes: contents.advertised_routes,
+            is_observer: contents.is_observer,
+            // Peers can only be staged when they're created, and enabling one activates it.
+            is_staged: self.is_staged && contents.is_disabled,
             ..self.contents.clone()
         };
+        Self::validate_advertised_routes(conn, &new_contents)?;
 
         let new_candidates = serde_json::to_string(&new_contents.candidates)?;
+        let new_tags = serde_json::to_string(&new_contents.tags)?;
+        let new_advertised_routes = serde_json::to_string(&new_contents.advertised_routes)?;
         conn.execute(
             "UPDATE peers SET
                 name = ?2,
                 endpoint = ?3,
                 is_admin = ?4,
                 is_disabled = ?5,
-                candidates = ?6
+                candidates = ?6,
+                tags = ?7,
+                advertised_routes = ?8,
+                is_observer = ?9,
+                is_staged = ?10
             WHERE id = ?1",
             params![
                 self.id,
@@ -180,6 +305,10 @@ impl DatabasePeer {
                 new_contents.is_admin,
                 new_contents.is_disabled,
                 new_candidates,
+                new_tags,
+                new_advertised_routes,
+                new_contents.is_observer,
+                new_contents.is_staged,
             ],
         )?;
 
@@ -187,6 +316,267 @@ impl DatabasePeer {
         Ok(())
     }
 
+    /// Advertised routes must be networks (no host bits set), outside of the network itself,
+    /// and not overlap the routes of any other enabled peer, since traffic for the overlap
+    /// could only go to one of them.
+    fn validate_advertised_routes(
+        conn: &Connection,
+        contents: &PeerContents,
+    ) -> Result<(), ServerError> {
+        if contents.advertised_routes.is_empty() {
+            return Ok(());
+        }
+        if let Some(route) = contents
+            .advertised_routes
+            .iter()
+            .find(|route| route.addr() != route.network())
+        {
+            log::warn!("advertised route {} has host bits set.", route);
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let cidrs = DatabaseCidr::list(conn)?;
+        let network = cidrs
+            .iter()
+            .find(|cidr| cidr.parent.is_none())
+            .map(|cidr| cidr.cidr);
+        if let Some((network, route)) = network.and_then(|network| {
+            contents
+                .advertised_routes
+                .iter()
+                .find(|route| route.contains(&network) || network.contains(*route))
+                .map(|route| (network, route))
+        }) {
+            log::warn!(
+                "advertised route {} overlaps the network {}.",
+                route,
+                network
+            );
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let peers = Self::list(conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .filter(|peer| !peer.is_disabled)
+            .collect::<Vec<_>>();
+        if let Some((other, route)) = overlapping_routes(contents, &peers).first() {
+            log::warn!(
+                "routes advertised by {} overlap with {} advertised by {}.",
+                contents.name,
+                route,
+                other.name
+            );
+            return Err(ServerError::InvalidQuery);
+        }
+        Ok(())
+    }
+
+    /// Disable this peer and move its advertised routes onto `successor` in one transaction,
+    /// so that no fetch sees the routes on both peers or on neither. Fails if the moved routes
+    /// overlap those of any other enabled peer.
+    pub fn hand_over_routes(
+        &mut self,
+        conn: &Connection,
+        successor: &mut DatabasePeer,
+    ) -> Result<(), ServerError> {
+        if self.id == successor.id || successor.is_disabled {
+            log::warn!("routes can only be moved to another enabled peer.");
+            return Err(ServerError::InvalidQuery);
+        }
+        let others = Self::list(conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .filter(|peer| peer.id != self.id && !peer.is_disabled)
+            .collect::<Vec<_>>();
+        let moved = PeerContents {
+            advertised_routes: self.advertised_routes.clone(),
+            ..successor.contents.clone()
+        };
+        if let Some((other, route)) = overlapping_routes(&moved, &others).first() {
+            log::warn!(
+                "can't move {}'s routes to {}, they overlap with {} advertised by {}.",
+                self.name,
+                successor.name,
+                route,
+                other.name
+            );
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let mut routes = successor.advertised_routes.clone();
+        for route in &self.advertised_routes {
+            if !routes.contains(route) {
+                routes.push(*route);
+            }
+        }
+        let tx = conn.unchecked_transaction()?;
+        self.update(
+            &tx,
+            PeerContents {
+                is_disabled: true,
+                advertised_routes: vec![],
+                ..self.contents.clone()
+            },
+        )?;
+        successor.update(
+            &tx,
+            PeerContents {
+                advertised_routes: routes,
+                ..successor.contents.clone()
+            },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Override the peer's keepalive interval. `None` goes back to the network's default,
+    /// and `Some(0)` disables keepalive for the peer entirely.
+    pub fn set_persistent_keepalive(
+        &mut self,
+        conn: &Connection,
+        interval: Option<u16>,
+    ) -> Result<(), ServerError> {
+        conn.execute(
+            "UPDATE peers SET persistent_keepalive = ?2 WHERE id = ?1",
+            params![self.id, interval],
+        )?;
+        self.persistent_keepalive = interval;
+        self.contents.persistent_keepalive_interval = Self::resolve_persistent_keepalive(interval);
+        Ok(())
+    }
+
+    /// Resolve the peer's keepalive interval against the network's `default`, if it doesn't
+    /// have its own. Needed before handing the peer to WireGuard or to other peers.
+    pub fn with_default_keepalive(&mut self, default: Option<u16>) {
+        self.contents.persistent_keepalive_interval =
+            Self::resolve_persistent_keepalive(self.persistent_keepalive.or(default));
+    }
+
+    /// The keepalive interval a peer ends up with, given its own or the network's (if any).
+    /// `None` if keepalive is disabled.
+    pub fn resolve_persistent_keepalive(interval: Option<u16>) -> Option<u16> {
+        match interval.unwrap_or(PERSISTENT_KEEPALIVE_INTERVAL_SECS) {
+            0 => None,
+            interval => Some(interval),
+        }
+    }
+
+    /// Move the peer to a new IP address within its CIDR.
+    ///
+    /// Unlike `update()`, this deliberately changes the peer's address, so it's only meant
+    /// to be used by administrative tooling on the server itself (i.e. defragmenting a CIDR).
+    pub fn set_ip(&mut self, conn: &Connection, ip: IpAddr) -> Result<(), ServerError> {
+        let cidr = DatabaseCidr::get(conn, self.cidr_id)?;
+        if !cidr.cidr.is_assignable(&ip) {
+            log::warn!("tried to move peer to an IP that isn't assignable in its CIDR.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        conn.execute(
+            "UPDATE peers SET ip = ?2 WHERE id = ?1",
+            params![self.id, ip.to_string()],
+        )?;
+
+        self.contents.ip = ip;
+        Ok(())
+    }
+
+    /// Plan how to pack the peers of `cidr` into its lowest assignable addresses, keeping
+    /// their relative order. Only peers that would actually move are returned, paired with
+    /// their new IP, in the order the moves need to be applied to avoid collisions.
+    pub fn defragment_plan<'a>(cidr: &Cidr, peers: &'a [Peer]) -> Vec<(&'a Peer, IpAddr)> {
+        let mut members: Vec<_> = peers.iter().filter(|p| p.cidr_id == cidr.id).collect();
+        members.sort_by_key(|p| p.ip);
+
+        cidr.hosts()
+            .filter(|ip| cidr.is_assignable(ip))
+            .zip(members)
+            .filter(|(new_ip, peer)| peer.ip != *new_ip)
+            .map(|(new_ip, peer)| (peer, new_ip))
+            .collect()
+    }
+
+    /// Fill in the preshared key for the peer's link with the server, if the network uses
+    /// preshared keys and the peer has opted in to them. Needed before adding the peer to
+    /// the server's interface.
+    pub fn with_server_preshared_key(
+        &mut self,
+        conn: &Connection,
+        preshared_keys: bool,
+    ) -> Result<(), ServerError> {
+        if preshared_keys && self.id != SERVER_PEER_ID {
+            self.contents.preshared_key = DatabasePresharedKey::get(conn, SERVER_PEER_ID, self.id)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the endpoint candidates as reported by the peer itself, recording when they
+    /// were reported so they can age out if the peer stops refreshing them.
+    pub fn set_candidates(
+        &mut self,
+        conn: &Connection,
+        candidates: Vec<Endpoint>,
+    ) -> Result<(), ServerError> {
+        let unix_now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.");
+        conn.execute(
+            "UPDATE peers SET candidates = ?2, candidates_updated = ?3 WHERE id = ?1",
+            params![
+                self.id,
+                serde_json::to_string(&candidates)?,
+                unix_now.as_secs()
+            ],
+        )?;
+
+        self.contents.candidates = candidates;
+        Ok(())
+    }
+
+    /// Replace the description the peer reports about itself, after sanitizing it. An empty
+    /// description clears it.
+    pub fn set_description(
+        &mut self,
+        conn: &Connection,
+        description: &PeerDescription,
+    ) -> Result<(), ServerError> {
+        let description = Some(description.sanitized()).filter(|d| !d.is_empty());
+        conn.execute(
+            "UPDATE peers SET description = ?2 WHERE id = ?1",
+            params![
+                self.id,
+                description
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?
+            ],
+        )?;
+
+        self.contents.description = description;
+        Ok(())
+    }
+
+    /// Clear reported candidates that haven't been refreshed within `max_age`, so that peers
+    /// stop trying addresses that are likely gone. Candidates that weren't reported by a peer
+    /// (i.e. the server's own) never age out.
+    pub fn expire_stale_candidates(
+        conn: &Connection,
+        max_age: Duration,
+    ) -> Result<usize, ServerError> {
+        let cutoff = SystemTime::now()
+            .checked_sub(max_age)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let expired = conn.execute(
+            "UPDATE peers SET candidates = '[]', candidates_updated = NULL
+            WHERE candidates_updated < ?1",
+            params![cutoff.as_secs()],
+        )?;
+
+        Ok(expired)
+    }
+
     pub fn disable(conn: &Connection, id: i64) -> Result<(), ServerError> {
         match conn.execute(
             "UPDATE peers SET is_disabled = 1 WHERE id = ?1",
@@ -206,6 +596,14 @@ impl DatabasePeer {
             return Err(ServerError::Unauthorized);
         }
 
+        if DatabaseRevokedKey::is_revoked(conn, pubkey)? {
+            log::warn!(
+                "peer {} tried to redeem with a revoked public key.",
+                self.name
+            );
+            return Err(ServerError::Unauthorized);
+        }
+
         match conn.execute(
             "UPDATE peers SET is_redeemed = 1, public_key = ?1 WHERE id = ?2 AND is_redeemed = 0",
             params![pubkey, self.id],
@@ -219,12 +617,48 @@ impl DatabasePeer {
         }
     }
 
+    /// Revoke the peer's current public key and give it a new, unredeemed invitation key,
+    /// as if it had just been added.
+    pub fn rotate_key(
+        &mut self,
+        conn: &Connection,
+        invite_public_key: &str,
+        invite_expires: SystemTime,
+    ) -> Result<(), ServerError> {
+        DatabaseRevokedKey::revoke(conn, self.id, &self.public_key)?;
+        let expires_secs = invite_expires
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .ok();
+        conn.execute(
+            "UPDATE peers SET public_key = ?1, is_redeemed = 0, invite_expires = ?2 WHERE id = ?3",
+            params![invite_public_key, expires_secs, self.id],
+        )?;
+        self.contents.public_key = invite_public_key.into();
+        self.contents.is_redeemed = false;
+        self.contents.invite_expires = Some(invite_expires);
+        Ok(())
+    }
+
+    /// Delete a peer whose invitation hasn't been redeemed yet and revoke the invitation's
+    /// key, so that a leaked invitation can't be used. Fails if the peer has redeemed it.
+    pub fn revoke_invite(&self, conn: &Connection) -> Result<(), ServerError> {
+        let tx = conn.unchecked_transaction()?;
+        DatabaseRevokedKey::revoke(&tx, self.id, &self.public_key)?;
+        // Checking is_redeemed in the same statement closes the race with a concurrent redeem.
+        match tx.execute(
+            "DELETE FROM peers WHERE id = ?1 AND is_redeemed = 0",
+            params![self.id],
+        )? {
+            0 => Err(ServerError::NotFound),
+            _ => Ok(tx.commit()?),
+        }
+    }
+
     fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
         let id = row.get(0)?;
-        let name = row
-            .get::<_, String>(1)?
-            .parse()
-            .map_err(|_| rusqlite::Error::InvalidColumnType(1, "hostname".into(), Type::Text))?;
+        // Stored names aren't re-checked, or a stricter rule could make a peer unloadable.
+        let name = Hostname::unchecked(row.get(1)?);
         let ip: IpAddr = row
             .get::<_, String>(2)?
             .parse()
@@ -249,9 +683,41 @@ impl DatabasePeer {
             vec![]
         };
 
-        let persistent_keepalive_interval = Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS);
+        let tags = if let Some(tags) = row.get::<_, Option<String>>(11)? {
+            serde_json::from_str(&tags).map_err(|_| {
+                rusqlite::Error::InvalidColumnType(11, "tags (json)".into(), Type::Text)
+            })?
+        } else {
+            vec![]
+        };
+
+        let persistent_keepalive = row.get(12)?;
 
-        Ok(Peer {
+        let advertised_routes = if let Some(routes) = row.get::<_, Option<String>>(13)? {
+            serde_json::from_str(&routes).map_err(|_| {
+                rusqlite::Error::InvalidColumnType(
+                    13,
+                    "advertised_routes (json)".into(),
+                    Type::Text,
+                )
+            })?
+        } else {
+            vec![]
+        };
+
+        let is_observer = row.get(14)?;
+        let created_at = row
+            .get::<_, Option<u64>>(15)?
+            .map(|unixtime| SystemTime::UNIX_EPOCH + Duration::from_secs(unixtime));
+        let created_by = row.get(16)?;
+        let is_staged = row.get(17)?;
+        // A description that doesn't parse is only advisory, so it's dropped rather than
+        // making the peer unloadable.
+        let description = row
+            .get::<_, Option<String>>(18)?
+            .and_then(|description| serde_json::from_str(&description).ok());
+
+        let inner = Peer {
             id,
             contents: PeerContents {
                 name,
@@ -259,15 +725,29 @@ impl DatabasePeer {
                 cidr_id,
                 public_key,
                 endpoint,
-                persistent_keepalive_interval,
+                persistent_keepalive_interval: Self::resolve_persistent_keepalive(
+                    persistent_keepalive,
+                ),
                 is_admin,
                 is_disabled,
                 is_redeemed,
                 invite_expires,
                 candidates,
+                tags,
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes,
+                is_observer,
+                is_staged,
+                created_at,
+                created_by,
+                description,
             },
-        }
-        .into())
+        };
+        Ok(Self {
+            inner,
+            persistent_keepalive,
+        })
     }
 
     pub fn get(conn: &Connection, id: i64) -> Result<Self, ServerError> {
@@ -290,6 +770,8 @@ impl DatabasePeer {
         Ok(result)
     }
 
+    /// List the peers this peer is allowed to see. Observers are left out for everyone but
+    /// themselves.
     pub fn get_all_allowed_peers(&self, conn: &Connection) -> Result<Vec<Self>, ServerError> {
         // This query is a handful, so an explanation of what's happening, and what each CTE does (https://sqlite.org/lang_with.html):
         //
@@ -321,12 +803,13 @@ impl DatabasePeer {
                 SELECT DISTINCT {}
                 FROM peers
                 JOIN associated_subcidrs ON peers.cidr_id=associated_subcidrs.cidr_id
-                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1;",
+                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1
+                    AND (peers.is_observer = 0 OR peers.id = ?2);",
                 COLUMNS.iter().map(|col| format!("peers.{col}")).collect::<Vec<_>>().join(", ")
             ),
         )?;
         let peers = stmt
-            .query_map(params![self.cidr_id], Self::from_row)?
+            .query_map(params![self.cidr_id, self.id], Self::from_row)?
             .collect::<Result<_, _>>()?;
         Ok(peers)
     }
@@ -351,3 +834,390 @@ impl DatabasePeer {
         Ok(deleted)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use shared::{Error, PeerStatus};
+
+    #[test]
+    fn test_expire_stale_candidates() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        let candidates = vec!["1.1.1.1:51820".parse::<Endpoint>().unwrap()];
+        let mut peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        peer.set_candidates(&conn, candidates.clone())?;
+
+        assert_eq!(
+            DatabasePeer::expire_stale_candidates(&conn, Duration::from_secs(60))?,
+            0
+        );
+        assert_eq!(
+            DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?.candidates,
+            candidates
+        );
+
+        conn.execute(
+            "UPDATE peers SET candidates_updated = candidates_updated - 120 WHERE id = ?1",
+            params![test::DEVELOPER1_PEER_ID],
+        )?;
+        assert_eq!(
+            DatabasePeer::expire_stale_candidates(&conn, Duration::from_secs(60))?,
+            1
+        );
+        assert!(DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?
+            .candidates
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_defragment_plan() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        let cidr = DatabaseCidr::get(&conn, test::DEVELOPER_CIDR_ID)?;
+        let assignable: Vec<_> = cidr
+            .hosts()
+            .filter(|ip| cidr.is_assignable(ip))
+            .take(10)
+            .collect();
+
+        // Leave a hole at the start of the developer CIDR by moving developer1 out of the way.
+        let mut developer1 = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        developer1.set_ip(&conn, assignable[9])?;
+
+        let peers: Vec<_> = DatabasePeer::list(&conn)?
+            .into_iter()
+            .map(|p| p.inner)
+            .collect();
+        let plan = DatabasePeer::defragment_plan(&cidr, &peers);
+        let moves: Vec<_> = plan.iter().map(|(p, ip)| (&*p.name, *ip)).collect();
+
+        // developer2 slides down into the first address, and developer1 fills in behind it.
+        assert_eq!(
+            moves,
+            vec![("developer2", assignable[0]), ("developer1", assignable[1])]
+        );
+
+        for (peer, new_ip) in plan {
+            DatabasePeer::get(&conn, peer.id)?.set_ip(&conn, new_ip)?;
+        }
+        let peers: Vec<_> = DatabasePeer::list(&conn)?
+            .into_iter()
+            .map(|p| p.inner)
+            .collect();
+        assert!(DatabasePeer::defragment_plan(&cidr, &peers).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_key_revokes_old_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        let mut admin = DatabasePeer::get(&conn, test::ADMIN_PEER_ID)?;
+        let old_key = admin.public_key.clone();
+        let invite_key = wireguard_control::KeyPair::generate().public.to_base64();
+        admin.rotate_key(
+            &conn,
+            &invite_key,
+            SystemTime::now() + Duration::from_secs(60),
+        )?;
+
+        let admin = DatabasePeer::get(&conn, test::ADMIN_PEER_ID)?;
+        assert_eq!(admin.public_key, invite_key);
+        assert!(!admin.is_redeemed);
+        assert!(DatabaseRevokedKey::is_revoked(&conn, &old_key)?);
+
+        // The old key can't come back, neither by redeeming nor as a new peer.
+        let mut admin = admin;
+        assert!(matches!(
+            admin.redeem(&conn, &old_key),
+            Err(ServerError::Unauthorized)
+        ));
+        let mut contents = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        contents.public_key = old_key;
+        assert!(DatabasePeer::create(&conn, contents).is_err());
+
+        // A fresh key redeems as usual.
+        let new_key = wireguard_control::KeyPair::generate().public.to_base64();
+        admin.redeem(&conn, &new_key)?;
+        assert_eq!(
+            DatabasePeer::get(&conn, test::ADMIN_PEER_ID)?.public_key,
+            new_key
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revoke_invite() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        // Redeemed peers aren't invitations anymore.
+        let developer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        assert!(matches!(
+            developer.revoke_invite(&conn),
+            Err(ServerError::NotFound)
+        ));
+        assert!(!DatabaseRevokedKey::is_revoked(
+            &conn,
+            &developer.public_key
+        )?);
+
+        let mut contents = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        contents.is_redeemed = false;
+        let invited = DatabasePeer::create(&conn, contents)?;
+        invited.revoke_invite(&conn)?;
+        assert!(DatabasePeer::get(&conn, invited.id).is_err());
+        assert!(DatabaseRevokedKey::is_revoked(&conn, &invited.public_key)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_peer() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+        let developer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        let visible = |name: &str| -> Result<bool, Error> {
+            Ok(developer
+                .get_all_allowed_peers(&conn)?
+                .iter()
+                .any(|peer| &*peer.name == name))
+        };
+
+        let mut contents = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        contents.is_staged = true;
+        assert!(DatabasePeer::create(&conn, contents.clone()).is_err());
+
+        contents.is_disabled = true;
+        let mut staged = DatabasePeer::create(&conn, contents)?;
+        assert_eq!(staged.status(), PeerStatus::Staged);
+        assert!(!visible("developer3")?);
+
+        // Disabling keeps it staged, enabling activates it for good.
+        staged.update(&conn, staged.contents.clone())?;
+        assert_eq!(
+            DatabasePeer::get(&conn, staged.id)?.status(),
+            PeerStatus::Staged
+        );
+        let contents = PeerContents {
+            is_disabled: false,
+            ..staged.contents.clone()
+        };
+        staged.update(&conn, contents)?;
+        assert_eq!(
+            DatabasePeer::get(&conn, staged.id)?.status(),
+            PeerStatus::Active
+        );
+        assert!(visible("developer3")?);
+
+        let contents = PeerContents {
+            is_disabled: true,
+            ..staged.contents.clone()
+        };
+        staged.update(&conn, contents)?;
+        assert_eq!(
+            DatabasePeer::get(&conn, staged.id)?.status(),
+            PeerStatus::Disabled
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hand_over_routes() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+        let route = |net: &str| net.parse::<ipnet::IpNet>().unwrap();
+
+        let mut old = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        old.update(
+            &conn,
+            PeerContents {
+                advertised_routes: vec![route("10.99.0.0/16")],
+                ..old.contents.clone()
+            },
+        )?;
+        let mut new = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+        new.update(
+            &conn,
+            PeerContents {
+                advertised_routes: vec![route("10.98.0.0/16")],
+                ..new.contents.clone()
+            },
+        )?;
+
+        // Routes can't be moved onto a peer where they'd overlap a third peer's, ex. ones
+        // from before overlapping routes were refused.
+        conn.execute(
+            "UPDATE peers SET advertised_routes = ?2 WHERE id = ?1",
+            params![test::USER1_PEER_ID, r#"["10.99.1.0/24"]"#],
+        )?;
+        assert!(matches!(
+            old.hand_over_routes(&conn, &mut new),
+            Err(ServerError::InvalidQuery)
+        ));
+        assert!(!DatabasePeer::get(&conn, old.id)?.is_disabled);
+
+        conn.execute(
+            "UPDATE peers SET advertised_routes = NULL WHERE id = ?1",
+            params![test::USER1_PEER_ID],
+        )?;
+        old.hand_over_routes(&conn, &mut new)?;
+        let old = DatabasePeer::get(&conn, old.id)?;
+        assert!(old.is_disabled);
+        assert!(old.advertised_routes.is_empty());
+        assert_eq!(
+            DatabasePeer::get(&conn, new.id)?.advertised_routes,
+            vec![route("10.98.0.0/16"), route("10.99.0.0/16")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_advertised_routes() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+        let route = |net: &str| net.parse::<ipnet::IpNet>().unwrap();
+        let advertise = |id: i64, routes: &[&str]| {
+            let mut peer = DatabasePeer::get(&conn, id)?;
+            let contents = PeerContents {
+                advertised_routes: routes.iter().map(|net| route(net)).collect(),
+                ..peer.contents.clone()
+            };
+            peer.update(&conn, contents)
+        };
+
+        advertise(test::DEVELOPER1_PEER_ID, &["192.168.1.0/24"])?;
+        // Host bits, the network itself, and another peer's routes are refused.
+        for routes in [
+            &["192.168.2.1/24"][..],
+            &[test::ROOT_CIDR],
+            &["192.168.1.128/25"],
+            &["192.168.0.0/16"],
+        ] {
+            assert!(matches!(
+                advertise(test::DEVELOPER2_PEER_ID, routes),
+                Err(ServerError::InvalidQuery)
+            ));
+        }
+        advertise(test::DEVELOPER2_PEER_ID, &["192.168.2.0/24"])?;
+        // A peer can keep its own routes, and a disabled peer's are free to take.
+        advertise(
+            test::DEVELOPER1_PEER_ID,
+            &["192.168.1.0/24", "192.168.3.0/24"],
+        )?;
+        DatabasePeer::disable(&conn, test::DEVELOPER1_PEER_ID)?;
+        advertise(test::USER1_PEER_ID, &["192.168.1.0/24"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_with_disabled_peers_ip() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        DatabasePeer::disable(&conn, test::DEVELOPER2_PEER_ID)?;
+        let contents = test::developer_peer_contents("developer3", test::DEVELOPER2_PEER_IP)?;
+        assert!(matches!(
+            DatabasePeer::create(&conn, contents),
+            Err(ServerError::InvalidQuery)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsafe_name() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        let mut contents = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        contents.name = Hostname::parse_allowed("Developer_3").map_err(Error::msg)?;
+        let peer = DatabasePeer::create(&conn, contents)?;
+        assert_eq!(&*DatabasePeer::get(&conn, peer.id)?.name, "Developer_3");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_ip_outside_cidr() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        let mut developer1 = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        assert!(developer1
+            .set_ip(&conn, test::USER1_PEER_IP.parse()?)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peers_without_creation_metadata() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+
+        // Peers from before creation was recorded have neither column set.
+        conn.execute(
+            "UPDATE peers SET created_at = NULL, created_by = NULL WHERE id = ?1",
+            params![test::DEVELOPER1_PEER_ID],
+        )?;
+        let peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.created_at, None);
+        assert_eq!(peer.created_by, None);
+
+        let json = serde_json::to_value(&peer.inner)?;
+        assert_eq!(json["created_at"], serde_json::Value::Null);
+        assert_eq!(json["created_by"], serde_json::Value::Null);
+
+        // ...and older servers don't send them at all.
+        let mut json = json.as_object().unwrap().clone();
+        json.remove("created_at");
+        json.remove("created_by");
+        let peer: Peer = serde_json::from_value(json.into())?;
+        assert_eq!(peer.created_at, None);
+
+        // Newer peers know when they were created.
+        let peer = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+        assert!(peer.created_at.unwrap() <= SystemTime::now());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_peers_match_reachability() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db();
+        let conn = conn.lock();
+        let cidrs = DatabaseCidr::list(&conn)?;
+        let associations = crate::db::DatabaseAssociation::list(&conn)?;
+        let peers = DatabasePeer::list(&conn)?;
+
+        for from in &peers {
+            let allowed = from
+                .get_all_allowed_peers(&conn)?
+                .into_iter()
+                .map(|peer| peer.id)
+                .collect::<Vec<_>>();
+            for to in &peers {
+                let linked = shared::reachability::link(&cidrs, &associations, from, to).is_ok();
+                assert_eq!(
+                    linked,
+                    allowed.contains(&to.id),
+                    "{} -> {}",
+                    from.name,
+                    to.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! A table of WireGuard preshared keys, one for each pair of peers that links up.
+//!
+//! Clients that predate preshared keys can't use them, so a peer only gets keys once its
+//! client opts in, either when redeeming its invitation or by rotating its keys. A peer
+//! has opted in when it has a key for its link with the server, and the pair of two peers
+//! that have both opted in gets a key when the second one does. Keys are removed along
+//! with either of their peers.
+
+use crate::{db::peer::SERVER_PEER_ID, ServerError};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use wireguard_control::Key;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE preshared_keys (
+      peer_id_1  INTEGER NOT NULL,                  /* The lower of the two peer IDs.                */
+      peer_id_2  INTEGER NOT NULL,                  /* The higher of the two peer IDs.               */
+      key        TEXT NOT NULL,                     /* The base64-encoded preshared key for the pair. */
+      PRIMARY KEY (peer_id_1, peer_id_2),
+      FOREIGN KEY (peer_id_1)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE,
+      FOREIGN KEY (peer_id_2)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+pub struct DatabasePresharedKey;
+
+impl DatabasePresharedKey {
+    /// Get the preshared key for the link between two peers, if both have opted in.
+    pub fn get(conn: &Connection, peer_a: i64, peer_b: i64) -> Result<Option<String>, ServerError> {
+        let (peer_id_1, peer_id_2) = (peer_a.min(peer_b), peer_a.max(peer_b));
+        let key = conn
+            .query_row(
+                "SELECT key FROM preshared_keys WHERE peer_id_1 = ?1 AND peer_id_2 = ?2",
+                params![peer_id_1, peer_id_2],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(key)
+    }
+
+    /// The keys for every link of the peer with id `peer_id`, keyed by the other peer's id.
+    pub fn for_peer(conn: &Connection, peer_id: i64) -> Result<HashMap<i64, String>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT CASE WHEN peer_id_1 = ?1 THEN peer_id_2 ELSE peer_id_1 END, key
+             FROM preshared_keys WHERE peer_id_1 = ?1 OR peer_id_2 = ?1",
+        )?;
+        let keys = stmt
+            .query_map(params![peer_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// List every pair's key, as the lower peer ID, the higher peer ID, and the key.
+    pub fn list(conn: &Connection) -> Result<Vec<(i64, i64, String)>, ServerError> {
+        let mut stmt =
+            conn.prepare_cached("SELECT peer_id_1, peer_id_2, key FROM preshared_keys")?;
+        let keys = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// Store a known key for the link between two peers, keeping the pair's key if it
+    /// already has one.
+    pub fn insert(
+        conn: &Connection,
+        peer_a: i64,
+        peer_b: i64,
+        key: &str,
+    ) -> Result<(), ServerError> {
+        let (peer_id_1, peer_id_2) = (peer_a.min(peer_b), peer_a.max(peer_b));
+        conn.execute(
+            "INSERT OR IGNORE INTO preshared_keys (peer_id_1, peer_id_2, key) VALUES (?1, ?2, ?3)",
+            params![peer_id_1, peer_id_2, key],
+        )?;
+        Ok(())
+    }
+
+    /// Opt the peer with id `peer_id` in to preshared keys, generating new keys for its
+    /// links with the server and every other peer that has opted in, and throwing away
+    /// any it had before. Returns the key for its link with the server.
+    pub fn enable(conn: &Connection, peer_id: i64) -> Result<String, ServerError> {
+        let opted_in: Vec<i64> = Self::for_peer(conn, SERVER_PEER_ID)?
+            .into_keys()
+            .filter(|id| *id != peer_id)
+            .collect();
+        conn.execute(
+            "DELETE FROM preshared_keys WHERE peer_id_1 = ?1 OR peer_id_2 = ?1",
+            params![peer_id],
+        )?;
+
+        let server_key = Key::generate_preshared().to_base64();
+        Self::insert(conn, SERVER_PEER_ID, peer_id, &server_key)?;
+        for other in opted_in {
+            Self::insert(conn, other, peer_id, &Key::generate_preshared().to_base64())?;
+        }
+        Ok(server_key)
+    }
+}
@@ -0,0 +1,67 @@
+//! Public keys that were taken away from a peer and must never be accepted again.
+//!
+//! Revocations deliberately outlive their peer, so there's no foreign key on `peer_id`.
+//! They're kept for good, but only told to clients for [`ADVERTISED_FOR`] after the fact.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+/// How long after a revocation clients are told about it, so they can re-pin the peer to
+/// its new key. Clients that don't fetch within this long refuse the new key instead.
+pub const ADVERTISED_FOR: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE revoked_keys (
+      public_key  TEXT PRIMARY KEY,                 /* The revoked WireGuard public key.             */
+      peer_id     INTEGER NOT NULL,                 /* The peer the key belonged to.                 */
+      revoked_at  INTEGER NOT NULL                  /* The UNIX time the key was revoked.            */
+    )";
+
+pub struct DatabaseRevokedKey;
+
+impl DatabaseRevokedKey {
+    pub fn revoke(conn: &Connection, peer_id: i64, public_key: &str) -> Result<(), ServerError> {
+        let revoked_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        conn.execute(
+            "INSERT OR IGNORE INTO revoked_keys (public_key, peer_id, revoked_at) VALUES (?1, ?2, ?3)",
+            params![public_key, peer_id, revoked_at],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_revoked(conn: &Connection, public_key: &str) -> Result<bool, ServerError> {
+        Ok(conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM revoked_keys WHERE public_key = ?1)",
+            params![public_key],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// The keys revoked at or after `since`, keyed by the peer they belonged to, oldest
+    /// first.
+    pub fn list_since(
+        conn: &Connection,
+        since: SystemTime,
+    ) -> Result<HashMap<i64, Vec<String>>, ServerError> {
+        let since = since
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut stmt = conn.prepare_cached(
+            "SELECT peer_id, public_key FROM revoked_keys WHERE revoked_at >= ?1
+             ORDER BY revoked_at, public_key",
+        )?;
+        let mut rows = stmt.query(params![since])?;
+        let mut keys: HashMap<i64, Vec<String>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            keys.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+        Ok(keys)
+    }
+}
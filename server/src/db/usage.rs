@@ -0,0 +1,207 @@
+//! Bytes transferred with each peer, accumulated from WireGuard's transfer counters.
+//!
+//! WireGuard's counters start over whenever the interface is recreated, so each sample
+//! only adds what was transferred since the previous one, by comparing against the
+//! counters it saw last. Totals are kept per hour, so they can be summed over a window, and
+//! hours older than [`RETENTION`] are dropped.
+//!
+//! Counters that started over can only be told apart from ones that kept going by being
+//! lower than last time. If they've already passed the last sample's by the time they're
+//! next sampled (ex. the interface was down only briefly, but busy since), only the
+//! difference is counted, so usage is undercounted.
+
+use crate::ServerError;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peer_usage (
+      peer_id   INTEGER NOT NULL,                   /* The peer the bytes were transferred with.     */
+      hour      INTEGER NOT NULL,                   /* The UNIX time the hour counted in started at. */
+      rx_bytes  INTEGER NOT NULL,                   /* Bytes received from the peer that hour.       */
+      tx_bytes  INTEGER NOT NULL,                   /* Bytes sent to the peer that hour.             */
+      PRIMARY KEY (peer_id, hour),
+      FOREIGN KEY (peer_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+pub static CREATE_COUNTERS_TABLE_SQL: &str = "CREATE TABLE peer_usage_counters (
+      peer_id   INTEGER PRIMARY KEY,                /* The peer the counters belong to.              */
+      rx_bytes  INTEGER NOT NULL,                   /* WireGuard's rx counter at the last sample.    */
+      tx_bytes  INTEGER NOT NULL,                   /* WireGuard's tx counter at the last sample.    */
+      FOREIGN KEY (peer_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+const HOUR: u64 = 60 * 60;
+
+/// How long hourly totals are kept before they're pruned.
+pub const RETENTION: Duration = Duration::from_secs(366 * 24 * HOUR);
+
+/// Bytes received from (`rx`) and sent to (`tx`) a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Transfer {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl Transfer {
+    /// What was transferred between the counters `last` seen and these ones. Counters lower
+    /// than last time mean they started over (ex. the interface was recreated, or the peer
+    /// was re-keyed), so everything they've counted since is new.
+    fn since(self, last: Option<Transfer>) -> Transfer {
+        match last {
+            Some(last) if self.rx_bytes >= last.rx_bytes && self.tx_bytes >= last.tx_bytes => {
+                Transfer {
+                    rx_bytes: self.rx_bytes - last.rx_bytes,
+                    tx_bytes: self.tx_bytes - last.tx_bytes,
+                }
+            },
+            _ => self,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.rx_bytes.saturating_add(self.tx_bytes)
+    }
+}
+
+pub struct DatabaseUsage;
+
+impl DatabaseUsage {
+    /// Record WireGuard's current `counters` for the peer with id `peer_id`, adding what
+    /// was transferred since the last sample to the hour `now` falls in.
+    pub fn record(
+        conn: &Connection,
+        peer_id: i64,
+        counters: Transfer,
+        now: SystemTime,
+    ) -> Result<Transfer, ServerError> {
+        let last = conn
+            .query_row(
+                "SELECT rx_bytes, tx_bytes FROM peer_usage_counters WHERE peer_id = ?1",
+                params![peer_id],
+                |row| {
+                    Ok(Transfer {
+                        rx_bytes: row.get::<_, i64>(0)? as u64,
+                        tx_bytes: row.get::<_, i64>(1)? as u64,
+                    })
+                },
+            )
+            .optional()?;
+        let transfer = counters.since(last);
+
+        if transfer.total() > 0 {
+            conn.execute(
+                "INSERT INTO peer_usage (peer_id, hour, rx_bytes, tx_bytes) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (peer_id, hour) DO UPDATE
+                 SET rx_bytes = rx_bytes + excluded.rx_bytes, tx_bytes = tx_bytes + excluded.tx_bytes",
+                params![
+                    peer_id,
+                    hour_of(now) as i64,
+                    transfer.rx_bytes as i64,
+                    transfer.tx_bytes as i64
+                ],
+            )?;
+        }
+        if last != Some(counters) {
+            conn.execute(
+                "INSERT OR REPLACE INTO peer_usage_counters (peer_id, rx_bytes, tx_bytes) VALUES (?1, ?2, ?3)",
+                params![peer_id, counters.rx_bytes as i64, counters.tx_bytes as i64],
+            )?;
+        }
+        Ok(transfer)
+    }
+
+    /// Drop the hourly totals from before the hour `before` falls in, returning how many
+    /// were dropped.
+    pub fn prune(conn: &Connection, before: SystemTime) -> Result<usize, ServerError> {
+        Ok(conn.execute(
+            "DELETE FROM peer_usage WHERE hour < ?1",
+            params![hour_of(before) as i64],
+        )?)
+    }
+
+    /// Each peer's total transfer, keyed by peer id. With `since`, only the hours from the
+    /// one it falls in onward are counted.
+    pub fn totals(
+        conn: &Connection,
+        since: Option<SystemTime>,
+    ) -> Result<HashMap<i64, Transfer>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT peer_id, SUM(rx_bytes), SUM(tx_bytes) FROM peer_usage
+             WHERE hour >= ?1 GROUP BY peer_id",
+        )?;
+        let totals = stmt
+            .query_map(params![since.map(hour_of).unwrap_or(0) as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    Transfer {
+                        rx_bytes: row.get::<_, i64>(1)? as u64,
+                        tx_bytes: row.get::<_, i64>(2)? as u64,
+                    },
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(totals)
+    }
+}
+
+/// The UNIX time of the start of the hour `time` falls in.
+fn hour_of(time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs - secs % HOUR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use anyhow::Result;
+
+    #[test]
+    fn test_usage() -> Result<()> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000 * HOUR);
+        let later = start + Duration::from_secs(2 * HOUR);
+        let transfer = |rx_bytes, tx_bytes| Transfer { rx_bytes, tx_bytes };
+        let record =
+            |counters, now| DatabaseUsage::record(&conn, test::ADMIN_PEER_ID, counters, now);
+
+        // The first sample counts everything, later ones only what's new.
+        assert_eq!(record(transfer(100, 10), start)?, transfer(100, 10));
+        assert_eq!(record(transfer(150, 10), start)?, transfer(50, 0));
+        assert_eq!(record(transfer(150, 10), later)?, transfer(0, 0));
+
+        // Counters that went backwards started over.
+        assert_eq!(record(transfer(30, 5), later)?, transfer(30, 5));
+        assert_eq!(record(transfer(40, 5), later)?, transfer(10, 0));
+
+        let totals = DatabaseUsage::totals(&conn, None)?;
+        assert_eq!(totals[&test::ADMIN_PEER_ID], transfer(190, 15));
+        assert_eq!(totals.len(), 1);
+        let totals = DatabaseUsage::totals(&conn, Some(later + Duration::from_secs(60)))?;
+        assert_eq!(totals[&test::ADMIN_PEER_ID], transfer(40, 5));
+        assert!(DatabaseUsage::totals(&conn, Some(later + Duration::from_secs(HOUR)))?.is_empty());
+
+        // Pruning only drops the hours before the one it's given.
+        assert_eq!(DatabaseUsage::prune(&conn, later)?, 1);
+        assert_eq!(
+            DatabaseUsage::totals(&conn, None)?[&test::ADMIN_PEER_ID],
+            transfer(40, 5)
+        );
+        Ok(())
+    }
+}
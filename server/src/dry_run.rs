@@ -0,0 +1,280 @@
+//! Support for `--dry-run`, which runs a command against a scratch copy of the network's
+//! database and reports what it would have changed.
+//!
+//! Commands open the scratch copy instead of the real database when the server config is
+//! in dry-run mode, and skip touching the live WireGuard interface. Everything else,
+//! including validation and prompts, runs exactly as it normally would.
+
+use crate::{
+    db::{DatabaseAssociation, DatabaseCidr, DatabasePeer},
+    ServerConfig,
+};
+use anyhow::bail;
+use colored::*;
+use rusqlite::Connection;
+use shared::{Association, Cidr, Error, Peer};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+use wireguard_control::InterfaceName;
+
+/// Where the scratch copy of an interface's database lives for this process.
+pub fn scratch_database_path(interface: &InterfaceName) -> PathBuf {
+    env::temp_dir().join(format!(
+        "innernet-server-dry-run-{}-{}.db",
+        interface,
+        process::id()
+    ))
+}
+
+struct Snapshot {
+    peers: Vec<Peer>,
+    cidrs: Vec<Cidr>,
+    associations: Vec<Association>,
+}
+
+impl Snapshot {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        crate::db::auto_migrate(&conn)?;
+        Ok(Self {
+            peers: DatabasePeer::list(&conn)?
+                .into_iter()
+                .map(|dp| dp.inner)
+                .collect(),
+            cidrs: DatabaseCidr::list(&conn)?,
+            associations: DatabaseAssociation::list(&conn)?,
+        })
+    }
+}
+
+pub struct DryRun {
+    scratch_path: PathBuf,
+    before: Snapshot,
+}
+
+impl DryRun {
+    /// Make the scratch copy of the interface's database for a command to run against.
+    pub fn start(conf: &ServerConfig, interface: &InterfaceName) -> Result<Self, Error> {
+        let database_path = conf.database_path(interface);
+        if !database_path.exists() {
+            bail!(
+                "no database file found at {}",
+                database_path.to_string_lossy()
+            );
+        }
+
+        let scratch_path = scratch_database_path(interface);
+        fs::copy(&database_path, &scratch_path)?;
+        shared::chmod(&fs::File::open(&scratch_path)?, 0o600)?;
+        let before = Snapshot::read(&scratch_path)?;
+        Ok(Self {
+            scratch_path,
+            before,
+        })
+    }
+
+    /// Print everything the command changed in the scratch database.
+    pub fn finish(self) -> Result<(), Error> {
+        let changes = diff(&self.before, &Snapshot::read(&self.scratch_path)?);
+
+        println!();
+        if changes.is_empty() {
+            println!("{} dry run, nothing would change.", "[*]".dimmed());
+        } else {
+            println!("{} dry run, these changes were not made:", "[*]".dimmed());
+            for change in changes {
+                println!("    {change}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DryRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.scratch_path);
+    }
+}
+
+fn by_id<T>(items: &[T], id: impl Fn(&T) -> i64) -> BTreeMap<i64, &T> {
+    items.iter().map(|item| (id(item), item)).collect()
+}
+
+/// Describe the differences between two snapshots, one line per change.
+fn diff(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    let mut changes = vec![];
+
+    let (old_cidrs, new_cidrs) = (
+        by_id(&before.cidrs, |c| c.id),
+        by_id(&after.cidrs, |c| c.id),
+    );
+    for (id, cidr) in &new_cidrs {
+        match old_cidrs.get(id) {
+            None => changes.push(format!(
+                "{} CIDR {} ({})",
+                "+".green(),
+                cidr.name.yellow(),
+                cidr.cidr
+            )),
+            Some(old) if old.name != cidr.name => changes.push(format!(
+                "{} CIDR {} renamed to {}",
+                "~".yellow(),
+                old.name.yellow(),
+                cidr.name.yellow()
+            )),
+            Some(_) => {},
+        }
+    }
+    for (id, cidr) in &old_cidrs {
+        if !new_cidrs.contains_key(id) {
+            changes.push(format!(
+                "{} CIDR {} ({})",
+                "-".red(),
+                cidr.name.yellow(),
+                cidr.cidr
+            ));
+        }
+    }
+
+    let (old_peers, new_peers) = (
+        by_id(&before.peers, |p| p.id),
+        by_id(&after.peers, |p| p.id),
+    );
+    for (id, peer) in &new_peers {
+        let Some(old) = old_peers.get(id) else {
+            changes.push(format!(
+                "{} peer {} (allocates {})",
+                "+".green(),
+                peer.name.yellow(),
+                peer.ip
+            ));
+            continue;
+        };
+        let name = old.name.yellow();
+        if old.name != peer.name {
+            changes.push(format!(
+                "{} peer {name} renamed to {}",
+                "~".yellow(),
+                peer.name.yellow()
+            ));
+        }
+        if old.ip != peer.ip {
+            changes.push(format!(
+                "{} peer {name} moves from {} to {}",
+                "~".yellow(),
+                old.ip,
+                peer.ip
+            ));
+        }
+        if old.is_disabled != peer.is_disabled {
+            let action = if peer.is_disabled {
+                "disabled"
+            } else {
+                "enabled"
+            };
+            changes.push(format!("{} peer {name} {action}", "~".yellow()));
+        }
+        if old.public_key != peer.public_key {
+            changes.push(format!("{} peer {name} gets a new key", "~".yellow()));
+        }
+        if old.tags != peer.tags {
+            let tags = peer.tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            changes.push(format!(
+                "{} peer {name} tags set to [{}]",
+                "~".yellow(),
+                tags.join(", ")
+            ));
+        }
+    }
+    for (id, peer) in &old_peers {
+        if !new_peers.contains_key(id) {
+            changes.push(format!(
+                "{} peer {} (frees {})",
+                "-".red(),
+                peer.name.yellow(),
+                peer.ip
+            ));
+        }
+    }
+
+    let cidr_name = |id: &i64| {
+        new_cidrs
+            .get(id)
+            .or_else(|| old_cidrs.get(id))
+            .map(|cidr| cidr.name.clone())
+            .unwrap_or_else(|| id.to_string())
+    };
+    let (old_associations, new_associations) = (
+        by_id(&before.associations, |a| a.id),
+        by_id(&after.associations, |a| a.id),
+    );
+    for (id, association) in &new_associations {
+        if !old_associations.contains_key(id) {
+            changes.push(format!(
+                "{} association {} <=> {}",
+                "+".green(),
+                cidr_name(&association.cidr_id_1).yellow(),
+                cidr_name(&association.cidr_id_2).yellow()
+            ));
+        }
+    }
+    for (id, association) in &old_associations {
+        if !new_associations.contains_key(id) {
+            changes.push(format!(
+                "{} association {} <=> {}",
+                "-".red(),
+                cidr_name(&association.cidr_id_1).yellow(),
+                cidr_name(&association.cidr_id_2).yellow()
+            ));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+
+    #[test]
+    fn test_diff() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let snapshot = || -> Result<Snapshot, Error> {
+            let conn = server.db();
+            let conn = conn.lock();
+            Ok(Snapshot {
+                peers: DatabasePeer::list(&conn)?
+                    .into_iter()
+                    .map(|dp| dp.inner)
+                    .collect(),
+                cidrs: DatabaseCidr::list(&conn)?,
+                associations: DatabaseAssociation::list(&conn)?,
+            })
+        };
+
+        let before = snapshot()?;
+        assert!(diff(&before, &snapshot()?).is_empty());
+
+        {
+            let conn = server.db();
+            let conn = conn.lock();
+            let mut peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+            let contents = shared::PeerContents {
+                is_disabled: true,
+                ..peer.contents.clone()
+            };
+            peer.update(&conn, contents)?;
+        }
+
+        let changes = diff(&before, &snapshot()?);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].contains("developer1"));
+        assert!(changes[0].contains("disabled"));
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Cross-checking the endpoints peers report for themselves against where WireGuard
+//! actually sees their traffic coming from.
+
+use crate::ServerError;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// What to do when a peer reports an endpoint that doesn't match the one WireGuard observed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EndpointCheck {
+    /// Accept any endpoint a peer reports.
+    #[default]
+    Off,
+    /// Accept any endpoint, but log the ones whose IP doesn't match.
+    Warn,
+    /// Refuse endpoints whose IP doesn't match. The port may differ, since NAT can
+    /// rewrite it.
+    Ip,
+    /// Refuse endpoints unless both the IP and the port match.
+    Strict,
+}
+
+impl EndpointCheck {
+    /// Whether reported endpoints need looking at (and resolving) at all.
+    pub fn is_enabled(self) -> bool {
+        self != Self::Off
+    }
+
+    /// Whether mismatched endpoints are refused, rather than accepted or only logged.
+    pub fn is_enforced(self) -> bool {
+        matches!(self, Self::Ip | Self::Strict)
+    }
+
+    /// Check the endpoint `reported` by the peer `name`, which resolved to `resolved`,
+    /// against the one WireGuard `observed` its traffic coming from. A peer WireGuard
+    /// hasn't seen an endpoint for yet (ex. right after it connected) can't be checked,
+    /// and is let through.
+    pub fn check(
+        self,
+        name: &str,
+        reported: &str,
+        resolved: &[SocketAddr],
+        observed: Option<SocketAddr>,
+    ) -> Result<(), ServerError> {
+        let Some(observed) = observed else {
+            if self.is_enabled() {
+                log::debug!(
+                    "no observed endpoint for {} yet, accepting {} unchecked.",
+                    name,
+                    reported
+                );
+            }
+            return Ok(());
+        };
+        let same_ip = |addr: &SocketAddr| addr.ip().to_canonical() == observed.ip().to_canonical();
+        let matches = match self {
+            Self::Off => return Ok(()),
+            Self::Warn | Self::Ip => resolved.iter().any(same_ip),
+            Self::Strict => resolved
+                .iter()
+                .any(|addr| same_ip(addr) && addr.port() == observed.port()),
+        };
+        if matches {
+            return Ok(());
+        }
+
+        if self == Self::Warn {
+            log::warn!(
+                "peer {} reported endpoint {}, but its traffic comes from {}.",
+                name,
+                reported,
+                observed
+            );
+            return Ok(());
+        }
+        log::warn!(
+            "rejected endpoint {} reported by peer {}, whose traffic comes from {}.",
+            reported,
+            name,
+            observed
+        );
+        Err(ServerError::EndpointMismatch(format!(
+            "{reported} doesn't match the address this peer connects from"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_check() {
+        let observed: SocketAddr = "1.2.3.4:40000".parse().unwrap();
+        let nat: SocketAddr = "1.2.3.4:51820".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:40000".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:40000".parse().unwrap();
+        let check = |policy: EndpointCheck, resolved: &[SocketAddr], observed| {
+            policy.check("peer", "reported", resolved, observed).is_ok()
+        };
+
+        for policy in [EndpointCheck::Off, EndpointCheck::Warn] {
+            assert!(check(policy, &[other], Some(observed)));
+        }
+
+        // Only the IP has to match, however it's written.
+        assert!(check(EndpointCheck::Ip, &[nat], Some(observed)));
+        assert!(check(EndpointCheck::Ip, &[mapped], Some(observed)));
+        assert!(check(EndpointCheck::Ip, &[other, nat], Some(observed)));
+        assert!(!check(EndpointCheck::Ip, &[other], Some(observed)));
+        assert!(!check(EndpointCheck::Ip, &[], Some(observed)));
+
+        // Unless the port has to as well.
+        assert!(check(EndpointCheck::Strict, &[observed], Some(observed)));
+        assert!(check(EndpointCheck::Strict, &[mapped], Some(observed)));
+        assert!(!check(EndpointCheck::Strict, &[nat], Some(observed)));
+
+        // Nothing to compare to yet.
+        assert!(check(EndpointCheck::Strict, &[other], None));
+    }
+}
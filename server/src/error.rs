@@ -17,6 +17,20 @@ pub enum ServerError {
     #[error("endpoint gone")]
     Gone,
 
+    #[error("the server is read-only for maintenance, try again later")]
+    ReadOnly,
+
+    #[error("peer limit reached: {0}")]
+    PeerLimit(String),
+
+    /// A CIDR or association that would loop, or nest CIDRs too deep.
+    #[error("{0}")]
+    CidrGraph(String),
+
+    /// A reported endpoint refused by the server's `endpoint-check` policy.
+    #[error("endpoint refused: {0}")]
+    EndpointMismatch(String),
+
     #[error("internal database error")]
     Database(#[from] rusqlite::Error),
 
@@ -36,14 +50,17 @@ pub enum ServerError {
     Hyper(#[from] hyper::Error),
 }
 
-impl<'a> From<&'a ServerError> for StatusCode {
+impl From<&ServerError> for StatusCode {
     fn from(error: &ServerError) -> StatusCode {
         use ServerError::*;
         match error {
             Unauthorized => StatusCode::UNAUTHORIZED,
             NotFound => StatusCode::NOT_FOUND,
             Gone => StatusCode::GONE,
-            InvalidQuery | Json(_) => StatusCode::BAD_REQUEST,
+            ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            PeerLimit(_) => StatusCode::CONFLICT,
+            EndpointMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InvalidQuery | CidrGraph(_) | Json(_) => StatusCode::BAD_REQUEST,
             // Special-case the constraint violation situation.
             Database(rusqlite::Error::SqliteFailure(libsqlite3_sys::Error { code, .. }, ..))
                 if *code == libsqlite3_sys::ErrorCode::ConstraintViolation =>
@@ -62,8 +79,14 @@ impl TryFrom<ServerError> for Response<Body> {
     type Error = http::Error;
 
     fn try_from(e: ServerError) -> Result<Self, Self::Error> {
-        Response::builder()
-            .status(StatusCode::from(&e))
-            .body(Body::empty())
+        // Refusals that aren't the caller's fault say why, so they aren't mistaken for an outage.
+        let body = match e {
+            ServerError::ReadOnly
+            | ServerError::PeerLimit(_)
+            | ServerError::CidrGraph(_)
+            | ServerError::EndpointMismatch(_) => Body::from(e.to_string()),
+            _ => Body::empty(),
+        };
+        Response::builder().status(StatusCode::from(&e)).body(body)
     }
 }
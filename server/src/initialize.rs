@@ -1,14 +1,15 @@
 use crate::*;
 use anyhow::anyhow;
 use clap::Parser;
-use db::DatabaseCidr;
+use db::{cidr::DEFAULT_MAX_CIDR_DEPTH, DatabaseCidr};
 use dialoguer::{theme::ColorfulTheme, Input};
 use indoc::printdoc;
 use ipnet::IpNet;
 use publicip::Preference;
 use rusqlite::{params, Connection};
 use shared::{
-    prompts, CidrContents, Endpoint, IpNetExt, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    prompts, CidrContents, Endpoint, IpNetExt, IpRange, PeerContents,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
 use wireguard_control::KeyPair;
 
@@ -20,6 +21,10 @@ fn create_database<P: AsRef<Path>>(
     conn.execute(db::peer::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::association::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::cidr::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::preshared_key::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::revoked_key::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::usage::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::usage::CREATE_COUNTERS_TABLE_SQL, params![])?;
     conn.pragma_update(None, "user_version", db::CURRENT_VERSION)?;
     log::debug!("set database version to db::CURRENT_VERSION");
 
@@ -47,11 +52,22 @@ pub struct InitializeOpts {
     /// Port to listen on (for the WireGuard interface)
     #[clap(long)]
     pub listen_port: Option<u16>,
+
+    /// This server's IP inside the network (ex: 10.42.0.1). Defaults to the network's first
+    /// usable address outside the allocation range
+    #[clap(long)]
+    pub server_ip: Option<IpAddr>,
+
+    /// Only auto-assign peers in the root CIDR IPs from this part of the network (ex:
+    /// 10.42.1.0-10.42.255.254), which mustn't include the server's IP
+    #[clap(long)]
+    pub allocation_range: Option<IpRange>,
 }
 
 struct DbInitData {
     network_name: String,
     network_cidr: IpNet,
+    allocation_range: Option<IpRange>,
     server_cidr: IpNet,
     our_ip: IpAddr,
     public_key_base64: String,
@@ -67,7 +83,12 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: db_init_data.network_name.clone(),
             cidr: db_init_data.network_cidr,
             parent: None,
+            allocation_range: db_init_data.allocation_range,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         },
+        DEFAULT_MAX_CIDR_DEPTH,
     )
     .map_err(|_| anyhow!("failed to create root CIDR"))?;
 
@@ -77,7 +98,12 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: SERVER_NAME.into(),
             cidr: db_init_data.server_cidr,
             parent: Some(root_cidr.id),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         },
+        DEFAULT_MAX_CIDR_DEPTH,
     )
     .map_err(|_| anyhow!("failed to create innernet-server CIDR"))?;
 
@@ -95,6 +121,15 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
             invite_expires: None,
             candidates: vec![],
+            tags: vec![],
+            preshared_key: None,
+            last_handshake: None,
+            advertised_routes: vec![],
+            is_observer: false,
+            is_staged: false,
+            created_at: None,
+            created_by: None,
+            description: None,
         },
     )
     .map_err(|_| anyhow!("failed to create innernet peer."))?;
@@ -102,6 +137,59 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
     Ok(())
 }
 
+/// The server's IP in `root_cidr`: `pinned` if given, or else the first address that can be
+/// assigned. Either way, it has to stay out of the `allocation_range` meant for peers.
+fn server_ip(
+    root_cidr: &IpNet,
+    pinned: Option<IpAddr>,
+    allocation_range: Option<&IpRange>,
+) -> Result<IpAddr, Error> {
+    if let Some(range) = allocation_range {
+        if !range.is_within(root_cidr) {
+            bail!(
+                "the allocation range {} isn't inside the network CIDR {}.",
+                range,
+                root_cidr
+            );
+        }
+    }
+    let reserved = |ip: &IpAddr| allocation_range.is_some_and(|range| range.contains(ip));
+
+    let Some(ip) = pinned else {
+        return root_cidr
+            .hosts()
+            .find(|ip| root_cidr.is_assignable(ip) && !reserved(ip))
+            .ok_or_else(|| {
+                anyhow!(
+                    "the allocation range leaves no address in {} for the server.",
+                    root_cidr
+                )
+            });
+    };
+    if !root_cidr.contains(&ip) {
+        bail!(
+            "the server IP {} is outside the network CIDR {}.",
+            ip,
+            root_cidr
+        );
+    }
+    if !root_cidr.is_assignable(&ip) {
+        bail!(
+            "the server IP {} is the network or broadcast address of {}, which can't be assigned.",
+            ip,
+            root_cidr
+        );
+    }
+    if reserved(&ip) {
+        bail!(
+            "the server IP {} overlaps the allocation range {} reserved for peers.",
+            ip,
+            allocation_range.unwrap()
+        );
+    }
+    Ok(ip)
+}
+
 pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Error> {
     let theme = ColorfulTheme::default();
 
@@ -142,6 +230,8 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
             .with_initial_text("10.42.0.0/16")
             .interact()?
     };
+    // Checked right away, so a bad address doesn't waste the rest of the prompts.
+    let our_ip = server_ip(&root_cidr, opts.server_ip, opts.allocation_range.as_ref())?;
 
     let listen_port: u16 = if let Some(listen_port) = opts.listen_port {
         listen_port
@@ -165,10 +255,6 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
         prompts::ask_endpoint(listen_port)?
     };
 
-    let our_ip = root_cidr
-        .hosts()
-        .find(|ip| root_cidr.is_assignable(ip))
-        .unwrap();
     let config_path = conf.config_path(&name);
     let our_keypair = KeyPair::generate();
 
@@ -177,12 +263,23 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
         listen_port,
         address: our_ip,
         network_cidr_prefix: root_cidr.prefix_len(),
+        preshared_keys: true,
+        persistent_keepalive: None,
+        post_install: None,
+        api_bind_addr: vec![],
+        max_peers: None,
+        peer_limit_policy: LimitPolicy::All,
+        max_cidr_depth: None,
+        ip_allocation: IpAllocation::LowestFree,
+        endpoint_check: EndpointCheck::Off,
+        usage_sample_interval: None,
     };
     config.write_to_path(config_path)?;
 
     let db_init_data = DbInitData {
         network_name: name.to_string(),
         network_cidr: root_cidr,
+        allocation_range: opts.allocation_range,
         server_cidr: IpNet::new(our_ip, root_cidr.max_prefix_len())?,
         our_ip,
         public_key_base64: our_keypair.public.to_base64(),
@@ -237,3 +334,33 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_ip() -> Result<(), Error> {
+        let cidr: IpNet = "10.42.0.0/16".parse()?;
+        let range: IpRange = "10.42.0.1-10.42.0.100"
+            .parse()
+            .map_err(|e: &str| anyhow!(e))?;
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(server_ip(&cidr, None, None)?, ip("10.42.0.1"));
+        assert_eq!(server_ip(&cidr, None, Some(&range))?, ip("10.42.0.101"));
+        assert_eq!(
+            server_ip(&cidr, Some(ip("10.42.255.1")), Some(&range))?,
+            ip("10.42.255.1")
+        );
+
+        for pinned in ["10.43.0.1", "10.42.0.0", "10.42.255.255", "10.42.0.50"] {
+            assert!(server_ip(&cidr, Some(ip(pinned)), Some(&range)).is_err());
+        }
+        let outside: IpRange = "10.42.0.1-10.43.0.1"
+            .parse()
+            .map_err(|e: &str| anyhow!(e))?;
+        assert!(server_ip(&cidr, None, Some(&outside)).is_err());
+        Ok(())
+    }
+}
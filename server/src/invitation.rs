@@ -0,0 +1,244 @@
+//! Creating peers and their invitations without the command line, for tools that manage an
+//! innernet network themselves.
+//!
+//! Nothing here prompts, prints, or writes files. Callers get the new peer and its
+//! invitation back, and decide what to do with them (ex. `InterfaceConfig::write_to_path`).
+//! Bringing the new peer up on a running server's WireGuard interface is also left to them.
+
+use crate::{
+    db::{peer::SERVER_PEER_ID, DatabaseCidr, DatabasePeer},
+    PeerLimits,
+};
+use anyhow::anyhow;
+use rusqlite::Connection;
+use shared::{
+    allocation::IpAllocation, interface_config::InterfaceConfig, CidrTree, Error, Hostname,
+    IpRange, Peer, PeerContents, Tag, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+use wireguard_control::{InterfaceName, KeyPair};
+
+/// How long an invitation can be redeemed for, unless told otherwise.
+pub const DEFAULT_INVITE_EXPIRATION: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// The network an invitation is for.
+pub struct InvitationServer<'a> {
+    pub interface: &'a InterfaceName,
+    /// The server's API address inside the network.
+    pub api_addr: SocketAddr,
+    /// The network's default keepalive interval, for peers without their own.
+    pub persistent_keepalive: Option<u16>,
+    /// The network's post-install steps, passed along in every invitation.
+    pub post_install: Option<&'a str>,
+    pub peer_limits: PeerLimits,
+    /// How the network picks new peers' IPs, unless an invitation says otherwise.
+    pub ip_allocation: IpAllocation,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerInvitationOpts {
+    /// The IP to give the peer. Without one, a free IP in `allocation_range` (or the CIDR's
+    /// own allocation range) is picked with `ip_allocation`, or the network's strategy.
+    pub ip: Option<IpAddr>,
+    pub allocation_range: Option<IpRange>,
+    pub ip_allocation: Option<IpAllocation>,
+    pub is_admin: bool,
+    pub is_observer: bool,
+    /// Keep the peer out of the network until it's activated, see `PeerContents::is_staged`.
+    pub is_staged: bool,
+    pub tags: Vec<Tag>,
+    pub invite_expires: Duration,
+}
+
+impl Default for PeerInvitationOpts {
+    fn default() -> Self {
+        Self {
+            ip: None,
+            allocation_range: None,
+            ip_allocation: None,
+            is_admin: false,
+            is_observer: false,
+            is_staged: false,
+            tags: vec![],
+            invite_expires: DEFAULT_INVITE_EXPIRATION,
+        }
+    }
+}
+
+/// Create a peer named `name` in the CIDR named `cidr`, returning it along with the
+/// invitation it needs to join the network.
+pub fn create_peer_invitation(
+    conn: &mut Connection,
+    server: &InvitationServer,
+    cidr: &str,
+    name: Hostname,
+    opts: PeerInvitationOpts,
+) -> Result<(Peer, InterfaceConfig), Error> {
+    let cidr = DatabaseCidr::list(conn)?
+        .into_iter()
+        .find(|candidate| candidate.name == cidr)
+        .ok_or_else(|| anyhow!("no CIDR named {} exists.", cidr))?;
+    let ip = match opts.ip {
+        Some(ip) => ip,
+        None => {
+            let taken = DatabasePeer::list(conn)?
+                .iter()
+                .map(|peer| peer.ip)
+                .collect::<Vec<_>>();
+            let range = opts.allocation_range.or(cidr.allocation_range);
+            opts.ip_allocation
+                .unwrap_or(server.ip_allocation)
+                .allocate(&name, &cidr.cidr, range.as_ref(), &taken)?
+        },
+    };
+
+    let keypair = KeyPair::generate();
+    let mut tags = opts.tags;
+    tags.sort();
+    tags.dedup();
+    let contents = PeerContents {
+        name,
+        ip,
+        cidr_id: cidr.id,
+        public_key: keypair.public.to_base64(),
+        endpoint: None,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        is_admin: opts.is_admin,
+        is_disabled: opts.is_staged,
+        is_redeemed: false,
+        invite_expires: Some(SystemTime::now() + opts.invite_expires),
+        candidates: vec![],
+        tags,
+        preshared_key: None,
+        last_handshake: None,
+        advertised_routes: vec![],
+        is_observer: opts.is_observer,
+        is_staged: opts.is_staged,
+        created_at: None,
+        created_by: None,
+        description: None,
+    };
+    insert_peer_invitation(conn, server, contents, &keypair)
+}
+
+/// Create a peer whose contents and keys were already decided on (ex. by the add-peer
+/// prompts), returning it along with its invitation. Unless the contents say otherwise, the
+/// peer is recorded as created by the server's own peer.
+///
+/// Everything happens in one transaction, so if any step fails, no peer is left behind.
+pub fn insert_peer_invitation(
+    conn: &mut Connection,
+    server: &InvitationServer,
+    contents: PeerContents,
+    keypair: &KeyPair,
+) -> Result<(Peer, InterfaceConfig), Error> {
+    let tx = conn.transaction()?;
+    let server_peer = DatabasePeer::get(&tx, SERVER_PEER_ID)?;
+    let contents = PeerContents {
+        created_by: contents
+            .created_by
+            .or_else(|| Some(server_peer.name.to_string())),
+        ..contents
+    };
+    server.peer_limits.check_new(&tx, &contents)?;
+    let mut peer = DatabasePeer::create(&tx, contents)?;
+    peer.with_default_keepalive(server.persistent_keepalive);
+    let cidrs = DatabaseCidr::list(&tx)?;
+    let mut invitation = InterfaceConfig::invitation(
+        server.interface,
+        &peer,
+        &server_peer,
+        &CidrTree::new(&cidrs[..]),
+        keypair,
+        &server.api_addr,
+    )?;
+    invitation.post_install = server.post_install.map(str::to_string);
+    tx.commit()?;
+    Ok((peer.inner, invitation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use rusqlite::params;
+
+    fn server_info(interface: &InterfaceName) -> InvitationServer<'_> {
+        InvitationServer {
+            interface,
+            api_addr: SocketAddr::new(test::WG_MANAGE_PEER_IP.parse().unwrap(), 51820),
+            persistent_keepalive: None,
+            post_install: None,
+            peer_limits: PeerLimits::default(),
+            ip_allocation: IpAllocation::LowestFree,
+        }
+    }
+
+    #[test]
+    fn test_create_peer_invitation() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let interface: InterfaceName = "test".parse()?;
+        let db = server.db();
+        let mut conn = db.lock();
+
+        let (peer, invitation) = create_peer_invitation(
+            &mut conn,
+            &server_info(&interface),
+            "user",
+            "user3".parse().unwrap(),
+            PeerInvitationOpts::default(),
+        )?;
+        assert_eq!(
+            DatabasePeer::get(&conn, peer.id)?.public_key,
+            peer.public_key
+        );
+        assert_eq!(invitation.interface.address.addr(), peer.ip);
+        let private_key = wireguard_control::Key::from_base64(&invitation.interface.private_key)?;
+        assert_eq!(private_key.get_public().to_base64(), peer.public_key);
+        // The preshared key for the server link only comes once the client opts in.
+        assert!(invitation.server.preshared_key.is_none());
+        assert_eq!(
+            peer.created_by,
+            Some(DatabasePeer::get(&conn, SERVER_PEER_ID)?.name.to_string())
+        );
+
+        assert!(create_peer_invitation(
+            &mut conn,
+            &server_info(&interface),
+            "nonexistent",
+            "user4".parse().unwrap(),
+            PeerInvitationOpts::default(),
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_invitation_leaves_no_peer() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let interface: InterfaceName = "test".parse()?;
+        let db = server.db();
+        let mut conn = db.lock();
+
+        // Without a server endpoint, no invitation can be made, but only after the peer
+        // has been inserted.
+        conn.execute(
+            "UPDATE peers SET endpoint = NULL WHERE id = ?1",
+            params![SERVER_PEER_ID],
+        )?;
+        let before = DatabasePeer::list(&conn)?.len();
+        assert!(create_peer_invitation(
+            &mut conn,
+            &server_info(&interface),
+            "user",
+            "user3".parse().unwrap(),
+            PeerInvitationOpts::default(),
+        )
+        .is_err());
+        assert_eq!(DatabasePeer::list(&conn)?.len(), before);
+        Ok(())
+    }
+}
@@ -0,0 +1,2940 @@
+//! The innernet server: its HTTP API, its database, and the `innernet-server` command line.
+//!
+//! Tools that manage a network themselves can use [`invitation`] to create peers and their
+//! invitations without going through the command line.
+
+use anyhow::{anyhow, bail};
+use clap::{Args, Parser, Subcommand};
+use colored::*;
+use dialoguer::Confirm;
+use hyper::{http, server::conn::AddrStream, Body, Method, Request, Response};
+use indoc::printdoc;
+use ipnet::IpNet;
+use parking_lot::{Mutex, RwLock};
+use publicip::Preference;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use shared::{
+    allocation::IpAllocation, get_local_addrs, AddCidrOpts, AddPeerOpts, DeleteCidrOpts, Endpoint,
+    IoErrorContext, NetworkOpts, Peer, PeerContents, RekeyPeerOpts, RenameCidrOpts, RenamePeerOpts,
+    RotateAdminOpts, TagPeerOpts, Timestring, INNERNET_PUBKEY_HEADER,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    env,
+    fs::File,
+    io::prelude::*,
+    net::{IpAddr, SocketAddr, TcpListener},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use subtle::ConstantTimeEq;
+use wireguard_control::{
+    Backend, Device, DeviceUpdate, InterfaceName, Key, KeyPair, PeerConfigBuilder, PeerInfo,
+};
+
+pub mod api;
+mod control;
+pub mod db;
+mod endpoint_check;
+pub mod error;
+#[cfg(test)]
+mod test;
+pub mod util;
+
+mod dry_run;
+mod initialize;
+pub mod invitation;
+mod limits;
+mod network_dump;
+pub mod request_log;
+mod topology;
+mod tui;
+
+use db::{
+    cidr::DEFAULT_MAX_CIDR_DEPTH,
+    peer::SERVER_PEER_ID,
+    usage::{self, Transfer},
+    DatabaseAssociation, DatabaseCidr, DatabasePeer, DatabaseUsage,
+};
+pub use endpoint_check::EndpointCheck;
+pub use error::ServerError;
+use initialize::InitializeOpts;
+pub use limits::{LimitPolicy, PeerLimits};
+use network_dump::NetworkDump;
+use request_log::{RequestLog, RequestLogOpts};
+use shared::{prompts, reachability, wg, CidrTree, Error, Hostname, Interface};
+pub use shared::{Association, AssociationContents};
+use topology::Topology;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How long to wait before trying again to bind an API address that isn't assigned yet.
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long peer-reported endpoint candidates are kept without the peer refreshing them.
+const CANDIDATE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Parser)]
+#[command(name = "innernet-server", author, version, about)]
+struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+
+    #[clap(short, long, default_value = "/etc/innernet-server")]
+    config_dir: PathBuf,
+
+    #[clap(short, long, default_value = "/var/lib/innernet-server")]
+    data_dir: PathBuf,
+
+    #[clap(flatten)]
+    network: NetworkOpts,
+
+    /// Show what a command would change, without touching the database or the WireGuard
+    /// interface
+    #[clap(long, global = true)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct DetectEndpointOpts {
+    /// Detect the server's public IP at startup and warn if the configured external
+    /// endpoint doesn't match it. An unset endpoint is filled in with the detected one
+    #[clap(long)]
+    detect_endpoint: bool,
+
+    /// Detect the public IP with this STUN server (ex. 'stun.l.google.com:19302')
+    /// instead of Cloudflare's DNS
+    #[clap(long, requires = "detect_endpoint")]
+    stun_server: Option<String>,
+
+    /// Replace the configured external endpoint with the detected one when they differ
+    #[clap(long, requires = "detect_endpoint")]
+    update_endpoint: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ServeOpts {
+    /// Restrict the data directory and database to their owner if other users can
+    /// read them, instead of only warning
+    #[clap(long)]
+    fix_permissions: bool,
+
+    /// Also serve a JSON-RPC control interface on this Unix socket, which only its
+    /// owner can use. Off by default
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Start in read-only mode, where peers keep getting the network's state but every
+    /// change is refused. Admins can turn it off (or back on) through the API
+    #[clap(long)]
+    read_only: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the configuration in effect, after the environment and CIDR defaults are
+    /// applied. Secrets are redacted.
+    Show {
+        interface: Interface,
+
+        /// Print JSON instead of TOML
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a new network.
+    #[clap(alias = "init")]
+    New {
+        #[clap(flatten)]
+        opts: InitializeOpts,
+    },
+
+    /// Permanently uninstall a created network, rendering it unusable. Use with care.
+    Uninstall {
+        interface: Interface,
+
+        /// Bypass confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+
+    /// Serve the coordinating server for an existing network.
+    Serve {
+        interface: Interface,
+
+        #[clap(flatten)]
+        network: NetworkOpts,
+
+        #[clap(flatten)]
+        detect_endpoint: DetectEndpointOpts,
+
+        #[clap(flatten)]
+        request_log: RequestLogOpts,
+
+        #[clap(flatten)]
+        serve: ServeOpts,
+    },
+
+    /// Add a peer to an existing network.
+    AddPeer {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: AddPeerOpts,
+    },
+
+    /// Disable an enabled peer
+    DisablePeer {
+        interface: Interface,
+
+        /// Move the disabled peer's advertised routes to this peer in the same step, to hand
+        /// them over to a replacement (ex. a new site gateway) without dropping traffic
+        #[clap(long, value_name = "PEER")]
+        reassign_routes_to: Option<Hostname>,
+    },
+
+    /// Enable a disabled peer
+    EnablePeer { interface: Interface },
+
+    /// Activate a peer that was added with --staged, bringing it into the network.
+    Activate {
+        interface: Interface,
+
+        /// Name of the staged peer
+        peer: Hostname,
+    },
+
+    /// Rename an existing peer.
+    RenamePeer {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: RenamePeerOpts,
+    },
+
+    /// Add or remove tags on an existing peer.
+    TagPeer {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: TagPeerOpts,
+    },
+
+    /// Replace an admin peer's key with a new invitation, revoking the old key.
+    ///
+    /// Use this when an admin's device is lost. The old key is blocked for good and the
+    /// admin has to redeem the new invitation.
+    RotateAdmin {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: RotateAdminOpts,
+    },
+
+    /// Replace any peer's key with a new invitation, revoking the old key.
+    ///
+    /// The peer keeps its name, IP, and everything else, and other peers switch to the new
+    /// key on their next fetch. The peer has to uninstall the network and install the new
+    /// invitation, which generates its new keypair as usual.
+    RekeyPeer {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: RekeyPeerOpts,
+    },
+
+    /// List invitations that haven't been redeemed yet, and whether their key was ever
+    /// used to connect.
+    ListInvitations {
+        interface: Interface,
+
+        /// Print the invitations as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Show how many bytes were transferred with each peer, as sampled by the running
+    /// server (see `usage-sample-interval`).
+    Usage {
+        interface: Interface,
+
+        /// Only count transfer from this long ago onward (ex. "7d"), to the hour
+        #[clap(long)]
+        since: Option<Timestring>,
+
+        /// Print the usage as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Delete a peer whose invitation hasn't been redeemed, so the invitation can't be used.
+    RevokeInvitation {
+        interface: Interface,
+
+        /// Name of the invited peer
+        peer: Hostname,
+
+        /// Bypass confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+
+    /// Browse and manage the network's peers in an interactive terminal UI.
+    Tui { interface: Interface },
+
+    /// Add a new CIDR to an existing network.
+    AddCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: AddCidrOpts,
+    },
+
+    /// Rename an existing CIDR.
+    RenameCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: RenameCidrOpts,
+    },
+
+    /// Delete a CIDR.
+    DeleteCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: DeleteCidrOpts,
+    },
+
+    /// Report a CIDR's address allocation, optionally compacting its peers into the lowest
+    /// available addresses.
+    Defragment {
+        interface: Interface,
+
+        /// Name of the CIDR to defragment
+        cidr: Option<String>,
+
+        /// Rewrite peer IPs so they occupy the lowest available addresses
+        #[clap(long)]
+        compact: bool,
+
+        /// Bypass confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+
+    /// Check whether two peers can reach each other, and which association allows it.
+    CanReach {
+        interface: Interface,
+
+        /// Name of the first peer
+        peer_a: Hostname,
+
+        /// Name of the second peer
+        peer_b: Hostname,
+    },
+
+    /// List every IP and route a peer can reach, which peer serves each, and why any of them
+    /// don't work right now.
+    Routes {
+        interface: Interface,
+
+        /// Name of the peer
+        peer: Hostname,
+    },
+
+    /// Save all of a network's CIDRs, peers, and associations to a single file.
+    ///
+    /// Together with the network's config file, the dump is enough to move the network to
+    /// another host without existing clients noticing.
+    ExportNetwork {
+        interface: Interface,
+
+        /// Where to write the dump
+        file: PathBuf,
+    },
+
+    /// Recreate the CIDRs, peers, and associations from an exported network dump.
+    ImportNetwork {
+        interface: Interface,
+
+        /// The dump to import
+        file: PathBuf,
+
+        /// Import into a network that already has peers, skipping whatever exists already
+        #[clap(long)]
+        merge: bool,
+    },
+
+    /// Create the CIDRs and associations described in a topology file that the network is
+    /// missing. Applying an unchanged file again changes nothing.
+    Apply {
+        interface: Interface,
+
+        /// The topology file (TOML)
+        file: PathBuf,
+
+        /// Also delete the CIDRs and associations the file doesn't mention. CIDRs that still
+        /// have peers are never deleted
+        #[clap(long)]
+        prune: bool,
+    },
+
+    /// Inspect a network's configuration.
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Generate shell completion scripts
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+pub type Db = Arc<Mutex<Connection>>;
+pub type Endpoints = Arc<RwLock<HashMap<String, SocketAddr>>>;
+pub type Handshakes = Arc<RwLock<HashMap<String, SystemTime>>>;
+
+#[derive(Clone)]
+pub struct Context {
+    pub db: Db,
+    pub endpoints: Arc<RwLock<HashMap<String, SocketAddr>>>,
+    pub handshakes: Handshakes,
+    pub interface: InterfaceName,
+    pub backend: Backend,
+    pub public_key: Key,
+    pub preshared_keys: bool,
+    /// The network's default keepalive interval, for peers without their own.
+    pub persistent_keepalive: Option<u16>,
+    pub peer_limits: PeerLimits,
+    /// How deep CIDRs may be nested, counting the root network as 1.
+    pub max_cidr_depth: u8,
+    /// How new peers' IPs are picked, passed along to admins.
+    pub ip_allocation: IpAllocation,
+    pub endpoint_check: EndpointCheck,
+    pub request_log: Arc<RequestLog>,
+    pub read_only: Arc<ReadOnly>,
+}
+
+/// Whether the server is in read-only mode. It's mirrored to a marker file (see
+/// [`ServerConfig::check_writable`]), so that the CLI refuses changes too while it is.
+#[derive(Debug, Default)]
+pub struct ReadOnly {
+    enabled: AtomicBool,
+    marker: Option<PathBuf>,
+}
+
+impl ReadOnly {
+    pub fn new(enabled: bool, marker: Option<PathBuf>) -> Result<Self, std::io::Error> {
+        let read_only = Self {
+            enabled: AtomicBool::new(enabled),
+            marker,
+        };
+        read_only.write_marker(enabled)?;
+        Ok(read_only)
+    }
+
+    pub fn get(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn read-only mode on or off, returning whether it was on.
+    pub fn set(&self, enabled: bool) -> Result<bool, std::io::Error> {
+        self.write_marker(enabled)?;
+        Ok(self.enabled.swap(enabled, Ordering::Relaxed))
+    }
+
+    fn write_marker(&self, enabled: bool) -> Result<(), std::io::Error> {
+        let Some(path) = &self.marker else {
+            return Ok(());
+        };
+        if enabled {
+            File::create(path)?;
+        } else if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Context {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.get()
+    }
+
+    /// Refuse a request with `method` if it could change anything while the server is
+    /// read-only.
+    pub fn check_writable(&self, method: &Method) -> Result<(), ServerError> {
+        if self.is_read_only() && !matches!(*method, Method::GET | Method::HEAD) {
+            return Err(ServerError::ReadOnly);
+        }
+        Ok(())
+    }
+}
+
+pub struct Session {
+    pub context: Context,
+    pub peer: DatabasePeer,
+}
+
+impl Session {
+    pub fn admin_capable(&self) -> bool {
+        self.peer.is_admin && self.user_capable()
+    }
+
+    pub fn user_capable(&self) -> bool {
+        !self.peer.is_disabled && self.peer.is_redeemed
+    }
+
+    pub fn redeemable(&self) -> bool {
+        !self.peer.is_disabled && !self.peer.is_redeemed
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// The server's WireGuard key
+    pub private_key: String,
+
+    /// The listen port of the server
+    pub listen_port: u16,
+
+    /// The internal WireGuard IP address assigned to the server
+    pub address: IpAddr,
+
+    /// The CIDR prefix of the WireGuard network
+    pub network_cidr_prefix: u8,
+
+    /// Whether every link in the network uses a WireGuard preshared key. Networks created
+    /// before preshared keys were supported don't have this set, and keep working without.
+    #[serde(default)]
+    pub preshared_keys: bool,
+
+    /// The keepalive interval (in seconds) for peers that don't set their own. Falls back to
+    /// innernet's built-in default if unset, and 0 disables keepalive network-wide.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<u16>,
+
+    /// Steps printed to whoever installs one of this network's invitations, once they're
+    /// done (ex. adding a route). One step per line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_install: Option<String>,
+
+    /// The local addresses to serve the API on (ex. `["10.42.0.1:51820", "10.42.0.1:8080"]`).
+    /// Defaults to the server's WireGuard address and listen port. Invitations always point
+    /// peers at the WireGuard address, whatever is set here. Requests are authenticated by
+    /// the WireGuard address they come from, so every address must be inside the network.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_bind_addr: Vec<SocketAddr>,
+
+    /// The most peers the network may hold, including the server. CIDRs can set their own
+    /// limit instead (`add-cidr --max-peers`). Unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_peers: Option<u32>,
+
+    /// Which peers count toward `max-peers` and CIDRs' limits: `all` (the default), or only
+    /// `enabled` ones.
+    #[serde(default)]
+    pub peer_limit_policy: LimitPolicy,
+
+    /// How deep CIDRs may be nested, counting the root network as 1. Defaults to 16.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cidr_depth: Option<u8>,
+
+    /// How new peers' IPs are picked when they aren't given one: `lowest-free` (the
+    /// default), or `name-hash` to derive them from the peers' names.
+    #[serde(default)]
+    pub ip_allocation: IpAllocation,
+
+    /// Whether endpoints peers report for themselves must match the address WireGuard sees
+    /// their traffic coming from: `off` (the default), `warn` to only log mismatches, `ip`
+    /// to refuse endpoints with a different IP, or `strict` to also refuse a different port.
+    #[serde(default)]
+    pub endpoint_check: EndpointCheck,
+
+    /// How often (in seconds) to add what WireGuard counted for each peer to their usage in
+    /// the database, for `innernet-server usage`. Usage isn't recorded if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage_sample_interval: Option<u64>,
+}
+
+impl ConfigFile {
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut invitation_file = File::create(&path).with_path(&path)?;
+        shared::chmod(&invitation_file, 0o600)?;
+        invitation_file
+            .write_all(toml::to_string(self).unwrap().as_bytes())
+            .with_path(path)?;
+        Ok(())
+    }
+
+    /// How deep CIDRs may be nested, see [`ConfigFile::max_cidr_depth`].
+    pub fn max_cidr_depth(&self) -> u8 {
+        self.max_cidr_depth.unwrap_or(DEFAULT_MAX_CIDR_DEPTH)
+    }
+
+    pub fn peer_limits(&self) -> PeerLimits {
+        PeerLimits {
+            max_peers: self.max_peers,
+            policy: self.peer_limit_policy,
+        }
+    }
+
+    /// The addresses the API listens on, see [`ConfigFile::api_bind_addr`].
+    pub fn api_bind_addrs(&self) -> Vec<SocketAddr> {
+        if self.api_bind_addr.is_empty() {
+            vec![SocketAddr::new(self.address, self.listen_port)]
+        } else {
+            self.api_bind_addr.clone()
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::open(path).with_path(path)?;
+        if shared::chmod(&file, 0o600)? {
+            println!(
+                "{} updated permissions for {} to 0600.",
+                "[!]".yellow(),
+                path.display()
+            );
+        }
+        let config: Self = toml::from_str(&std::fs::read_to_string(path).with_path(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Refuse settings that parse but couldn't work, so they fail at startup rather than
+    /// on every request.
+    fn validate(&self) -> Result<(), Error> {
+        let network = IpNet::new(self.address, self.network_cidr_prefix)?;
+        // Requests from outside the network have no peer to authenticate as.
+        if let Some(addr) = self
+            .api_bind_addr
+            .iter()
+            .find(|addr| !network.contains(&addr.ip()))
+        {
+            bail!(
+                "api-bind-addr {} is outside the network {}, so no request to it could be authenticated.",
+                addr,
+                network.trunc()
+            );
+        }
+        Ok(())
+    }
+
+    /// Read the config file at `path`, or if there isn't one, build the config from the
+    /// `INNERNET_SERVER_*` environment variables (see [`ConfigFile::from_env`]). The config
+    /// file always wins when both exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            if let Some(config) = Self::from_env(|name| env::var(name).ok())? {
+                log::debug!(
+                    "no config file at {}, using the environment.",
+                    path.display()
+                );
+                return Ok(config);
+            }
+        }
+        Self::from_file(path)
+    }
+
+    /// Build a config from environment variables, looked up with `var`. `None` if none of
+    /// them are set.
+    ///
+    /// `INNERNET_SERVER_PRIVATE_KEY` and `INNERNET_SERVER_NETWORK_CIDR` (the server's address
+    /// with the network's prefix, ex. `10.42.0.1/16`) are required.
+    /// `INNERNET_SERVER_LISTEN_PORT` defaults to 51820, and `INNERNET_SERVER_PRESHARED_KEYS`
+    /// to `true`, like a network created with `innernet-server new`. Any of them can instead
+    /// be given as `<NAME>_FILE`, the path of a file holding the value.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, Error> {
+        let private_key = env_value(&var, ENV_PRIVATE_KEY)?;
+        let network_cidr = env_value(&var, ENV_NETWORK_CIDR)?;
+        let listen_port = env_value(&var, ENV_LISTEN_PORT)?;
+        let preshared_keys = env_value(&var, ENV_PRESHARED_KEYS)?;
+        if private_key.is_none()
+            && network_cidr.is_none()
+            && listen_port.is_none()
+            && preshared_keys.is_none()
+        {
+            return Ok(None);
+        }
+
+        let private_key = private_key.ok_or_else(|| anyhow!("{} isn't set.", ENV_PRIVATE_KEY))?;
+        Key::from_base64(&private_key)
+            .map_err(|_| anyhow!("{} isn't a valid private key.", ENV_PRIVATE_KEY))?;
+        let network_cidr: IpNet = network_cidr
+            .ok_or_else(|| anyhow!("{} isn't set.", ENV_NETWORK_CIDR))?
+            .parse()
+            .map_err(|e| anyhow!("{}: {}", ENV_NETWORK_CIDR, e))?;
+        let listen_port = listen_port
+            .map(|port| port.parse())
+            .transpose()
+            .map_err(|e| anyhow!("{}: {}", ENV_LISTEN_PORT, e))?
+            .unwrap_or(shared::DEFAULT_ENDPOINT_PORT);
+        let preshared_keys = preshared_keys
+            .map(|enabled| enabled.parse())
+            .transpose()
+            .map_err(|e| anyhow!("{}: {}", ENV_PRESHARED_KEYS, e))?
+            .unwrap_or(true);
+
+        Ok(Some(Self {
+            private_key,
+            listen_port,
+            address: network_cidr.addr(),
+            network_cidr_prefix: network_cidr.prefix_len(),
+            preshared_keys,
+            persistent_keepalive: None,
+            post_install: None,
+            api_bind_addr: vec![],
+            max_peers: None,
+            peer_limit_policy: LimitPolicy::All,
+            max_cidr_depth: None,
+            ip_allocation: IpAllocation::LowestFree,
+            endpoint_check: EndpointCheck::Off,
+            usage_sample_interval: None,
+        }))
+    }
+}
+
+const ENV_PRIVATE_KEY: &str = "INNERNET_SERVER_PRIVATE_KEY";
+const ENV_NETWORK_CIDR: &str = "INNERNET_SERVER_NETWORK_CIDR";
+const ENV_LISTEN_PORT: &str = "INNERNET_SERVER_LISTEN_PORT";
+const ENV_PRESHARED_KEYS: &str = "INNERNET_SERVER_PRESHARED_KEYS";
+const ENV_DATABASE_PATH: &str = "INNERNET_SERVER_DATABASE_PATH";
+
+/// The value of the environment variable `name`, or the contents of the file named by
+/// `<name>_FILE` (Docker secrets style), with any trailing newline removed.
+fn env_value(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<String>, Error> {
+    let file_name = format!("{name}_FILE");
+    match (var(name), var(&file_name)) {
+        (Some(_), Some(_)) => bail!("only one of {} and {} can be set.", name, file_name),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(path)) => Ok(Some(
+            std::fs::read_to_string(&path)
+                .with_path(&path)?
+                .trim_end()
+                .to_string(),
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+
+    /// Run commands against a scratch copy of the database, and leave the WireGuard
+    /// interface alone.
+    pub dry_run: bool,
+}
+
+impl ServerConfig {
+    pub fn new(config_dir: PathBuf, data_dir: PathBuf, dry_run: bool) -> Self {
+        Self {
+            config_dir,
+            data_dir,
+            dry_run,
+        }
+    }
+
+    fn database_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// The interface's database, which `INNERNET_SERVER_DATABASE_PATH` (or `_FILE`) can move
+    /// when the interface has no config file.
+    fn database_path(&self, interface: &InterfaceName) -> PathBuf {
+        if !self.config_path(interface).exists() {
+            match env_value(|name| env::var(name).ok(), ENV_DATABASE_PATH) {
+                Ok(Some(path)) => return path.into(),
+                Ok(None) => {},
+                Err(e) => log::warn!("ignoring {}: {}", ENV_DATABASE_PATH, e),
+            }
+        }
+        PathBuf::new()
+            .join(self.database_dir())
+            .join(interface.to_string())
+            .with_extension("db")
+    }
+
+    fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    fn config_path(&self, interface: &InterfaceName) -> PathBuf {
+        PathBuf::new()
+            .join(self.config_dir())
+            .join(interface.to_string())
+            .with_extension("conf")
+    }
+
+    /// The file that's there while the interface's server is read-only.
+    fn read_only_path(&self, interface: &InterfaceName) -> PathBuf {
+        self.data_dir
+            .join(interface.to_string())
+            .with_extension("read-only")
+    }
+
+    /// Refuse to change the interface's network from the CLI while its server is read-only.
+    pub fn check_writable(&self, interface: &InterfaceName) -> Result<(), Error> {
+        let path = self.read_only_path(interface);
+        if path.exists() {
+            bail!(
+                "{}'s server is read-only for maintenance. Turn it off through the API, or if \
+                 the server isn't running, remove {}.",
+                interface,
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Run the `innernet-server` command line.
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    if env::var_os("RUST_LOG").is_none() {
+        // Set some default log settings.
+        env::set_var("RUST_LOG", "warn,warp=info,wg_manage_server=info");
+    }
+
+    pretty_env_logger::init();
+    let opts = Opts::parse();
+
+    if unsafe { libc::getuid() } != 0 && !matches!(opts.command, Command::Completions { .. }) {
+        return Err("innernet-server must run as root.".into());
+    }
+
+    let conf = ServerConfig::new(opts.config_dir, opts.data_dir, opts.dry_run);
+    if let Some(interface) = opts.command.changed_interface() {
+        if !conf.dry_run {
+            conf.check_writable(interface)?;
+        }
+    }
+
+    let dry_run = if conf.dry_run {
+        match opts.command.mutated_interface() {
+            Some(interface) => Some(dry_run::DryRun::start(&conf, interface)?),
+            None => return Err("this command doesn't support --dry-run.".into()),
+        }
+    } else {
+        None
+    };
+
+    match opts.command {
+        Command::New { opts } => {
+            if let Err(e) = initialize::init_wizard(&conf, opts) {
+                eprintln!("{}: {}.", "creation failed".red(), e);
+                std::process::exit(1);
+            }
+        },
+        Command::Uninstall { interface, yes } => uninstall(&interface, &conf, opts.network, yes)?,
+        Command::Serve {
+            interface,
+            network: routing,
+            detect_endpoint,
+            request_log,
+            serve: serve_opts,
+        } => {
+            serve(
+                *interface,
+                &conf,
+                routing,
+                detect_endpoint,
+                request_log,
+                serve_opts,
+            )
+            .await?
+        },
+        Command::AddPeer { interface, args } => add_peer(&interface, &conf, args, opts.network)?,
+        Command::RenamePeer { interface, args } => rename_peer(&interface, &conf, args)?,
+        Command::TagPeer { interface, args } => tag_peer(&interface, &conf, args)?,
+        Command::RotateAdmin { interface, args } => {
+            rotate_admin(&interface, &conf, args, opts.network)?
+        },
+        Command::RekeyPeer { interface, args } => {
+            rekey_peer(&interface, &conf, args, opts.network)?
+        },
+        Command::DisablePeer {
+            interface,
+            reassign_routes_to,
+        } => match reassign_routes_to {
+            Some(successor) => {
+                disable_peer_reassigning_routes(&interface, &conf, &successor, opts.network)?
+            },
+            None => enable_or_disable_peer(&interface, &conf, false, opts.network)?,
+        },
+        Command::Activate { interface, peer } => {
+            activate_peer(&interface, &conf, &peer, opts.network)?
+        },
+        Command::EnablePeer { interface } => {
+            enable_or_disable_peer(&interface, &conf, true, opts.network)?
+        },
+        Command::ListInvitations { interface, json } => {
+            list_invitations(&interface, &conf, json, opts.network)?
+        },
+        Command::Usage {
+            interface,
+            since,
+            json,
+        } => usage(&interface, &conf, since, json)?,
+        Command::RevokeInvitation {
+            interface,
+            peer,
+            yes,
+        } => revoke_invitation(&interface, &conf, &peer, yes, opts.network)?,
+        Command::Tui { interface } => tui::run(&interface, &conf, opts.network)?,
+        Command::AddCidr { interface, args } => add_cidr(&interface, &conf, args)?,
+        Command::RenameCidr { interface, args } => rename_cidr(&interface, &conf, args)?,
+        Command::DeleteCidr { interface, args } => delete_cidr(&interface, &conf, args)?,
+        Command::Defragment {
+            interface,
+            cidr,
+            compact,
+            yes,
+        } => defragment(&interface, &conf, cidr, compact, yes, opts.network)?,
+        Command::CanReach {
+            interface,
+            peer_a,
+            peer_b,
+        } => can_reach(&interface, &conf, &peer_a, &peer_b)?,
+        Command::Routes { interface, peer } => peer_routes(&interface, &conf, &peer)?,
+        Command::ExportNetwork { interface, file } => export_network(&interface, &conf, &file)?,
+        Command::ImportNetwork {
+            interface,
+            file,
+            merge,
+        } => import_network(&interface, &conf, &file, merge, opts.network)?,
+        Command::Apply {
+            interface,
+            file,
+            prune,
+        } => apply_topology(&interface, &conf, &file, prune)?,
+        Command::Config {
+            command: ConfigCommand::Show { interface, json },
+        } => show_config(&interface, &conf, json)?,
+        Command::Completions { shell } => {
+            use clap::CommandFactory;
+            let mut app = Opts::command();
+            let app_name = app.get_name().to_string();
+            clap_complete::generate(shell, &mut app, app_name, &mut std::io::stdout());
+            std::process::exit(0);
+        },
+    }
+
+    if let Some(dry_run) = dry_run {
+        dry_run.finish()?;
+    }
+
+    Ok(())
+}
+
+impl Command {
+    /// The interface whose network a command changes, for commands that are refused while
+    /// its server is read-only. The TUI checks for itself, since it can also just browse.
+    fn changed_interface(&self) -> Option<&InterfaceName> {
+        match self {
+            Command::Uninstall { interface, .. }
+            | Command::AddPeer { interface, .. }
+            | Command::DisablePeer { interface, .. }
+            | Command::EnablePeer { interface }
+            | Command::Activate { interface, .. }
+            | Command::RenamePeer { interface, .. }
+            | Command::TagPeer { interface, .. }
+            | Command::RotateAdmin { interface, .. }
+            | Command::RekeyPeer { interface, .. }
+            | Command::RevokeInvitation { interface, .. }
+            | Command::AddCidr { interface, .. }
+            | Command::RenameCidr { interface, .. }
+            | Command::DeleteCidr { interface, .. }
+            | Command::Defragment { interface, .. }
+            | Command::ImportNetwork { interface, .. }
+            | Command::Apply { interface, .. } => Some(interface),
+            Command::New { .. }
+            | Command::Serve { .. }
+            | Command::ListInvitations { .. }
+            | Command::Usage { .. }
+            | Command::Tui { .. }
+            | Command::CanReach { .. }
+            | Command::Routes { .. }
+            | Command::ExportNetwork { .. }
+            | Command::Config { .. }
+            | Command::Completions { .. } => None,
+        }
+    }
+
+    /// The interface whose database a command changes, for commands that can be dry-run.
+    fn mutated_interface(&self) -> Option<&InterfaceName> {
+        match self {
+            Command::AddPeer { interface, .. }
+            | Command::DisablePeer { interface, .. }
+            | Command::EnablePeer { interface }
+            | Command::Activate { interface, .. }
+            | Command::RenamePeer { interface, .. }
+            | Command::TagPeer { interface, .. }
+            | Command::RotateAdmin { interface, .. }
+            | Command::RekeyPeer { interface, .. }
+            | Command::ListInvitations { interface, .. }
+            | Command::Usage { interface, .. }
+            | Command::RevokeInvitation { interface, .. }
+            | Command::Tui { interface }
+            | Command::AddCidr { interface, .. }
+            | Command::RenameCidr { interface, .. }
+            | Command::DeleteCidr { interface, .. }
+            | Command::Defragment { interface, .. }
+            | Command::CanReach { interface, .. }
+            | Command::Routes { interface, .. }
+            | Command::ExportNetwork { interface, .. }
+            | Command::ImportNetwork { interface, .. }
+            | Command::Apply { interface, .. } => Some(interface),
+            Command::New { .. }
+            | Command::Uninstall { .. }
+            | Command::Serve { .. }
+            | Command::Config { .. }
+            | Command::Completions { .. } => None,
+        }
+    }
+}
+
+fn open_database_connection(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+) -> Result<rusqlite::Connection, Error> {
+    let database_path = if conf.dry_run {
+        dry_run::scratch_database_path(interface)
+    } else {
+        conf.database_path(interface)
+    };
+    if !Path::new(&database_path).exists() {
+        bail!(
+            "no database file found at {}",
+            database_path.to_string_lossy()
+        );
+    }
+
+    let conn = Connection::open(&database_path)?;
+    // Foreign key constraints aren't on in SQLite by default. Enable.
+    conn.pragma_update(None, "foreign_keys", 1)?;
+    db::auto_migrate(&conn)?;
+
+    Ok(conn)
+}
+
+fn add_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    mut opts: AddPeerOpts,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    opts.ip_allocation.get_or_insert(config.ip_allocation);
+    let mut conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let cidr_tree = CidrTree::new(&cidrs[..]);
+
+    if let Some(result) = shared::prompts::add_peer(&peers, &cidr_tree, &opts)? {
+        let (peer_request, keypair, target_path, mut target_file) = result;
+        let server = invitation::InvitationServer {
+            interface,
+            api_addr: SocketAddr::new(config.address, config.listen_port),
+            persistent_keepalive: config.persistent_keepalive,
+            post_install: config.post_install.as_deref(),
+            peer_limits: config.peer_limits(),
+            ip_allocation: config.ip_allocation,
+        };
+        let (peer, peer_invitation) =
+            invitation::insert_peer_invitation(&mut conn, &server, peer_request, &keypair)?;
+        if cfg!(not(test))
+            && !conf.dry_run
+            && !peer.is_staged
+            && Device::get(interface, network.backend).is_ok()
+        {
+            // Update the current WireGuard interface with the new peers.
+            DeviceUpdate::new()
+                .add_peer(PeerConfigBuilder::from(&peer))
+                .apply(interface, network.backend)
+                .map_err(|_| ServerError::WireGuard)?;
+
+            println!("adding to WireGuard interface: {}", &peer);
+        }
+
+        if conf.dry_run {
+            discard_invitation(target_file, &target_path)?;
+            return Ok(());
+        }
+
+        prompts::write_invitation((&mut target_file, &target_path), &peer, &peer_invitation)?;
+    } else {
+        println!("exited without creating peer.");
+    }
+
+    Ok(())
+}
+
+/// Remove the invitation file a prompt created for a dry run, before anything is written to it.
+fn discard_invitation(target_file: File, target_path: &str) -> Result<(), Error> {
+    drop(target_file);
+    std::fs::remove_file(target_path).with_path(target_path)?;
+    println!("{} dry run, no invitation written.", "[*]".dimmed());
+    Ok(())
+}
+
+fn rename_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: RenamePeerOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+
+    if let Some((peer_request, old_name)) = shared::prompts::rename_peer(&peers, &opts)? {
+        let peer = peers
+            .iter()
+            .find(|p| p.name == old_name)
+            .ok_or_else(|| anyhow!("Peer not found."))?;
+        set_peer_name(&conn, peer, peer_request.name)?;
+    } else {
+        println!("exited without creating peer.");
+    }
+
+    Ok(())
+}
+
+/// Give `peer` a new name.
+pub(crate) fn set_peer_name(conn: &Connection, peer: &Peer, name: Hostname) -> Result<(), Error> {
+    let mut db_peer = DatabasePeer::get(conn, peer.id)?;
+    db_peer.update(
+        conn,
+        PeerContents {
+            name,
+            ..peer.contents.clone()
+        },
+    )?;
+    Ok(())
+}
+
+fn rotate_admin(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: RotateAdminOpts,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    rekey(interface, conf, network, |peers| {
+        prompts::rotate_admin(peers, &opts)
+    })
+}
+
+fn rekey_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: RekeyPeerOpts,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    rekey(interface, conf, network, |peers| {
+        prompts::rekey_peer(peers, &opts)
+    })
+}
+
+/// Revoke the key of the peer that `prompt` picks, and write its new invitation.
+fn rekey(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    prompt: impl FnOnce(&[shared::Peer]) -> Result<Option<(shared::Peer, KeyPair, String, File)>, Error>,
+) -> Result<(), Error> {
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let cidr_tree = CidrTree::new(&cidrs[..]);
+
+    if let Some((selected, keypair, target_path, mut target_file)) = prompt(&peers)? {
+        let old_key = Key::from_base64(&selected.public_key)?;
+        let invite_expires = selected
+            .invite_expires
+            .ok_or_else(|| anyhow!("missing invitation expiration"))?;
+        let mut peer = DatabasePeer::get(&conn, selected.id)?;
+        peer.rotate_key(&conn, &keypair.public.to_base64(), invite_expires)?;
+        peer.with_server_preshared_key(&conn, config.preshared_keys)?;
+        peer.with_default_keepalive(config.persistent_keepalive);
+
+        if conf.dry_run {
+            discard_invitation(target_file, &target_path)?;
+            return Ok(());
+        }
+
+        if Device::get(interface, network.backend).is_ok() {
+            // Cut the old key off right away rather than waiting for a restart.
+            DeviceUpdate::new()
+                .remove_peer_by_key(&old_key)
+                .add_peer(PeerConfigBuilder::from(&*peer))
+                .apply(interface, network.backend)
+                .map_err(|_| ServerError::WireGuard)?;
+        }
+
+        let server_peer = DatabasePeer::get(&conn, SERVER_PEER_ID)?;
+        prompts::write_peer_invitation(
+            (&mut target_file, &target_path),
+            interface,
+            &peer,
+            &server_peer,
+            &cidr_tree,
+            keypair,
+            &SocketAddr::new(config.address, config.listen_port),
+        )?;
+        log::info!("revoked the previous key of {}.", peer.name);
+    } else {
+        println!("exited without rotating keys.");
+    }
+
+    Ok(())
+}
+
+fn tag_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: TagPeerOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+
+    if let Some(peer_request) = shared::prompts::tag_peer(&peers, &opts)? {
+        let mut db_peer = DatabasePeer::list(&conn)?
+            .into_iter()
+            .find(|p| p.name == peer_request.name)
+            .ok_or_else(|| anyhow!("Peer not found."))?;
+        db_peer.update(&conn, peer_request)?;
+        println!("Tags for peer {} updated.", db_peer.name.yellow());
+    } else {
+        println!("exited without tagging peer.");
+    }
+
+    Ok(())
+}
+
+/// An invitation that hasn't been redeemed, as listed by `list-invitations`.
+#[derive(Debug, Serialize)]
+struct OutstandingInvitation {
+    name: Hostname,
+    ip: IpAddr,
+    /// When the invitation was issued, as seconds since the UNIX epoch.
+    created_at: Option<u64>,
+    created_by: Option<String>,
+    /// When the invitation can no longer be redeemed, as seconds since the UNIX epoch.
+    expires: Option<u64>,
+    /// Seconds since the last handshake with the invitation's key, or `None` if it never
+    /// connected.
+    last_handshake_secs: Option<u64>,
+}
+
+/// The network's unredeemed invitations, given the interface's last handshake per public key.
+fn outstanding_invitations(
+    peers: &[DatabasePeer],
+    handshakes: &HashMap<String, SystemTime>,
+) -> Vec<OutstandingInvitation> {
+    let unix_secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    };
+    let now = SystemTime::now();
+    peers
+        .iter()
+        .filter(|peer| !peer.is_redeemed && !peer.is_disabled)
+        .map(|peer| OutstandingInvitation {
+            name: peer.name.clone(),
+            ip: peer.ip,
+            created_at: peer.created_at.and_then(unix_secs),
+            created_by: peer.created_by.clone(),
+            expires: peer.invite_expires.and_then(unix_secs),
+            last_handshake_secs: wg::handshake_age(handshakes.get(&peer.public_key).copied(), now)
+                .map(|age| age.as_secs()),
+        })
+        .collect()
+}
+
+/// Each peer's last handshake with the interface, by public key. Empty when the interface
+/// isn't up.
+pub(crate) fn last_handshakes(
+    interface: &InterfaceName,
+    backend: Backend,
+) -> HashMap<String, SystemTime> {
+    Device::get(interface, backend)
+        .map(|device| {
+            device
+                .peers
+                .into_iter()
+                .filter_map(|peer| {
+                    let time = peer.stats.last_handshake_time?;
+                    Some((peer.config.public_key.to_base64(), time))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn list_invitations(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    json: bool,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?;
+    // Without a running interface, there's no way to tell whether a key was ever used.
+    let handshakes = last_handshakes(interface, network.backend);
+    let invitations = outstanding_invitations(&peers, &handshakes);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&invitations)?);
+        return Ok(());
+    }
+    if invitations.is_empty() {
+        println!("no outstanding invitations.");
+    }
+    for invitation in invitations {
+        let created_by = invitation
+            .created_by
+            .map(|name| format!(" by {name}"))
+            .unwrap_or_default();
+        let connected = match invitation.last_handshake_secs {
+            None => "never connected".normal(),
+            // The invitation's key is only used briefly while redeeming, so any handshake
+            // without a redemption deserves a closer look.
+            Some(secs) => format!("connected {secs}s ago").yellow(),
+        };
+        println!(
+            "{} ({}){}, {}",
+            invitation.name.to_string().yellow(),
+            invitation.ip,
+            created_by,
+            connected
+        );
+    }
+    Ok(())
+}
+
+/// A peer's transfer, for `usage`.
+#[derive(Debug, Serialize)]
+struct PeerUsage {
+    name: Hostname,
+    ip: IpAddr,
+    #[serde(flatten)]
+    transfer: Transfer,
+}
+
+fn usage(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    since: Option<Timestring>,
+    json: bool,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let since = since.map(|since| SystemTime::now() - Duration::from(since));
+    let totals = DatabaseUsage::totals(&conn, since)?;
+    let mut usage: Vec<_> = DatabasePeer::list(&conn)?
+        .into_iter()
+        .filter(|peer| peer.id != SERVER_PEER_ID)
+        .map(|peer| PeerUsage {
+            transfer: totals.get(&peer.id).copied().unwrap_or_default(),
+            name: peer.contents.name.clone(),
+            ip: peer.contents.ip,
+        })
+        .collect();
+    usage.sort_by_key(|peer| std::cmp::Reverse(peer.transfer.total()));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    if config.usage_sample_interval.is_none() {
+        log::warn!("usage-sample-interval isn't set, so no new usage is being recorded.");
+    }
+    for peer in usage {
+        println!(
+            "{} ({}): {} received, {} sent",
+            peer.name.to_string().yellow(),
+            peer.ip,
+            shared::human_size(peer.transfer.rx_bytes),
+            shared::human_size(peer.transfer.tx_bytes)
+        );
+    }
+    Ok(())
+}
+
+fn revoke_invitation(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    name: &Hostname,
+    yes: bool,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peer = DatabasePeer::list(&conn)?
+        .into_iter()
+        .find(|peer| &peer.name == name)
+        .ok_or_else(|| anyhow!("No peer named {} exists.", name))?;
+    if peer.is_redeemed {
+        bail!(
+            "{} has already redeemed their invitation, use disable-peer instead.",
+            name
+        );
+    }
+
+    if yes
+        || Confirm::with_theme(&*prompts::THEME)
+            .with_prompt(format!(
+                "Revoke the invitation for {}?",
+                name.to_string().yellow()
+            ))
+            .default(false)
+            .interact()?
+    {
+        remove_invitation(&conn, interface, conf, network, peer)?;
+        println!("{} invitation for {} revoked.", "[*]".dimmed(), name);
+    }
+    Ok(())
+}
+
+/// Delete an unredeemed peer, so that its invitation can't be used, and take it off the
+/// WireGuard interface.
+pub(crate) fn remove_invitation(
+    conn: &Connection,
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    peer: DatabasePeer,
+) -> Result<(), Error> {
+    if peer.is_redeemed {
+        bail!(
+            "{} has already redeemed their invitation, disable it instead.",
+            peer.name
+        );
+    }
+    peer.revoke_invite(conn)?;
+    if !conf.dry_run && Device::get(interface, network.backend).is_ok() {
+        DeviceUpdate::new()
+            .remove_peer_by_key(&Key::from_base64(&peer.public_key)?)
+            .apply(interface, network.backend)
+            .map_err(|_| ServerError::WireGuard)?;
+    }
+    Ok(())
+}
+
+fn enable_or_disable_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    enable: bool,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+
+    if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], enable)? {
+        set_peer_enabled(&conn, interface, conf, network, &peer, enable)?;
+    } else {
+        log::info!("exiting without enabling or disabling peer.");
+    }
+
+    Ok(())
+}
+
+/// Enable or disable `peer`, within the network's peer limits, and add it to or remove it
+/// from the WireGuard interface to match.
+pub(crate) fn set_peer_enabled(
+    conn: &Connection,
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    peer: &Peer,
+    enable: bool,
+) -> Result<(), Error> {
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    let mut db_peer = DatabasePeer::get(conn, peer.id)?;
+    let contents = PeerContents {
+        is_disabled: !enable,
+        ..peer.contents.clone()
+    };
+    config
+        .peer_limits()
+        .check_update(conn, &peer.contents, &contents)?;
+    if conf.dry_run {
+        db_peer.update(conn, contents)?;
+    } else {
+        update_peer(
+            conn,
+            &mut db_peer,
+            contents,
+            interface,
+            network.backend,
+            config.preshared_keys,
+            config.persistent_keepalive,
+        )?;
+    }
+    Ok(())
+}
+
+fn activate_peer(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    name: &Hostname,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let mut peer = DatabasePeer::list(&conn)?
+        .into_iter()
+        .find(|peer| &peer.name == name)
+        .ok_or_else(|| anyhow!("No peer named {} exists.", name))?;
+    if !peer.is_staged {
+        bail!("{} isn't staged, it's {}.", name, peer.status());
+    }
+
+    let contents = PeerContents {
+        is_disabled: false,
+        ..peer.contents.clone()
+    };
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    config
+        .peer_limits()
+        .check_update(&conn, &peer.contents, &contents)?;
+    if conf.dry_run {
+        peer.update(&conn, contents)?;
+    } else {
+        update_peer(
+            &conn,
+            &mut peer,
+            contents,
+            interface,
+            network.backend,
+            config.preshared_keys,
+            config.persistent_keepalive,
+        )?;
+    }
+    println!(
+        "{} activated {}.",
+        "[*]".dimmed(),
+        name.to_string().yellow()
+    );
+    Ok(())
+}
+
+fn disable_peer_reassigning_routes(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    successor: &Hostname,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let successor = peers
+        .iter()
+        .find(|peer| &peer.name == successor && !peer.is_disabled)
+        .ok_or_else(|| anyhow!("No enabled peer named {} exists.", successor))?;
+
+    if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], false)? {
+        let mut db_peer = DatabasePeer::get(&conn, peer.id)?;
+        let mut db_successor = DatabasePeer::get(&conn, successor.id)?;
+        db_peer
+            .hand_over_routes(&conn, &mut db_successor)
+            .map_err(|e| match e {
+                ServerError::InvalidQuery => anyhow!(
+                    "can't move {}'s routes to {}, see the log for why.",
+                    peer.name,
+                    successor.name
+                ),
+                e => e.into(),
+            })?;
+
+        if !conf.dry_run && Device::get(interface, network.backend).is_ok() {
+            let successor_key = Key::from_base64(&db_successor.public_key)?;
+            DeviceUpdate::new()
+                .remove_peer_by_key(&Key::from_base64(&db_peer.public_key)?)
+                .add_peer(
+                    PeerConfigBuilder::new(&successor_key)
+                        .replace_allowed_ips()
+                        .add_allowed_ips(&db_successor.allowed_ips()),
+                )
+                .apply(interface, network.backend)
+                .map_err(|_| ServerError::WireGuard)?;
+        }
+        println!(
+            "{} disabled {} and moved its routes to {}.",
+            "[*]".dimmed(),
+            peer.name.to_string().yellow(),
+            successor.name.to_string().yellow()
+        );
+    } else {
+        log::info!("exiting without disabling peer.");
+    }
+
+    Ok(())
+}
+
+/// Update a peer, adding it to or removing it from the live WireGuard interface right away
+/// if it was enabled or disabled.
+///
+/// The database change is rolled back if the interface can't be updated, so a peer is never
+/// enabled in the database but missing from the interface, or the other way around.
+pub(crate) fn update_peer(
+    conn: &Connection,
+    peer: &mut DatabasePeer,
+    contents: PeerContents,
+    interface: &InterfaceName,
+    backend: Backend,
+    preshared_keys: bool,
+    persistent_keepalive: Option<u16>,
+) -> Result<(), ServerError> {
+    let was_disabled = peer.is_disabled;
+    let tx = conn.unchecked_transaction()?;
+    peer.update(&tx, contents)?;
+
+    if peer.is_disabled != was_disabled && cfg!(not(test)) {
+        let update = if peer.is_disabled {
+            let public_key =
+                Key::from_base64(&peer.public_key).map_err(|_| ServerError::WireGuard)?;
+            DeviceUpdate::new().remove_peer_by_key(&public_key)
+        } else {
+            peer.with_server_preshared_key(&tx, preshared_keys)?;
+            peer.with_default_keepalive(persistent_keepalive);
+            DeviceUpdate::new().add_peer(PeerConfigBuilder::from(&**peer))
+        };
+        // Dropping the transaction on failure rolls back the database change.
+        update.apply(interface, backend).map_err(|e| {
+            log::error!(
+                "failed to update WireGuard interface for {}: {}",
+                &**peer,
+                e
+            );
+            ServerError::WireGuard
+        })?;
+        log::info!(
+            "{} {} on the WireGuard interface.",
+            if peer.is_disabled { "removed" } else { "added" },
+            &**peer
+        );
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+fn add_cidr(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: AddCidrOpts,
+) -> Result<(), Error> {
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    if let Some(cidr_request) = shared::prompts::add_cidr(&cidrs, &opts)? {
+        let cidr = DatabaseCidr::create(&conn, cidr_request, config.max_cidr_depth())?;
+        printdoc!(
+            "
+            CIDR \"{cidr_name}\" added.
+
+            Right now, peers within {cidr_name} can only see peers in the same CIDR, and in
+            the special \"innernet-server\" CIDR that includes the innernet server peer.
+
+            You'll need to add more associations for peers in diffent CIDRs to communicate.
+            ",
+            cidr_name = cidr.name.bold()
+        );
+    } else {
+        println!("exited without creating CIDR.");
+    }
+
+    Ok(())
+}
+
+fn rename_cidr(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: RenameCidrOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+
+    if let Some((cidr_request, old_name)) = shared::prompts::rename_cidr(&cidrs, &opts)? {
+        let mut db_cidr = DatabaseCidr::from(cidr_request.clone());
+        db_cidr.update(&conn, cidr_request.contents)?;
+        println!(
+            "CIDR \"{}\" renamed to \"{}\".",
+            old_name,
+            db_cidr.name.bold()
+        );
+    } else {
+        println!("exited without renaming CIDR.");
+    }
+
+    Ok(())
+}
+
+fn delete_cidr(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    args: DeleteCidrOpts,
+) -> Result<(), Error> {
+    println!("Fetching eligible CIDRs");
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+
+    let cidr_id = prompts::delete_cidr(&cidrs, &peers, &args)?;
+
+    println!("Deleting CIDR...");
+    DatabaseCidr::delete(&conn, cidr_id)?;
+
+    println!("CIDR deleted.");
+
+    Ok(())
+}
+
+fn can_reach(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    peer_a: &Hostname,
+    peer_b: &Hostname,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let associations = DatabaseAssociation::list(&conn)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let find = |name: &Hostname| {
+        peers
+            .iter()
+            .find(|peer| &peer.name == name)
+            .ok_or_else(|| anyhow!("No peer named {} exists.", name))
+    };
+
+    reachability::report(&cidrs, &associations, find(peer_a)?, find(peer_b)?);
+    Ok(())
+}
+
+fn peer_routes(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    name: &Hostname,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let associations = DatabaseAssociation::list(&conn)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let peer = peers
+        .iter()
+        .find(|peer| &peer.name == name)
+        .ok_or_else(|| anyhow!("No peer named {} exists.", name))?;
+
+    reachability::print_routes(&cidrs, &associations, &peers, peer);
+    Ok(())
+}
+
+fn export_network(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    file: &Path,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let dump = NetworkDump::read(&conn)?;
+    dump.write_to_path(file)?;
+    println!(
+        "{} exported {} CIDRs, {} peers, and {} associations to {}.",
+        "[*]".dimmed(),
+        dump.cidrs.len(),
+        dump.peers.len(),
+        dump.associations.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+fn import_network(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    file: &Path,
+    merge: bool,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let dump = NetworkDump::from_file(file)?;
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    let mut conn = open_database_connection(interface, conf)?;
+    let summary = dump.import(&mut conn, merge, config.max_cidr_depth())?;
+
+    if cfg!(not(test)) && !conf.dry_run && Device::get(interface, network.backend).is_ok() {
+        // Update the current WireGuard interface with the imported peers.
+        let mut peers = vec![];
+        for mut peer in DatabasePeer::list(&conn)? {
+            if peer.id != SERVER_PEER_ID && !peer.is_disabled {
+                peer.with_server_preshared_key(&conn, config.preshared_keys)?;
+                peer.with_default_keepalive(config.persistent_keepalive);
+                peers.push(PeerConfigBuilder::from(&*peer));
+            }
+        }
+        DeviceUpdate::new()
+            .add_peers(&peers)
+            .apply(interface, network.backend)
+            .map_err(|_| ServerError::WireGuard)?;
+    }
+
+    println!(
+        "{} imported {} CIDRs, {} peers, and {} associations.",
+        "[*]".dimmed(),
+        summary.cidrs,
+        summary.peers,
+        summary.associations
+    );
+    Ok(())
+}
+
+fn apply_topology(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    file: &Path,
+    prune: bool,
+) -> Result<(), Error> {
+    let topology = Topology::from_file(file)?;
+    let config = ConfigFile::load(conf.config_path(interface))?;
+    let mut conn = open_database_connection(interface, conf)?;
+    let summary = topology.apply(&mut conn, prune, config.max_cidr_depth())?;
+
+    if summary.is_empty() {
+        println!(
+            "{} the network already matches the topology.",
+            "[*]".dimmed()
+        );
+    } else {
+        println!(
+            "{} created {} CIDRs and {} associations, deleted {} CIDRs and {} associations.",
+            "[*]".dimmed(),
+            summary.created_cidrs,
+            summary.created_associations,
+            summary.deleted_cidrs,
+            summary.deleted_associations
+        );
+    }
+    Ok(())
+}
+
+fn defragment(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    cidr_name: Option<String>,
+    compact: bool,
+    yes: bool,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let mut conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+
+    let leaves = CidrTree::new(&cidrs[..]).leaves();
+    let cidr = if let Some(name) = cidr_name {
+        leaves
+            .iter()
+            .find(|cidr| cidr.name == name)
+            .ok_or_else(|| anyhow!("No eligible CIDR with that name exists."))?
+    } else {
+        prompts::choose_cidr(&leaves[..], "CIDR to defragment")?
+    };
+
+    let mut members: Vec<_> = peers.iter().filter(|p| p.cidr_id == cidr.id).collect();
+    members.sort_by_key(|p| p.ip);
+    let highest = members.last().map(|p| p.ip.to_string());
+    println!(
+        "{} {} has {} peer(s) allocated, highest address {}.",
+        "[*]".dimmed(),
+        cidr.to_string().yellow(),
+        members.len(),
+        highest.as_deref().unwrap_or("[none]"),
+    );
+    for peer in &members {
+        println!("    {} {}", peer.ip.to_string().bold(), peer.name);
+    }
+
+    let plan = DatabasePeer::defragment_plan(cidr, &peers);
+    if plan.is_empty() {
+        println!("{} {} is already compact.", "[*]".dimmed(), cidr.name);
+        return Ok(());
+    }
+    if !compact {
+        println!(
+            "{} {} peer(s) could be moved to lower addresses (use --compact to see the plan).",
+            "[*]".dimmed(),
+            plan.len()
+        );
+        return Ok(());
+    }
+
+    println!("{} address changes:", "[*]".dimmed());
+    for (peer, new_ip) in &plan {
+        println!(
+            "    {}: {} => {}",
+            peer.name.yellow(),
+            peer.ip,
+            new_ip.to_string().bold()
+        );
+    }
+    printdoc!(
+        "
+
+        {warning} Moving a peer changes its allowed-IPs on every other peer. The peers above
+        will need to be given their new address, and all other peers need to fetch before
+        they can reach them again.
+
+        ",
+        warning = "[!]".yellow(),
+    );
+
+    if !yes && !conf.dry_run && !prompts::confirm(&format!("Move {} peer(s)?", plan.len()))? {
+        println!("exited without defragmenting CIDR.");
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    let mut moved = vec![];
+    for (peer, new_ip) in plan {
+        let mut db_peer = DatabasePeer::get(&tx, peer.id)?;
+        db_peer.set_ip(&tx, new_ip)?;
+        moved.push(db_peer);
+    }
+    tx.commit()?;
+
+    if !conf.dry_run && Device::get(interface, network.backend).is_ok() {
+        let config = ConfigFile::load(conf.config_path(interface))?;
+        let updates = moved
+            .iter_mut()
+            .filter(|peer| !peer.is_disabled)
+            .map(|peer| {
+                peer.with_default_keepalive(config.persistent_keepalive);
+                PeerConfigBuilder::from(&**peer)
+            })
+            .collect::<Vec<_>>();
+        DeviceUpdate::new()
+            .add_peers(&updates)
+            .apply(interface, network.backend)
+            .map_err(|_| ServerError::WireGuard)?;
+    }
+
+    println!("{} moved {} peer(s).", "[*]".dimmed(), moved.len());
+
+    Ok(())
+}
+
+/// A network's configuration as the server applies it, for `config show`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct EffectiveConfig {
+    /// The config file, or "environment" if the config came from `INNERNET_SERVER_*`.
+    source: String,
+    private_key: &'static str,
+    listen_port: u16,
+    address: IpAddr,
+    network_cidr_prefix: u8,
+    preshared_keys: bool,
+    /// The keepalive interval for peers without their own, 0 if disabled.
+    persistent_keepalive: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_install: Option<String>,
+    api_bind_addr: Vec<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_peers: Option<u32>,
+    peer_limit_policy: LimitPolicy,
+    max_cidr_depth: u8,
+    ip_allocation: IpAllocation,
+    endpoint_check: EndpointCheck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage_sample_interval: Option<u64>,
+    database_path: PathBuf,
+    cidrs: Vec<EffectiveCidr>,
+}
+
+/// The defaults new peers in a CIDR get, after inheriting from its ancestors.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct EffectiveCidr {
+    name: String,
+    cidr: IpNet,
+    /// 0 if disabled.
+    default_keepalive: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_mtu: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_peers: Option<u32>,
+}
+
+fn effective_config(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+) -> Result<EffectiveConfig, Error> {
+    let config_path = conf.config_path(interface);
+    let config = ConfigFile::load(&config_path)?;
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let keepalive = |interval| DatabasePeer::resolve_persistent_keepalive(interval).unwrap_or(0);
+
+    Ok(EffectiveConfig {
+        source: if config_path.exists() {
+            config_path.display().to_string()
+        } else {
+            "environment".into()
+        },
+        private_key: "(redacted)",
+        listen_port: config.listen_port,
+        address: config.address,
+        network_cidr_prefix: config.network_cidr_prefix,
+        preshared_keys: config.preshared_keys,
+        persistent_keepalive: keepalive(config.persistent_keepalive),
+        api_bind_addr: config.api_bind_addrs(),
+        max_cidr_depth: config.max_cidr_depth(),
+        post_install: config.post_install,
+        max_peers: config.max_peers,
+        peer_limit_policy: config.peer_limit_policy,
+        ip_allocation: config.ip_allocation,
+        endpoint_check: config.endpoint_check,
+        usage_sample_interval: config.usage_sample_interval,
+        database_path: conf.database_path(interface),
+        cidrs: cidrs
+            .iter()
+            .map(|cidr| EffectiveCidr {
+                name: cidr.name.clone(),
+                cidr: cidr.cidr,
+                default_keepalive: keepalive(shared::inherited_cidr_default(
+                    &cidrs,
+                    cidr.id,
+                    |c| c.default_keepalive,
+                )),
+                default_mtu: shared::inherited_cidr_default(&cidrs, cidr.id, |c| c.default_mtu),
+                max_peers: cidr.max_peers,
+            })
+            .collect(),
+    })
+}
+
+fn show_config(interface: &InterfaceName, conf: &ServerConfig, json: bool) -> Result<(), Error> {
+    let effective = effective_config(interface, conf)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+    } else {
+        print!("{}", toml::to_string(&effective)?);
+    }
+    Ok(())
+}
+
+fn uninstall(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    yes: bool,
+) -> Result<(), Error> {
+    if yes
+        || Confirm::with_theme(&*prompts::THEME)
+            .with_prompt(format!(
+                "Permanently delete network \"{}\"?",
+                interface.as_str_lossy().yellow()
+            ))
+            .default(false)
+            .interact()?
+    {
+        println!("{} bringing down interface (if up).", "[*]".dimmed());
+        wg::down(interface, network.backend).ok();
+        let config = conf.config_path(interface);
+        let data = conf.database_path(interface);
+        std::fs::remove_file(&config)
+            .with_path(&config)
+            .map_err(|e| println!("[!] {}", e.to_string().yellow()))
+            .ok();
+        std::fs::remove_file(&data)
+            .with_path(&data)
+            .map_err(|e| println!("[!] {}", e.to_string().yellow()))
+            .ok();
+        println!(
+            "{} network {} is uninstalled.",
+            "[*]".dimmed(),
+            interface.as_str_lossy().yellow()
+        );
+    }
+    Ok(())
+}
+
+/// Periodically collect each peer's endpoint and most recent handshake from the interface.
+fn spawn_endpoint_refresher(
+    interface: InterfaceName,
+    network: NetworkOpts,
+) -> (Endpoints, Handshakes) {
+    let endpoints = Arc::new(RwLock::new(HashMap::new()));
+    let handshakes = Arc::new(RwLock::new(HashMap::new()));
+    tokio::task::spawn({
+        let endpoints = endpoints.clone();
+        let handshakes = handshakes.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                if let Ok(info) = Device::get(&interface, network.backend) {
+                    for peer in info.peers {
+                        let public_key = peer.config.public_key.to_base64();
+                        if let Some(last_handshake) = peer.stats.last_handshake_time {
+                            handshakes
+                                .write()
+                                .insert(public_key.clone(), last_handshake);
+                        }
+                        if let Some(endpoint) = peer.config.endpoint {
+                            endpoints.write().insert(public_key, endpoint);
+                        }
+                    }
+                }
+            }
+        }
+    });
+    (endpoints, handshakes)
+}
+
+fn spawn_expired_invite_sweeper(db: Db, read_only: Arc<ReadOnly>) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            if read_only.get() {
+                continue;
+            }
+            match DatabasePeer::delete_expired_invites(&db.lock()) {
+                Ok(deleted) if deleted > 0 => {
+                    log::info!("Deleted {} expired peer invitations.", deleted)
+                },
+                Err(e) => log::error!("Failed to delete expired peer invitations: {}", e),
+                _ => {},
+            }
+        }
+    });
+}
+
+fn spawn_stale_candidate_sweeper(db: Db, read_only: Arc<ReadOnly>) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if read_only.get() {
+                continue;
+            }
+            match DatabasePeer::expire_stale_candidates(&db.lock(), CANDIDATE_MAX_AGE) {
+                Ok(expired) if expired > 0 => {
+                    log::info!("Expired stale endpoint candidates of {} peers.", expired)
+                },
+                Err(e) => log::error!("Failed to expire stale endpoint candidates: {}", e),
+                _ => {},
+            }
+        }
+    });
+}
+
+/// Usage isn't recorded while the server is read-only. WireGuard's counters keep counting,
+/// so the next sample after it's turned off still adds what was transferred meanwhile.
+fn spawn_usage_sampler(
+    db: Db,
+    read_only: Arc<ReadOnly>,
+    interface: InterfaceName,
+    network: NetworkOpts,
+    every: Duration,
+) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if read_only.get() {
+                continue;
+            }
+            let Ok(info) = Device::get(&interface, network.backend) else {
+                continue;
+            };
+            if let Err(e) = record_usage(&db.lock(), &info.peers, SystemTime::now()) {
+                log::error!("Failed to record peer usage: {}", e);
+            }
+        }
+    });
+}
+
+/// Add what WireGuard counted for each of `peers` since the last sample to their usage.
+fn record_usage(conn: &Connection, peers: &[PeerInfo], now: SystemTime) -> Result<(), Error> {
+    let ids: HashMap<_, _> = DatabasePeer::list(conn)?
+        .into_iter()
+        .map(|peer| (peer.public_key.clone(), peer.id))
+        .collect();
+    let tx = conn.unchecked_transaction()?;
+    for peer in peers {
+        let Some(&id) = ids.get(&peer.config.public_key.to_base64()) else {
+            continue;
+        };
+        let counters = Transfer {
+            rx_bytes: peer.stats.rx_bytes,
+            tx_bytes: peer.stats.tx_bytes,
+        };
+        DatabaseUsage::record(&tx, id, counters, now)?;
+    }
+    DatabaseUsage::prune(&tx, now - usage::RETENTION)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Compare the server's configured external endpoint, which is what invitations point
+/// peers at, with the public IP the server is seen from.
+fn check_external_endpoint(
+    conn: &Connection,
+    config: &ConfigFile,
+    opts: &DetectEndpointOpts,
+) -> Result<(), Error> {
+    let detected_ip = match &opts.stun_server {
+        Some(stun_server) => publicip::get_via_stun(stun_server.as_str())
+            .map_err(|e| log::warn!("STUN request to {} failed: {}", stun_server, e))
+            .ok(),
+        None => publicip::get_any(Preference::Ipv4),
+    };
+    let Some(detected_ip) = detected_ip else {
+        log::warn!("couldn't detect this server's public IP, skipping the endpoint check.");
+        return Ok(());
+    };
+    let detected = Endpoint::from(SocketAddr::new(detected_ip, config.listen_port));
+
+    let mut server_peer = DatabasePeer::get(conn, SERVER_PEER_ID)?;
+    let replace = match &server_peer.endpoint {
+        None => {
+            log::info!(
+                "no external endpoint configured, using detected {}.",
+                detected
+            );
+            true
+        },
+        Some(configured) => match configured.resolve() {
+            Ok(addr) if addr.ip() == detected_ip && addr.port() == config.listen_port => {
+                log::info!(
+                    "external endpoint {} matches the detected public IP.",
+                    configured
+                );
+                false
+            },
+            resolved => {
+                let resolved = match resolved {
+                    Ok(addr) => addr.to_string(),
+                    Err(e) => format!("unresolvable: {e}"),
+                };
+                log::warn!(
+                    "{}",
+                    format!(
+                        "configured external endpoint {configured} ({resolved}) doesn't match \
+                         the detected {detected}. New invitations may not be able to connect."
+                    )
+                    .red()
+                    .bold()
+                );
+                if !opts.update_endpoint {
+                    log::warn!("pass --update-endpoint to replace it with the detected one.");
+                }
+                opts.update_endpoint
+            },
+        },
+    };
+
+    if replace {
+        log::info!("setting external endpoint to {}.", detected);
+        server_peer.update(
+            conn,
+            PeerContents {
+                endpoint: Some(detected),
+                ..server_peer.contents.clone()
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Check that other users can't read the data directory or the network's database, which
+/// holds preshared keys. Exposed paths are restricted if `fix` is set, and otherwise only
+/// warned about. Returns the paths that were exposed.
+fn check_data_permissions(
+    conf: &ServerConfig,
+    interface: &InterfaceName,
+    fix: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut exposed = vec![];
+    let database_path = conf.database_path(interface);
+    let database_dir = database_path.parent().unwrap_or(conf.database_dir());
+    for (path, private_mode) in [
+        (database_dir.to_path_buf(), 0o700),
+        (database_path.clone(), 0o600),
+    ] {
+        let Some(mode) = shared::exposed_mode(&path).with_path(&path)? else {
+            continue;
+        };
+        if fix {
+            shared::chmod(&File::open(&path).with_path(&path)?, private_mode)?;
+            println!(
+                "{} updated permissions for {} to {:#05o}.",
+                "[!]".yellow(),
+                path.display(),
+                private_mode
+            );
+        } else {
+            println!(
+                "{} {} can be read by other users (mode is {:#05o}). Restrict it with \
+                 {}, or pass {}.",
+                "[!]".yellow(),
+                path.display(),
+                mode,
+                format!("chmod {:o} {}", private_mode, path.display()).yellow(),
+                "--fix-permissions".yellow()
+            );
+        }
+        exposed.push(path);
+    }
+    Ok(exposed)
+}
+
+async fn serve(
+    interface: InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    detect_endpoint: DetectEndpointOpts,
+    request_log: RequestLogOpts,
+    opts: ServeOpts,
+) -> Result<(), Error> {
+    let ServeOpts {
+        fix_permissions,
+        control_socket,
+        read_only,
+    } = opts;
+    let config = ConfigFile::load(conf.config_path(&interface))?;
+    check_data_permissions(conf, &interface, fix_permissions)?;
+    log::debug!("opening database connection...");
+    let conn = open_database_connection(&interface, conf)?;
+
+    if detect_endpoint.detect_endpoint {
+        check_external_endpoint(&conn, &config, &detect_endpoint)?;
+    }
+
+    let mut peers = DatabasePeer::list(&conn)?;
+    for peer in &mut peers {
+        peer.with_server_preshared_key(&conn, config.preshared_keys)?;
+        peer.with_default_keepalive(config.persistent_keepalive);
+    }
+    log::debug!("peers listed...");
+    let peer_configs = peers
+        .iter()
+        .map(|peer| peer.deref().into())
+        .collect::<Vec<PeerConfigBuilder>>();
+
+    log::info!("bringing up interface.");
+    wg::up(
+        &interface,
+        &config.private_key,
+        IpNet::new(config.address, config.network_cidr_prefix)?,
+        Some(config.listen_port),
+        None,
+        network,
+    )?;
+
+    DeviceUpdate::new()
+        .add_peers(&peer_configs)
+        .apply(&interface, network.backend)?;
+
+    log::info!("{} peers added to wireguard interface.", peers.len());
+
+    if !read_only {
+        let candidates: Vec<Endpoint> = get_local_addrs()?
+            .map(|addr| SocketAddr::from((addr, config.listen_port)).into())
+            .collect();
+        let num_candidates = candidates.len();
+        let myself = peers
+            .iter_mut()
+            .find(|peer| peer.ip == config.address)
+            .expect("Couldn't find server peer in peer list.");
+        myself.update(
+            &conn,
+            PeerContents {
+                candidates,
+                ..myself.contents.clone()
+            },
+        )?;
+
+        log::info!(
+            "{} local candidates added to server peer config.",
+            num_candidates
+        );
+    }
+
+    let public_key = wireguard_control::Key::from_base64(&config.private_key)?.get_public();
+    let db = Arc::new(Mutex::new(conn));
+    let read_only = Arc::new(ReadOnly::new(
+        read_only,
+        Some(conf.read_only_path(&interface)),
+    )?);
+    let (endpoints, handshakes) = spawn_endpoint_refresher(interface, network);
+    spawn_expired_invite_sweeper(db.clone(), read_only.clone());
+    spawn_stale_candidate_sweeper(db.clone(), read_only.clone());
+    if let Some(secs) = config.usage_sample_interval.filter(|&secs| secs > 0) {
+        spawn_usage_sampler(
+            db.clone(),
+            read_only.clone(),
+            interface,
+            network,
+            Duration::from_secs(secs),
+        );
+    }
+
+    let context = Context {
+        db,
+        endpoints,
+        handshakes,
+        interface,
+        public_key,
+        backend: network.backend,
+        preshared_keys: config.preshared_keys,
+        persistent_keepalive: config.persistent_keepalive,
+        peer_limits: config.peer_limits(),
+        max_cidr_depth: config.max_cidr_depth(),
+        ip_allocation: config.ip_allocation,
+        endpoint_check: config.endpoint_check,
+        request_log: Arc::new(RequestLog::new(request_log)),
+        read_only,
+    };
+
+    log::info!("innernet-server {} starting.", VERSION);
+    if context.is_read_only() {
+        log::warn!("serving read-only, changes to the network will be refused.");
+    }
+
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in config.api_bind_addrs() {
+        let context = context.clone();
+        servers.spawn(async move {
+            // Every address is inside the network, so only reachable through the interface,
+            // see get_listener.
+            let listener = bind_listener(addr, &interface, true).await?;
+            log::info!("serving the API on {}.", addr);
+
+            let make_svc = hyper::service::make_service_fn(move |socket: &AddrStream| {
+                let remote_addr = socket.remote_addr();
+                let context = context.clone();
+                async move {
+                    Ok::<_, http::Error>(hyper::service::service_fn(move |req: Request<Body>| {
+                        hyper_service(req, context.clone(), remote_addr)
+                    }))
+                }
+            });
+
+            hyper::Server::from_tcp(listener)?.serve(make_svc).await?;
+            Ok::<_, Error>(())
+        });
+    }
+
+    if let Some(path) = control_socket {
+        let context = context.clone();
+        servers.spawn(async move { control::serve(&path, context).await });
+    }
+
+    // Listeners only stop on errors, so the first one to stop takes the server down with it.
+    if let Some(result) = servers.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
+
+/// Bind one of the API's addresses. An address that isn't assigned to any interface yet
+/// (ex. while the network is still coming up) is retried until it is, rather than failing.
+async fn bind_listener(
+    addr: SocketAddr,
+    interface: &InterfaceName,
+    bind_device: bool,
+) -> Result<TcpListener, Error> {
+    let mut warned = false;
+    loop {
+        match get_listener(addr, interface, bind_device) {
+            Err(e)
+                if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                    == Some(std::io::ErrorKind::AddrNotAvailable) =>
+            {
+                if !warned {
+                    log::warn!("{} isn't available yet, retrying until it is.", addr);
+                    warned = true;
+                }
+                tokio::time::sleep(BIND_RETRY_INTERVAL).await;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// This function differs per OS, because different operating systems have
+/// opposing characteristics when binding to a specific IP address.
+/// On Linux, binding to a specific local IP address does *not* bind it to
+/// that IP's interface, allowing for spoofing attacks.
+///
+/// See https://github.com/tonarino/innernet/issues/26 for more details.
+#[cfg(target_os = "linux")]
+fn get_listener(
+    addr: SocketAddr,
+    interface: &InterfaceName,
+    bind_device: bool,
+) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let sock = socket2::Socket::from(listener);
+    if bind_device {
+        sock.bind_device(Some(interface.as_str_lossy().as_bytes()))?;
+    }
+    Ok(sock.into())
+}
+
+/// BSD-likes do seem to bind to an interface when binding to an IP,
+/// according to the internet, but we may want to explicitly use
+/// IP_BOUND_IF in the future regardless. This isn't currently in
+/// the socket2 crate however, so we aren't currently using it.
+///
+/// See https://github.com/tonarino/innernet/issues/26 for more details.
+#[cfg(not(target_os = "linux"))]
+fn get_listener(
+    addr: SocketAddr,
+    _interface: &InterfaceName,
+    _bind_device: bool,
+) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub(crate) async fn hyper_service(
+    req: Request<Body>,
+    context: Context,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, http::Error> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let request_log = context.request_log.clone();
+
+    // Break the path into components.
+    let components: VecDeque<_> = path
+        .trim_start_matches('/')
+        .split('/')
+        .map(String::from)
+        .collect();
+
+    let mut peer_key = None;
+    let response = routes(req, context, remote_addr, components, &mut peer_key)
+        .await
+        .or_else(TryInto::try_into)?;
+
+    request_log.record(
+        method.as_str(),
+        &path,
+        peer_key.as_deref(),
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    Ok(response)
+}
+
+/// Route the request, filling in `peer_key` with the requesting peer's public key once its
+/// session is established.
+async fn routes(
+    req: Request<Body>,
+    context: Context,
+    remote_addr: SocketAddr,
+    mut components: VecDeque<String>,
+    peer_key: &mut Option<String>,
+) -> Result<Response<Body>, ServerError> {
+    // Must be "/v1/[something]", or the unauthenticated health check.
+    let version = components.pop_front();
+    if version.as_deref() == Some("healthz") && components.is_empty() {
+        healthz(&req, &context)
+    } else if version.as_deref() != Some("v1") {
+        Err(ServerError::NotFound)
+    } else {
+        let session = get_session(&req, context, remote_addr.ip())?;
+        *peer_key = Some(session.peer.public_key.clone());
+        let component = components.pop_front();
+        match component.as_deref() {
+            Some("user") => api::user::routes(req, components, session).await,
+            Some("admin") => api::admin::routes(req, components, session).await,
+            _ => Err(ServerError::NotFound),
+        }
+    }
+}
+
+/// A liveness check for tooling, which also says whether the server is read-only.
+fn healthz(req: &Request<Body>, context: &Context) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::NotFound);
+    }
+    util::json_response(serde_json::json!({
+        "status": "ok",
+        "read-only": context.is_read_only(),
+    }))
+}
+
+fn get_session(
+    req: &Request<Body>,
+    context: Context,
+    addr: IpAddr,
+) -> Result<Session, ServerError> {
+    let pubkey = req
+        .headers()
+        .get(INNERNET_PUBKEY_HEADER)
+        .ok_or(ServerError::Unauthorized)?;
+    let pubkey = pubkey.to_str().map_err(|_| ServerError::Unauthorized)?;
+    let pubkey = Key::from_base64(pubkey).map_err(|_| ServerError::Unauthorized)?;
+    if pubkey
+        .as_bytes()
+        .ct_eq(context.public_key.as_bytes())
+        .into()
+    {
+        let peer = DatabasePeer::get_from_ip(&context.db.lock(), addr).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => ServerError::Unauthorized,
+            e => ServerError::Database(e),
+        })?;
+
+        if !peer.is_disabled {
+            return Ok(Session { context, peer });
+        }
+    }
+
+    Err(ServerError::Unauthorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use anyhow::Result;
+    use hyper::StatusCode;
+    use std::path::Path;
+
+    #[test]
+    fn test_init_wizard() -> Result<(), Error> {
+        // This runs init_wizard().
+        let server = test::Server::new()?;
+
+        assert!(Path::new(&server.wg_conf_path()).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outstanding_invitations() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+        let mut contents = test::developer_peer_contents("invited", "10.80.64.4")?;
+        contents.is_redeemed = false;
+        contents.created_by = Some("admin".into());
+        let invited = DatabasePeer::create(&conn, contents)?;
+        let peers = DatabasePeer::list(&conn)?;
+
+        let invitations = outstanding_invitations(&peers, &HashMap::new());
+        assert_eq!(invitations.len(), 1);
+        assert_eq!(invitations[0].name, invited.name);
+        assert_eq!(invitations[0].created_by.as_deref(), Some("admin"));
+        assert_eq!(invitations[0].last_handshake_secs, None);
+
+        // WireGuard reports a key that never connected as a handshake at the epoch.
+        let mut handshakes = HashMap::new();
+        handshakes.insert(invited.public_key.clone(), SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            outstanding_invitations(&peers, &handshakes)[0].last_handshake_secs,
+            None
+        );
+        handshakes.insert(
+            invited.public_key.clone(),
+            SystemTime::now() - Duration::from_secs(30),
+        );
+        assert!(outstanding_invitations(&peers, &handshakes)[0]
+            .last_handshake_secs
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_bind_addrs() -> Result<(), Error> {
+        let config: ConfigFile = toml::from_str(indoc::indoc! {r#"
+            private-key = "uIHdbFFnK6gaUZ4Wdo4ZLjhHoGk6u8u5JlknEWmXLGk="
+            listen-port = 51820
+            address = "10.42.0.1"
+            network-cidr-prefix = 16
+        "#})?;
+        assert_eq!(config.api_bind_addrs(), vec!["10.42.0.1:51820".parse()?]);
+
+        let config = ConfigFile {
+            api_bind_addr: vec!["10.42.0.1:51820".parse()?, "10.42.0.1:8080".parse()?],
+            ..config
+        };
+        assert_eq!(config.api_bind_addrs(), config.api_bind_addr);
+        assert!(config.validate().is_ok());
+
+        // Nothing from outside the network could be authenticated.
+        let config = ConfigFile {
+            api_bind_addr: vec!["127.0.0.1:51820".parse()?],
+            ..config
+        };
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_waits_for_address() -> Result<(), Error> {
+        let interface: InterfaceName = "innernet-test".parse()?;
+        // An address from TEST-NET-1, which no interface has.
+        let pending = tokio::time::timeout(
+            Duration::from_millis(100),
+            bind_listener("192.0.2.1:0".parse()?, &interface, false),
+        )
+        .await;
+        assert!(pending.is_err());
+
+        assert!(bind_listener("127.0.0.1:0".parse()?, &interface, false)
+            .await
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_effective_config() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let (conf, interface) = (server.conf(), server.context().interface);
+        server.db.lock().execute(
+            "UPDATE cidrs SET default_keepalive = 0, default_mtu = 1380 WHERE id = ?1",
+            [test::DEVELOPER_CIDR_ID],
+        )?;
+
+        let effective = effective_config(&interface, conf)?;
+        assert_eq!(effective.private_key, "(redacted)");
+        assert_eq!(
+            effective.api_bind_addr,
+            vec![SocketAddr::new(effective.address, effective.listen_port)]
+        );
+        let cidr = |id| {
+            let name = &DatabaseCidr::get(&server.db.lock(), id).unwrap().name;
+            effective.cidrs.iter().find(|c| &c.name == name).unwrap()
+        };
+        assert_eq!(
+            cidr(test::ROOT_CIDR_ID).default_keepalive,
+            effective.persistent_keepalive
+        );
+        assert_eq!(cidr(test::DEVELOPER_CIDR_ID).default_keepalive, 0);
+        assert_eq!(cidr(test::DEVELOPER_CIDR_ID).default_mtu, Some(1380));
+
+        let shown = toml::to_string(&effective)?;
+        let config = ConfigFile::from_file(conf.config_path(&interface))?;
+        assert!(!shown.contains(&config.private_key));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_data_permissions() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let server = test::Server::new()?;
+        let (conf, interface) = (server.conf(), server.context().interface);
+        let database_path = conf.database_path(&interface);
+        let set_mode = |path: &Path, mode: u32| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        };
+        set_mode(conf.database_dir(), 0o700)?;
+        set_mode(&database_path, 0o600)?;
+        assert!(check_data_permissions(conf, &interface, false)?.is_empty());
+
+        // A world-readable database is caught, and left alone without --fix-permissions.
+        set_mode(&database_path, 0o644)?;
+        assert_eq!(
+            check_data_permissions(conf, &interface, false)?,
+            vec![database_path.clone()]
+        );
+        assert_eq!(shared::exposed_mode(&database_path)?, Some(0o644));
+
+        check_data_permissions(conf, &interface, true)?;
+        assert_eq!(shared::exposed_mode(&database_path)?, None);
+        assert!(check_data_permissions(conf, &interface, false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_from_env() -> Result<(), Error> {
+        let private_key = wireguard_control::Key::generate_private().to_base64();
+        let mut key_file = tempfile::NamedTempFile::new()?;
+        writeln!(key_file, "{private_key}")?;
+        let key_path = key_file.path().to_string_lossy().to_string();
+        let from_env = |vars: &[(&str, &str)]| {
+            let vars: HashMap<_, _> = vars.iter().copied().collect();
+            ConfigFile::from_env(|name| vars.get(name).map(|value| value.to_string()))
+        };
+
+        assert!(from_env(&[])?.is_none());
+
+        // Secrets can come from a file, and unset values get the same defaults as `new`.
+        let config = from_env(&[
+            ("INNERNET_SERVER_PRIVATE_KEY_FILE", &key_path),
+            ("INNERNET_SERVER_NETWORK_CIDR", "10.42.0.1/16"),
+        ])?
+        .unwrap();
+        assert_eq!(config.private_key, private_key);
+        assert_eq!(config.address, "10.42.0.1".parse::<IpAddr>()?);
+        assert_eq!(config.network_cidr_prefix, 16);
+        assert_eq!(config.listen_port, 51820);
+        assert!(config.preshared_keys);
+
+        let config = from_env(&[
+            ("INNERNET_SERVER_PRIVATE_KEY", &private_key),
+            ("INNERNET_SERVER_NETWORK_CIDR", "fd00:1337::1/64"),
+            ("INNERNET_SERVER_LISTEN_PORT", "51821"),
+            ("INNERNET_SERVER_PRESHARED_KEYS", "false"),
+        ])?
+        .unwrap();
+        assert_eq!(config.listen_port, 51821);
+        assert!(!config.preshared_keys);
+
+        // A partial config, or a value given both ways, is refused.
+        assert!(from_env(&[("INNERNET_SERVER_LISTEN_PORT", "51821")]).is_err());
+        assert!(from_env(&[
+            ("INNERNET_SERVER_PRIVATE_KEY", &private_key),
+            ("INNERNET_SERVER_PRIVATE_KEY_FILE", &key_path),
+            ("INNERNET_SERVER_NETWORK_CIDR", "10.42.0.1/16"),
+        ])
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_session_disguised_with_headers() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let path = if cfg!(feature = "v6-test") {
+            format!("http://[{}]/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        } else {
+            format!("http://{}/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        };
+        let req = Request::builder()
+            .uri(path)
+            .header("Forwarded", format!("for={}", test::ADMIN_PEER_IP))
+            .header("X-Forwarded-For", test::ADMIN_PEER_IP)
+            .header("X-Real-IP", test::ADMIN_PEER_IP)
+            .body(Body::empty())
+            .unwrap();
+
+        // Request from an unknown IP, trying to disguise as an admin using HTTP headers.
+        let res = if cfg!(feature = "v6-test") {
+            server.raw_request("fd00:1337::1337", req).await
+        } else {
+            server.raw_request("10.80.80.80", req).await
+        };
+
+        // addr::remote() filter only look at remote_addr from TCP socket.
+        // HTTP headers are not considered. This also means that innernet
+        // server would not function behind an HTTP proxy.
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incorrect_public_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let key = Key::generate_private().get_public();
+
+        let path = if cfg!(feature = "v6-test") {
+            format!("http://[{}]/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        } else {
+            format!("http://{}/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        };
+        // Request from an unknown IP, trying to disguise as an admin using HTTP headers.
+        let req = Request::builder()
+            .uri(path)
+            .header(shared::INNERNET_PUBKEY_HEADER, key.to_base64())
+            .body(Body::empty())
+            .unwrap();
+        let res = if cfg!(feature = "v6-test") {
+            server.raw_request("fd00:1337::1337", req).await
+        } else {
+            server.raw_request("10.80.80.80", req).await
+        };
+
+        // addr::remote() filter only look at remote_addr from TCP socket.
+        // HTTP headers are not considered. This also means that innernet
+        // server would not function behind an HTTP proxy.
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_public_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let path = if cfg!(feature = "v6-test") {
+            format!("http://[{}]/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        } else {
+            format!("http://{}/v1/admin/peers", test::WG_MANAGE_PEER_IP)
+        };
+        let req = Request::builder()
+            .uri(path)
+            .header(shared::INNERNET_PUBKEY_HEADER, "!!!")
+            .body(Body::empty())
+            .unwrap();
+        let res = if cfg!(feature = "v6-test") {
+            server.raw_request("fd00:1337::1337", req).await
+        } else {
+            server.raw_request("10.80.80.80", req).await
+        };
+
+        // addr::remote() filter only look at remote_addr from TCP socket.
+        // HTTP headers are not considered. This also means that innernet
+        // server would not function behind an HTTP proxy.
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Caps on how many peers a network, or one of its CIDRs, may hold.
+
+use crate::{db::DatabaseCidr, ServerError};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use shared::PeerContents;
+
+/// Which peers count toward a limit.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimitPolicy {
+    /// Every peer, including disabled and staged ones, which still hold their IPs.
+    #[default]
+    All,
+    /// Only enabled peers, so disabling one frees up its slot.
+    Enabled,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerLimits {
+    /// The most peers the network may hold (including the server), for CIDRs without a
+    /// `max_peers` of their own.
+    pub max_peers: Option<u32>,
+    pub policy: LimitPolicy,
+}
+
+impl PeerLimits {
+    /// Refuse to create a peer with `contents` if it would go over a limit.
+    pub fn check_new(&self, conn: &Connection, contents: &PeerContents) -> Result<(), ServerError> {
+        if self.counts(contents.is_disabled) {
+            self.check(conn, contents.cidr_id)?;
+        }
+        Ok(())
+    }
+
+    /// Refuse to update a peer from `old` to `new` if it would go over a limit, which only
+    /// enabling a peer can when disabled ones don't count.
+    pub fn check_update(
+        &self,
+        conn: &Connection,
+        old: &PeerContents,
+        new: &PeerContents,
+    ) -> Result<(), ServerError> {
+        if !self.counts(old.is_disabled) && self.counts(new.is_disabled) {
+            self.check(conn, new.cidr_id)?;
+        }
+        Ok(())
+    }
+
+    fn counts(&self, is_disabled: bool) -> bool {
+        self.policy == LimitPolicy::All || !is_disabled
+    }
+
+    /// Refuse to add another peer that counts toward limits to the CIDR with id `cidr_id`.
+    /// The CIDR's own limit takes precedence over the network's.
+    fn check(&self, conn: &Connection, cidr_id: i64) -> Result<(), ServerError> {
+        let cidr = DatabaseCidr::get(conn, cidr_id)?;
+        let (limit, scope) = match (cidr.max_peers, self.max_peers) {
+            (Some(limit), _) => (limit, format!("CIDR {}", cidr.name)),
+            (None, Some(limit)) => (limit, "the network".to_string()),
+            (None, None) => return Ok(()),
+        };
+        let count: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM peers WHERE (?1 IS NULL OR cidr_id = ?1) AND (?2 OR NOT is_disabled)",
+            params![
+                cidr.max_peers.map(|_| cidr_id),
+                self.policy == LimitPolicy::All
+            ],
+            |row| row.get(0),
+        )?;
+        if count >= limit {
+            log::warn!(
+                "tried to add a peer to {}, which is at its limit of {}.",
+                scope,
+                limit
+            );
+            return Err(ServerError::PeerLimit(format!(
+                "{scope} already has {count} of its {limit} peers"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::DatabasePeer, test};
+    use anyhow::Result;
+
+    #[test]
+    fn test_peer_limits() -> Result<()> {
+        let server = test::Server::new()?;
+        let conn = server.db.lock();
+        let peers = DatabasePeer::list(&conn)?.len() as u32;
+        let developers = DatabasePeer::list(&conn)?
+            .iter()
+            .filter(|peer| peer.cidr_id == test::DEVELOPER_CIDR_ID)
+            .count() as u32;
+
+        // The Nth peer fits, the N+1th doesn't.
+        let limits = PeerLimits {
+            max_peers: Some(peers + 1),
+            policy: LimitPolicy::All,
+        };
+        limits.check(&conn, test::DEVELOPER_CIDR_ID)?;
+        let ip = if cfg!(feature = "v6-test") {
+            "fd00:1337::2:0:0:3"
+        } else {
+            "10.80.64.4"
+        };
+        DatabasePeer::create(&conn, test::developer_peer_contents("developer3", ip)?)?;
+        assert!(matches!(
+            limits.check(&conn, test::DEVELOPER_CIDR_ID),
+            Err(ServerError::PeerLimit(_))
+        ));
+
+        // A CIDR's own limit takes precedence, in either direction.
+        conn.execute(
+            "UPDATE cidrs SET max_peers = ?1 WHERE id = ?2",
+            params![developers + 2, test::DEVELOPER_CIDR_ID],
+        )?;
+        limits.check(&conn, test::DEVELOPER_CIDR_ID)?;
+        assert!(limits.check(&conn, test::USER_CIDR_ID).is_err());
+        conn.execute(
+            "UPDATE cidrs SET max_peers = ?1 WHERE id = ?2",
+            params![developers + 1, test::DEVELOPER_CIDR_ID],
+        )?;
+        assert!(limits.check(&conn, test::DEVELOPER_CIDR_ID).is_err());
+
+        // Disabled peers only count if the policy says so.
+        conn.execute(
+            "UPDATE peers SET is_disabled = 1 WHERE cidr_id = ?1",
+            params![test::DEVELOPER_CIDR_ID],
+        )?;
+        assert!(limits.check(&conn, test::DEVELOPER_CIDR_ID).is_err());
+        let limits = PeerLimits {
+            policy: LimitPolicy::Enabled,
+            ..limits
+        };
+        limits.check(&conn, test::DEVELOPER_CIDR_ID)?;
+        assert!(!limits.counts(true));
+        Ok(())
+    }
+}
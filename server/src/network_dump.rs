@@ -0,0 +1,361 @@
+//! A whole network's definition in one portable file, for backups and for moving a network
+//! to another host.
+//!
+//! The dump has every CIDR, peer, and association, along with the preshared keys the server
+//! holds for each link. Peers keep their public keys and IPs, so existing clients keep
+//! working against the new host as long as the server's own config file (which holds its
+//! private key) moves along with it.
+
+use crate::{
+    db::{
+        peer::SERVER_PEER_ID, DatabaseAssociation, DatabaseCidr, DatabasePeer, DatabasePresharedKey,
+    },
+    ServerError,
+};
+use anyhow::{anyhow, bail};
+use colored::*;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use shared::{
+    Association, AssociationContents, Cidr, CidrContents, Error, IoErrorContext, Peer, PeerContents,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+/// Bumped whenever the dump's layout changes in a way older servers can't read.
+const DUMP_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PresharedKeyEntry {
+    pub peer_id_1: i64,
+    pub peer_id_2: i64,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NetworkDump {
+    pub version: u32,
+    pub cidrs: Vec<Cidr>,
+    pub peers: Vec<Peer>,
+    pub associations: Vec<Association>,
+    #[serde(default)]
+    pub preshared_keys: Vec<PresharedKeyEntry>,
+}
+
+/// How many of each thing an import created.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub cidrs: usize,
+    pub peers: usize,
+    pub associations: usize,
+}
+
+impl NetworkDump {
+    pub fn read(conn: &Connection) -> Result<Self, ServerError> {
+        Ok(Self {
+            version: DUMP_VERSION,
+            cidrs: DatabaseCidr::list(conn)?,
+            peers: DatabasePeer::list(conn)?
+                .into_iter()
+                .map(|dp| dp.inner)
+                .collect(),
+            associations: DatabaseAssociation::list(conn)?,
+            preshared_keys: DatabasePresharedKey::list(conn)?
+                .into_iter()
+                .map(|(peer_id_1, peer_id_2, key)| PresharedKeyEntry {
+                    peer_id_1,
+                    peer_id_2,
+                    key,
+                })
+                .collect(),
+        })
+    }
+
+    /// Write the dump as JSON. It holds preshared keys, so only the owner can read it.
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut file = File::create(&path).with_path(&path)?;
+        shared::chmod(&file, 0o600)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())
+            .with_path(path)?;
+        Ok(())
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let dump: Self = serde_json::from_str(&fs::read_to_string(path).with_path(path)?)?;
+        if dump.version != DUMP_VERSION {
+            bail!(
+                "{} is a version {} network dump, but only version {} is supported.",
+                path.display(),
+                dump.version,
+                DUMP_VERSION
+            );
+        }
+        Ok(dump)
+    }
+
+    /// Recreate the dump's CIDRs, peers, and associations in the database.
+    ///
+    /// CIDRs and peers that already exist (the same range, or the same public key) are
+    /// reused rather than duplicated. A network that has peers besides the server is only
+    /// imported into with `merge`. CIDRs can't be nested deeper than `max_depth`.
+    pub fn import(
+        &self,
+        conn: &mut Connection,
+        merge: bool,
+        max_depth: u8,
+    ) -> Result<ImportSummary, Error> {
+        let tx = conn.transaction()?;
+        let existing_peers = DatabasePeer::list(&tx)?;
+        if !merge && existing_peers.iter().any(|peer| peer.id != SERVER_PEER_ID) {
+            bail!("the network already has peers, use --merge to import into it anyway.");
+        }
+
+        let mut summary = ImportSummary::default();
+
+        // Parents always have shorter prefixes than their children, so they get created
+        // first.
+        let mut cidrs = self.cidrs.iter().collect::<Vec<_>>();
+        cidrs.sort_by_key(|cidr| cidr.cidr.prefix_len());
+        let mut cidr_ids = HashMap::new();
+        for cidr in cidrs {
+            let existing = DatabaseCidr::list(&tx)?
+                .into_iter()
+                .find(|existing| existing.cidr == cidr.cidr);
+            let id = match (existing, cidr.parent) {
+                (Some(existing), _) => existing.id,
+                (None, None) => bail!(
+                    "the dump's network range {} doesn't match this network's.",
+                    cidr.cidr
+                ),
+                (None, Some(parent)) => {
+                    let parent = *cidr_ids
+                        .get(&parent)
+                        .ok_or_else(|| anyhow!("CIDR {} has an unknown parent.", cidr.name))?;
+                    summary.cidrs += 1;
+                    DatabaseCidr::create(
+                        &tx,
+                        CidrContents {
+                            parent: Some(parent),
+                            ..cidr.contents.clone()
+                        },
+                        max_depth,
+                    )
+                    .map_err(|e| anyhow!("couldn't create CIDR {}: {}", cidr.name, e))?
+                    .id
+                },
+            };
+            cidr_ids.insert(cidr.id, id);
+        }
+
+        let mut peer_ids = HashMap::new();
+        for peer in &self.peers {
+            if peer.id == SERVER_PEER_ID {
+                let server = DatabasePeer::get(&tx, SERVER_PEER_ID)?;
+                if server.public_key != peer.public_key {
+                    println!(
+                        "{} the dump's server key doesn't match this server's, so clients won't \
+                         connect until its config file is copied over as well.",
+                        "[!]".yellow()
+                    );
+                }
+                peer_ids.insert(peer.id, SERVER_PEER_ID);
+                continue;
+            }
+            if let Some(existing) = existing_peers
+                .iter()
+                .find(|existing| existing.public_key == peer.public_key)
+            {
+                peer_ids.insert(peer.id, existing.id);
+                continue;
+            }
+            if let Some(existing) = existing_peers
+                .iter()
+                .find(|existing| existing.name == peer.name || existing.ip == peer.ip)
+            {
+                bail!(
+                    "peer {} ({}) conflicts with existing peer {} ({}).",
+                    peer.name,
+                    peer.ip,
+                    existing.name,
+                    existing.ip
+                );
+            }
+
+            let cidr_id = *cidr_ids
+                .get(&peer.cidr_id)
+                .ok_or_else(|| anyhow!("peer {} has an unknown CIDR.", peer.name))?;
+            let mut created = DatabasePeer::create(
+                &tx,
+                PeerContents {
+                    cidr_id,
+                    preshared_key: None,
+                    last_handshake: None,
+                    ..peer.contents.clone()
+                },
+            )
+            .map_err(|e| anyhow!("couldn't create peer {}: {}", peer.name, e))?;
+            if created.persistent_keepalive_interval != peer.persistent_keepalive_interval {
+                created.set_persistent_keepalive(
+                    &tx,
+                    Some(peer.persistent_keepalive_interval.unwrap_or(0)),
+                )?;
+            }
+            summary.peers += 1;
+            peer_ids.insert(peer.id, created.id);
+        }
+
+        let existing_associations = DatabaseAssociation::list(&tx)?;
+        for association in &self.associations {
+            let (Some(&cidr_id_1), Some(&cidr_id_2)) = (
+                cidr_ids.get(&association.cidr_id_1),
+                cidr_ids.get(&association.cidr_id_2),
+            ) else {
+                bail!("association {} has an unknown CIDR.", association.id);
+            };
+            let exists = existing_associations.iter().any(|existing| {
+                (existing.cidr_id_1, existing.cidr_id_2) == (cidr_id_1, cidr_id_2)
+                    || (existing.cidr_id_1, existing.cidr_id_2) == (cidr_id_2, cidr_id_1)
+            });
+            if !exists {
+                DatabaseAssociation::create(
+                    &tx,
+                    AssociationContents {
+                        cidr_id_1,
+                        cidr_id_2,
+                    },
+                    max_depth,
+                )?;
+                summary.associations += 1;
+            }
+        }
+
+        for entry in &self.preshared_keys {
+            if let (Some(&peer_a), Some(&peer_b)) = (
+                peer_ids.get(&entry.peer_id_1),
+                peer_ids.get(&entry.peer_id_2),
+            ) {
+                DatabasePresharedKey::insert(&tx, peer_a, peer_b, &entry.key)?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{self, cidr::DEFAULT_MAX_CIDR_DEPTH},
+        test,
+    };
+    use rusqlite::params;
+    use wireguard_control::KeyPair;
+
+    /// A database like the one `innernet-server new` makes, with its own server key.
+    fn fresh_database() -> Result<Connection, Error> {
+        let conn = Connection::open_in_memory()?;
+        conn.pragma_update(None, "foreign_keys", 1)?;
+        for sql in [
+            db::peer::CREATE_TABLE_SQL,
+            db::association::CREATE_TABLE_SQL,
+            db::cidr::CREATE_TABLE_SQL,
+            db::preshared_key::CREATE_TABLE_SQL,
+            db::revoked_key::CREATE_TABLE_SQL,
+            db::usage::CREATE_TABLE_SQL,
+            db::usage::CREATE_COUNTERS_TABLE_SQL,
+        ] {
+            conn.execute(sql, params![])?;
+        }
+        DatabaseCidr::create(
+            &conn,
+            CidrContents {
+                name: "test".into(),
+                cidr: test::ROOT_CIDR.parse()?,
+                parent: None,
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
+            },
+            DEFAULT_MAX_CIDR_DEPTH,
+        )?;
+        test::create_cidr(&conn, "innernet-server", test::SERVER_CIDR)?;
+        DatabasePeer::create(
+            &conn,
+            PeerContents {
+                public_key: KeyPair::generate().public.to_base64(),
+                ..test::infra_peer_contents(
+                    "innernet-server",
+                    test::SERVER_CIDR.split('/').next().unwrap(),
+                )?
+            },
+        )?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_export_import() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let dump = {
+            let conn = server.db();
+            let conn = conn.lock();
+            DatabaseAssociation::create(
+                &conn,
+                AssociationContents {
+                    cidr_id_1: test::DEVELOPER_CIDR_ID,
+                    cidr_id_2: test::USER_CIDR_ID,
+                },
+                DEFAULT_MAX_CIDR_DEPTH,
+            )?;
+            DatabasePresharedKey::enable(&conn, test::USER1_PEER_ID)?;
+            NetworkDump::read(&conn)?
+        };
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("network.json");
+        dump.write_to_path(&path)?;
+        let dump = NetworkDump::from_file(&path)?;
+
+        let mut conn = fresh_database()?;
+        let summary = dump.import(&mut conn, false, DEFAULT_MAX_CIDR_DEPTH)?;
+        assert_eq!(
+            summary,
+            ImportSummary {
+                cidrs: 3,
+                peers: 5,
+                associations: 1
+            }
+        );
+
+        // Keys and IPs carry over, even though the peers' IDs may not.
+        let imported = NetworkDump::read(&conn)?;
+        for peer in dump.peers.iter().filter(|peer| peer.id != SERVER_PEER_ID) {
+            let copy = imported
+                .peers
+                .iter()
+                .find(|copy| copy.public_key == peer.public_key)
+                .unwrap();
+            assert_eq!((&copy.name, copy.ip), (&peer.name, peer.ip));
+        }
+        assert_eq!(imported.preshared_keys.len(), 1);
+        assert_eq!(imported.preshared_keys[0].key, dump.preshared_keys[0].key);
+
+        // Now that the network has peers, importing again needs --merge, which skips
+        // everything that's already there.
+        assert!(dump
+            .import(&mut conn, false, DEFAULT_MAX_CIDR_DEPTH)
+            .is_err());
+        assert_eq!(
+            dump.import(&mut conn, true, DEFAULT_MAX_CIDR_DEPTH)?,
+            ImportSummary::default()
+        );
+        Ok(())
+    }
+}
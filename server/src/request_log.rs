@@ -0,0 +1,280 @@
+//! Logging of API requests, and the per-endpoint latencies behind the admin metrics
+//! endpoint.
+//!
+//! Clients poll the API constantly, so successful requests can be sampled down to one in
+//! every N. Failed requests are always logged. Peers are identified by a truncated public
+//! key, which is enough to tell them apart without filling the log with full keys. The key
+//! comes from the request's session, so logging a request never touches the database.
+
+use clap::Args;
+use log::LevelFilter;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How many of an endpoint's most recent requests its latency histogram covers.
+const LATENCY_WINDOW: usize = 1024;
+
+/// The upper bounds (in milliseconds) of the latency histogram's buckets. Anything slower
+/// than the last one lands in a final, unbounded bucket.
+const LATENCY_BUCKETS_MS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// How much of a peer's public key to log by default.
+const TRUNCATED_KEY_LEN: usize = 8;
+
+/// The API's endpoints, which are the only ones with latencies of their own. Requests for
+/// anything else count towards [`OTHER_ENDPOINT`], so the latencies can't grow without bound
+/// no matter what paths are requested.
+const KNOWN_ENDPOINTS: &[&str] = &[
+    "GET /healthz",
+    "GET /v1/user/state",
+    "POST /v1/user/redeem",
+    "PUT /v1/user/endpoint",
+    "POST /v1/user/rotate-preshared-key",
+    "PUT /v1/user/candidates",
+    "PUT /v1/user/description",
+    "GET /v1/admin/associations",
+    "POST /v1/admin/associations",
+    "DELETE /v1/admin/associations/{id}",
+    "GET /v1/admin/cidrs",
+    "POST /v1/admin/cidrs",
+    "PUT /v1/admin/cidrs/{id}",
+    "DELETE /v1/admin/cidrs/{id}",
+    "GET /v1/admin/maintenance",
+    "PUT /v1/admin/maintenance",
+    "GET /v1/admin/metrics",
+    "GET /v1/admin/peers",
+    "POST /v1/admin/peers",
+    "PUT /v1/admin/peers/{id}",
+    "PUT /v1/admin/peers/{id}/keepalive",
+    "DELETE /v1/admin/peers/{id}",
+];
+
+/// Where the latencies of requests for unknown endpoints go.
+const OTHER_ENDPOINT: &str = "other";
+
+#[derive(Debug, Clone, Args)]
+pub struct RequestLogOpts {
+    /// The level API requests are logged at ('off' to not log them at all)
+    #[clap(long, default_value = "debug")]
+    pub request_log_level: LevelFilter,
+
+    /// Only log one in every N successful requests. Failed requests are always logged
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_log_sample: u64,
+
+    /// Log peers' full public keys instead of a truncated prefix
+    #[clap(long)]
+    pub request_log_full_keys: bool,
+}
+
+impl Default for RequestLogOpts {
+    fn default() -> Self {
+        Self {
+            request_log_level: LevelFilter::Debug,
+            request_log_sample: 1,
+            request_log_full_keys: false,
+        }
+    }
+}
+
+/// One endpoint's latency over its recent requests.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    /// The number of requests at or under each bucket's bound, keyed by the bound in
+    /// milliseconds ("+Inf" for the last one), like a Prometheus histogram.
+    pub buckets: BTreeMap<String, usize>,
+}
+
+pub struct RequestLog {
+    opts: RequestLogOpts,
+    successes: AtomicU64,
+    latencies: Mutex<HashMap<&'static str, VecDeque<Duration>>>,
+}
+
+impl RequestLog {
+    pub fn new(opts: RequestLogOpts) -> Self {
+        Self {
+            opts,
+            successes: AtomicU64::new(0),
+            latencies: Mutex::default(),
+        }
+    }
+
+    /// Record a finished request from the peer with `peer_key` (if it got that far), and log
+    /// it unless it's a successful one that's sampled out.
+    pub fn record(
+        &self,
+        method: &str,
+        path: &str,
+        peer_key: Option<&str>,
+        status: u16,
+        duration: Duration,
+    ) {
+        {
+            let mut latencies = self.latencies.lock();
+            let window = latencies.entry(endpoint(method, path)).or_default();
+            if window.len() == LATENCY_WINDOW {
+                window.pop_front();
+            }
+            window.push_back(duration);
+        }
+
+        let Some(level) = self.opts.request_log_level.to_level() else {
+            return;
+        };
+        if status < 400
+            && !self
+                .successes
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.opts.request_log_sample)
+        {
+            return;
+        }
+        let peer = match peer_key {
+            Some(key) if !self.opts.request_log_full_keys => truncate_key(key),
+            Some(key) => key,
+            None => "-",
+        };
+        log::log!(
+            level,
+            "request method={} path={} peer={} status={} duration_ms={:.2}",
+            method,
+            path,
+            peer,
+            status,
+            duration.as_secs_f64() * 1000.0
+        );
+    }
+
+    /// Summarize every endpoint's latency, keyed by method and endpoint
+    /// (ex. "GET /v1/admin/peers/{id}").
+    pub fn summary(&self) -> BTreeMap<String, LatencySummary> {
+        self.latencies
+            .lock()
+            .iter()
+            .map(|(endpoint, window)| (endpoint.to_string(), summarize(window)))
+            .collect()
+    }
+}
+
+/// The known endpoint a request is for, with IDs in its path replaced so that requests for
+/// different peers or CIDRs count towards the same one, or [`OTHER_ENDPOINT`].
+fn endpoint(method: &str, path: &str) -> &'static str {
+    let path = path
+        .split('/')
+        .map(|component| {
+            if !component.is_empty() && component.chars().all(|c| c.is_ascii_digit()) {
+                "{id}"
+            } else {
+                component
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    let endpoint = format!("{method} {path}");
+    KNOWN_ENDPOINTS
+        .iter()
+        .find(|known| **known == endpoint)
+        .copied()
+        .unwrap_or(OTHER_ENDPOINT)
+}
+
+fn truncate_key(key: &str) -> &str {
+    key.get(..TRUNCATED_KEY_LEN).unwrap_or(key)
+}
+
+fn summarize(window: &VecDeque<Duration>) -> LatencySummary {
+    let mut millis = window
+        .iter()
+        .map(|duration| duration.as_secs_f64() * 1000.0)
+        .collect::<Vec<_>>();
+    millis.sort_by(f64::total_cmp);
+    let percentile = |p: usize| {
+        if millis.is_empty() {
+            0.0
+        } else {
+            millis[(millis.len() * p).div_ceil(100).max(1) - 1]
+        }
+    };
+
+    let mut buckets = BTreeMap::new();
+    for bound in LATENCY_BUCKETS_MS {
+        let count = millis.iter().filter(|ms| **ms <= *bound).count();
+        buckets.insert(format!("{bound}"), count);
+    }
+    buckets.insert("+Inf".into(), millis.len());
+
+    LatencySummary {
+        count: millis.len(),
+        p50_ms: percentile(50),
+        p95_ms: percentile(95),
+        p99_ms: percentile(99),
+        max_ms: millis.last().copied().unwrap_or_default(),
+        buckets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            endpoint("DELETE", "/v1/admin/peers/12"),
+            "DELETE /v1/admin/peers/{id}"
+        );
+        assert_eq!(
+            endpoint("PUT", "/v1/admin/peers/3/keepalive"),
+            "PUT /v1/admin/peers/{id}/keepalive"
+        );
+        assert_eq!(endpoint("GET", "/v1/user/state"), "GET /v1/user/state");
+        // Anything the API doesn't serve is lumped together.
+        assert_eq!(endpoint("GET", "/v1/user/nope"), OTHER_ENDPOINT);
+        assert_eq!(endpoint("BREW", "/v1/user/state"), OTHER_ENDPOINT);
+    }
+
+    #[test]
+    fn test_truncate_key() {
+        let key = "J3Jv1OTl46Ne5jY4ylU+xoh7jB2UOxvMm0eWSjF1zHc=";
+        assert_eq!(truncate_key(key), "J3Jv1OTl");
+        assert_eq!(truncate_key("short"), "short");
+    }
+
+    #[test]
+    fn test_latency_window() {
+        let log = RequestLog::new(RequestLogOpts {
+            request_log_level: LevelFilter::Off,
+            ..Default::default()
+        });
+        for ms in 1..=LATENCY_WINDOW as u64 + 100 {
+            log.record(
+                "PUT",
+                &format!("/v1/admin/peers/{}", ms % 7),
+                None,
+                200,
+                Duration::from_millis(ms),
+            );
+        }
+
+        let summary = log.summary();
+        let peers = &summary["PUT /v1/admin/peers/{id}"];
+        // Only the most recent requests are kept, so the first 100 have rolled off.
+        assert_eq!(peers.count, LATENCY_WINDOW);
+        assert_eq!(peers.buckets["+Inf"], LATENCY_WINDOW);
+        assert_eq!(peers.buckets["100"], 0);
+        assert_eq!(peers.buckets["250"], 150);
+        assert_eq!(peers.max_ms, 1124.0);
+        assert_eq!(peers.p50_ms, 612.0);
+    }
+}
@@ -1,15 +1,19 @@
 #![allow(dead_code)]
 use crate::{
-    db::{DatabaseCidr, DatabasePeer},
+    db::{cidr::DEFAULT_MAX_CIDR_DEPTH, DatabaseCidr, DatabasePeer},
     initialize::{init_wizard, InitializeOpts},
-    Context, Db, Endpoints, ServerConfig,
+    request_log::{RequestLog, RequestLogOpts},
+    Context, Db, EndpointCheck, Endpoints, Handshakes, PeerLimits, ReadOnly, ServerConfig,
 };
 use anyhow::anyhow;
 use hyper::{header::HeaderValue, http, Body, Request, Response};
 use parking_lot::{Mutex, RwLock};
 use rusqlite::Connection;
 use serde::Serialize;
-use shared::{Cidr, CidrContents, Error, PeerContents};
+use shared::{
+    allocation::IpAllocation, Cidr, CidrContents, Error, PeerContents,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+};
 use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
 use tempfile::TempDir;
 use wireguard_control::{Backend, InterfaceName, Key, KeyPair};
@@ -71,6 +75,11 @@ pub const USER2_PEER_ID: i64 = 6;
 pub struct Server {
     pub db: Db,
     endpoints: Endpoints,
+    handshakes: Handshakes,
+    request_log: Arc<RequestLog>,
+    read_only: Arc<ReadOnly>,
+    peer_limits: PeerLimits,
+    endpoint_check: EndpointCheck,
     interface: InterfaceName,
     conf: ServerConfig,
     public_key: Key,
@@ -90,6 +99,7 @@ impl Server {
         let conf = ServerConfig {
             config_dir: test_dir_path.to_path_buf(),
             data_dir: test_dir_path.to_path_buf(),
+            dry_run: false,
         };
 
         let opts = InitializeOpts {
@@ -98,6 +108,8 @@ impl Server {
             external_endpoint: Some("155.155.155.155:54321".parse().unwrap()),
             listen_port: Some(54321),
             auto_external_endpoint: false,
+            server_ip: None,
+            allocation_range: None,
         };
         init_wizard(&conf, opts).map_err(|_| anyhow!("init_wizard failed"))?;
 
@@ -142,27 +154,59 @@ impl Server {
 
         let db = Arc::new(Mutex::new(db));
         let endpoints = Arc::new(RwLock::new(HashMap::new()));
+        let handshakes = Arc::new(RwLock::new(HashMap::new()));
+        let read_only = Arc::new(ReadOnly::new(false, Some(conf.read_only_path(&interface)))?);
 
         Ok(Self {
             conf,
             db,
             endpoints,
+            handshakes,
+            request_log: Arc::new(RequestLog::new(RequestLogOpts::default())),
+            read_only,
+            peer_limits: PeerLimits::default(),
+            endpoint_check: EndpointCheck::Off,
             interface,
             public_key,
             _test_dir: test_dir,
         })
     }
 
+    pub fn set_peer_limits(&mut self, peer_limits: PeerLimits) {
+        self.peer_limits = peer_limits;
+    }
+
+    pub fn set_endpoint_check(&mut self, endpoint_check: EndpointCheck) {
+        self.endpoint_check = endpoint_check;
+    }
+
     pub fn db(&self) -> Arc<Mutex<Connection>> {
         self.db.clone()
     }
 
+    pub fn endpoints(&self) -> Endpoints {
+        self.endpoints.clone()
+    }
+
+    pub fn handshakes(&self) -> Handshakes {
+        self.handshakes.clone()
+    }
+
     pub fn context(&self) -> Context {
         Context {
             db: self.db.clone(),
             interface: self.interface,
             endpoints: self.endpoints.clone(),
+            handshakes: self.handshakes.clone(),
             public_key: self.public_key.clone(),
+            preshared_keys: true,
+            persistent_keepalive: None,
+            peer_limits: self.peer_limits,
+            max_cidr_depth: DEFAULT_MAX_CIDR_DEPTH,
+            ip_allocation: IpAllocation::LowestFree,
+            endpoint_check: self.endpoint_check,
+            request_log: self.request_log.clone(),
+            read_only: self.read_only.clone(),
             #[cfg(target_os = "linux")]
             backend: Backend::Kernel,
             #[cfg(not(target_os = "linux"))]
@@ -170,6 +214,10 @@ impl Server {
         }
     }
 
+    pub fn conf(&self) -> &ServerConfig {
+        &self.conf
+    }
+
     pub fn wg_conf_path(&self) -> PathBuf {
         self.conf.config_path(&self.interface)
     }
@@ -185,7 +233,7 @@ impl Server {
         .unwrap()
     }
 
-    fn base_request_builder(&self, verb: &str, path: &str) -> http::request::Builder {
+    pub fn base_request_builder(&self, verb: &str, path: &str) -> http::request::Builder {
         let path = if cfg!(feature = "v6-test") {
             format!("http://[{WG_MANAGE_PEER_IP}]{path}")
         } else {
@@ -230,7 +278,12 @@ pub fn create_cidr(db: &Connection, name: &str, cidr_str: &str) -> Result<Cidr,
             name: name.to_string(),
             cidr: cidr_str.parse()?,
             parent: Some(ROOT_CIDR_ID),
+            allocation_range: None,
+            default_keepalive: None,
+            default_mtu: None,
+            max_peers: None,
         },
+        DEFAULT_MAX_CIDR_DEPTH,
     )?;
 
     Ok(cidr)
@@ -255,11 +308,20 @@ pub fn peer_contents(
         public_key: public_key.to_base64(),
         is_admin,
         endpoint: None,
-        persistent_keepalive_interval: None,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
         is_disabled: false,
         is_redeemed: true,
         invite_expires: None,
         candidates: vec![],
+        tags: vec![],
+        preshared_key: None,
+        last_handshake: None,
+        advertised_routes: vec![],
+        is_observer: false,
+        is_staged: false,
+        created_at: None,
+        created_by: None,
+        description: None,
     })
 }
 
@@ -0,0 +1,287 @@
+//! A declarative description of a network's CIDRs and associations, which `apply` reconciles
+//! the database against.
+//!
+//! ```toml
+//! [[cidr]]
+//! name = "humans"
+//! cidr = "10.42.1.0/24"
+//!
+//! [[association]]
+//! cidrs = ["humans", "infra"]
+//! ```
+//!
+//! CIDRs are matched by name, and slot into the tree under the smallest CIDR containing
+//! them, so the file doesn't spell out parents. The root CIDR and the server's CIDR exist
+//! from `innernet-server new` on and are always kept, so they don't need to be listed.
+
+use crate::db::{peer::SERVER_PEER_ID, DatabaseAssociation, DatabaseCidr, DatabasePeer};
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use shared::{AssociationContents, Cidr, CidrContents, Error, IoErrorContext};
+use std::{fs, path::Path};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TopologyCidr {
+    pub name: String,
+    pub cidr: IpNet,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TopologyAssociation {
+    pub cidrs: [String; 2],
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Topology {
+    #[serde(default, rename = "cidr")]
+    pub cidrs: Vec<TopologyCidr>,
+    #[serde(default, rename = "association")]
+    pub associations: Vec<TopologyAssociation>,
+}
+
+/// How many of each thing an apply created and removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ApplySummary {
+    pub created_cidrs: usize,
+    pub created_associations: usize,
+    pub deleted_cidrs: usize,
+    pub deleted_associations: usize,
+}
+
+impl ApplySummary {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Topology {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        Ok(toml::from_str(&fs::read_to_string(path).with_path(path)?)?)
+    }
+
+    /// Create the CIDRs and associations that are missing from the database, and with
+    /// `prune`, delete the ones the topology doesn't mention. It all happens in one
+    /// transaction, so a topology that can't be applied leaves the database untouched.
+    /// CIDRs can't be nested deeper than `max_depth`.
+    pub fn apply(
+        &self,
+        conn: &mut Connection,
+        prune: bool,
+        max_depth: u8,
+    ) -> Result<ApplySummary, Error> {
+        let tx = conn.transaction()?;
+        let mut summary = ApplySummary::default();
+        let mut existing = DatabaseCidr::list(&tx)?;
+        let server_cidr_id = DatabasePeer::get(&tx, SERVER_PEER_ID)?.cidr_id;
+
+        // Parents always have shorter prefixes than their children, so they get created
+        // first.
+        let mut wanted = self.cidrs.iter().collect::<Vec<_>>();
+        wanted.sort_by_key(|cidr| cidr.cidr.prefix_len());
+        for cidr in wanted {
+            if let Some(current) = existing.iter().find(|current| current.name == cidr.name) {
+                if current.cidr != cidr.cidr {
+                    bail!(
+                        "CIDR {} is {} on the server, not {}. Delete it first to change its range.",
+                        cidr.name,
+                        current.cidr,
+                        cidr.cidr
+                    );
+                }
+                continue;
+            }
+            if let Some(current) = existing.iter().find(|current| current.cidr == cidr.cidr) {
+                bail!(
+                    "{} is already CIDR {} on the server, rename it to {} first.",
+                    cidr.cidr,
+                    current.name,
+                    cidr.name
+                );
+            }
+            let parent = existing
+                .iter()
+                .filter(|current| current.cidr.contains(&cidr.cidr))
+                .max_by_key(|current| current.cidr.prefix_len())
+                .ok_or_else(|| anyhow!("CIDR {} is outside of the network.", cidr.name))?;
+            let created = DatabaseCidr::create(
+                &tx,
+                CidrContents {
+                    name: cidr.name.clone(),
+                    cidr: cidr.cidr,
+                    parent: Some(parent.id),
+                    allocation_range: None,
+                    default_keepalive: None,
+                    default_mtu: None,
+                    max_peers: None,
+                },
+                max_depth,
+            )
+            .map_err(|e| anyhow!("couldn't create CIDR {}: {}", cidr.name, e))?;
+            existing.push(created);
+            summary.created_cidrs += 1;
+        }
+
+        let find = |name: &str| -> Result<&Cidr, Error> {
+            existing
+                .iter()
+                .find(|cidr| cidr.name == name)
+                .ok_or_else(|| anyhow!("association with unknown CIDR {}.", name))
+        };
+        let mut wanted_pairs = vec![];
+        for association in &self.associations {
+            let [a, b] = &association.cidrs;
+            wanted_pairs.push((find(a)?.id, find(b)?.id));
+        }
+        let is_wanted = |id_1: i64, id_2: i64| {
+            wanted_pairs.contains(&(id_1, id_2)) || wanted_pairs.contains(&(id_2, id_1))
+        };
+
+        let mut associations = DatabaseAssociation::list(&tx)?;
+        for &(cidr_id_1, cidr_id_2) in &wanted_pairs {
+            let exists = associations.iter().any(|current| {
+                (current.cidr_id_1, current.cidr_id_2) == (cidr_id_1, cidr_id_2)
+                    || (current.cidr_id_1, current.cidr_id_2) == (cidr_id_2, cidr_id_1)
+            });
+            if !exists {
+                associations.push(DatabaseAssociation::create(
+                    &tx,
+                    AssociationContents {
+                        cidr_id_1,
+                        cidr_id_2,
+                    },
+                    max_depth,
+                )?);
+                summary.created_associations += 1;
+            }
+        }
+
+        if prune {
+            for association in &associations {
+                if !is_wanted(association.cidr_id_1, association.cidr_id_2) {
+                    DatabaseAssociation::delete(&tx, association.id)?;
+                    summary.deleted_associations += 1;
+                }
+            }
+
+            let peers = DatabasePeer::list(&tx)?;
+            let mut unwanted = existing
+                .iter()
+                .filter(|cidr| {
+                    cidr.parent.is_some()
+                        && cidr.id != server_cidr_id
+                        && !self.cidrs.iter().any(|wanted| wanted.name == cidr.name)
+                })
+                .collect::<Vec<_>>();
+            // Children go before their parents.
+            unwanted.sort_by_key(|cidr| std::cmp::Reverse(cidr.cidr.prefix_len()));
+            for cidr in unwanted {
+                let attached = peers.iter().filter(|peer| peer.cidr_id == cidr.id).count();
+                if attached > 0 {
+                    bail!(
+                        "CIDR {} isn't in the topology, but can't be deleted since it still has {} peer(s).",
+                        cidr.name,
+                        attached
+                    );
+                }
+                if DatabaseCidr::list(&tx)?
+                    .iter()
+                    .any(|child| child.parent == Some(cidr.id))
+                {
+                    bail!(
+                        "CIDR {} isn't in the topology, but can't be deleted since CIDRs in it are.",
+                        cidr.name
+                    );
+                }
+                DatabaseCidr::delete(&tx, cidr.id)?;
+                summary.deleted_cidrs += 1;
+            }
+        }
+
+        tx.commit()?;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::cidr::DEFAULT_MAX_CIDR_DEPTH, test};
+
+    fn topology(extra_cidrs: &[(&str, &str)], associations: &[[&str; 2]]) -> Topology {
+        let mut cidrs = vec![
+            ("admin", test::ADMIN_CIDR),
+            ("developer", test::DEVELOPER_CIDR),
+            ("user", test::USER_CIDR),
+        ];
+        cidrs.extend_from_slice(extra_cidrs);
+        let mut toml = String::new();
+        for (name, cidr) in cidrs {
+            toml += &format!("[[cidr]]\nname = \"{name}\"\ncidr = \"{cidr}\"\n\n");
+        }
+        for [a, b] in associations {
+            toml += &format!("[[association]]\ncidrs = [\"{a}\", \"{b}\"]\n\n");
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let mut conn = db.lock();
+        let experimental = [
+            ("experimental", test::EXPERIMENTAL_CIDR),
+            ("experimental-sub", test::EXPERIMENTAL_SUBCIDR),
+        ];
+        let associations = [["developer", "user"], ["experimental-sub", "admin"]];
+
+        let full = topology(&experimental, &associations);
+        assert_eq!(
+            full.apply(&mut conn, false, DEFAULT_MAX_CIDR_DEPTH)?,
+            ApplySummary {
+                created_cidrs: 2,
+                created_associations: 2,
+                ..Default::default()
+            }
+        );
+        let cidrs = DatabaseCidr::list(&conn)?;
+        let find = |name: &str| cidrs.iter().find(|cidr| cidr.name == name).unwrap();
+        assert_eq!(
+            find("experimental-sub").parent,
+            Some(find("experimental").id)
+        );
+        assert!(full
+            .apply(&mut conn, true, DEFAULT_MAX_CIDR_DEPTH)?
+            .is_empty());
+
+        // The user CIDR still has peers, so pruning it fails without changing anything.
+        let without_user = Topology {
+            cidrs: full
+                .cidrs
+                .into_iter()
+                .filter(|cidr| cidr.name != "user")
+                .collect(),
+            associations: vec![],
+        };
+        assert!(without_user
+            .apply(&mut conn, true, DEFAULT_MAX_CIDR_DEPTH)
+            .is_err());
+        assert_eq!(DatabaseAssociation::list(&conn)?.len(), 2);
+
+        let pruned = topology(&experimental[..1], &associations[..1]);
+        assert_eq!(
+            pruned.apply(&mut conn, true, DEFAULT_MAX_CIDR_DEPTH)?,
+            ApplySummary {
+                deleted_cidrs: 1,
+                deleted_associations: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(DatabaseCidr::list(&conn)?.len(), cidrs.len() - 1);
+        Ok(())
+    }
+}
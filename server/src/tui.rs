@@ -0,0 +1,462 @@
+//! `innernet-server tui`: a full-screen view of a network's peers, with their handshakes
+//! kept up to date, that can also enable, disable, rename, and revoke them. Changes go
+//! through the same functions as the matching subcommands, so they're checked the same way.
+
+use crate::{
+    db::{peer::SERVER_PEER_ID, DatabaseAssociation, DatabaseCidr, DatabasePeer},
+    last_handshakes, open_database_connection, remove_invitation, set_peer_enabled, set_peer_name,
+    ServerConfig,
+};
+use anyhow::bail;
+use console::{measure_text_width, pad_str, style, truncate_str, Alignment, Key, Term};
+use rusqlite::Connection;
+use shared::{wg, Association, Cidr, Error, Hostname, NetworkOpts, Peer};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, SystemTime},
+};
+use wireguard_control::InterfaceName;
+
+/// How often the screen is redrawn with fresh handshakes while no keys are pressed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+const HELP: &str = "/ search  j/k move  e enable  d disable  r rename  x revoke invitation  q quit";
+
+/// A change the user asked for, for [`run`] to carry out.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    SetEnabled(i64, bool),
+    Rename(i64, Hostname),
+    RevokeInvitation(i64),
+    Quit,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Mode {
+    Browse,
+    Search,
+    Rename(String),
+    ConfirmRevoke,
+}
+
+struct App {
+    peers: Vec<Peer>,
+    cidrs: Vec<Cidr>,
+    associations: Vec<Association>,
+    handshakes: HashMap<String, SystemTime>,
+    filter: String,
+    selected: usize,
+    mode: Mode,
+    status: String,
+}
+
+impl App {
+    fn new() -> Self {
+        Self {
+            peers: vec![],
+            cidrs: vec![],
+            associations: vec![],
+            handshakes: HashMap::new(),
+            filter: String::new(),
+            selected: 0,
+            mode: Mode::Browse,
+            status: String::new(),
+        }
+    }
+
+    fn reload(
+        &mut self,
+        conn: &Connection,
+        interface: &InterfaceName,
+        network: NetworkOpts,
+    ) -> Result<(), Error> {
+        self.peers = DatabasePeer::list(conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .collect();
+        self.peers.sort_by_key(|peer| peer.ip);
+        self.cidrs = DatabaseCidr::list(conn)?;
+        self.associations = DatabaseAssociation::list(conn)?;
+        self.handshakes = last_handshakes(interface, network.backend);
+        Ok(())
+    }
+
+    fn cidr_name(&self, id: i64) -> &str {
+        self.cidrs
+            .iter()
+            .find(|cidr| cidr.id == id)
+            .map(|cidr| cidr.name.as_str())
+            .unwrap_or("?")
+    }
+
+    /// The peers matching the search, by name, IP, or CIDR name.
+    fn visible(&self) -> Vec<&Peer> {
+        let filter = self.filter.to_lowercase();
+        self.peers
+            .iter()
+            .filter(|peer| {
+                filter.is_empty()
+                    || peer.name.to_lowercase().contains(&filter)
+                    || peer.ip.to_string().contains(&filter)
+                    || self
+                        .cidr_name(peer.cidr_id)
+                        .to_lowercase()
+                        .contains(&filter)
+            })
+            .collect()
+    }
+
+    fn selected_peer(&self) -> Option<&Peer> {
+        let visible = self.visible();
+        visible
+            .get(self.selected.min(visible.len().saturating_sub(1)))
+            .copied()
+    }
+
+    fn handle_key(&mut self, key: Key) -> Option<Action> {
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => return self.browse(key),
+            Mode::Search => match key {
+                Key::Char(c) => {
+                    self.filter.push(c);
+                    self.selected = 0;
+                    self.mode = Mode::Search;
+                },
+                Key::Backspace => {
+                    self.filter.pop();
+                    self.mode = Mode::Search;
+                },
+                Key::Escape => self.filter.clear(),
+                Key::Enter => {},
+                _ => self.mode = Mode::Search,
+            },
+            Mode::Rename(mut name) => match key {
+                Key::Char(c) => {
+                    name.push(c);
+                    self.mode = Mode::Rename(name);
+                },
+                Key::Backspace => {
+                    name.pop();
+                    self.mode = Mode::Rename(name);
+                },
+                Key::Enter => {
+                    let id = self.selected_peer()?.id;
+                    match name.parse() {
+                        Ok(name) => return Some(Action::Rename(id, name)),
+                        Err(e) => self.status = format!("{name:?}: {e}"),
+                    }
+                },
+                Key::Escape => {},
+                _ => self.mode = Mode::Rename(name),
+            },
+            Mode::ConfirmRevoke => match key {
+                Key::Char('y') => return Some(Action::RevokeInvitation(self.selected_peer()?.id)),
+                _ => self.status = "not revoked.".into(),
+            },
+        }
+        None
+    }
+
+    fn browse(&mut self, key: Key) -> Option<Action> {
+        self.status.clear();
+        match key {
+            Key::ArrowUp | Key::Char('k') => self.selected = self.selected.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => {
+                self.selected = (self.selected + 1).min(self.visible().len().saturating_sub(1))
+            },
+            Key::Char('/') => self.mode = Mode::Search,
+            Key::Escape if !self.filter.is_empty() => self.filter.clear(),
+            Key::Char('q') | Key::Escape => return Some(Action::Quit),
+            Key::Char('e') => return Some(Action::SetEnabled(self.selected_peer()?.id, true)),
+            Key::Char('d') => return Some(Action::SetEnabled(self.selected_peer()?.id, false)),
+            Key::Char('r') => {
+                self.mode = Mode::Rename(self.selected_peer()?.name.to_string());
+            },
+            Key::Char('x') | Key::Del => {
+                self.selected_peer()?;
+                self.mode = Mode::ConfirmRevoke;
+            },
+            _ => {},
+        }
+        None
+    }
+
+    /// The screen's lines, `rows` of at most `cols` characters each.
+    fn render(&self, network_name: &str, rows: usize, cols: usize) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut panel = vec![style("CIDRs").bold().to_string()];
+        for cidr in &self.cidrs {
+            let mut depth = 0;
+            let mut parent = cidr.parent;
+            while let Some(id) = parent.filter(|_| depth < self.cidrs.len()) {
+                depth += 1;
+                parent = self
+                    .cidrs
+                    .iter()
+                    .find(|c| c.id == id)
+                    .and_then(|c| c.parent);
+            }
+            panel.push(format!("{}{} {}", "  ".repeat(depth), cidr.name, cidr.cidr));
+        }
+        panel.push(style("Associations").bold().to_string());
+        for association in &self.associations {
+            panel.push(format!(
+                "  {} <-> {}",
+                self.cidr_name(association.cidr_id_1),
+                self.cidr_name(association.cidr_id_2)
+            ));
+        }
+        if self.associations.is_empty() {
+            panel.push("  none".into());
+        }
+        panel.truncate(rows / 3);
+
+        let visible = self.visible();
+        let height = rows.saturating_sub(panel.len() + 5).max(1);
+        let selected = self.selected.min(visible.len().saturating_sub(1));
+        let offset = selected.saturating_sub(height - 1);
+
+        let mut lines = vec![
+            format!(
+                "{} {} ({} of {} peers)",
+                style("innernet").bold(),
+                network_name,
+                visible.len(),
+                self.peers.len()
+            ),
+            style(HELP).dim().to_string(),
+            String::new(),
+            style(format!(
+                "  {:<24} {:<24} {:<16} {:<10} HANDSHAKE",
+                "NAME", "IP", "CIDR", "STATUS"
+            ))
+            .dim()
+            .to_string(),
+        ];
+        for (i, peer) in visible.iter().enumerate().skip(offset).take(height) {
+            let status = if peer.is_disabled {
+                style("disabled").red()
+            } else if peer.is_staged {
+                style("staged").yellow()
+            } else if !peer.is_redeemed {
+                style("invited").yellow()
+            } else {
+                style("enabled").green()
+            };
+            let handshake =
+                match wg::handshake_age(self.handshakes.get(&peer.public_key).copied(), now) {
+                    Some(age) => format!("{}s ago", age.as_secs()),
+                    None => "never".into(),
+                };
+            let line = format!(
+                "{} {:<24} {:<24} {:<16} {} {}",
+                if i == selected { ">" } else { " " },
+                peer.name.to_string(),
+                peer.ip.to_string(),
+                self.cidr_name(peer.cidr_id),
+                pad_str(&status.to_string(), 10, Alignment::Left, None),
+                handshake
+            );
+            lines.push(if i == selected {
+                style(line).reverse().to_string()
+            } else {
+                line
+            });
+        }
+        lines.resize(rows.saturating_sub(panel.len() + 1), String::new());
+        lines.extend(panel);
+        lines.push(match &self.mode {
+            Mode::Browse if !self.filter.is_empty() && self.status.is_empty() => {
+                format!("search: {}", self.filter)
+            },
+            Mode::Browse => self.status.clone(),
+            Mode::Search => format!("search: {}_", self.filter),
+            Mode::Rename(name) => format!("new name: {name}_"),
+            Mode::ConfirmRevoke => match self.selected_peer() {
+                Some(peer) => format!("revoke the invitation for {}? [y/N]", peer.name),
+                None => String::new(),
+            },
+        });
+        lines
+            .into_iter()
+            .map(|line| truncate_str(&line, cols, "").into_owned())
+            .collect()
+    }
+}
+
+fn apply(
+    action: Action,
+    conn: &Connection,
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+) -> Result<String, Error> {
+    conf.check_writable(interface)?;
+    let peer = match &action {
+        Action::SetEnabled(id, _) | Action::Rename(id, _) | Action::RevokeInvitation(id) => {
+            DatabasePeer::get(conn, *id)?
+        },
+        Action::Quit => unreachable!(),
+    };
+    match action {
+        Action::SetEnabled(_, enable) if peer.is_disabled != enable => Ok(format!(
+            "{} is already {}.",
+            peer.name,
+            if enable { "enabled" } else { "disabled" }
+        )),
+        Action::SetEnabled(_, _) | Action::RevokeInvitation(_) if peer.id == SERVER_PEER_ID => {
+            bail!("the server's own peer can't be disabled, and has no invitation to revoke.")
+        },
+        Action::SetEnabled(_, enable) => {
+            set_peer_enabled(conn, interface, conf, network, &peer, enable)?;
+            Ok(format!(
+                "{} {}.",
+                peer.name,
+                if enable { "enabled" } else { "disabled" }
+            ))
+        },
+        Action::Rename(_, name) => {
+            set_peer_name(conn, &peer, name.clone())?;
+            Ok(format!("{} renamed to {}.", peer.name, name))
+        },
+        Action::RevokeInvitation(_) => {
+            let name = peer.name.clone();
+            remove_invitation(conn, interface, conf, network, peer)?;
+            Ok(format!("invitation for {name} revoked."))
+        },
+        Action::Quit => unreachable!(),
+    }
+}
+
+pub fn run(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        bail!("the TUI needs an interactive terminal, use the other subcommands in scripts.");
+    }
+    let conn = open_database_connection(interface, conf)?;
+    let mut app = App::new();
+    app.reload(&conn, interface, network)?;
+
+    // Keys are read on their own thread so the screen keeps refreshing while none come in.
+    let (keys_tx, keys) = mpsc::channel();
+    thread::spawn(move || {
+        let term = Term::stdout();
+        while let Ok(key) = term.read_key() {
+            if keys_tx.send(key).is_err() {
+                break;
+            }
+        }
+    });
+
+    term.hide_cursor()?;
+    let result = (|| -> Result<(), Error> {
+        loop {
+            let (rows, cols) = term.size();
+            let lines = app.render(&interface.to_string(), rows as usize, cols as usize);
+            term.move_cursor_to(0, 0)?;
+            for (i, line) in lines.iter().enumerate() {
+                term.clear_line()?;
+                term.write_str(line)?;
+                let padding = (cols as usize).saturating_sub(measure_text_width(line));
+                term.write_str(&" ".repeat(padding))?;
+                if i + 1 < lines.len() {
+                    term.write_str("\r\n")?;
+                }
+            }
+
+            match keys.recv_timeout(REFRESH_INTERVAL) {
+                Ok(key) => match app.handle_key(key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(action) => {
+                        app.status = match apply(action, &conn, interface, conf, network) {
+                            Ok(message) => message,
+                            Err(e) => style(format!("error: {e}")).red().to_string(),
+                        };
+                        // Anything the change printed has scrolled the screen.
+                        term.clear_screen()?;
+                    },
+                    None => {},
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            app.reload(&conn, interface, network)?;
+        }
+    })();
+    term.show_cursor()?;
+    term.clear_screen()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use anyhow::Result;
+
+    #[test]
+    fn test_browse_and_edit() -> Result<()> {
+        let server = test::Server::new()?;
+        let mut app = App::new();
+        {
+            let db = server.db();
+            let conn = db.lock();
+            app.peers = DatabasePeer::list(&conn)?
+                .into_iter()
+                .map(|peer| peer.inner)
+                .collect();
+            app.cidrs = DatabaseCidr::list(&conn)?;
+        }
+        let lines = app.render("test", 30, 120);
+        assert_eq!(lines.len(), 30);
+        assert!(lines.iter().all(|line| measure_text_width(line) <= 120));
+
+        // Searching matches on CIDR names too, and narrows what's selectable.
+        for key in "/developer".chars().map(Key::Char).chain([Key::Enter]) {
+            assert_eq!(app.handle_key(key), None);
+        }
+        assert!(app.visible().len() >= 2);
+        assert!(app
+            .visible()
+            .iter()
+            .all(|peer| peer.cidr_id == test::DEVELOPER_CIDR_ID));
+        app.handle_key(Key::ArrowDown);
+        let peer = app.visible()[1].id;
+        assert_eq!(
+            app.handle_key(Key::Char('d')),
+            Some(Action::SetEnabled(peer, false))
+        );
+
+        // Renaming starts from the current name, and only accepts valid hostnames.
+        app.handle_key(Key::Char('r'));
+        for key in [Key::Backspace, Key::Char('_'), Key::Enter] {
+            assert_eq!(app.handle_key(key), None);
+        }
+        assert!(!app.status.is_empty());
+        app.handle_key(Key::Char('r'));
+        app.handle_key(Key::Char('x'));
+        let name = format!("{}x", app.visible()[1].name).parse().unwrap();
+        assert_eq!(app.handle_key(Key::Enter), Some(Action::Rename(peer, name)));
+
+        // Revoking an invitation needs a confirmation.
+        app.handle_key(Key::Char('x'));
+        assert_eq!(app.handle_key(Key::Char('n')), None);
+        app.handle_key(Key::Char('x'));
+        assert_eq!(
+            app.handle_key(Key::Char('y')),
+            Some(Action::RevokeInvitation(peer))
+        );
+
+        // Escape clears the search before it quits.
+        assert_eq!(app.handle_key(Key::Escape), None);
+        assert_eq!(app.visible().len(), app.peers.len());
+        assert_eq!(app.handle_key(Key::Escape), Some(Action::Quit));
+        Ok(())
+    }
+}
@@ -1,9 +1,22 @@
-use bytes::Buf;
-use hyper::{header, Body, Request, Response, StatusCode};
+use bytes::{Buf, Bytes};
+use flate2::{write::GzEncoder, Compression};
+use hyper::{body::Sender, header, Body, Request, Response, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    io::{self, Write},
+};
+use tokio::runtime::Handle;
 
 use crate::ServerError;
 
+/// JSON responses smaller than this aren't worth compressing.
+const GZIP_THRESHOLD: u64 = 4 * 1024;
+
+/// How much compressed output is buffered before it's sent on as a chunk of the body.
+const GZIP_CHUNK_SIZE: usize = 16 * 1024;
+
 pub async fn form_body<F: DeserializeOwned>(req: Request<Body>) -> Result<F, ServerError> {
     let content_len: usize = req
         .headers()
@@ -29,6 +42,138 @@ pub fn json_response<F: Serialize>(form: F) -> Result<Response<Body>, ServerErro
         .body(Body::from(json))?)
 }
 
+/// Whether a request's `Accept-Encoding` header allows a gzipped response.
+pub fn accepts_gzip<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+                && !params.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                })
+        })
+}
+
+/// Like [`json_response`], but gzipped if `gzip` is set and the JSON is large enough for it
+/// to pay off. The body is serialized and compressed on a blocking thread as it's sent, so
+/// only a chunk of it is in memory at a time.
+pub fn compressed_json_response<F: Serialize + Send + 'static>(
+    form: F,
+    gzip: bool,
+) -> Result<Response<Body>, ServerError> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, &form)?;
+    if !gzip || counter.0 < GZIP_THRESHOLD {
+        return json_response(form);
+    }
+
+    let (sender, body) = Body::channel();
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let writer = ChunkWriter {
+            sender,
+            handle,
+            buffer: Vec::with_capacity(GZIP_CHUNK_SIZE),
+        };
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        let result = serde_json::to_writer(&mut encoder, &form)
+            .map_err(io::Error::from)
+            .and_then(|()| encoder.finish())
+            .and_then(|mut writer| writer.flush());
+        if let Err(e) = result {
+            // Usually the client hanging up, which leaves no one to tell.
+            log::debug!("stopped sending a compressed response: {}", e);
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_ENCODING, "gzip")
+        .header(header::VARY, "accept-encoding")
+        .body(body)?)
+}
+
+/// A strong ETag for a response, derived from its JSON.
+pub fn etag<F: Serialize>(form: &F) -> Result<String, ServerError> {
+    let mut hasher = HashWriter(DefaultHasher::new());
+    serde_json::to_writer(&mut hasher, form)?;
+    Ok(format!("\"{:016x}\"", hasher.0.finish()))
+}
+
+/// Whether a request's `If-None-Match` header lists `etag`.
+pub fn matches_etag<B>(req: &Request<B>, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| matches!(tag.trim(), "*") || tag.trim() == etag)
+}
+
+struct HashWriter(DefaultHasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts the bytes written to it, to size a response without building it.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Feeds what's written to it into a response body in chunks, waiting for each one to be
+/// taken before accepting more.
+struct ChunkWriter {
+    sender: Sender,
+    handle: Handle,
+    buffer: Vec<u8>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= GZIP_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(GZIP_CHUNK_SIZE),
+        ));
+        self.handle
+            .block_on(self.sender.send_data(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the response was dropped"))
+    }
+}
+
 pub fn json_status_response<F: Serialize>(
     form: F,
     status: StatusCode,
@@ -43,3 +188,44 @@ pub fn json_status_response<F: Serialize>(
 pub fn status_response(status: StatusCode) -> Result<Response<Body>, ServerError> {
     Ok(Response::builder().status(status).body(Body::empty())?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use shared::Error;
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &str| {
+            accepts_gzip(
+                &Request::builder()
+                    .header(header::ACCEPT_ENCODING, value)
+                    .body(())
+                    .unwrap(),
+            )
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("deflate"));
+        assert!(!accepts_gzip(&Request::new(())));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_json_response() -> Result<(), Error> {
+        let names = (0..1000).map(|i| format!("peer-{i}")).collect::<Vec<_>>();
+
+        let res = compressed_json_response(names.clone(), true)?;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(res).await?;
+        let decoded: Vec<String> = serde_json::from_reader(GzDecoder::new(&body[..]))?;
+        assert_eq!(decoded, names);
+
+        let res = compressed_json_response(names[..10].to_vec(), true)?;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        let res = compressed_json_response(names, false)?;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Picking IPs for new peers.
+//!
+//! New peers get the first free, assignable IP in their CIDR, or with
+//! [`IpAllocation::NameHash`], the first free one from a spot picked by their name. A CIDR
+//! (or a single add-peer) can narrow that down to an allocation range, so that the rest of
+//! the CIDR stays free for addresses handed out by hand.
+
+use crate::{IpNetExt, IpRange};
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AllocationError {
+    /// Every assignable IP in the CIDR is taken.
+    CidrFull(IpNet),
+    /// Every IP in the allocation range is taken, though the CIDR has room outside of it.
+    RangeExhausted(IpRange),
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CidrFull(cidr) => write!(f, "no IPs in {cidr} are available"),
+            Self::RangeExhausted(range) => write!(
+                f,
+                "no IPs in the allocation range {range} are available (the rest of the CIDR \
+                 still has room, pick an IP by hand or widen the range)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AllocationError {}
+
+/// How new peers' IPs are picked when they aren't given one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpAllocation {
+    /// The lowest free IP.
+    #[default]
+    LowestFree,
+    /// Start at an IP derived from a hash of the peer's name and count up from there,
+    /// wrapping around, until a free one turns up. A name gets the same IP every time it's
+    /// free, so re-running provisioning hands out the same addresses.
+    NameHash,
+}
+
+impl IpAllocation {
+    /// Pick an IP for a new peer named `name`, like [`allocate_ip`] does.
+    pub fn allocate(
+        self,
+        name: &str,
+        cidr: &IpNet,
+        range: Option<&IpRange>,
+        taken: &[IpAddr],
+    ) -> Result<IpAddr, AllocationError> {
+        let seed = match self {
+            Self::LowestFree => 0,
+            Self::NameHash => name_hash(name).into(),
+        };
+        allocate(cidr, range, taken, seed)
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `std`'s hashers is guaranteed to stay the same across
+/// releases and platforms.
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip).into(),
+        IpAddr::V6(ip) => ip.into(),
+    }
+}
+
+fn from_u128(n: u128, like: &IpAddr) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => Ipv4Addr::from(n as u32).into(),
+        IpAddr::V6(_) => Ipv6Addr::from(n).into(),
+    }
+}
+
+/// The first assignable IP between `start` and `end` (inclusive) that isn't taken, looking
+/// from `seed` places after `start` (modulo the span) and wrapping around at `end`.
+fn first_free(
+    cidr: &IpNet,
+    start: IpAddr,
+    end: IpAddr,
+    taken: &HashSet<IpAddr>,
+    seed: u128,
+) -> Option<IpAddr> {
+    let start = to_u128(start).max(to_u128(cidr.network()));
+    let end = to_u128(end).min(to_u128(cidr.broadcast()));
+    if start > end {
+        return None;
+    }
+    let span = (end - start).saturating_add(1);
+    let offset = seed % span;
+    // Past the taken IPs and the network and broadcast addresses, every IP is free, so
+    // there's no need to look any further than that (which matters in IPv6 CIDRs).
+    let probes = span.min(taken.len() as u128 + 3);
+    (0..probes)
+        .map(|i| match i.checked_add(offset).filter(|&n| n < span) {
+            Some(n) => start + n,
+            None => start + (i - (span - offset)),
+        })
+        .map(|n| from_u128(n, &cidr.addr()))
+        .find(|ip| cidr.is_assignable(ip) && !taken.contains(ip))
+}
+
+/// Pick the first free IP in `cidr`, or only in `range` if one's given. `taken` is every IP
+/// already in use.
+pub fn allocate_ip(
+    cidr: &IpNet,
+    range: Option<&IpRange>,
+    taken: &[IpAddr],
+) -> Result<IpAddr, AllocationError> {
+    allocate(cidr, range, taken, 0)
+}
+
+fn allocate(
+    cidr: &IpNet,
+    range: Option<&IpRange>,
+    taken: &[IpAddr],
+    seed: u128,
+) -> Result<IpAddr, AllocationError> {
+    let taken = taken.iter().copied().collect::<HashSet<_>>();
+    if let Some(range) = range {
+        if range.start.is_ipv4() == cidr.addr().is_ipv4() {
+            if let Some(ip) = first_free(cidr, range.start, range.end, &taken, seed) {
+                return Ok(ip);
+            }
+        }
+    }
+    match (
+        first_free(cidr, cidr.network(), cidr.broadcast(), &taken, seed),
+        range,
+    ) {
+        (None, _) => Err(AllocationError::CidrFull(*cidr)),
+        (Some(_), Some(range)) => Err(AllocationError::RangeExhausted(*range)),
+        (Some(ip), None) => Ok(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allocate_whole_cidr() {
+        let cidr = "10.42.5.0/24".parse().unwrap();
+        // The network address is never handed out.
+        assert_eq!(allocate_ip(&cidr, None, &[]), Ok(ip("10.42.5.1")));
+        assert_eq!(
+            allocate_ip(&cidr, None, &[ip("10.42.5.1"), ip("10.42.5.3")]),
+            Ok(ip("10.42.5.2"))
+        );
+
+        // Nor is the broadcast address, so .254 is the last one.
+        let taken = (1..=253)
+            .map(|n| ip(&format!("10.42.5.{n}")))
+            .collect::<Vec<_>>();
+        assert_eq!(allocate_ip(&cidr, None, &taken), Ok(ip("10.42.5.254")));
+        let taken = (1..=254)
+            .map(|n| ip(&format!("10.42.5.{n}")))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            allocate_ip(&cidr, None, &taken),
+            Err(AllocationError::CidrFull(cidr))
+        );
+    }
+
+    #[test]
+    fn test_allocate_range_edges() {
+        let cidr = "10.42.5.0/24".parse().unwrap();
+        let reserved = range("10.42.5.100-10.42.5.102");
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &[]),
+            Ok(ip("10.42.5.100"))
+        );
+        // IPs just outside of the range being free doesn't help.
+        let taken = [ip("10.42.5.100"), ip("10.42.5.101")];
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &taken),
+            Ok(ip("10.42.5.102"))
+        );
+        let taken = [ip("10.42.5.100"), ip("10.42.5.101"), ip("10.42.5.102")];
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &taken),
+            Err(AllocationError::RangeExhausted(reserved))
+        );
+
+        // A single-IP range.
+        let single = range("10.42.5.7-10.42.5.7");
+        assert_eq!(allocate_ip(&cidr, Some(&single), &[]), Ok(ip("10.42.5.7")));
+    }
+
+    #[test]
+    fn test_allocate_range_past_cidr() {
+        let cidr: IpNet = "10.42.5.0/30".parse().unwrap();
+        // A range that runs over the broadcast address only yields the usable IPs.
+        let reserved = range("10.42.5.2-10.42.5.9");
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &[]),
+            Ok(ip("10.42.5.2"))
+        );
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &[ip("10.42.5.2")]),
+            Err(AllocationError::RangeExhausted(reserved))
+        );
+
+        // Once the whole CIDR is full, that's reported instead of the range.
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &[ip("10.42.5.1"), ip("10.42.5.2")]),
+            Err(AllocationError::CidrFull(cidr))
+        );
+    }
+
+    #[test]
+    fn test_allocate_ipv6_range() {
+        let cidr = "fd00:1337::/64".parse().unwrap();
+        let reserved = range("fd00:1337::ffff-fd00:1337::1:1");
+        assert_eq!(
+            allocate_ip(&cidr, Some(&reserved), &[ip("fd00:1337::ffff")]),
+            Ok(ip("fd00:1337::1:0"))
+        );
+    }
+
+    #[test]
+    fn test_name_hash_allocation() {
+        // The published FNV-1a test vectors, so IPs don't move between builds.
+        assert_eq!(name_hash(""), 0xcbf29ce484222325);
+        assert_eq!(name_hash("a"), 0xaf63dc4c8601ec8c);
+
+        let cidr = "10.42.0.0/16".parse().unwrap();
+        let alice = IpAllocation::NameHash.allocate("alice", &cidr, None, &[]);
+        assert_eq!(
+            alice,
+            IpAllocation::NameHash.allocate("alice", &cidr, None, &[ip("10.42.0.1")])
+        );
+        assert_ne!(
+            alice,
+            IpAllocation::NameHash.allocate("bob", &cidr, None, &[])
+        );
+        assert_eq!(
+            IpAllocation::LowestFree.allocate("alice", &cidr, None, &[]),
+            Ok(ip("10.42.0.1"))
+        );
+
+        // A collision moves on to the next free IP.
+        let alice = alice.unwrap();
+        let next = from_u128(to_u128(alice) + 1, &alice);
+        assert_eq!(
+            IpAllocation::NameHash.allocate("alice", &cidr, None, &[alice]),
+            Ok(next)
+        );
+
+        // Names land inside the allocation range, if there is one.
+        let reserved = range("10.42.9.10-10.42.9.19");
+        let assigned = IpAllocation::NameHash
+            .allocate("alice", &cidr, Some(&reserved), &[])
+            .unwrap();
+        assert!(reserved.contains(&assigned));
+    }
+
+    #[test]
+    fn test_probing_wraps_around() {
+        let cidr: IpNet = "10.42.5.0/24".parse().unwrap();
+        let (start, end) = (cidr.network(), cidr.broadcast());
+        let taken = [ip("10.42.5.254")].into_iter().collect();
+        // Starting on a taken IP, past the broadcast and network addresses, back to .1.
+        assert_eq!(
+            first_free(&cidr, start, end, &taken, 254),
+            Some(ip("10.42.5.1"))
+        );
+        assert_eq!(
+            first_free(&cidr, start, end, &taken, 256 + 7),
+            Some(ip("10.42.5.7"))
+        );
+        let taken = (1..=254).map(|n| ip(&format!("10.42.5.{n}"))).collect();
+        assert_eq!(first_free(&cidr, start, end, &taken, 100), None);
+    }
+}
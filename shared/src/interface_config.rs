@@ -1,14 +1,21 @@
-use crate::{chmod, ensure_dirs_exist, Endpoint, Error, IoErrorContext, WrappedIoError};
+use crate::{
+    chmod, ensure_dirs_exist, keyring, Cidr, Endpoint, Error, FamilyPreference, IoErrorContext,
+    IpNetExt, Peer, PortRange, WrappedIoError,
+};
+use anyhow::{anyhow, bail};
 use indoc::writedoc;
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{File, OpenOptions},
     io::{self, Write},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
+    time::Duration,
 };
-use wireguard_control::InterfaceName;
+use wireguard_control::{InterfaceName, Key, KeyPair};
+
+pub use toml_edit;
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
@@ -18,8 +25,19 @@ pub struct InterfaceConfig {
 
     /// The necessary contact information for the server.
     pub server: ServerInfo,
+
+    /// Steps for whoever installs an invitation to follow afterwards (ex. adding a route).
+    /// Only for humans, so it's kept in a comment block rather than in the TOML.
+    #[serde(skip)]
+    pub post_install: Option<String>,
 }
 
+/// Starts the comment block holding an invitation's post-install steps.
+const POST_INSTALL_HEADER: &str = "# post_install:";
+
+/// Indents each line of the post-install steps inside their comment block.
+const POST_INSTALL_INDENT: &str = "#   ";
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct InterfaceInfo {
@@ -30,11 +48,108 @@ pub struct InterfaceInfo {
     /// the entire network's CIDR prefix.
     pub address: IpNet,
 
-    /// WireGuard private key (base64)
+    /// WireGuard private key (base64). Left out of the file when it's kept in the keyring.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub private_key: String,
 
-    /// The local listen port. A random port will be used if `None`.
+    /// The identifier of the private key in the OS keyring, if it's kept there instead of
+    /// in this file. See [`crate::keyring`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_keyring: Option<String>,
+
+    /// The local listen port. A free port is picked (and saved) on the next bring-up if `None`.
     pub listen_port: Option<u16>,
+
+    /// Whether `listen_port` was picked automatically rather than set by the user, in
+    /// which case it's replaced with another free port if something else takes it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub listen_port_auto: bool,
+
+    /// Ports that an automatically picked listen port has to come from (ex. the range a
+    /// firewall lets through). Any free port is used if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen_port_range: Option<PortRange>,
+
+    /// Only keep the link to the server alive, for metered connections. Other peers
+    /// handshake on demand instead, which saves data at some cost to NAT traversal.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metered: bool,
+
+    /// An external endpoint this peer controls (ex. a static IP with a port forward). It's
+    /// always what the server advertises for this peer, and observed NAT reflections are
+    /// never reported in its place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_endpoint: Option<Endpoint>,
+
+    /// AmneziaWG obfuscation parameters, for networks that need to get past DPI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obfuscation: Option<ObfuscationParams>,
+
+    /// DNS servers for the `DNS =` line of wg-quick exports. innernet itself doesn't
+    /// configure DNS from them (see `innernet up --resolved-dns` for that).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<IpAddr>,
+
+    /// Search domains appended to the `DNS =` line of wg-quick exports, so that short names
+    /// (ex. `host` for `host.mynet`) resolve. They need at least one DNS server.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_search: Vec<String>,
+
+    /// Which IP version's endpoints to try first when connecting to peers, and to report
+    /// first as this peer's own.
+    #[serde(default, skip_serializing_if = "FamilyPreference::is_auto")]
+    pub prefer_family: FamilyPreference,
+
+    /// Send all traffic through the server, not just the network's. The server has to
+    /// forward (and usually NAT) it for that to reach the internet.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub full_tunnel: bool,
+
+    /// Report this machine's hostname, OS, and innernet version to the server when
+    /// fetching, so that they show up in other peers' `innernet list`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub report_description: bool,
+
+    /// Only remove peers the server no longer lists at most this many seconds apart, and
+    /// otherwise just add and update peers when fetching, so that a glitch in the list
+    /// doesn't tear down working tunnels. Unset removes them on every fetch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removal_interval: Option<u64>,
+
+    /// The routing table to put the network's route in instead of the main one (Linux
+    /// only), for hosts with their own policy rules pointing at it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_table: Option<u32>,
+
+    /// The fwmark on the interface's own packets (Linux only), so that policy rules can
+    /// keep them from being routed back into the tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<u32>,
+}
+
+/// AmneziaWG's junk-packet and header obfuscation parameters, named as in its configs.
+/// Standard WireGuard implementations don't understand them, so they're only ever written
+/// out for networks that set them.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct ObfuscationParams {
+    /// The number of junk packets sent before a handshake.
+    pub jc: u16,
+    /// The minimum size of a junk packet.
+    pub jmin: u16,
+    /// The maximum size of a junk packet.
+    pub jmax: u16,
+    /// The junk prepended to handshake initiation packets.
+    pub s1: u16,
+    /// The junk prepended to handshake response packets.
+    pub s2: u16,
+    /// The message type header used for handshake initiations.
+    pub h1: u32,
+    /// The message type header used for handshake responses.
+    pub h2: u32,
+    /// The message type header used for cookie replies.
+    pub h3: u32,
+    /// The message type header used for transport data.
+    pub h4: u32,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -48,9 +163,81 @@ pub struct ServerInfo {
 
     /// An internal endpoint in the WireGuard network that hosts the coordination API.
     pub internal_endpoint: SocketAddr,
+
+    /// The preshared key for the link with the server, if the network uses them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+
+    /// Seconds to wait for a connection to the coordination API (default 5).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the coordination API to respond (default 5).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+
+    /// How many more times to try a read-only request to the coordination API when it
+    /// times out or can't connect (default 2).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+
+    /// `AllowedIPs` for the server set by hand in an imported wg-quick config, kept so that
+    /// exporting the config again reproduces them. innernet's own routes don't use them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_ips: Option<Vec<IpNet>>,
 }
 
 impl InterfaceConfig {
+    /// The invitation for a newly created (or re-keyed) peer, holding its private key and
+    /// everything it needs to reach the server.
+    pub fn invitation(
+        network_name: &InterfaceName,
+        peer: &Peer,
+        server_peer: &Peer,
+        root_cidr: &Cidr,
+        keypair: &KeyPair,
+        server_api_addr: &SocketAddr,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            interface: InterfaceInfo {
+                network_name: network_name.to_string(),
+                private_key: keypair.private.to_base64(),
+                private_key_keyring: None,
+                address: IpNet::new(peer.ip, root_cidr.prefix_len())?,
+                listen_port: None,
+                listen_port_auto: false,
+                listen_port_range: None,
+                metered: false,
+                pinned_endpoint: None,
+                obfuscation: None,
+                dns: vec![],
+                dns_search: vec![],
+                prefer_family: FamilyPreference::Auto,
+                full_tunnel: false,
+                report_description: false,
+                removal_interval: None,
+                route_table: None,
+                fwmark: None,
+            },
+            server: ServerInfo {
+                external_endpoint: server_peer
+                    .endpoint
+                    .clone()
+                    .ok_or_else(|| anyhow!("the innernet server has no WireGuard endpoint"))?,
+                internal_endpoint: *server_api_addr,
+                public_key: server_peer.public_key.clone(),
+                // When the network uses preshared keys, the server fills in the new peer's
+                // key for its link with the server.
+                preshared_key: peer.preshared_key.clone(),
+                connect_timeout: None,
+                read_timeout: None,
+                retries: None,
+                allowed_ips: None,
+            },
+            post_install: None,
+        })
+    }
+
     pub fn write_to(
         &self,
         target_file: &mut File,
@@ -77,8 +264,18 @@ impl InterfaceConfig {
                     # Don't edit the contents below unless you love chaos and dysfunction.
                 "
             )?;
+            if let Some(post_install) = &self.post_install {
+                writeln!(target_file, "#\n{POST_INSTALL_HEADER}")?;
+                for line in post_install.lines() {
+                    writeln!(target_file, "{POST_INSTALL_INDENT}{line}")?;
+                }
+                writeln!(target_file)?;
+            }
         }
-        target_file.write_all(toml::to_string(self).unwrap().as_bytes())?;
+        let contents = self
+            .to_toml()
+            .map_err(|e| io::Error::other(format!("{e:#}")))?;
+        target_file.write_all(contents.as_bytes())?;
         Ok(())
     }
 
@@ -107,14 +304,142 @@ impl InterfaceConfig {
         let path = Self::build_config_file_path(config_dir, interface)?;
         File::create(&path)
             .with_path(&path)?
-            .write_all(toml::to_string(self).unwrap().as_bytes())?;
+            .write_all(self.to_toml()?.as_bytes())?;
         Ok(path)
     }
 
+    /// Edit the config file at `path` in place, leaving everything `edit` doesn't touch
+    /// (comments, formatting, the order of keys) as it was. Nothing is written unless the
+    /// edited document is still a valid config.
+    pub fn edit<P: AsRef<Path>>(
+        path: P,
+        edit: impl FnOnce(&mut toml_edit::Document) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut document: toml_edit::Document =
+            std::fs::read_to_string(path).with_path(path)?.parse()?;
+        edit(&mut document)?;
+        let contents = document.to_string();
+        Self::parse(&contents)?;
+        std::fs::write(path, contents).with_path(path)?;
+        Ok(())
+    }
+
+    /// Set a single field of the config file at `path`, ex. `listen-port` in `interface`,
+    /// the same way as [`InterfaceConfig::edit`].
+    pub fn edit_field<P: AsRef<Path>>(
+        path: P,
+        table: &str,
+        key: &str,
+        value: impl Into<toml_edit::Value>,
+    ) -> Result<(), Error> {
+        Self::edit(path, |document| {
+            let table = document
+                .get_mut(table)
+                .and_then(toml_edit::Item::as_table_mut)
+                .ok_or_else(|| anyhow!("the config has no [{}] table", table))?;
+            table[key] = toml_edit::value(value);
+            Ok(())
+        })
+    }
+
+    /// Remove `key` from `[table]` in the config file at `path`, see [`Self::edit`].
+    pub fn remove_field<P: AsRef<Path>>(path: P, table: &str, key: &str) -> Result<(), Error> {
+        Self::edit(path, |document| {
+            if let Some(table) = document
+                .get_mut(table)
+                .and_then(toml_edit::Item::as_table_mut)
+            {
+                table.remove(key);
+            }
+            Ok(())
+        })
+    }
+
+    /// The config as written to files. A private key that's kept in the keyring is saved
+    /// there (if it changed, ex. after a key rotation) and left out of the TOML.
+    fn to_toml(&self) -> Result<String, Error> {
+        let Some(id) = &self.interface.private_key_keyring else {
+            return Ok(toml::to_string(self)?);
+        };
+        if keyring::load(id).ok().as_ref() != Some(&self.interface.private_key) {
+            keyring::store(id, &self.interface.private_key)?;
+        }
+        let mut config = self.clone();
+        config.interface.private_key.clear();
+        Ok(toml::to_string(&config)?)
+    }
+
+    /// Read a config, fetching its private key from the keyring if it's kept there.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        Ok(toml::from_str(
-            &std::fs::read_to_string(&path).with_path(path)?,
-        )?)
+        let path = path.as_ref();
+        let mut config = Self::parse(&std::fs::read_to_string(path).with_path(path)?)?;
+        if config.interface.private_key.is_empty() {
+            let id = config
+                .interface
+                .private_key_keyring
+                .as_ref()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} has neither a private-key nor a private-key-keyring.",
+                        path.display()
+                    )
+                })?;
+            config.interface.private_key = keyring::load(id)?;
+        }
+        Ok(config)
+    }
+
+    /// Check an invitation for everything installing it relies on beyond parsing: valid keys,
+    /// an address a peer can have, and a server inside the network. `innernet install` runs
+    /// this before changing anything, and `innernet check` runs only this.
+    pub fn validate(&self) -> Result<(), Error> {
+        let interface = &self.interface;
+        interface
+            .network_name
+            .parse::<InterfaceName>()
+            .map_err(|e| anyhow!("invalid network name \"{}\": {}", interface.network_name, e))?;
+        let key = |key: &str, what: &str| {
+            Key::from_base64(key).map_err(|_| anyhow!("{} isn't a valid WireGuard key.", what))
+        };
+        key(&interface.private_key, "the private key")?;
+        key(&self.server.public_key, "the server's public key")?;
+        if let Some(preshared_key) = &self.server.preshared_key {
+            key(preshared_key, "the preshared key")?;
+        }
+
+        let network = interface.address.trunc();
+        if network.prefix_len() == 0 || !network.is_assignable(&interface.address.addr()) {
+            bail!("{} isn't an address a peer can have.", interface.address);
+        }
+        let api = self.server.internal_endpoint;
+        if !network.contains(&api.ip()) || api.ip() == interface.address.addr() || api.port() == 0 {
+            bail!(
+                "the server's internal endpoint {} isn't another address in {}.",
+                api,
+                network
+            );
+        }
+        Ok(())
+    }
+
+    fn parse(contents: &str) -> Result<Self, Error> {
+        let mut config: Self = toml::from_str(contents)?;
+        config.post_install = Self::parse_post_install(contents);
+        Ok(config)
+    }
+
+    /// Pull the post-install steps out of their comment block, if there is one.
+    fn parse_post_install(contents: &str) -> Option<String> {
+        let mut lines = contents
+            .lines()
+            .skip_while(|line| line.trim_end() != POST_INSTALL_HEADER)
+            .skip(1);
+        let steps = lines
+            .by_ref()
+            .map_while(|line| line.strip_prefix(POST_INSTALL_INDENT))
+            .collect::<Vec<_>>();
+        (!steps.is_empty()).then(|| steps.join("\n"))
     }
 
     pub fn from_interface(config_dir: &Path, interface: &InterfaceName) -> Result<Self, Error> {
@@ -138,6 +463,32 @@ impl InterfaceConfig {
     }
 }
 
+/// The default for [`ServerInfo::connect_timeout`].
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// The default for [`ServerInfo::read_timeout`].
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 5;
+
+/// The default for [`ServerInfo::retries`].
+const DEFAULT_RETRIES: u32 = 2;
+
+impl ServerInfo {
+    /// How long to wait for a connection to the coordination API.
+    pub fn api_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS))
+    }
+
+    /// How long to wait for the coordination API to respond.
+    pub fn api_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT_SECS))
+    }
+
+    /// How many more times to try a read-only request to the coordination API.
+    pub fn api_retries(&self) -> u32 {
+        self.retries.unwrap_or(DEFAULT_RETRIES)
+    }
+}
+
 impl InterfaceInfo {
     pub fn public_key(&self) -> Result<String, Error> {
         Ok(wireguard_control::Key::from_base64(&self.private_key)?
@@ -145,3 +496,155 @@ impl InterfaceInfo {
             .to_base64())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> InterfaceConfig {
+        InterfaceConfig {
+            interface: InterfaceInfo {
+                network_name: "office".into(),
+                address: "10.66.0.2/16".parse().unwrap(),
+                private_key: KeyPair::generate().private.to_base64(),
+                private_key_keyring: None,
+                listen_port: None,
+                listen_port_auto: false,
+                listen_port_range: None,
+                metered: false,
+                pinned_endpoint: None,
+                obfuscation: None,
+                dns: vec![],
+                dns_search: vec![],
+                prefer_family: FamilyPreference::Auto,
+                full_tunnel: false,
+                report_description: false,
+                removal_interval: None,
+                route_table: None,
+                fwmark: None,
+            },
+            server: ServerInfo {
+                external_endpoint: "203.0.113.1:51820".parse().unwrap(),
+                internal_endpoint: "10.66.0.1:51820".parse().unwrap(),
+                public_key: KeyPair::generate().public.to_base64(),
+                preshared_key: None,
+                connect_timeout: None,
+                read_timeout: None,
+                retries: None,
+                allowed_ips: None,
+            },
+            post_install: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() -> Result<(), Error> {
+        config().validate()?;
+        let invalid = |edit: fn(&mut InterfaceConfig)| {
+            let mut config = config();
+            edit(&mut config);
+            config.validate().is_err()
+        };
+        // Truncated keys.
+        assert!(invalid(|c| c.interface.private_key.truncate(20)));
+        assert!(invalid(|c| c.server.public_key.clear()));
+        assert!(invalid(|c| c.server.preshared_key = Some("nope".into())));
+        // Addresses a peer can't have.
+        assert!(invalid(
+            |c| c.interface.address = "10.66.0.0/16".parse().unwrap()
+        ));
+        assert!(invalid(
+            |c| c.interface.address = "10.66.0.2/0".parse().unwrap()
+        ));
+        // A server outside the network, or on the peer's own address.
+        assert!(invalid(
+            |c| c.server.internal_endpoint = "10.67.0.1:51820".parse().unwrap()
+        ));
+        assert!(invalid(
+            |c| c.server.internal_endpoint = "10.66.0.2:51820".parse().unwrap()
+        ));
+        assert!(invalid(|c| c.interface.network_name = "no spaces".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_install_roundtrip() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("invitation.toml");
+        let mut invitation = config();
+        invitation.post_install =
+            Some("sudo ip route add 10.99.0.0/16 dev office\n\n# then reboot".into());
+        invitation.write_to_path(&path, true, None)?;
+
+        let contents = std::fs::read_to_string(&path)?;
+        assert!(contents.contains("#   sudo ip route add 10.99.0.0/16 dev office\n"));
+        let parsed = InterfaceConfig::from_file(&path)?;
+        assert_eq!(parsed.post_install, invitation.post_install);
+        assert_eq!(parsed.interface.address, invitation.interface.address);
+
+        // Without any steps, there's no block at all.
+        let path = dir.path().join("plain.toml");
+        config().write_to_path(&path, true, None)?;
+        assert!(!std::fs::read_to_string(&path)?.contains(POST_INSTALL_HEADER));
+        assert_eq!(InterfaceConfig::from_file(&path)?.post_install, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_field() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("office.conf");
+        let contents = format!(
+            "# the office network, see the wiki\n{}",
+            toml::to_string(&config())?
+                .replace("[server]\n", "[server]\n# moved to the new rack in 2024\n")
+        );
+        std::fs::write(&path, &contents)?;
+
+        InterfaceConfig::edit_field(&path, "interface", "listen-port", 51820)?;
+        let edited = std::fs::read_to_string(&path)?;
+        assert!(edited.starts_with("# the office network, see the wiki\n"));
+        assert!(edited.contains("# moved to the new rack in 2024\n"));
+        assert_eq!(
+            InterfaceConfig::from_file(&path)?.interface.listen_port,
+            Some(51820)
+        );
+
+        // Edits that would leave an invalid config aren't written.
+        assert!(InterfaceConfig::edit_field(&path, "interface", "listen-port", "soon").is_err());
+        assert!(InterfaceConfig::edit_field(&path, "peer", "listen-port", 1).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, edited);
+
+        InterfaceConfig::remove_field(&path, "interface", "listen-port")?;
+        assert_eq!(
+            InterfaceConfig::from_file(&path)?.interface.listen_port,
+            None
+        );
+        assert!(std::fs::read_to_string(&path)?.contains("# moved to the new rack in 2024\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyring_reference() -> Result<(), Error> {
+        let mut config = config();
+        config.interface.private_key.clear();
+        let contents = toml::to_string(&config)?;
+        assert!(!contents.contains("private-key"));
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("office.conf");
+        std::fs::write(&path, &contents)?;
+        assert!(InterfaceConfig::from_file(&path).is_err());
+
+        let contents = contents.replace(
+            "[interface]\n",
+            "[interface]\nprivate-key-keyring = \"office\"\n",
+        );
+        let parsed = InterfaceConfig::parse(&contents)?;
+        assert_eq!(
+            parsed.interface.private_key_keyring.as_deref(),
+            Some("office")
+        );
+        assert!(parsed.interface.private_key.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Private keys kept in the OS keyring instead of in interface configs.
+//!
+//! Keys are stored under the `innernet` service with the identifier the config names in
+//! `private-key-keyring`: through `secret-tool` for the Secret Service (GNOME Keyring,
+//! KWallet), and through the Security framework for the macOS Keychain. Either way, keys
+//! never show up in the process list.
+
+use crate::Error;
+use anyhow::{anyhow, bail};
+#[cfg(not(target_os = "macos"))]
+use std::{
+    io::{self, Write},
+    process::{Command, Output, Stdio},
+};
+
+const SERVICE: &str = "innernet";
+
+#[cfg(not(target_os = "macos"))]
+fn run(mut command: Command, stdin: Option<&str>) -> Result<Output, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!(
+                "{} isn't installed, it's needed for keyring access",
+                program
+            ),
+            _ => anyhow!("couldn't run {}: {}", program, e),
+        })?;
+    if let Some(input) = stdin {
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn load(id: &str) -> Result<String, Error> {
+    let mut command = Command::new("secret-tool");
+    command.args(["lookup", "service", SERVICE, "id", id]);
+    let output = run(command, None)?;
+    let key = String::from_utf8(output.stdout)?.trim().to_string();
+    if key.is_empty() {
+        bail!("the keyring has no key {}", id);
+    }
+    Ok(key)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn store(id: &str, key: &str) -> Result<(), Error> {
+    let mut command = Command::new("secret-tool");
+    command.args(["store", "--label", &format!("innernet private key ({id})")]);
+    command.args(["service", SERVICE, "id", id]);
+    // secret-tool reads the secret from stdin, so it never shows up in the process list.
+    run(command, Some(key))?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+mod security {
+    //! The few Keychain Services calls needed, from the Security framework. They're
+    //! deprecated in favor of `SecItem*`, but still the simplest way to a generic password.
+
+    use std::{ffi::c_void, ptr};
+
+    pub type OSStatus = i32;
+    type SecKeychainItemRef = *mut c_void;
+
+    pub const ERR_SEC_SUCCESS: OSStatus = 0;
+    pub const ERR_SEC_ITEM_NOT_FOUND: OSStatus = -25300;
+
+    #[link(name = "Security", kind = "framework")]
+    extern "C" {
+        fn SecKeychainAddGenericPassword(
+            keychain: *mut c_void,
+            service_name_length: u32,
+            service_name: *const u8,
+            account_name_length: u32,
+            account_name: *const u8,
+            password_length: u32,
+            password_data: *const c_void,
+            item_ref: *mut SecKeychainItemRef,
+        ) -> OSStatus;
+
+        fn SecKeychainFindGenericPassword(
+            keychain_or_array: *const c_void,
+            service_name_length: u32,
+            service_name: *const u8,
+            account_name_length: u32,
+            account_name: *const u8,
+            password_length: *mut u32,
+            password_data: *mut *mut c_void,
+            item_ref: *mut SecKeychainItemRef,
+        ) -> OSStatus;
+
+        fn SecKeychainItemModifyAttributesAndData(
+            item_ref: SecKeychainItemRef,
+            attr_list: *const c_void,
+            length: u32,
+            data: *const c_void,
+        ) -> OSStatus;
+
+        fn SecKeychainItemFreeContent(attr_list: *mut c_void, data: *mut c_void) -> OSStatus;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFRelease(cf: *const c_void);
+    }
+
+    /// The password stored for `service` and `account` in the default keychain.
+    pub fn find(service: &str, account: &str) -> Result<Vec<u8>, OSStatus> {
+        let mut length = 0;
+        let mut data = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                service.len() as u32,
+                service.as_ptr(),
+                account.len() as u32,
+                account.as_ptr(),
+                &mut length,
+                &mut data,
+                ptr::null_mut(),
+            )
+        };
+        if status != ERR_SEC_SUCCESS {
+            return Err(status);
+        }
+        let password =
+            unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) }.to_vec();
+        unsafe { SecKeychainItemFreeContent(ptr::null_mut(), data) };
+        Ok(password)
+    }
+
+    /// Store `password` for `service` and `account` in the default keychain, replacing the
+    /// one already there.
+    pub fn store(service: &str, account: &str, password: &[u8]) -> Result<(), OSStatus> {
+        let mut item: SecKeychainItemRef = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                service.len() as u32,
+                service.as_ptr(),
+                account.len() as u32,
+                account.as_ptr(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut item,
+            )
+        };
+        let status = match status {
+            ERR_SEC_SUCCESS => unsafe {
+                let status = SecKeychainItemModifyAttributesAndData(
+                    item,
+                    ptr::null(),
+                    password.len() as u32,
+                    password.as_ptr() as *const c_void,
+                );
+                CFRelease(item as *const c_void);
+                status
+            },
+            ERR_SEC_ITEM_NOT_FOUND => unsafe {
+                SecKeychainAddGenericPassword(
+                    ptr::null_mut(),
+                    service.len() as u32,
+                    service.as_ptr(),
+                    account.len() as u32,
+                    account.as_ptr(),
+                    password.len() as u32,
+                    password.as_ptr() as *const c_void,
+                    ptr::null_mut(),
+                )
+            },
+            status => status,
+        };
+        match status {
+            ERR_SEC_SUCCESS => Ok(()),
+            status => Err(status),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn load(id: &str) -> Result<String, Error> {
+    match security::find(SERVICE, id) {
+        Ok(key) => Ok(String::from_utf8(key)?.trim().to_string()),
+        Err(security::ERR_SEC_ITEM_NOT_FOUND) => bail!("the keychain has no key {}", id),
+        Err(status) => Err(anyhow!(
+            "couldn't read key {} from the keychain (OSStatus {})",
+            id,
+            status
+        )),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn store(id: &str, key: &str) -> Result<(), Error> {
+    security::store(SERVICE, id, key.as_bytes()).map_err(|status| {
+        anyhow!(
+            "couldn't store key {} in the keychain (OSStatus {})",
+            id,
+            status
+        )
+    })
+}
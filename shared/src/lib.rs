@@ -1,4 +1,5 @@
 pub use anyhow::Error;
+use colored::*;
 use ipnet::IpNet;
 use std::{
     fs::{self, File, Permissions},
@@ -9,11 +10,15 @@ use std::{
     time::Duration,
 };
 
+pub mod allocation;
 pub mod interface_config;
+pub mod keyring;
 #[cfg(target_os = "linux")]
 mod netlink;
 pub mod prompts;
+pub mod reachability;
 pub mod types;
+pub mod vanilla;
 pub mod wg;
 
 pub use types::*;
@@ -56,6 +61,13 @@ pub fn warn_on_dangerous_mode(path: &Path) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// The mode of a file or directory if anyone but its owner can access it, for paths that
+/// hold secrets.
+pub fn exposed_mode(path: &Path) -> Result<Option<u32>, io::Error> {
+    let mode = File::open(path)?.metadata()?.permissions().mode() & 0o777;
+    Ok((mode & 0o077 != 0).then_some(mode))
+}
+
 /// Updates the permissions of a file or directory. Returns `Ok(true)` if
 /// permissions had to be changed, `Ok(false)` if permissions were already
 /// correct.
@@ -106,6 +118,17 @@ pub fn _get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::
 #[cfg(target_os = "linux")]
 pub use netlink::get_local_addrs as _get_local_addrs;
 
+#[cfg(target_os = "linux")]
+pub use netlink::wait_for_network_change;
+
+/// Without netlink, there's no cheap way to watch for network changes, so this just waits
+/// out the full `timeout` and reports no change.
+#[cfg(not(target_os = "linux"))]
+pub fn wait_for_network_change(timeout: Duration, _debounce: Duration) -> Result<bool, io::Error> {
+    std::thread::sleep(timeout);
+    Ok(false)
+}
+
 pub fn get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::Error> {
     // TODO(jake): this is temporary pending the stabilization of rust-lang/rust#27709
     fn is_unicast_global(ip: &Ipv6Addr) -> bool {
@@ -141,3 +164,17 @@ impl IpNetExt for IpNet {
             }
     }
 }
+
+pub fn human_size(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    const GB: u64 = 1024 * MB;
+    const TB: u64 = 1024 * GB;
+    match bytes {
+        n if n < 2 * KB => format!("{} {}", n, "B".cyan()),
+        n if n < 2 * MB => format!("{:.2} {}", n as f64 / KB as f64, "KiB".cyan()),
+        n if n < 2 * GB => format!("{:.2} {}", n as f64 / MB as f64, "MiB".cyan()),
+        n if n < 2 * TB => format!("{:.2} {}", n as f64 / GB as f64, "GiB".cyan()),
+        n => format!("{:.2} {}", n as f64 / TB as f64, "TiB".cyan()),
+    }
+}
@@ -7,11 +7,17 @@ use netlink_packet_route::{
     address,
     constants::*,
     link::{self, nlas::State},
-    route, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
-    RtnlMessage, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
+    route, rule, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
+    RtnlMessage, RuleHeader, RuleMessage, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
 };
 use netlink_request::netlink_request_rtnl;
-use std::{io, net::IpAddr};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use std::{
+    io,
+    net::IpAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
 use wireguard_control::InterfaceName;
 
 fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
@@ -37,6 +43,20 @@ pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Move the interface into the network namespace `netns` (an open namespace file). It keeps
+/// its WireGuard socket in the namespace it was created in.
+pub fn set_netns(interface: &InterfaceName, netns: RawFd) -> Result<(), io::Error> {
+    let index = if_nametoindex(interface)?;
+    let mut header = LinkHeader::default();
+    header.index = index;
+    let mut message = LinkMessage::default();
+    message.header = header;
+    message.nlas = vec![link::nlas::Nla::NetNsFd(netns)];
+    netlink_request_rtnl(RtnlMessage::SetLink(message), None)?;
+    log::debug!("moved interface {} to another network namespace", interface);
+    Ok(())
+}
+
 pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error> {
     let index = if_nametoindex(interface)?;
     let (family, nlas) = match addr {
@@ -72,26 +92,42 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
     Ok(())
 }
 
-pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+/// Route `cidr` through the interface, in `table` or else the main table. The route goes
+/// away with the interface, so nothing is left behind even if innernet never got to bring
+/// the interface down.
+pub fn add_route(
+    interface: &InterfaceName,
+    cidr: IpNet,
+    table: Option<u32>,
+) -> Result<bool, io::Error> {
     let if_index = if_nametoindex(interface)?;
     let (address_family, dst) = match cidr {
         IpNet::V4(network) => (AF_INET as u8, network.network().octets().to_vec()),
         IpNet::V6(network) => (AF_INET6 as u8, network.network().octets().to_vec()),
     };
     let mut header = RouteHeader::default();
-    header.table = RT_TABLE_MAIN;
     header.protocol = RTPROT_BOOT;
     header.scope = RT_SCOPE_LINK;
     header.kind = RTN_UNICAST;
     header.destination_prefix_length = cidr.prefix_len();
     header.address_family = address_family;
     let mut message = RouteMessage::default();
-    message.header = header;
     message.nlas = vec![route::Nla::Destination(dst), route::Nla::Oif(if_index)];
+    match table {
+        // Tables past 255 don't fit in the header.
+        Some(table) => message.nlas.push(route::Nla::Table(table)),
+        None => header.table = RT_TABLE_MAIN,
+    }
+    message.header = header;
 
     match netlink_request_rtnl(RtnlMessage::NewRoute(message), None) {
         Ok(_) => {
-            log::debug!("added route {} to interface {}", cidr, interface);
+            log::debug!(
+                "added route {} to interface {} (table {}).",
+                cidr,
+                interface,
+                table.map_or("main".into(), |table| table.to_string())
+            );
             Ok(true)
         },
         Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
@@ -102,6 +138,92 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
     }
 }
 
+/// The policy rules that send everything but the interface's own traffic (marked with
+/// `table` as its fwmark) to `table`, as wg-quick(8) does: first look up the main table
+/// without its default routes, then `table` for anything not from the interface.
+fn full_tunnel_rules(family: u8, table: u32) -> [RuleMessage; 2] {
+    let rule = |flags, nlas| {
+        let mut header = RuleHeader::default();
+        header.family = family;
+        header.action = FR_ACT_TO_TBL;
+        header.flags = flags;
+        let mut message = RuleMessage::default();
+        message.header = header;
+        message.nlas = nlas;
+        message
+    };
+    [
+        rule(
+            FIB_RULE_INVERT,
+            vec![rule::Nla::FwMark(table), rule::Nla::Table(table)],
+        ),
+        rule(
+            0,
+            vec![
+                rule::Nla::Table(RT_TABLE_MAIN.into()),
+                rule::Nla::SuppressPrefixLen(0),
+            ],
+        ),
+    ]
+}
+
+/// Route all traffic through the interface, by way of a default route in `table` and the
+/// rules from [`full_tunnel_rules`]. The main table isn't touched, so removing the rules
+/// (or the interface, which takes the default route with it) restores the host's routing.
+pub fn add_full_tunnel_routes(interface: &InterfaceName, table: u32) -> Result<(), io::Error> {
+    let if_index = if_nametoindex(interface)?;
+    for (family, dst) in [(AF_INET as u8, vec![0; 4]), (AF_INET6 as u8, vec![0; 16])] {
+        let mut header = RouteHeader::default();
+        header.protocol = RTPROT_BOOT;
+        header.scope = RT_SCOPE_UNIVERSE;
+        header.kind = RTN_UNICAST;
+        header.address_family = family;
+        let mut message = RouteMessage::default();
+        message.header = header;
+        message.nlas = vec![
+            route::Nla::Destination(dst),
+            route::Nla::Oif(if_index),
+            route::Nla::Table(table),
+        ];
+        let mut requests = std::iter::once(RtnlMessage::NewRoute(message)).chain(
+            full_tunnel_rules(family, table)
+                .into_iter()
+                .map(RtnlMessage::NewRule),
+        );
+        let result = requests.try_for_each(|request| match netlink_request_rtnl(request, None) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+            _ => Ok(()),
+        });
+        match result {
+            Ok(()) => log::debug!(
+                "routing all traffic through {} (table {}).",
+                interface,
+                table
+            ),
+            // Hosts without IPv6 just keep their IPv4 traffic in the tunnel.
+            Err(e) if family == AF_INET6 as u8 => {
+                log::warn!("couldn't route IPv6 traffic through {}: {}", interface, e)
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Remove the rules [`add_full_tunnel_routes`] added for `table`, if they're still there.
+pub fn remove_full_tunnel_routes(table: u32) -> Result<(), io::Error> {
+    for family in [AF_INET as u8, AF_INET6 as u8] {
+        for rule in full_tunnel_rules(family, table) {
+            match netlink_request_rtnl(RtnlMessage::DelRule(rule), Some(NLM_F_REQUEST | NLM_F_ACK))
+            {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+    }
+    Ok(())
+}
+
 fn get_links() -> Result<Vec<String>, io::Error> {
     let link_responses = netlink_request_rtnl(
         RtnlMessage::GetLink(LinkMessage::default()),
@@ -177,6 +299,69 @@ pub fn get_local_addrs() -> Result<impl Iterator<Item = IpAddr>, io::Error> {
     Ok(addrs)
 }
 
+/// Wait until `socket` has data to read, or `timeout` passes. Returns whether it's readable.
+fn poll_readable(socket: &Socket, timeout: Duration) -> Result<bool, io::Error> {
+    let mut pollfd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Block until the host's addresses, links, or routes change, or until `timeout` passes.
+///
+/// Once a change is seen, this keeps waiting until there have been no further changes for
+/// `debounce` (within reason), so that a flurry of events (ex. joining a new WiFi network) is
+/// reported once.
+/// Returns whether a change happened.
+pub fn wait_for_network_change(timeout: Duration, debounce: Duration) -> Result<bool, io::Error> {
+    let groups = libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_ROUTE;
+    let mut socket = Socket::new(NETLINK_ROUTE)?;
+    socket.bind(&SocketAddr::new(0, groups as u32))?;
+    socket.set_non_blocking(true)?;
+
+    if !poll_readable(&socket, timeout)? {
+        return Ok(false);
+    }
+
+    // Don't wait forever if the events never settle down.
+    let settle_deadline = Instant::now() + debounce * 10;
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        // Drain everything that's queued up so far.
+        loop {
+            match socket.recv(&mut &mut buf[..], 0) {
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if Instant::now() >= settle_deadline || !poll_readable(&socket, debounce)? {
+            break;
+        }
+    }
+    log::debug!("detected a network change.");
+
+    Ok(true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,20 +1,21 @@
 use crate::{
-    interface_config::{InterfaceConfig, InterfaceInfo, ServerInfo},
+    find_conflicting_cidr,
+    interface_config::{InterfaceConfig, InterfaceInfo},
     AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, Cidr, CidrContents, CidrTree,
-    DeleteCidrOpts, Endpoint, Error, Hostname, IpNetExt, ListenPortOpts, OverrideEndpointOpts,
-    Peer, PeerContents, RenamePeerOpts, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    DeleteCidrOpts, Endpoint, Error, Hostname, IpNetExt, IpRange, ListenPortOpts,
+    OverrideEndpointOpts, Peer, PeerContents, RekeyPeerOpts, RenameCidrOpts, RenamePeerOpts,
+    RotateAdminOpts, SetKeepaliveOpts, Tag, TagPeerOpts, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use colored::*;
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use ipnet::IpNet;
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password, Select};
 use once_cell::sync::Lazy;
 use publicip::Preference;
 use std::{
     fmt::{Debug, Display},
     fs::{File, OpenOptions},
     io,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     time::SystemTime,
 };
@@ -73,6 +74,12 @@ where
     .interact()
 }
 
+/// Like [`input`], but without echoing what's typed (ex. for keys).
+pub fn password(prompt: &str) -> Result<String, io::Error> {
+    ensure_interactive(prompt)?;
+    Password::with_theme(&*THEME).with_prompt(prompt).interact()
+}
+
 /// Bring up a prompt to create a new CIDR. Returns the peer request.
 pub fn add_cidr(cidrs: &[Cidr], request: &AddCidrOpts) -> Result<Option<CidrContents>, Error> {
     let parent_cidr = if let Some(ref parent_name) = request.parent {
@@ -96,10 +103,23 @@ pub fn add_cidr(cidrs: &[Cidr], request: &AddCidrOpts) -> Result<Option<CidrCont
         input("CIDR", Prefill::None)?
     };
 
+    if let Some(conflict) = find_conflicting_cidr(&cidr, cidrs) {
+        bail!("{} overlaps the existing CIDR {}.", cidr, conflict);
+    }
+    if let Some(range) = request.allocation_range {
+        if !range.is_within(&cidr) {
+            bail!("allocation range {} isn't within {}.", range, cidr);
+        }
+    }
+
     let cidr_request = CidrContents {
         name: name.to_string(),
         cidr,
         parent: Some(parent_cidr.id),
+        allocation_range: request.allocation_range,
+        default_keepalive: request.default_keepalive,
+        default_mtu: request.default_mtu,
+        max_peers: request.max_peers,
     };
 
     Ok(
@@ -111,6 +131,38 @@ pub fn add_cidr(cidrs: &[Cidr], request: &AddCidrOpts) -> Result<Option<CidrCont
     )
 }
 
+/// Bring up a prompt to rename a CIDR. Returns the CIDR request and the CIDR's old name.
+pub fn rename_cidr(cidrs: &[Cidr], args: &RenameCidrOpts) -> Result<Option<(Cidr, String)>, Error> {
+    let old_cidr = if let Some(ref name) = args.name {
+        find_cidr(cidrs, name)?
+    } else {
+        choose_cidr(cidrs, "CIDR to rename")?
+    };
+    let old_name = old_cidr.name.clone();
+    let new_name = if let Some(ref name) = args.new_name {
+        name.clone()
+    } else {
+        input("New Name", Prefill::None)?
+    };
+
+    let mut new_cidr = old_cidr.clone();
+    new_cidr.contents.name = new_name.to_string();
+
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "Rename CIDR {} to {}?",
+                old_name.yellow(),
+                new_name.yellow()
+            ))?
+        {
+            Some((new_cidr, old_name))
+        } else {
+            None
+        },
+    )
+}
+
 /// Bring up a prompt to delete a CIDR. Returns the peer request.
 pub fn delete_cidr(cidrs: &[Cidr], peers: &[Peer], request: &DeleteCidrOpts) -> Result<i64, Error> {
     let eligible_cidrs: Vec<_> = cidrs
@@ -247,6 +299,68 @@ pub fn delete_association<'a>(
     )
 }
 
+/// The range to pick a new peer's IP from: the one given with `--from`/`--to` (either end
+/// defaulting to the edge of the CIDR), or else the CIDR's own allocation range.
+fn allocation_range(cidr: &Cidr, args: &AddPeerOpts) -> Result<Option<IpRange>, Error> {
+    if args.from.is_none() && args.to.is_none() {
+        return Ok(cidr.allocation_range);
+    }
+    let range = IpRange::new(
+        args.from.unwrap_or_else(|| cidr.network()),
+        args.to.unwrap_or_else(|| cidr.broadcast()),
+    )
+    .map_err(|e| anyhow!(e))?;
+    if !range.is_within(&cidr.cidr) {
+        bail!("{} isn't within CIDR {} ({}).", range, cidr.name, cidr.cidr);
+    }
+    Ok(Some(range))
+}
+
+/// Make sure a new peer's name is DNS-safe, since it ends up in hosts files and DNS. With
+/// `allow_unsafe`, a name that isn't is only warned about.
+pub fn check_peer_name(name: &Hostname, allow_unsafe: bool) -> Result<(), Error> {
+    if let Some(reason) = Hostname::dns_unsafe_reason(name) {
+        if !allow_unsafe {
+            bail!(
+                "\"{}\" isn't a DNS-safe name ({}). Use lowercase letters, digits, hyphens and dots, or pass --allow-unsafe-name if this network doesn't use the hosts file or DNS.",
+                name,
+                reason
+            );
+        }
+        log::warn!(
+            "\"{}\" isn't a DNS-safe name ({}), so it may not resolve through the hosts file or DNS.",
+            name,
+            reason
+        );
+    }
+    Ok(())
+}
+
+/// Make sure `ip` can be given to a new peer in `cidr`: it has to be assignable within the
+/// CIDR, and not held by any peer. Every peer counts as holding its IP, whether it's active,
+/// staged, or disabled, so a disabled peer's IP is never handed out twice.
+pub fn check_peer_ip(peers: &[Peer], cidr: &Cidr, ip: IpAddr) -> Result<(), Error> {
+    if !cidr.cidr.contains(&ip) {
+        bail!("{} is outside of CIDR {} ({}).", ip, cidr.name, cidr.cidr);
+    }
+    if !cidr.cidr.is_assignable(&ip) {
+        bail!(
+            "{} can't be assigned to a peer, since it's {}'s network or broadcast address.",
+            ip,
+            cidr.cidr
+        );
+    }
+    if let Some(holder) = peers.iter().find(|peer| peer.ip == ip) {
+        bail!(
+            "{} is already assigned to peer {} ({}).",
+            ip,
+            holder.name,
+            holder.status()
+        );
+    }
+    Ok(())
+}
+
 /// Bring up a prompt to create a new peer. Returns the peer request.
 pub fn add_peer(
     peers: &[Peer],
@@ -264,30 +378,34 @@ pub fn add_peer(
         choose_cidr(&leaves[..], "Eligible CIDRs for peer")?
     };
 
-    let mut available_ip = None;
-    let candidate_ips = cidr.hosts().filter(|ip| cidr.is_assignable(ip));
-    for ip in candidate_ips {
-        if !peers.iter().any(|peer| peer.ip == ip) {
-            available_ip = Some(ip);
-            break;
-        }
-    }
-
-    let available_ip = available_ip.expect("No IPs in this CIDR are avavilable");
-
-    let ip = if let Some(ip) = args.ip {
-        ip
-    } else if args.auto_ip {
-        available_ip
-    } else {
-        input("IP", Prefill::Default(available_ip))?
-    };
-
+    // The name comes first, since the IP can be derived from it.
     let name = if let Some(ref name) = args.name {
         name.clone()
+    } else if args.allow_unsafe_name {
+        Hostname::parse_allowed(&input::<String>("Name", Prefill::None)?).map_err(|e| anyhow!(e))?
     } else {
         input("Name", Prefill::None)?
     };
+    check_peer_name(&name, args.allow_unsafe_name)?;
+
+    let ip = if let Some(ip) = args.ip {
+        ip
+    } else {
+        let range = allocation_range(cidr, args)?;
+        let taken = peers.iter().map(|peer| peer.ip).collect::<Vec<_>>();
+        let available_ip = args.ip_allocation.unwrap_or_default().allocate(
+            &name,
+            &cidr.cidr,
+            range.as_ref(),
+            &taken,
+        )?;
+        if args.auto_ip {
+            available_ip
+        } else {
+            input("IP", Prefill::Default(available_ip))?
+        }
+    };
+    check_peer_ip(peers, cidr, ip)?;
 
     let is_admin = if let Some(is_admin) = args.admin {
         is_admin
@@ -313,6 +431,10 @@ pub fn add_peer(
         )?
     };
 
+    let mut tags = args.tags.clone();
+    tags.sort();
+    tags.dedup();
+
     let default_keypair = KeyPair::generate();
     let peer_request = PeerContents {
         name,
@@ -321,11 +443,20 @@ pub fn add_peer(
         public_key: default_keypair.public.to_base64(),
         endpoint: None,
         is_admin,
-        is_disabled: false,
+        is_disabled: args.staged,
         is_redeemed: false,
         persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
         invite_expires: Some(SystemTime::now() + invite_expires.into()),
         candidates: vec![],
+        tags,
+        preshared_key: None,
+        last_handshake: None,
+        advertised_routes: vec![],
+        is_observer: args.observer,
+        is_staged: args.staged,
+        created_at: None,
+        created_by: None,
+        description: None,
     };
 
     Ok(
@@ -392,6 +523,204 @@ pub fn rename_peer(
     )
 }
 
+/// Bring up a prompt to replace an admin peer's key with a new invitation. Returns the admin
+/// peer with its new invitation expiration, the invitation keypair, and the opened invitation
+/// file.
+pub fn rotate_admin(
+    peers: &[Peer],
+    args: &RotateAdminOpts,
+) -> Result<Option<(Peer, KeyPair, String, File)>, Error> {
+    let admins = peers
+        .iter()
+        .filter(|p| p.is_admin && &*p.name != "innernet-server")
+        .collect::<Vec<_>>();
+    rekey(
+        &admins,
+        "Admin peer",
+        &RekeyPeerOpts {
+            name: args.name.clone(),
+            yes: args.yes,
+            save_config: args.save_config.clone(),
+            invite_expires: args.invite_expires.clone(),
+        },
+    )
+}
+
+/// Bring up a prompt to replace any peer's key with a new invitation, like [`rotate_admin`].
+pub fn rekey_peer(
+    peers: &[Peer],
+    args: &RekeyPeerOpts,
+) -> Result<Option<(Peer, KeyPair, String, File)>, Error> {
+    let peers = peers
+        .iter()
+        .filter(|p| &*p.name != "innernet-server")
+        .collect::<Vec<_>>();
+    rekey(&peers, "Peer", args)
+}
+
+fn rekey(
+    peers: &[&Peer],
+    kind: &str,
+    args: &RekeyPeerOpts,
+) -> Result<Option<(Peer, KeyPair, String, File)>, Error> {
+    let peer = if let Some(ref name) = args.name {
+        *peers
+            .iter()
+            .find(|p| &p.name == name)
+            .ok_or_else(|| anyhow!("{} '{}' does not exist", kind, name))?
+    } else {
+        let (index, _) = select(
+            &format!("{kind} to rekey"),
+            &peers.iter().map(|p| p.name.clone()).collect::<Vec<_>>(),
+        )?;
+        peers[index]
+    };
+
+    let invite_expires = if let Some(ref invite_expires) = args.invite_expires {
+        invite_expires.clone()
+    } else {
+        input(
+            "Invite expires after",
+            Prefill::Default("14d".parse().map_err(|s: &str| anyhow!(s))?),
+        )?
+    };
+
+    let invite_save_path = if let Some(ref location) = args.save_config {
+        location.clone()
+    } else {
+        input(
+            "Save peer invitation file to",
+            Prefill::Default(format!("{}.toml", peer.name)),
+        )?
+    };
+
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "Revoke {}'s current key and write a new invitation?",
+                peer.name.yellow()
+            ))?
+        {
+            let invite_file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&invite_save_path)?;
+            let mut peer = peer.clone();
+            peer.contents.invite_expires = Some(SystemTime::now() + invite_expires.into());
+            Some((peer, KeyPair::generate(), invite_save_path, invite_file))
+        } else {
+            None
+        },
+    )
+}
+
+/// Bring up a prompt to add or remove tags on a peer. Returns the updated peer contents.
+/// Pick a peer and the keepalive interval to give it, where `None` means the network's
+/// default.
+pub fn set_keepalive(
+    peers: &[Peer],
+    args: &SetKeepaliveOpts,
+) -> Result<Option<(Peer, Option<u16>)>, Error> {
+    let peer = if let Some(ref name) = args.name {
+        peers
+            .iter()
+            .find(|p| &p.name == name)
+            .ok_or_else(|| anyhow!("Peer '{}' does not exist", name))?
+            .clone()
+    } else {
+        let (peer_index, _) = select(
+            "Peer to set the keepalive of",
+            &peers.iter().map(|p| p.name.clone()).collect::<Vec<_>>(),
+        )?;
+        peers[peer_index].clone()
+    };
+
+    let interval = if args.default {
+        None
+    } else if let Some(interval) = args.interval {
+        Some(interval)
+    } else {
+        Some(input(
+            "Keepalive interval in seconds (0 disables)",
+            Prefill::Default(peer.persistent_keepalive_interval.unwrap_or(0)),
+        )?)
+    };
+
+    let description = match interval {
+        None => "the network default".to_string(),
+        Some(0) => "disabled".to_string(),
+        Some(interval) => format!("{interval}s"),
+    };
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "Set keepalive of peer {} to {}?",
+                peer.name.yellow(),
+                description.yellow()
+            ))?
+        {
+            Some((peer, interval))
+        } else {
+            None
+        },
+    )
+}
+
+pub fn tag_peer(peers: &[Peer], args: &TagPeerOpts) -> Result<Option<PeerContents>, Error> {
+    let mut peer = if let Some(ref name) = args.name {
+        peers
+            .iter()
+            .find(|p| &p.name == name)
+            .ok_or_else(|| anyhow!("Peer '{}' does not exist", name))?
+            .clone()
+    } else {
+        let (peer_index, _) = select(
+            "Peer to tag",
+            &peers.iter().map(|p| p.name.clone()).collect::<Vec<_>>(),
+        )?;
+        peers[peer_index].clone()
+    };
+
+    let tags = if args.tags.is_empty() {
+        let tags: String = input("Tags (comma-separated)", Prefill::None)?;
+        tags.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Tag>, _>>()
+            .map_err(|e| anyhow!(e))?
+    } else {
+        args.tags.clone()
+    };
+
+    if args.remove {
+        peer.tags.retain(|tag| !tags.contains(tag));
+    } else {
+        peer.tags.extend(tags.iter().cloned());
+    }
+    peer.tags.sort();
+    peer.tags.dedup();
+
+    let tag_list = tags
+        .iter()
+        .map(|t| t.yellow().to_string())
+        .collect::<Vec<_>>();
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "{} tag(s) {} {} peer {}?",
+                if args.remove { "Remove" } else { "Add" },
+                tag_list.join(", "),
+                if args.remove { "from" } else { "to" },
+                peer.name.yellow()
+            ))?
+        {
+            Some(peer.contents)
+        } else {
+            None
+        },
+    )
+}
+
 /// Presents a selection and confirmation of eligible peers for either disabling or enabling,
 /// and returns back the ID of the selected peer.
 pub fn enable_or_disable_peer(peers: &[Peer], enable: bool) -> Result<Option<Peer>, Error> {
@@ -433,23 +762,23 @@ pub fn write_peer_invitation(
     keypair: KeyPair,
     server_api_addr: &SocketAddr,
 ) -> Result<(), Error> {
-    let peer_invitation = InterfaceConfig {
-        interface: InterfaceInfo {
-            network_name: network_name.to_string(),
-            private_key: keypair.private.to_base64(),
-            address: IpNet::new(peer.ip, root_cidr.prefix_len())?,
-            listen_port: None,
-        },
-        server: ServerInfo {
-            external_endpoint: server_peer
-                .endpoint
-                .clone()
-                .expect("The innernet server should have a WireGuard endpoint"),
-            internal_endpoint: *server_api_addr,
-            public_key: server_peer.public_key.clone(),
-        },
-    };
+    let peer_invitation = InterfaceConfig::invitation(
+        network_name,
+        peer,
+        server_peer,
+        root_cidr,
+        &keypair,
+        server_api_addr,
+    )?;
+    write_invitation(target_file, peer, &peer_invitation)
+}
 
+/// Write an already built invitation file for `peer`.
+pub fn write_invitation(
+    target_file: (&mut File, &str),
+    peer: &Peer,
+    peer_invitation: &InterfaceConfig,
+) -> Result<(), Error> {
     peer_invitation.write_to(target_file.0, true, None)?;
 
     println!(
@@ -0,0 +1,517 @@
+//! Working out whether two peers are allowed to reach each other, and why.
+//!
+//! A peer sees every peer in its own CIDR (and the CIDRs below it), every infra peer, and
+//! every peer below a CIDR that's associated with its own CIDR or one of its ancestors.
+//! Associations aren't transitive, so at most one of them is ever involved.
+
+use crate::{Association, Cidr, Peer, PeerStatus};
+use colored::*;
+use ipnet::IpNet;
+use std::{collections::HashSet, fmt};
+
+/// The special CIDR that every peer can see, and that the server lives in.
+const INFRA_CIDR_ID: i64 = 2;
+
+/// The server's own peer, which has every peer on its interface.
+const SERVER_PEER_ID: i64 = 1;
+
+/// Why one peer is allowed to see another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Link<'a> {
+    /// The other peer is in the same CIDR as this one, or a CIDR below it.
+    SameCidr(&'a Cidr),
+    /// The other peer is an infra peer, which everyone can see.
+    Infra,
+    /// One of this peer's CIDRs is associated with one of the other peer's CIDRs.
+    Association {
+        association: &'a Association,
+        from: &'a Cidr,
+        to: &'a Cidr,
+    },
+}
+
+impl fmt::Display for Link<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SameCidr(cidr) => write!(f, "both in {}", cidr.name),
+            Self::Infra => write!(f, "infra peers are visible to everyone"),
+            Self::Association {
+                association,
+                from,
+                to,
+            } => write!(
+                f,
+                "association {}: {} <=> {}",
+                association.id, from.name, to.name
+            ),
+        }
+    }
+}
+
+/// What keeps one peer from seeing another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Blocker<'a> {
+    /// The other peer is staged, and hasn't been activated yet.
+    Staged,
+    /// The other peer is disabled.
+    Disabled,
+    /// The other peer hasn't redeemed its invitation yet.
+    NotRedeemed,
+    /// The other peer is an observer, which only the server knows about.
+    Observer,
+    /// The other peer is visible, but isn't given this one in return (ex. it's in a CIDR
+    /// below this one's).
+    OneWay,
+    /// None of this peer's CIDRs are associated with any of the other peer's CIDRs.
+    NoAssociation {
+        from: Vec<&'a Cidr>,
+        to: Vec<&'a Cidr>,
+    },
+}
+
+impl fmt::Display for Blocker<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |cidrs: &[&Cidr]| {
+            cidrs
+                .iter()
+                .map(|cidr| cidr.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Self::Staged => write!(f, "the peer is staged"),
+            Self::Disabled => write!(f, "the peer is disabled"),
+            Self::NotRedeemed => write!(f, "the peer hasn't redeemed its invitation"),
+            Self::Observer => write!(f, "the peer is an observer"),
+            Self::OneWay => write!(f, "the peer doesn't get this one in return"),
+            Self::NoAssociation { from, to } => write!(
+                f,
+                "no association between any of [{}] and any of [{}]",
+                names(from),
+                names(to)
+            ),
+        }
+    }
+}
+
+/// A CIDR followed by all of its ancestors, up to the root. A loop in the parent links
+/// (which shouldn't exist, but isn't worth hanging over) ends the walk.
+fn ancestors(cidrs: &[Cidr], cidr_id: i64) -> Vec<&Cidr> {
+    let mut seen = HashSet::new();
+    let mut chain = vec![];
+    let mut next = Some(cidr_id);
+    while let Some(id) = next {
+        if !seen.insert(id) {
+            break;
+        }
+        let Some(cidr) = cidrs.iter().find(|cidr| cidr.id == id) else {
+            break;
+        };
+        chain.push(cidr);
+        next = cidr.parent;
+    }
+    chain
+}
+
+/// Work out whether `from` is given `to` in its peer list, mirroring the server's rules.
+pub fn link<'a>(
+    cidrs: &'a [Cidr],
+    associations: &'a [Association],
+    from: &Peer,
+    to: &Peer,
+) -> Result<Link<'a>, Blocker<'a>> {
+    match state_blocker(from, to) {
+        Some(blocker) => Err(blocker),
+        None => cidr_link(cidrs, associations, from, to),
+    }
+}
+
+/// What keeps `from` from seeing `to` because of `to`'s state, regardless of their CIDRs.
+fn state_blocker<'a>(from: &Peer, to: &Peer) -> Option<Blocker<'a>> {
+    if to.is_staged {
+        Some(Blocker::Staged)
+    } else if to.is_disabled {
+        Some(Blocker::Disabled)
+    } else if !to.is_redeemed {
+        Some(Blocker::NotRedeemed)
+    } else if to.is_observer && to.id != from.id {
+        Some(Blocker::Observer)
+    } else {
+        None
+    }
+}
+
+/// Whether `from`'s CIDRs give it `to`, as if both peers were active.
+fn cidr_link<'a>(
+    cidrs: &'a [Cidr],
+    associations: &'a [Association],
+    from: &Peer,
+    to: &Peer,
+) -> Result<Link<'a>, Blocker<'a>> {
+    let from_cidrs = ancestors(cidrs, from.cidr_id);
+    let to_cidrs = ancestors(cidrs, to.cidr_id);
+    if let Some(own) = from_cidrs.first() {
+        if to_cidrs.iter().any(|cidr| cidr.id == own.id) {
+            return Ok(Link::SameCidr(own));
+        }
+    }
+    if to_cidrs.iter().any(|cidr| cidr.id == INFRA_CIDR_ID) {
+        return Ok(Link::Infra);
+    }
+
+    for association in associations {
+        let ends = [
+            (association.cidr_id_1, association.cidr_id_2),
+            (association.cidr_id_2, association.cidr_id_1),
+        ];
+        for (from_id, to_id) in ends {
+            let from_cidr = from_cidrs.iter().find(|cidr| cidr.id == from_id);
+            let to_cidr = to_cidrs.iter().find(|cidr| cidr.id == to_id);
+            if let (Some(from_cidr), Some(to_cidr)) = (from_cidr, to_cidr) {
+                return Ok(Link::Association {
+                    association,
+                    from: from_cidr,
+                    to: to_cidr,
+                });
+            }
+        }
+    }
+
+    Err(Blocker::NoAssociation {
+        from: from_cidrs,
+        to: to_cidrs,
+    })
+}
+
+/// One destination in a peer's routing table.
+#[derive(Debug)]
+pub struct Route<'a> {
+    /// The serving peer's own IP, or one of the routes it advertises.
+    pub destination: IpNet,
+    /// The peer that traffic to `destination` goes to.
+    pub via: &'a Peer,
+    /// Why the peer gets this route.
+    pub link: Link<'a>,
+    /// What keeps the route from working right now, if anything: `via`'s state, or `via`
+    /// not having the peer in its own peer list (WireGuard needs both).
+    pub blocker: Option<Blocker<'a>>,
+}
+
+/// Every destination `from`'s CIDRs and associations give it a route to, sorted by
+/// destination. Routes to peers that are only unusable because of their (or `from`'s)
+/// current state are kept, with the reason.
+pub fn routes<'a>(
+    cidrs: &'a [Cidr],
+    associations: &'a [Association],
+    peers: &'a [Peer],
+    from: &Peer,
+) -> Vec<Route<'a>> {
+    let mut routes = vec![];
+    for via in peers.iter().filter(|peer| peer.id != from.id) {
+        let Ok(link) = cidr_link(cidrs, associations, from, via) else {
+            continue;
+        };
+        let blocker = state_blocker(from, via).or_else(|| {
+            let one_way =
+                via.id != SERVER_PEER_ID && cidr_link(cidrs, associations, via, from).is_err();
+            one_way.then_some(Blocker::OneWay)
+        });
+        let destinations =
+            std::iter::once(IpNet::from(via.ip)).chain(via.advertised_routes.iter().copied());
+        for destination in destinations {
+            routes.push(Route {
+                destination,
+                via,
+                link: link.clone(),
+                blocker: blocker.clone(),
+            });
+        }
+    }
+    routes.sort_by_key(|route| route.destination);
+    routes
+}
+
+/// Print `peer`'s routing table, marking the routes that don't work right now.
+pub fn print_routes(cidrs: &[Cidr], associations: &[Association], peers: &[Peer], peer: &Peer) {
+    let routes = routes(cidrs, associations, peers, peer);
+    println!(
+        "{} {} ({}) can route to {} destination{}:",
+        "[*]".dimmed(),
+        peer.name.yellow(),
+        peer.ip,
+        routes.len(),
+        if routes.len() == 1 { "" } else { "s" }
+    );
+    // The peer's own state affects every route at once, so it's only mentioned once.
+    let own_state = match peer.status() {
+        PeerStatus::Staged => Some("is staged, so none of these work until it's activated"),
+        PeerStatus::Disabled => Some("is disabled, so none of these work"),
+        PeerStatus::Active if !peer.is_redeemed => {
+            Some("hasn't redeemed its invitation, so none of these work yet")
+        },
+        PeerStatus::Active if peer.is_observer => {
+            Some("is an observer, so only the server has it in its peer list")
+        },
+        PeerStatus::Active => None,
+    };
+    if let Some(own_state) = own_state {
+        println!("{} {} {}.", "[!]".red(), peer.name.yellow(), own_state);
+    }
+    let width = routes
+        .iter()
+        .map(|route| route.destination.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for route in &routes {
+        let destination = format!("{:width$}", route.destination.to_string());
+        match &route.blocker {
+            None => println!(
+                "    {} via {}: {}",
+                destination,
+                route.via.name.yellow(),
+                route.link
+            ),
+            Some(blocker) => println!(
+                "    {} via {}: {} ({})",
+                destination.dimmed(),
+                route.via.name.yellow(),
+                route.link,
+                format!("unreachable, {blocker}").red()
+            ),
+        }
+    }
+}
+
+/// Print whether two peers can reach each other, with the link or blocker for each
+/// direction. Returns whether they're mutually routable.
+pub fn report(cidrs: &[Cidr], associations: &[Association], a: &Peer, b: &Peer) -> bool {
+    let mut reachable = true;
+    for (from, to) in [(a, b), (b, a)] {
+        match link(cidrs, associations, from, to) {
+            Ok(link) => println!(
+                "    {} -> {}: {}",
+                from.name.yellow(),
+                to.name.yellow(),
+                link
+            ),
+            Err(blocker) => {
+                reachable = false;
+                println!(
+                    "    {} -> {}: {}",
+                    from.name.yellow(),
+                    to.name.yellow(),
+                    blocker.to_string().red()
+                );
+            },
+        }
+    }
+
+    if reachable {
+        println!(
+            "{} {} and {} can reach each other.",
+            "[*]".dimmed(),
+            a.name.yellow(),
+            b.name.yellow()
+        );
+    } else {
+        println!(
+            "{} {} and {} can't reach each other.",
+            "[!]".red(),
+            a.name.yellow(),
+            b.name.yellow()
+        );
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AssociationContents, CidrContents, PeerContents};
+
+    fn cidr(id: i64, name: &str, cidr: &str, parent: Option<i64>) -> Cidr {
+        Cidr {
+            id,
+            contents: CidrContents {
+                name: name.into(),
+                cidr: cidr.parse().unwrap(),
+                parent,
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
+            },
+        }
+    }
+
+    fn association(id: i64, cidr_id_1: i64, cidr_id_2: i64) -> Association {
+        Association {
+            id,
+            contents: AssociationContents {
+                cidr_id_1,
+                cidr_id_2,
+            },
+        }
+    }
+
+    fn peer(id: i64, name: &str, cidr_id: i64) -> Peer {
+        Peer {
+            id,
+            contents: PeerContents {
+                name: name.parse().unwrap(),
+                ip: format!("10.80.{}.{}", cidr_id, id).parse().unwrap(),
+                cidr_id,
+                public_key: format!("key{id}"),
+                endpoint: None,
+                persistent_keepalive_interval: None,
+                is_admin: false,
+                is_disabled: false,
+                is_redeemed: true,
+                invite_expires: None,
+                candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
+            },
+        }
+    }
+
+    fn network() -> Vec<Cidr> {
+        vec![
+            cidr(1, "root", "10.80.0.0/15", None),
+            cidr(2, "infra", "10.80.0.0/24", Some(1)),
+            cidr(3, "engineering", "10.80.64.0/18", Some(1)),
+            cidr(4, "developers", "10.80.64.0/24", Some(3)),
+            cidr(5, "ops", "10.80.65.0/24", Some(3)),
+            cidr(6, "users", "10.80.128.0/17", Some(1)),
+        ]
+    }
+
+    #[test]
+    fn test_same_cidr_and_infra() {
+        let cidrs = network();
+        let (dev1, dev2, server) = (
+            peer(10, "dev1", 4),
+            peer(11, "dev2", 4),
+            peer(1, "server", 2),
+        );
+        assert_eq!(
+            link(&cidrs, &[], &dev1, &dev2),
+            Ok(Link::SameCidr(&cidrs[3]))
+        );
+        assert_eq!(link(&cidrs, &[], &dev1, &server), Ok(Link::Infra));
+    }
+
+    #[test]
+    fn test_association_through_ancestor() {
+        let cidrs = network();
+        let associations = [association(1, 6, 3)];
+        let (dev, ops, user) = (peer(10, "dev", 4), peer(11, "ops", 5), peer(12, "user", 6));
+
+        // Associating engineering with users covers everyone below engineering too.
+        assert_eq!(
+            link(&cidrs, &associations, &dev, &user),
+            Ok(Link::Association {
+                association: &associations[0],
+                from: &cidrs[2],
+                to: &cidrs[5],
+            })
+        );
+        assert!(link(&cidrs, &associations, &user, &dev).is_ok());
+
+        // Sharing an ancestor isn't enough without an association.
+        match link(&cidrs, &[], &dev, &ops) {
+            Err(Blocker::NoAssociation { from, to }) => {
+                assert_eq!(from.len(), 3);
+                assert_eq!(to.len(), 3);
+            },
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_blocked_peers() {
+        let cidrs = network();
+        let dev = peer(10, "dev", 4);
+        let mut other = peer(11, "other", 4);
+        other.is_observer = true;
+        assert_eq!(link(&cidrs, &[], &dev, &other), Err(Blocker::Observer));
+        other.is_disabled = true;
+        assert_eq!(link(&cidrs, &[], &dev, &other), Err(Blocker::Disabled));
+    }
+
+    #[test]
+    fn test_routes() {
+        let cidrs = network();
+        let associations = [association(1, 6, 3)];
+        let mut gateway = peer(11, "gateway", 5);
+        gateway.advertised_routes = vec!["192.168.5.0/24".parse().unwrap()];
+        let mut staged = peer(12, "staged", 6);
+        (staged.is_staged, staged.is_disabled) = (true, true);
+        let peers = [
+            peer(1, "server", 2),
+            peer(10, "dev", 4),
+            gateway,
+            staged,
+            peer(13, "engineer", 3),
+        ];
+
+        let routes = routes(&cidrs, &associations, &peers, &peers[1]);
+        let table = routes
+            .iter()
+            .map(|route| {
+                (
+                    route.destination.to_string(),
+                    &*route.via.name,
+                    route.blocker.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // Ops isn't associated with developers, and the engineer in the CIDR above sees
+        // developers but isn't seen by them.
+        assert_eq!(
+            table,
+            [
+                ("10.80.2.1/32".into(), "server", None),
+                ("10.80.6.12/32".into(), "staged", Some(Blocker::Staged)),
+            ]
+        );
+
+        let routes = super::routes(&cidrs, &associations, &peers, &peers[4]);
+        assert_eq!(
+            routes
+                .iter()
+                .map(|route| (route.destination.to_string(), route.blocker.clone()))
+                .collect::<Vec<_>>(),
+            [
+                ("10.80.2.1/32".into(), None),
+                ("10.80.4.10/32".into(), Some(Blocker::OneWay)),
+                ("10.80.5.11/32".into(), Some(Blocker::OneWay)),
+                ("10.80.6.12/32".into(), Some(Blocker::Staged)),
+                ("192.168.5.0/24".into(), Some(Blocker::OneWay)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cidr_cycle() {
+        // A broken tree where two CIDRs are each other's parent.
+        let cidrs = vec![
+            cidr(3, "a", "10.80.64.0/24", Some(4)),
+            cidr(4, "b", "10.80.65.0/24", Some(3)),
+            cidr(5, "c", "10.80.66.0/24", Some(5)),
+        ];
+        let associations = [association(1, 3, 4), association(2, 4, 3)];
+        let (a, c) = (peer(10, "a", 3), peer(11, "c", 5));
+        assert!(matches!(
+            link(&cidrs, &associations, &a, &c),
+            Err(Blocker::NoAssociation { .. })
+        ));
+    }
+}
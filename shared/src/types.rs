@@ -1,3 +1,4 @@
+use crate::allocation::IpAllocation;
 use anyhow::{anyhow, Error};
 use clap::{
     builder::{PossibleValuesParser, TypedValueParser},
@@ -10,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     fmt::{self, Display, Formatter},
     io,
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
     ops::{Deref, DerefMut},
     path::Path,
     str::FromStr,
@@ -58,6 +59,9 @@ impl Display for Interface {
     }
 }
 
+/// The port used for endpoints that are given without one, WireGuard's conventional port.
+pub const DEFAULT_ENDPOINT_PORT: u16 = 51820;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 /// An external endpoint that supports both IP and domain name hosts.
 pub struct Endpoint {
@@ -83,15 +87,47 @@ impl From<SocketAddr> for Endpoint {
 impl FromStr for Endpoint {
     type Err = &'static str;
 
+    /// Parses `host:port`, where an IPv6 host must be in brackets (`[2001:db8::1]:51820`),
+    /// as WireGuard requires. A host without a port, including a bare IPv6 address, gets
+    /// [`DEFAULT_ENDPOINT_PORT`].
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.rsplitn(2, ':').collect::<Vec<&str>>().as_slice() {
-            [port, host] => {
-                let port = port.parse().map_err(|_| "couldn't parse port")?;
-                let host = Host::parse(host).map_err(|_| "couldn't parse host")?;
-                Ok(Endpoint { host, port })
-            },
-            _ => Err("couldn't parse in form of 'host:port'"),
+        if let Some(rest) = s.strip_prefix('[') {
+            let (ip, rest) = rest
+                .split_once(']')
+                .ok_or("missing closing ']' in IPv6 endpoint")?;
+            let ip = ip.parse().map_err(|_| "couldn't parse IPv6 address")?;
+            let port = match rest {
+                "" => DEFAULT_ENDPOINT_PORT,
+                _ => rest
+                    .strip_prefix(':')
+                    .ok_or("expected ':port' after IPv6 address")?
+                    .parse()
+                    .map_err(|_| "couldn't parse port")?,
+            };
+            return Ok(Endpoint {
+                host: Host::Ipv6(ip),
+                port,
+            });
+        }
+
+        if let Ok(ip) = s.parse::<Ipv6Addr>() {
+            return Ok(Endpoint {
+                host: Host::Ipv6(ip),
+                port: DEFAULT_ENDPOINT_PORT,
+            });
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "couldn't parse port")?),
+            None => (s, DEFAULT_ENDPOINT_PORT),
+        };
+        if host.contains(':') {
+            return Err(
+                "IPv6 addresses with a port must be in brackets, ex. '[2001:db8::1]:51820'",
+            );
         }
+        let host = Host::parse(host).map_err(|_| "couldn't parse host")?;
+        Ok(Endpoint { host, port })
     }
 }
 
@@ -137,15 +173,77 @@ impl Display for Endpoint {
 }
 
 impl Endpoint {
+    /// Whether the endpoint is a hostname rather than an IP address.
+    pub fn is_hostname(&self) -> bool {
+        matches!(self.host, Host::Domain(_))
+    }
+
+    /// Resolve the endpoint to a single socket address.
+    ///
+    /// Hostnames aren't cached, so calling this again later follows any DNS changes. If a
+    /// hostname has both A and AAAA records, IPv4 is preferred unless this machine has no
+    /// route to it, and the lowest address of the chosen family is used so that the choice
+    /// stays consistent regardless of resolver ordering.
     pub fn resolve(&self) -> Result<SocketAddr, io::Error> {
-        let mut addrs = self.to_string().to_socket_addrs()?;
-        addrs.next().ok_or_else(|| {
+        let mut addrs: Vec<_> = self.to_string().to_socket_addrs()?.collect();
+        addrs.sort_by_key(|addr| (addr.is_ipv6(), *addr));
+
+        let routable = if addrs.len() > 1 {
+            addrs.iter().find(|addr| Self::is_routable(addr))
+        } else {
+            None
+        };
+        routable.or_else(|| addrs.first()).copied().ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::AddrNotAvailable,
                 "failed to resolve address".to_string(),
             )
         })
     }
+
+    /// Whether the OS has a route to the given address. Connecting a UDP socket doesn't
+    /// send any packets, but fails if there's no route.
+    fn is_routable(addr: &SocketAddr) -> bool {
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        UdpSocket::bind(bind_addr)
+            .and_then(|socket| socket.connect(addr))
+            .is_ok()
+    }
+}
+
+/// Which IP version to try first among a peer's endpoints.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FamilyPreference {
+    /// Keep endpoints in the order they were reported.
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+impl FamilyPreference {
+    pub fn is_auto(&self) -> bool {
+        *self == Self::Auto
+    }
+
+    /// Whether `endpoint` should only be tried after the preferred family. Hostnames never
+    /// are, since their family isn't known until they're resolved.
+    pub fn is_fallback(&self, endpoint: &Endpoint) -> bool {
+        matches!(
+            (self, &endpoint.host),
+            (Self::Ipv4, Host::Ipv6(_)) | (Self::Ipv6, Host::Ipv4(_))
+        )
+    }
+
+    /// Move endpoints of the preferred family ahead of the others, keeping the order within
+    /// each family.
+    pub fn sort(&self, endpoints: &mut [Endpoint]) {
+        endpoints.sort_by_key(|endpoint| self.is_fallback(endpoint));
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -173,13 +271,13 @@ impl From<Option<Endpoint>> for EndpointContents {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct AssociationContents {
     pub cidr_id_1: i64,
     pub cidr_id_2: i64,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct Association {
     pub id: i64,
 
@@ -200,6 +298,21 @@ pub struct CidrContents {
     pub name: String,
     pub cidr: IpNet,
     pub parent: Option<i64>,
+    /// The part of the CIDR that new peers are automatically given IPs from, leaving the
+    /// rest for addresses handed out by hand (ex. infrastructure).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocation_range: Option<IpRange>,
+    /// The keepalive interval new peers in this CIDR (or its children) are created with.
+    /// Zero disables keepalive. See [`inherited_cidr_default`] for how it's inherited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_keepalive: Option<u16>,
+    /// The MTU peers in this CIDR (or its children) bring their interface up with, unless
+    /// given `--mtu`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_mtu: Option<u32>,
+    /// The most peers this CIDR may hold, in place of the network's `max-peers`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_peers: Option<u32>,
 }
 
 impl Deref for CidrContents {
@@ -232,6 +345,44 @@ impl Deref for Cidr {
     }
 }
 
+/// Whether two networks share any addresses. Since CIDR blocks are aligned, that only
+/// happens when one contains the other.
+pub fn cidrs_overlap(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+/// Find an existing CIDR that a new `cidr` would make routing ambiguous with, i.e. one that
+/// overlaps it without strictly containing it. Strictly larger CIDRs are left alone since
+/// they can be its ancestors.
+pub fn find_conflicting_cidr<'a>(cidr: &IpNet, cidrs: &'a [Cidr]) -> Option<&'a Cidr> {
+    cidrs.iter().find(|existing| {
+        let is_ancestor = existing.cidr.prefix_len() < cidr.prefix_len()
+            && existing.cidr.contains(&cidr.network());
+        cidrs_overlap(&existing.cidr, cidr) && !is_ancestor
+    })
+}
+
+/// Look up a per-CIDR default (ex. `|c| c.default_mtu`) for the CIDR with id `cidr_id`.
+///
+/// The CIDR's own value wins, then its parent's, and so on up to the root, so the nearest
+/// ancestor that sets a value is the one that applies. `None` if no CIDR on the way sets one.
+pub fn inherited_cidr_default<T>(
+    cidrs: &[Cidr],
+    cidr_id: i64,
+    get: impl Fn(&CidrContents) -> Option<T>,
+) -> Option<T> {
+    let mut next = Some(cidr_id);
+    // Bounded by the number of CIDRs, in case the tree has a cycle in it.
+    for _ in 0..cidrs.len() {
+        let cidr = cidrs.iter().find(|cidr| Some(cidr.id) == next)?;
+        if let Some(value) = get(&cidr.contents) {
+            return Some(value);
+        }
+        next = cidr.parent;
+    }
+    None
+}
+
 #[derive(Clone, PartialEq, PartialOrd, Eq, Ord)]
 pub struct CidrTree<'a> {
     cidrs: &'a [Cidr],
@@ -284,6 +435,17 @@ impl<'a> CidrTree<'a> {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RedeemContents {
     pub public_key: String,
+    /// Whether the client supports preshared keys, opting the peer in to them. Older
+    /// clients leave this out.
+    #[serde(default)]
+    pub preshared_keys: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KeepaliveContents {
+    /// The keepalive interval in seconds, where 0 disables keepalive and `None` uses the
+    /// network's default.
+    pub persistent_keepalive_interval: Option<u16>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
@@ -299,22 +461,57 @@ pub struct InstallOpts {
     /// Delete the invitation after a successful install
     #[clap(short, long)]
     pub delete_invite: bool,
+
+    /// Generate and enable a systemd service (innernet@<interface>) that keeps the
+    /// interface up to date. Uninstalling the network removes it again
+    #[clap(long)]
+    pub systemd: bool,
+
+    /// Keep the private key in the OS keyring (Secret Service or macOS Keychain) under this
+    /// identifier, instead of in the interface's config file
+    #[clap(long, value_name = "ID")]
+    pub keyring: Option<String>,
+
+    /// Only redeem the invitation and write the interface's config, leaving the interface
+    /// down for a later `innernet up` (or the systemd service) to bring up
+    #[clap(long, alias = "install-only")]
+    pub no_up: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct AddPeerOpts {
     /// Name of new peer
-    #[clap(long)]
+    #[clap(long, value_parser = Hostname::parse_allowed)]
     pub name: Option<Hostname>,
 
+    /// Allow a name that isn't DNS-safe (ex. with uppercase letters or underscores), for
+    /// networks that don't use the hosts file or DNS
+    #[clap(long)]
+    pub allow_unsafe_name: bool,
+
     /// Specify desired IP of new peer (within parent CIDR)
     #[clap(long, conflicts_with = "auto_ip")]
     pub ip: Option<IpAddr>,
 
+    /// Only pick the new peer's IP from at or after this address, instead of the CIDR's
+    /// allocation range
+    #[clap(long, conflicts_with = "ip")]
+    pub from: Option<IpAddr>,
+
+    /// Only pick the new peer's IP from at or before this address, instead of the CIDR's
+    /// allocation range
+    #[clap(long, conflicts_with = "ip")]
+    pub to: Option<IpAddr>,
+
     /// Auto-assign the peer the first available IP within the CIDR
     #[clap(long = "auto-ip")]
     pub auto_ip: bool,
 
+    /// How to pick the peer's IP: the lowest free one, or one derived from the peer's name.
+    /// On the server, defaults to the network's `ip-allocation` setting
+    #[clap(long, value_enum, conflicts_with = "ip")]
+    pub ip_allocation: Option<IpAllocation>,
+
     /// Name of CIDR to add new peer under
     #[clap(long)]
     pub cidr: Option<String>,
@@ -334,6 +531,20 @@ pub struct AddPeerOpts {
     /// Invite expiration period (eg. '30d', '7w', '2h', '60m', '1000s')
     #[clap(long)]
     pub invite_expires: Option<Timestring>,
+
+    /// Tag to attach to the new peer (can be repeated)
+    #[clap(long = "tag")]
+    pub tags: Vec<Tag>,
+
+    /// Make the new peer a read-only observer, which can fetch the peer list but isn't
+    /// routable from other peers
+    #[clap(long)]
+    pub observer: bool,
+
+    /// Stage the new peer: its IP and invitation are created, but it stays out of the
+    /// network until it's activated
+    #[clap(long)]
+    pub staged: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
@@ -351,6 +562,79 @@ pub struct RenamePeerOpts {
     pub yes: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct TagPeerOpts {
+    /// Name of peer to tag
+    pub name: Option<Hostname>,
+
+    /// The tags to add to (or remove from) the peer
+    pub tags: Vec<Tag>,
+
+    /// Remove the given tags instead of adding them
+    #[clap(long)]
+    pub remove: bool,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct SetKeepaliveOpts {
+    /// Name of peer to set the keepalive interval of
+    #[clap(long)]
+    pub name: Option<Hostname>,
+
+    /// The keepalive interval in seconds, or 0 to disable keepalive for the peer
+    #[clap(long, conflicts_with = "default")]
+    pub interval: Option<u16>,
+
+    /// Go back to using the network's default interval
+    #[clap(long)]
+    pub default: bool,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct RotateAdminOpts {
+    /// Name of the admin peer whose key should be replaced
+    #[clap(long)]
+    pub name: Option<Hostname>,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+
+    /// Save the new invitation to the given location
+    #[clap(long)]
+    pub save_config: Option<String>,
+
+    /// Invite expiration period (eg. '30d', '7w', '2h', '60m', '1000s')
+    #[clap(long)]
+    pub invite_expires: Option<Timestring>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct RekeyPeerOpts {
+    /// Name of the peer whose key should be replaced
+    pub name: Option<Hostname>,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+
+    /// Save the new invitation to the given location
+    #[clap(long)]
+    pub save_config: Option<String>,
+
+    /// Invite expiration period (eg. '30d', '7w', '2h', '60m', '1000s')
+    #[clap(long)]
+    pub invite_expires: Option<Timestring>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct AddCidrOpts {
     /// The CIDR name (eg. 'engineers')
@@ -365,6 +649,37 @@ pub struct AddCidrOpts {
     #[clap(long)]
     pub parent: Option<String>,
 
+    /// Only auto-assign new peers IPs from this part of the CIDR
+    /// (eg. '10.42.5.100-10.42.5.200')
+    #[clap(long)]
+    pub allocation_range: Option<IpRange>,
+
+    /// The keepalive interval in seconds that new peers in this CIDR are created with
+    /// (0 disables keepalive)
+    #[clap(long)]
+    pub default_keepalive: Option<u16>,
+
+    /// The MTU that peers in this CIDR bring their interface up with
+    #[clap(long)]
+    pub default_mtu: Option<u32>,
+
+    /// The most peers this CIDR may hold, in place of the network's limit
+    #[clap(long)]
+    pub max_peers: Option<u32>,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct RenameCidrOpts {
+    /// Name of CIDR to rename
+    pub name: Option<String>,
+
+    /// The new name of the CIDR
+    pub new_name: Option<Hostname>,
+
     /// Bypass confirmation
     #[clap(long)]
     pub yes: bool,
@@ -411,7 +726,8 @@ pub struct ListenPortOpts {
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct OverrideEndpointOpts {
-    /// The listen port you'd like to set for the interface
+    /// The external endpoint you'd like to set, in the form 'host:port'. The host can be
+    /// a DNS name, in which case peers re-resolve it rather than using a snapshot IP
     #[clap(short, long)]
     pub endpoint: Option<Endpoint>,
 
@@ -469,8 +785,9 @@ pub struct NetworkOpts {
     pub no_routing: bool,
 
     #[clap(long, default_value_t, value_parser = PossibleValuesParser::new(Backend::variants()).map(|s| s.parse::<Backend>().unwrap()))]
-    /// Specify a WireGuard backend to use.
-    /// If not set, innernet will auto-select based on availability.
+    /// Specify a WireGuard backend to use (auto, kernel, or userspace).
+    /// 'auto' uses the kernel module if it's available, and falls back to a userspace
+    /// implementation (wireguard-go, or $WG_USERSPACE_IMPLEMENTATION) otherwise.
     pub backend: Backend,
 
     #[clap(long)]
@@ -492,6 +809,170 @@ pub struct PeerContents {
     pub invite_expires: Option<SystemTime>,
     #[serde(default)]
     pub candidates: Vec<Endpoint>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    /// The WireGuard preshared key for the link between the requesting peer and this one.
+    /// Only filled in by the server for the peer it's talking to, and never stored as-is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+    /// When the server's interface last completed a handshake with this peer, if ever.
+    /// Only filled in by the server in peer lists, and never stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_handshake: Option<SystemTime>,
+    /// Extra subnets routed through this peer (ex. a site-to-site gateway's LAN), which are
+    /// added to its allowed-IPs on every other peer. Always serialized, so the server can tell
+    /// an update clearing them from an older client that leaves them out.
+    #[serde(default)]
+    pub advertised_routes: Vec<IpNet>,
+    /// Observers can fetch the peer list, but are left out of every other peer's
+    /// configuration, so the only tunnel they get is the one to the server. Always serialized,
+    /// like `advertised_routes`, so an update can't mistake it for being left out.
+    #[serde(default)]
+    pub is_observer: bool,
+    /// Staged peers have been created (ex. to send their invitation) but not activated yet.
+    /// They're kept disabled until then, see [`PeerContents::status`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_staged: bool,
+    /// When the peer was created, or null for peers created before this was recorded.
+    /// Set by the server.
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+    /// The name of the admin peer that created this peer (the server's own peer for
+    /// `innernet-server`), or null for peers created before this was recorded. Set by the
+    /// server.
+    #[serde(default)]
+    pub created_by: Option<String>,
+    /// What the peer last reported about itself, if it opted in. Advisory only, since
+    /// nothing stops a peer from reporting whatever it likes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<PeerDescription>,
+}
+
+/// A peer's own description of the machine it runs on, for inventory.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PeerDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// The version of innernet the peer runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl PeerDescription {
+    /// The most characters any one field keeps.
+    pub const MAX_FIELD_LEN: usize = 64;
+
+    /// Drop control characters, surrounding whitespace, anything past `MAX_FIELD_LEN`, and
+    /// fields left empty, so that whatever a peer reports is safe to store and print.
+    pub fn sanitized(&self) -> Self {
+        let sanitize = |field: &Option<String>| {
+            field
+                .as_deref()
+                .map(|field| {
+                    field
+                        .chars()
+                        .filter(|c| !c.is_control())
+                        .collect::<String>()
+                        .trim()
+                        .chars()
+                        .take(Self::MAX_FIELD_LEN)
+                        .collect::<String>()
+                })
+                .filter(|field| !field.is_empty())
+        };
+        Self {
+            hostname: sanitize(&self.hostname),
+            os: sanitize(&self.os),
+            version: sanitize(&self.version),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.os.is_none() && self.version.is_none()
+    }
+}
+
+impl Display for PeerDescription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let version = self.version.as_ref().map(|v| format!("innernet {v}"));
+        let fields = [self.hostname.as_ref(), self.os.as_ref(), version.as_ref()];
+        let fields = fields.into_iter().flatten().cloned().collect::<Vec<_>>();
+        f.write_str(&fields.join(", "))
+    }
+}
+
+/// Whether a peer is part of the network, or why it isn't.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerStatus {
+    Active,
+    /// Never activated yet.
+    Staged,
+    /// Deactivated.
+    Disabled,
+}
+
+impl Display for PeerStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Active => "active",
+            Self::Staged => "staged",
+            Self::Disabled => "disabled",
+        })
+    }
+}
+
+impl PeerContents {
+    pub fn status(&self) -> PeerStatus {
+        if self.is_staged {
+            PeerStatus::Staged
+        } else if self.is_disabled {
+            PeerStatus::Disabled
+        } else {
+            PeerStatus::Active
+        }
+    }
+
+    /// The peer's own address, followed by any routes it advertises, without duplicates.
+    pub fn allowed_ips(&self) -> Vec<AllowedIp> {
+        let own = AllowedIp {
+            address: self.ip,
+            cidr: if self.ip.is_ipv4() { 32 } else { 128 },
+        };
+        let mut allowed_ips = vec![own];
+        for route in &self.advertised_routes {
+            let allowed_ip = AllowedIp {
+                address: route.network(),
+                cidr: route.prefix_len(),
+            };
+            if !allowed_ips.contains(&allowed_ip) {
+                allowed_ips.push(allowed_ip);
+            }
+        }
+        allowed_ips
+    }
+}
+
+/// Find the advertised routes of `peer` that overlap with a route advertised by another
+/// peer, which means traffic for the overlap only goes to one of them.
+pub fn overlapping_routes<'a>(peer: &PeerContents, peers: &'a [Peer]) -> Vec<(&'a Peer, IpNet)> {
+    peers
+        .iter()
+        .filter(|other| other.public_key != peer.public_key)
+        .flat_map(|other| {
+            other
+                .advertised_routes
+                .iter()
+                .filter(|route| {
+                    peer.advertised_routes
+                        .iter()
+                        .any(|own| own.contains(*route) || route.contains(own))
+                })
+                .map(move |route| (other, *route))
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -536,6 +1017,7 @@ pub enum PeerChange {
         old: Option<SocketAddr>,
         new: Option<SocketAddr>,
     },
+    PresharedKey,
     NatTraverseReattempt,
 }
 
@@ -555,6 +1037,7 @@ impl Display for PeerChange {
                 old.display_string(),
                 new.display_string()
             ),
+            Self::PresharedKey => write!(f, "Preshared Key updated"),
             Self::NatTraverseReattempt => write!(f, "NAT Traversal Reattempt"),
         }
     }
@@ -589,6 +1072,16 @@ impl<'a> PeerDiff<'a> {
     pub fn new(
         old_info: Option<&'a PeerInfo>,
         new: Option<&'a Peer>,
+    ) -> Result<Option<Self>, Error> {
+        Self::with_resolver(old_info, new, |endpoint| endpoint.resolve().ok())
+    }
+
+    /// Like `new()`, but with the new peer's endpoint (if it needs one) looked up by
+    /// `resolve`, so that callers can resolve endpoints ahead of time.
+    pub fn with_resolver(
+        old_info: Option<&'a PeerInfo>,
+        new: Option<&'a Peer>,
+        resolve: impl FnOnce(&Endpoint) -> Option<SocketAddr>,
     ) -> Result<Option<Self>, Error> {
         let old = old_info.map(|p| &p.config);
         match (old_info, new) {
@@ -597,7 +1090,7 @@ impl<'a> PeerDiff<'a> {
             ),
             (None, None) => Ok(None),
             _ => Ok(
-                Self::peer_config_builder(old_info, new).map(|(builder, changes)| Self {
+                Self::peer_config_builder(old_info, new, resolve).map(|(builder, changes)| Self {
                     old,
                     new,
                     builder,
@@ -618,6 +1111,7 @@ impl<'a> PeerDiff<'a> {
     fn peer_config_builder(
         old_info: Option<&PeerInfo>,
         new: Option<&Peer>,
+        resolve: impl FnOnce(&Endpoint) -> Option<SocketAddr>,
     ) -> Option<(PeerConfigBuilder, Vec<PeerChange>)> {
         let old = old_info.map(|p| &p.config);
         let public_key = match (old, new) {
@@ -635,40 +1129,58 @@ impl<'a> PeerDiff<'a> {
         // diff.new is now guaranteed to be a Some(_) variant.
         let new = new.unwrap();
 
-        let new_allowed_ips = &[AllowedIp {
-            address: new.ip,
-            cidr: if new.ip.is_ipv4() { 32 } else { 128 },
-        }];
-        if old.is_none() || matches!(old, Some(old) if old.allowed_ips != new_allowed_ips) {
+        let new_allowed_ips = &new.allowed_ips();
+        // The interface may report allowed-IPs in any order.
+        let allowed_ips_changed = |old: &PeerConfig| {
+            old.allowed_ips.len() != new_allowed_ips.len()
+                || !new_allowed_ips
+                    .iter()
+                    .all(|ip| old.allowed_ips.contains(ip))
+        };
+        if old.is_none() || matches!(old, Some(old) if allowed_ips_changed(old)) {
             builder = builder
                 .replace_allowed_ips()
                 .add_allowed_ips(new_allowed_ips);
             changes.push(PeerChange::AllowedIPs {
-                old: old.map(|o| o.allowed_ips.clone()).unwrap_or_else(Vec::new),
+                old: old.map(|o| o.allowed_ips.clone()).unwrap_or_default(),
                 new: new_allowed_ips.to_vec(),
             });
         }
 
-        if old.is_none()
-            || matches!(old, Some(old) if old.persistent_keepalive_interval != new.persistent_keepalive_interval)
-        {
+        // Devices report a disabled keepalive as an interval of 0.
+        let old_keepalive = old
+            .and_then(|p| p.persistent_keepalive_interval)
+            .filter(|&secs| secs != 0);
+        if old.is_none() || old_keepalive != new.persistent_keepalive_interval {
             builder = match new.persistent_keepalive_interval {
                 Some(interval) => builder.set_persistent_keepalive_interval(interval),
                 None => builder.unset_persistent_keepalive(),
             };
             changes.push(PeerChange::PersistentKeepalive {
-                old: old.and_then(|p| p.persistent_keepalive_interval),
+                old: old_keepalive,
                 new: new.persistent_keepalive_interval,
             });
         }
 
+        let new_preshared_key = new
+            .preshared_key
+            .as_ref()
+            .and_then(|key| Key::from_base64(key).ok());
+        if old.and_then(|p| p.preshared_key.as_ref()) != new_preshared_key.as_ref() {
+            builder = match new_preshared_key {
+                Some(key) => builder.set_preshared_key(key),
+                None => builder.unset_preshared_key(),
+            };
+            changes.push(PeerChange::PresharedKey);
+        }
+
         // We won't update the endpoint if there's already a stable connection.
         if !old_info
             .map(|info| info.is_recently_connected())
             .unwrap_or_default()
         {
             let mut endpoint_changed = false;
-            let resolved = new.endpoint.as_ref().and_then(|e| e.resolve().ok());
+            let resolved = new.endpoint.as_ref().and_then(resolve);
             if let Some(addr) = resolved {
                 if old.is_none() || matches!(old, Some(old) if old.endpoint != resolved) {
                     builder = builder.set_endpoint(addr);
@@ -692,7 +1204,7 @@ impl<'a> PeerDiff<'a> {
     }
 }
 
-impl<'a> From<&'a Peer> for PeerConfigBuilder {
+impl From<&Peer> for PeerConfigBuilder {
     fn from(peer: &Peer) -> Self {
         PeerDiff::new(None, Some(peer))
             .expect("No Err on explicitly set peer data")
@@ -721,6 +1233,16 @@ pub struct State {
     /// At the moment, this is all CIDRs, regardless of whether the peer is
     /// eligible to communicate with them or not.
     pub cidrs: Vec<Cidr>,
+
+    /// Keys that listed peers had before an admin replaced them (ex. with `rekey-peer`), so
+    /// clients can tell a deliberate key change from an impersonation attempt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revoked_keys: Vec<String>,
+
+    /// How the network picks new peers' IPs. Only admins are told, so that peers they add
+    /// get IPs the same way as ones added on the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_allocation: Option<IpAllocation>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -776,20 +1298,76 @@ pub struct Hostname(String);
 /// Note that the full length also must be maximum 63 characters, which this regex does not check.
 static HOSTNAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([a-z0-9]-?)*[a-z0-9]$").unwrap());
 
+/// Regex for names that aren't DNS-safe but are still allowed for peers with
+/// `--allow-unsafe-name`: nothing that could break up a hosts file line or a shell argument.
+static UNSAFE_HOSTNAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z0-9._-]+$").unwrap());
+
 impl Hostname {
+    /// Whether `name` is a single hostname(7) label, as interface and CIDR names must be.
     pub fn is_valid(name: &str) -> bool {
         name.len() < 64 && HOSTNAME_REGEX.is_match(name)
     }
+
+    /// Whether `name` is DNS-safe, i.e. it works in hosts files and DNS lookups: one or
+    /// more dot-separated labels.
+    pub fn is_dns_safe(name: &str) -> bool {
+        name.len() < 64 && name.split('.').all(|label| HOSTNAME_REGEX.is_match(label))
+    }
+
+    /// Whether `name` can be stored for a peer at all, even if it isn't DNS-safe.
+    pub fn is_allowed(name: &str) -> bool {
+        name.len() < 64 && UNSAFE_HOSTNAME_REGEX.is_match(name)
+    }
+
+    /// Why `name` isn't DNS-safe, or `None` if it is.
+    pub fn dns_unsafe_reason(name: &str) -> Option<&'static str> {
+        if Self::is_dns_safe(name) {
+            None
+        } else if name.is_empty() {
+            Some("it's empty")
+        } else if name.len() >= 64 {
+            Some("it's longer than 63 characters")
+        } else if name.chars().any(char::is_uppercase) {
+            Some("it has uppercase letters")
+        } else if name.chars().any(char::is_whitespace) {
+            Some("it has spaces")
+        } else if name.contains('_') {
+            Some("it has underscores")
+        } else if name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            Some("hyphens and dots can't start or end it or its parts, or come in twos")
+        } else {
+            Some("only lowercase letters, digits, hyphens and dots are allowed")
+        }
+    }
+
+    /// Parse a name that only has to be allowed, not DNS-safe (ex. for
+    /// `--allow-unsafe-name`).
+    pub fn parse_allowed(name: &str) -> Result<Self, &'static str> {
+        if Self::is_allowed(name) {
+            Ok(Self(name.to_string()))
+        } else {
+            Err("invalid name (only letters, digits, dots, underscores and dashes)")
+        }
+    }
+
+    /// A name as it was stored, without checking it. Names from before a rule existed (or
+    /// let through with `--allow-unsafe-name`) mustn't become unloadable.
+    pub fn unchecked(name: String) -> Self {
+        Self(name)
+    }
 }
 
 impl FromStr for Hostname {
     type Err = &'static str;
 
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        if Self::is_valid(name) {
+        if Self::is_dns_safe(name) {
             Ok(Self(name.to_string()))
         } else {
-            Err("invalid hostname string (only alphanumeric with dashes)")
+            Err("invalid hostname string (only lowercase alphanumeric with dashes and dots)")
         }
     }
 }
@@ -808,6 +1386,175 @@ impl Display for Hostname {
     }
 }
 
+/// A free-form, informational label attached to a peer (ex. "servers", "ci-runners").
+///
+/// Tags are normalized to be lowercase and trimmed, and can't contain commas since they're
+/// displayed comma-separated.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Tag(String);
+
+impl FromStr for Tag {
+    type Err = &'static str;
+
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            Err("tags can't be empty")
+        } else if tag.contains(',') {
+            Err("tags can't contain commas")
+        } else {
+            Ok(Self(tag))
+        }
+    }
+}
+
+impl TryFrom<String> for Tag {
+    type Error = &'static str;
+
+    fn try_from(tag: String) -> Result<Self, Self::Error> {
+        tag.parse()
+    }
+}
+
+impl From<Tag> for String {
+    fn from(tag: Tag) -> Self {
+        tag.0
+    }
+}
+
+impl Deref for Tag {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An inclusive range of ports, written as `51820-51830` (or just `51820` for one port).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = &'static str;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let parse = |port: &str| port.trim().parse::<u16>().map_err(|_| "invalid port");
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || start > end {
+            Err("port range must be of the form 'start-end', with 0 < start <= end")
+        } else {
+            Ok(Self { start, end })
+        }
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = &'static str;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        range.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// An inclusive range of IPs, written as `10.42.0.100-10.42.0.200`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+impl IpRange {
+    pub fn new(start: IpAddr, end: IpAddr) -> Result<Self, &'static str> {
+        if start.is_ipv4() != end.is_ipv4() {
+            Err("IP range must start and end with the same IP version")
+        } else if start > end {
+            Err("IP range must start at or before its end")
+        } else {
+            Ok(Self { start, end })
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        (self.start..=self.end).contains(ip)
+    }
+
+    /// Whether the whole range lies within `net`.
+    pub fn is_within(&self, net: &IpNet) -> bool {
+        net.contains(&self.start) && net.contains(&self.end)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = &'static str;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let (start, end) = range
+            .split_once('-')
+            .ok_or("IP range must be of the form 'start-end'")?;
+        let parse = |ip: &str| ip.trim().parse::<IpAddr>().map_err(|_| "invalid IP");
+        Self::new(parse(start)?, parse(end)?)
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = &'static str;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        range.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 pub trait IoErrorContext<T> {
     fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T, WrappedIoError>;
     fn with_str<S: Into<String>>(self, context: S) -> Result<T, WrappedIoError>;
@@ -872,6 +1619,15 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
             },
         };
         let builder =
@@ -887,6 +1643,11 @@ mod tests {
 
         println!("{diff:?}");
         assert_eq!(diff, None);
+
+        // Nor is a disabled keepalive, which the device reports as 0.
+        let mut info = info;
+        info.config.persistent_keepalive_interval = Some(0);
+        assert_eq!(PeerDiff::new(Some(&info), Some(&peer)).unwrap(), None);
     }
 
     #[test]
@@ -907,6 +1668,15 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
             },
         };
         let builder =
@@ -921,7 +1691,366 @@ mod tests {
 
         println!("{peer:?}");
         println!("{:?}", info.config);
-        assert!(matches!(diff, Some(_)));
+        assert!(diff.is_some());
+    }
+
+    #[test]
+    fn test_advertised_routes_in_allowed_ips() {
+        const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut peer = Peer {
+            id: 1,
+            contents: PeerContents {
+                name: "gateway".parse().unwrap(),
+                ip,
+                cidr_id: 1,
+                public_key: PUBKEY.to_owned(),
+                endpoint: None,
+                persistent_keepalive_interval: None,
+                is_admin: false,
+                is_disabled: false,
+                is_redeemed: true,
+                invite_expires: None,
+                candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![
+                    "192.168.1.0/24".parse().unwrap(),
+                    "10.0.0.1/32".parse().unwrap(),
+                    "192.168.1.0/24".parse().unwrap(),
+                ],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
+            },
+        };
+
+        // The peer's own address comes first, and duplicates are dropped.
+        let allowed_ips = peer.allowed_ips();
+        assert_eq!(allowed_ips.len(), 2);
+        assert_eq!((allowed_ips[0].address, allowed_ips[0].cidr), (ip, 32));
+        assert_eq!(
+            (allowed_ips[1].address, allowed_ips[1].cidr),
+            ("192.168.1.0".parse().unwrap(), 24)
+        );
+
+        // An interface with the same allowed-IPs in another order needs no update.
+        let config = PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap())
+            .add_allowed_ip("192.168.1.0".parse().unwrap(), 24)
+            .add_allowed_ip(ip, 32)
+            .into_peer_config();
+        let info = PeerInfo {
+            config,
+            stats: Default::default(),
+        };
+        assert_eq!(PeerDiff::new(Some(&info), Some(&peer)).unwrap(), None);
+
+        peer.advertised_routes.clear();
+        let diff = PeerDiff::new(Some(&info), Some(&peer)).unwrap().unwrap();
+        assert!(matches!(diff.changes(), [PeerChange::AllowedIPs { .. }]));
+    }
+
+    #[test]
+    fn test_overlapping_routes() {
+        let peer = |id: i64, name: &str, routes: &[&str]| Peer {
+            id,
+            contents: PeerContents {
+                name: name.parse().unwrap(),
+                ip: format!("10.0.0.{id}").parse().unwrap(),
+                cidr_id: 1,
+                public_key: name.to_owned(),
+                endpoint: None,
+                persistent_keepalive_interval: None,
+                is_admin: false,
+                is_disabled: false,
+                is_redeemed: true,
+                invite_expires: None,
+                candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: routes.iter().map(|r| r.parse().unwrap()).collect(),
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
+            },
+        };
+        let peers = [
+            peer(1, "office", &["192.168.0.0/16"]),
+            peer(2, "lab", &["172.16.0.0/12"]),
+        ];
+
+        let gateway = peer(3, "gateway", &["192.168.5.0/24"]);
+        let overlaps = overlapping_routes(&gateway, &peers);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(&*overlaps[0].0.name, "office");
+
+        // A peer doesn't overlap with its own routes.
+        assert!(overlapping_routes(&peers[0], &peers).is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_keeps_hostname() {
+        let endpoint: Endpoint = "vpn.example.com:51820".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "vpn.example.com:51820");
+        assert_eq!(endpoint, endpoint.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn test_endpoint_round_trip() {
+        let cases = [
+            ("1.2.3.4:51820", "1.2.3.4:51820"),
+            ("1.2.3.4", "1.2.3.4:51820"),
+            ("[2001:db8::1]:51821", "[2001:db8::1]:51821"),
+            ("[2001:db8::1]", "[2001:db8::1]:51820"),
+            ("2001:db8::1", "[2001:db8::1]:51820"),
+            ("[::ffff:1.2.3.4]:1", "[::ffff:102:304]:1"),
+            ("[::]:65535", "[::]:65535"),
+            ("vpn.example.com:51820", "vpn.example.com:51820"),
+            ("VPN.Example.com", "vpn.example.com:51820"),
+            ("localhost:1", "localhost:1"),
+        ];
+        for (input, expected) in cases {
+            let endpoint: Endpoint = input.parse().unwrap();
+            assert_eq!(endpoint.to_string(), expected, "displaying {input}");
+            assert_eq!(endpoint, expected.parse().unwrap(), "reparsing {input}");
+        }
+
+        let invalid = [
+            "",
+            "2001:db8::1:51820:",
+            "[2001:db8::1:51820",
+            "[2001:db8::1]51820",
+            "[vpn.example.com]:51820",
+            "1.2.3.4:65536",
+            "1.2.3.4:",
+            "vpn.example.com:port",
+        ];
+        for input in invalid {
+            assert!(input.parse::<Endpoint>().is_err(), "parsing {input}");
+        }
+    }
+
+    #[test]
+    fn test_endpoint_resolve_prefers_ipv4() {
+        let endpoint: Endpoint = "localhost:51820".parse().unwrap();
+        assert_eq!(
+            endpoint.resolve().unwrap(),
+            "127.0.0.1:51820".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_family_preference_sort() {
+        let endpoints: Vec<Endpoint> = [
+            "192.0.2.1:51820",
+            "[2001:db8::1]:51820",
+            "vpn.example.com:51820",
+            "[2001:db8::2]:51820",
+        ]
+        .iter()
+        .map(|endpoint| endpoint.parse().unwrap())
+        .collect();
+        let sorted = |prefer: FamilyPreference| {
+            let mut endpoints = endpoints.clone();
+            prefer.sort(&mut endpoints);
+            endpoints
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sorted(FamilyPreference::Auto),
+            endpoints
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sorted(FamilyPreference::Ipv6),
+            [
+                "[2001:db8::1]:51820",
+                "vpn.example.com:51820",
+                "[2001:db8::2]:51820",
+                "192.0.2.1:51820"
+            ]
+        );
+        assert_eq!(
+            sorted(FamilyPreference::Ipv4),
+            [
+                "192.0.2.1:51820",
+                "vpn.example.com:51820",
+                "[2001:db8::1]:51820",
+                "[2001:db8::2]:51820"
+            ]
+        );
+    }
+
+    fn cidr(id: i64, cidr: &str) -> Cidr {
+        Cidr {
+            id,
+            contents: CidrContents {
+                name: format!("cidr-{id}"),
+                cidr: cidr.parse().unwrap(),
+                parent: None,
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_cidrs_overlap() {
+        let overlap = |a: &str, b: &str| cidrs_overlap(&a.parse().unwrap(), &b.parse().unwrap());
+
+        assert!(overlap("10.0.0.0/8", "10.1.0.0/16"));
+        assert!(overlap("10.1.0.0/16", "10.0.0.0/8"));
+        // Host bits are ignored.
+        assert!(overlap("10.0.0.1/24", "10.0.0.200/24"));
+        assert!(overlap("10.0.0.255/32", "10.0.0.0/24"));
+        assert!(!overlap("10.0.0.0/24", "10.0.1.0/24"));
+        assert!(!overlap("10.0.0.0/25", "10.0.0.128/25"));
+        assert!(overlap("fd00::/64", "fd00::1:0:0:0/80"));
+        assert!(!overlap("fd00::/80", "fd00::1:0:0:0/80"));
+        // Different families never overlap, even for mapped addresses.
+        assert!(!overlap("0.0.0.0/0", "::/0"));
+        assert!(!overlap("10.0.0.0/8", "::ffff:10.0.0.0/104"));
+    }
+
+    #[test]
+    fn test_find_conflicting_cidr() {
+        let cidrs = [
+            cidr(1, "10.0.0.0/8"),
+            cidr(2, "10.1.0.0/16"),
+            cidr(3, "fd00::/64"),
+        ];
+        let conflict = |new: &str| find_conflicting_cidr(&new.parse().unwrap(), &cidrs);
+
+        // Nesting under existing CIDRs is fine.
+        assert_eq!(conflict("10.1.2.0/24"), None);
+        assert_eq!(conflict("10.2.0.0/16"), None);
+        assert_eq!(conflict("fd00::1:0:0:0/80"), None);
+        assert_eq!(conflict("192.168.0.0/16"), None);
+
+        // Duplicates and supersets of existing CIDRs conflict.
+        assert_eq!(conflict("10.1.0.0/16").map(|c| c.id), Some(2));
+        assert_eq!(conflict("10.1.5.5/16").map(|c| c.id), Some(2));
+        assert_eq!(conflict("10.0.0.0/15").map(|c| c.id), Some(2));
+        assert_eq!(conflict("fd00::/48").map(|c| c.id), Some(3));
+        assert_eq!(conflict("0.0.0.0/0").map(|c| c.id), Some(1));
+    }
+
+    #[test]
+    fn test_inherited_cidr_default() {
+        let mut root = cidr(1, "10.0.0.0/8");
+        root.contents.default_mtu = Some(1400);
+        root.contents.default_keepalive = Some(25);
+        let mut office = cidr(2, "10.1.0.0/16");
+        office.contents.parent = Some(1);
+        office.contents.default_keepalive = Some(0);
+        let mut desks = cidr(3, "10.1.1.0/24");
+        desks.contents.parent = Some(2);
+        desks.contents.default_mtu = Some(1380);
+        let mut lab = cidr(4, "10.2.0.0/16");
+        lab.contents.parent = Some(1);
+        let cidrs = [root, office, desks, lab];
+        let mtu = |id| inherited_cidr_default(&cidrs, id, |c| c.default_mtu);
+        let keepalive = |id| inherited_cidr_default(&cidrs, id, |c| c.default_keepalive);
+
+        // A CIDR's own value wins over any ancestor's...
+        assert_eq!(mtu(3), Some(1380));
+        assert_eq!(keepalive(2), Some(0));
+        // ...then the nearest ancestor's, skipping ancestors without one.
+        assert_eq!(keepalive(3), Some(0));
+        assert_eq!(mtu(2), Some(1400));
+        assert_eq!(mtu(4), Some(1400));
+        assert_eq!(keepalive(4), Some(25));
+
+        assert_eq!(
+            inherited_cidr_default(&cidrs[1..], 3, |c| c.default_mtu),
+            Some(1380)
+        );
+        assert_eq!(
+            inherited_cidr_default(&cidrs[3..], 4, |c| c.default_mtu),
+            None
+        );
+        assert_eq!(mtu(99), None);
+    }
+
+    #[test]
+    fn test_hostname_dns_safety() {
+        assert_eq!(Hostname::dns_unsafe_reason("laptop-2"), None);
+        assert_eq!(
+            Hostname::dns_unsafe_reason("Laptop"),
+            Some("it has uppercase letters")
+        );
+        assert_eq!(
+            Hostname::dns_unsafe_reason("my laptop"),
+            Some("it has spaces")
+        );
+        assert_eq!(
+            Hostname::dns_unsafe_reason("my_laptop"),
+            Some("it has underscores")
+        );
+        assert_eq!(
+            Hostname::dns_unsafe_reason("-laptop"),
+            Some("hyphens and dots can't start or end it or its parts, or come in twos")
+        );
+        assert_eq!(
+            Hostname::dns_unsafe_reason("laptop..home"),
+            Some("hyphens and dots can't start or end it or its parts, or come in twos")
+        );
+
+        // Dots only separate labels, each of which has to be DNS-safe on its own.
+        assert_eq!(Hostname::dns_unsafe_reason("laptop.home"), None);
+        assert!("laptop.home".parse::<Hostname>().is_ok());
+        assert!(!Hostname::is_valid("laptop.home"));
+
+        assert!(Hostname::parse_allowed("My_Laptop.home").is_ok());
+        assert!(Hostname::parse_allowed("my laptop").is_err());
+        assert!("My_Laptop".parse::<Hostname>().is_err());
+    }
+
+    #[test]
+    fn test_tag_normalization() {
+        assert_eq!(&*" CI-Runners ".parse::<Tag>().unwrap(), "ci-runners");
+        assert!("".parse::<Tag>().is_err());
+        assert!("servers,laptops".parse::<Tag>().is_err());
+        assert!(Tag::try_from("a,b".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_port_range() {
+        let range: PortRange = "51820-51830".parse().unwrap();
+        assert!(range.contains(51820) && range.contains(51830) && !range.contains(51831));
+        assert_eq!(range.ports().count(), 11);
+        assert_eq!(range.to_string(), "51820-51830");
+        assert_eq!("51820".parse::<PortRange>().unwrap().to_string(), "51820");
+        assert!("51830-51820".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("51820-".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "10.42.5.100-10.42.5.200".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(range.contains(&ip("10.42.5.100")) && range.contains(&ip("10.42.5.200")));
+        assert!(!range.contains(&ip("10.42.5.99")) && !range.contains(&ip("10.42.5.201")));
+        assert!(range.is_within(&"10.42.5.0/24".parse().unwrap()));
+        assert!(!range.is_within(&"10.42.5.128/25".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.42.5.100-10.42.5.200");
+        assert!("fd00::1-fd00::ff".parse::<IpRange>().is_ok());
+        assert!("10.42.5.200-10.42.5.100".parse::<IpRange>().is_err());
+        assert!("10.42.5.1-fd00::1".parse::<IpRange>().is_err());
+        assert!("10.42.5.1".parse::<IpRange>().is_err());
     }
 
     #[test]
@@ -942,6 +2071,15 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                tags: vec![],
+                preshared_key: None,
+                last_handshake: None,
+                advertised_routes: vec![],
+                is_observer: false,
+                is_staged: false,
+                created_at: None,
+                created_by: None,
+                description: None,
             },
         };
         let builder =
@@ -0,0 +1,943 @@
+//! Reading and writing plain wg-quick(8) configs, so that existing WireGuard setups can be
+//! adopted as innernet interfaces, and innernet interfaces can be used without innernet.
+//!
+//! A wg-quick config has everything innernet needs except for the network's name and the
+//! server's internal API endpoint. innernet records those in metadata comments:
+//!
+//! ```text
+//! # !network_name = evilcorp
+//! # !internal_endpoint = 10.42.0.1:51820
+//! ```
+//!
+//! A comma works as the separator too (`# !internal_endpoint,[fd00::1]:5555`), and IPv6
+//! endpoints are bracketed as usual.
+//!
+//! Configs without them still parse, and the missing values can be filled in with
+//! [`VanillaConfig::set_network_name`] and [`VanillaConfig::set_internal_endpoint`].
+//!
+//! Exports also record the server's `AllowedIPs` as innernet generated them
+//! (`# !generated_allowed_ips = 10.42.0.0/16`), so that a hand-edited `AllowedIPs` is
+//! recognized on import and kept for the next export.
+//!
+//! An interface's DNS servers and search domains become its `DNS =` line
+//! (`DNS = 10.42.0.1, mynet`). A search domain can't be set without a DNS server, so
+//! configs that have one but not the other are refused both ways.
+//!
+//! Networks with AmneziaWG obfuscation parameters get them under `[Interface]`, which only
+//! Amnezia clients understand.
+//!
+//! [`VanillaConfig::to_strict_string`] leaves out the metadata comments for tools that
+//! should see nothing but standard wg-quick keys, at the cost of not being re-importable.
+//!
+//! For keys kept in a hardware token or agent, [`VanillaConfig::remove_private_key`] leaves
+//! a commented-out `PrivateKey` line in its place. Configs without a `PrivateKey` still
+//! parse, but need one set with [`VanillaConfig::set_private_key`] before they're imported.
+//!
+//! Full-tunnel configs ([`VanillaConfig::set_full_tunnel`]) send everything through the
+//! server with `AllowedIPs = 0.0.0.0/0, ::/0`, and add wg-quick's fwmark kill-switch as
+//! `PostUp`/`PreDown` hooks so nothing leaks around the tunnel while it's up. Those are
+//! recognized on import and become the interface's `full-tunnel` setting.
+
+use crate::{
+    interface_config::{InterfaceConfig, InterfaceInfo, ObfuscationParams, ServerInfo},
+    Cidr, Endpoint, Error, FamilyPreference, IoErrorContext, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+};
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+
+const NETWORK_NAME_COMMENT: &str = "!network_name";
+const INTERNAL_ENDPOINT_COMMENT: &str = "!internal_endpoint";
+const GENERATED_ALLOWED_IPS_COMMENT: &str = "!generated_allowed_ips";
+
+/// Split a metadata comment into its key and value. Neither separator can appear in a
+/// socket address, so bracketed IPv6 endpoints come through whole.
+fn split_metadata_comment(comment: &str) -> Option<(&str, &str)> {
+    comment.split_once(['=', ','])
+}
+
+/// The addresses in both networks. CIDR blocks are aligned, so that's all of the smaller one
+/// if they overlap at all.
+fn intersection(a: &IpNet, b: &IpNet) -> Option<IpNet> {
+    let (a, b) = (a.trunc(), b.trunc());
+    if a.contains(&b) {
+        Some(b)
+    } else if b.contains(&a) {
+        Some(a)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VanillaConfig {
+    network_name: Option<String>,
+    internal_endpoint: Option<SocketAddr>,
+    private_key: Option<String>,
+    address: IpNet,
+    listen_port: Option<u16>,
+    obfuscation: Option<ObfuscationParams>,
+    dns: Vec<IpAddr>,
+    dns_search: Vec<String>,
+    server_public_key: String,
+    server_endpoint: Endpoint,
+    server_preshared_key: Option<String>,
+    server_allowed_ips: Vec<IpNet>,
+    /// `AllowedIPs` set by hand in place of `server_allowed_ips`.
+    custom_allowed_ips: Option<Vec<IpNet>>,
+    /// Route everything through the server instead of just the allowed-IPs.
+    full_tunnel: bool,
+}
+
+/// Everything, for full-tunnel configs.
+const DEFAULT_ROUTES: [&str; 2] = ["0.0.0.0/0", "::/0"];
+
+/// wg-quick's kill-switch: reject anything that would leave through another interface,
+/// unless it's the tunnel's own (fwmarked) traffic or local. `%i` is the interface name.
+fn kill_switch(action: &str) -> String {
+    ["iptables", "ip6tables"]
+        .iter()
+        .map(|command| {
+            format!(
+                "{command} -{action} OUTPUT ! -o %i -m mark ! --mark $(wg show %i fwmark) \
+                 -m addrtype ! --dst-type LOCAL -j REJECT"
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" && ")
+}
+
+/// Parse a comma-separated list of networks, as in `AllowedIPs`.
+fn parse_allowed_ips(value: &str) -> Result<Vec<IpNet>, Error> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|allowed_ip| !allowed_ip.is_empty())
+        .map(|allowed_ip| {
+            allowed_ip
+                .parse()
+                .map_err(|_| anyhow!("invalid allowed IP '{}'", allowed_ip))
+        })
+        .collect()
+}
+
+/// WireGuard can't set a search domain without a resolver to go with it.
+fn check_dns(dns: &[IpAddr], dns_search: &[String]) -> Result<(), Error> {
+    if dns.is_empty() && !dns_search.is_empty() {
+        bail!(
+            "DNS search domains ({}) need a DNS server to go with them",
+            dns_search.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn format_allowed_ips(allowed_ips: &[IpNet]) -> String {
+    allowed_ips
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+impl VanillaConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        fs::read_to_string(path).with_path(path)?.parse()
+    }
+
+    pub fn network_name(&self) -> Option<&str> {
+        self.network_name.as_deref()
+    }
+
+    pub fn set_network_name(&mut self, network_name: String) {
+        self.network_name = Some(network_name);
+    }
+
+    pub fn internal_endpoint(&self) -> Option<SocketAddr> {
+        self.internal_endpoint
+    }
+
+    pub fn private_key(&self) -> Option<&str> {
+        self.private_key.as_deref()
+    }
+
+    pub fn set_private_key(&mut self, private_key: String) {
+        self.private_key = Some(private_key);
+    }
+
+    /// Leave the private key out of the config, for whoever fills it in themselves.
+    pub fn remove_private_key(&mut self) {
+        self.private_key = None;
+    }
+
+    pub fn set_internal_endpoint(&mut self, internal_endpoint: SocketAddr) {
+        self.internal_endpoint = Some(internal_endpoint);
+    }
+
+    pub fn is_full_tunnel(&self) -> bool {
+        self.full_tunnel
+    }
+
+    /// Route all traffic through the server, with a kill-switch so that none of it leaks
+    /// out of another interface while the tunnel is up.
+    pub fn set_full_tunnel(&mut self) {
+        self.full_tunnel = true;
+    }
+
+    /// Add routes the server advertises to its allowed-IPs, skipping any already there.
+    pub fn add_server_routes(&mut self, routes: &[IpNet]) {
+        for route in routes {
+            if !self.server_allowed_ips.contains(route) {
+                self.server_allowed_ips.push(*route);
+            }
+        }
+    }
+
+    /// Narrow the server's allowed-IPs down to the parts of `cidrs` that are also in
+    /// `permitted`, the allowed-IPs innernet configures for the peers this one can reach.
+    /// Nothing outside of `permitted` is ever added, and the server's own address is always
+    /// kept so that its API stays reachable. Hand-set allowed-IPs are dropped, since they
+    /// could be wider.
+    ///
+    /// Fails if the peer can't reach anything in one of `cidrs`.
+    pub fn restrict_server_routes(
+        &mut self,
+        cidrs: &[&Cidr],
+        permitted: &[IpNet],
+    ) -> Result<(), Error> {
+        let mut allowed_ips: Vec<IpNet> = self
+            .internal_endpoint
+            .map(|endpoint| endpoint.ip().into())
+            .into_iter()
+            .collect();
+        for cidr in cidrs {
+            let reachable = permitted
+                .iter()
+                .filter_map(|net| intersection(&cidr.cidr, net))
+                .collect::<Vec<_>>();
+            if reachable.is_empty() {
+                bail!("this peer can't reach anything in CIDR {}.", cidr);
+            }
+            for net in reachable {
+                if !allowed_ips.contains(&net) {
+                    allowed_ips.push(net);
+                }
+            }
+        }
+        self.server_allowed_ips = allowed_ips;
+        self.custom_allowed_ips = None;
+        Ok(())
+    }
+
+    /// Convert to an innernet interface config, which needs the network name, internal
+    /// endpoint, and private key to have been found in the file or set.
+    pub fn to_interface_config(&self) -> Result<InterfaceConfig, Error> {
+        let network_name = self
+            .network_name
+            .clone()
+            .ok_or_else(|| anyhow!("the config doesn't have a network name"))?;
+        let internal_endpoint = self
+            .internal_endpoint
+            .ok_or_else(|| anyhow!("the config doesn't have an internal endpoint"))?;
+        let private_key = self
+            .private_key
+            .clone()
+            .ok_or_else(|| anyhow!("the config doesn't have a private key"))?;
+
+        Ok(InterfaceConfig {
+            interface: InterfaceInfo {
+                network_name,
+                address: self.address,
+                private_key,
+                private_key_keyring: None,
+                listen_port: self.listen_port,
+                listen_port_auto: false,
+                listen_port_range: None,
+                metered: false,
+                pinned_endpoint: None,
+                obfuscation: self.obfuscation,
+                dns: self.dns.clone(),
+                dns_search: self.dns_search.clone(),
+                prefer_family: FamilyPreference::Auto,
+                full_tunnel: self.full_tunnel,
+                report_description: false,
+                removal_interval: None,
+                route_table: None,
+                fwmark: None,
+            },
+            server: ServerInfo {
+                public_key: self.server_public_key.clone(),
+                external_endpoint: self.server_endpoint.clone(),
+                internal_endpoint,
+                preshared_key: self.server_preshared_key.clone(),
+                connect_timeout: None,
+                read_timeout: None,
+                retries: None,
+                allowed_ips: self.custom_allowed_ips.clone(),
+            },
+            post_install: None,
+        })
+    }
+}
+
+impl TryFrom<&InterfaceConfig> for VanillaConfig {
+    type Error = Error;
+
+    fn try_from(config: &InterfaceConfig) -> Result<Self, Self::Error> {
+        check_dns(&config.interface.dns, &config.interface.dns_search)?;
+        Ok(Self {
+            network_name: Some(config.interface.network_name.clone()),
+            internal_endpoint: Some(config.server.internal_endpoint),
+            private_key: Some(config.interface.private_key.clone()),
+            address: config.interface.address,
+            listen_port: config.interface.listen_port,
+            obfuscation: config.interface.obfuscation,
+            dns: config.interface.dns.clone(),
+            dns_search: config.interface.dns_search.clone(),
+            server_public_key: config.server.public_key.clone(),
+            server_endpoint: config.server.external_endpoint.clone(),
+            server_preshared_key: config.server.preshared_key.clone(),
+            server_allowed_ips: vec![config.interface.address.trunc()],
+            custom_allowed_ips: config.server.allowed_ips.clone(),
+            full_tunnel: config.interface.full_tunnel,
+        })
+    }
+}
+
+impl fmt::Display for VanillaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, true)
+    }
+}
+
+impl VanillaConfig {
+    /// Render the config with only keys that stock wg-quick accepts, and without innernet's
+    /// metadata comments, so it can't be imported again. wg-quick doesn't know about the
+    /// AmneziaWG parameters, so obfuscated networks can't be rendered this way.
+    pub fn to_strict_string(&self) -> Result<String, Error> {
+        if self.obfuscation.is_some() {
+            bail!("wg-quick doesn't support this network's AmneziaWG obfuscation parameters");
+        }
+        let mut config = String::new();
+        self.write(&mut config, false)?;
+        Ok(config)
+    }
+
+    fn write(&self, f: &mut impl fmt::Write, annotated: bool) -> fmt::Result {
+        if annotated {
+            if let Some(network_name) = &self.network_name {
+                writeln!(f, "# {NETWORK_NAME_COMMENT} = {network_name}")?;
+            }
+            if let Some(internal_endpoint) = &self.internal_endpoint {
+                writeln!(f, "# {INTERNAL_ENDPOINT_COMMENT} = {internal_endpoint}")?;
+            }
+            writeln!(
+                f,
+                "# {GENERATED_ALLOWED_IPS_COMMENT} = {}",
+                format_allowed_ips(&self.server_allowed_ips)
+            )?;
+        }
+
+        writeln!(f, "[Interface]")?;
+        match &self.private_key {
+            Some(private_key) => writeln!(f, "PrivateKey = {private_key}")?,
+            None => writeln!(f, "# PrivateKey = (left out, fill in your own)")?,
+        }
+        writeln!(f, "Address = {}", self.address)?;
+        if let Some(listen_port) = self.listen_port {
+            writeln!(f, "ListenPort = {listen_port}")?;
+        }
+        if !self.dns.is_empty() {
+            let servers = self.dns.iter().map(IpAddr::to_string);
+            let dns = servers
+                .chain(self.dns_search.iter().cloned())
+                .collect::<Vec<_>>();
+            writeln!(f, "DNS = {}", dns.join(", "))?;
+        }
+        if let Some(params) = &self.obfuscation {
+            for (key, value) in obfuscation_fields(params) {
+                writeln!(f, "{key} = {value}")?;
+            }
+        }
+        if self.full_tunnel {
+            writeln!(f, "PostUp = {}", kill_switch("I"))?;
+            writeln!(f, "PreDown = {}", kill_switch("D"))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "[Peer]")?;
+        writeln!(f, "PublicKey = {}", self.server_public_key)?;
+        if let Some(preshared_key) = &self.server_preshared_key {
+            writeln!(f, "PresharedKey = {preshared_key}")?;
+        }
+        writeln!(f, "Endpoint = {}", self.server_endpoint)?;
+        if self.full_tunnel {
+            writeln!(f, "AllowedIPs = {}", DEFAULT_ROUTES.join(", "))?;
+        } else {
+            let allowed_ips = self
+                .custom_allowed_ips
+                .as_ref()
+                .unwrap_or(&self.server_allowed_ips);
+            writeln!(f, "AllowedIPs = {}", format_allowed_ips(allowed_ips))?;
+        }
+        writeln!(
+            f,
+            "PersistentKeepalive = {PERSISTENT_KEEPALIVE_INTERVAL_SECS}"
+        )
+    }
+}
+
+fn obfuscation_fields(params: &ObfuscationParams) -> [(&'static str, u32); 9] {
+    [
+        ("Jc", params.jc.into()),
+        ("Jmin", params.jmin.into()),
+        ("Jmax", params.jmax.into()),
+        ("S1", params.s1.into()),
+        ("S2", params.s2.into()),
+        ("H1", params.h1),
+        ("H2", params.h2),
+        ("H3", params.h3),
+        ("H4", params.h4),
+    ]
+}
+
+/// Build the obfuscation parameters from the `[Interface]` keys that were found, which
+/// have to be either all or none of them.
+fn parse_obfuscation(found: &HashMap<String, u32>) -> Result<Option<ObfuscationParams>, Error> {
+    if found.is_empty() {
+        return Ok(None);
+    }
+    let get = |key: &str| {
+        found
+            .get(&key.to_ascii_lowercase())
+            .copied()
+            .ok_or_else(|| anyhow!("incomplete AmneziaWG parameters, missing {}", key))
+    };
+    let get_u16 = |key: &str| -> Result<u16, Error> {
+        get(key)?
+            .try_into()
+            .map_err(|_| anyhow!("{} is out of range", key))
+    };
+    Ok(Some(ObfuscationParams {
+        jc: get_u16("Jc")?,
+        jmin: get_u16("Jmin")?,
+        jmax: get_u16("Jmax")?,
+        s1: get_u16("S1")?,
+        s2: get_u16("S2")?,
+        h1: get("H1")?,
+        h2: get("H2")?,
+        h3: get("H3")?,
+        h4: get("H4")?,
+    }))
+}
+
+impl FromStr for VanillaConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut network_name = None;
+        let mut internal_endpoint = None;
+        let mut private_key = None;
+        let mut address = None;
+        let mut listen_port = None;
+        let mut obfuscation = HashMap::new();
+        let mut dns = vec![];
+        let mut dns_search = vec![];
+        let mut server_public_key = None;
+        let mut server_endpoint = None;
+        let mut server_preshared_key = None;
+        let mut server_allowed_ips = vec![];
+        let mut generated_allowed_ips = None;
+
+        let mut section = Section::None;
+        let mut peers = 0;
+        for line in s.lines().map(str::trim) {
+            if let Some(comment) = line.strip_prefix('#') {
+                match split_metadata_comment(comment) {
+                    Some((key, value)) if key.trim() == NETWORK_NAME_COMMENT => {
+                        network_name = Some(value.trim().to_string())
+                    },
+                    Some((key, value)) if key.trim() == GENERATED_ALLOWED_IPS_COMMENT => {
+                        generated_allowed_ips = Some(parse_allowed_ips(value)?)
+                    },
+                    Some((key, value)) if key.trim() == INTERNAL_ENDPOINT_COMMENT => {
+                        internal_endpoint = Some(
+                            value
+                                .trim()
+                                .parse()
+                                .map_err(|_| anyhow!("invalid internal endpoint '{}'", value))?,
+                        )
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if line.eq_ignore_ascii_case("[Interface]") {
+                section = Section::Interface;
+                continue;
+            }
+            if line.eq_ignore_ascii_case("[Peer]") {
+                section = Section::Peer;
+                peers += 1;
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim()))
+                .ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+            match (&section, key.as_str()) {
+                (Section::Interface, "privatekey") => private_key = Some(value.to_string()),
+                (Section::Interface, "address") => {
+                    // wg-quick allows a list of addresses, but an innernet peer has just one.
+                    let first = value.split(',').next().unwrap_or_default().trim();
+                    address = Some(
+                        first
+                            .parse::<IpNet>()
+                            .map_err(|_| anyhow!("invalid address '{}'", first))?,
+                    );
+                },
+                (Section::Interface, "listenport") => {
+                    listen_port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| anyhow!("invalid listen port '{}'", value))?,
+                    )
+                },
+                (
+                    Section::Interface,
+                    "jc" | "jmin" | "jmax" | "s1" | "s2" | "h1" | "h2" | "h3" | "h4",
+                ) => {
+                    let value = value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid {} '{}'", key, value))?;
+                    obfuscation.insert(key, value);
+                },
+                (Section::Interface, "dns") => {
+                    // Anything that isn't an IP address is a search domain.
+                    for token in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                        match token.parse() {
+                            Ok(ip) => dns.push(ip),
+                            Err(_) => dns_search.push(token.to_string()),
+                        }
+                    }
+                },
+                (Section::Peer, "publickey") => server_public_key = Some(value.to_string()),
+                (Section::Peer, "endpoint") => {
+                    server_endpoint = Some(value.parse::<Endpoint>().map_err(|e| anyhow!(e))?)
+                },
+                (Section::Peer, "presharedkey") => server_preshared_key = Some(value.to_string()),
+                (Section::Peer, "allowedips") => {
+                    server_allowed_ips.extend(parse_allowed_ips(value)?);
+                },
+                (Section::None, _) => bail!("'{}' is outside of any section", line),
+                // Everything else (MTU, hooks, ...) is managed by innernet itself.
+                _ => {},
+            }
+        }
+
+        if peers != 1 {
+            bail!(
+                "expected exactly one [Peer] (the innernet server), found {}",
+                peers
+            );
+        }
+        check_dns(&dns, &dns_search)?;
+
+        // A default route means a full tunnel, whose kill-switch hooks innernet sets up itself.
+        let full_tunnel = server_allowed_ips.iter().any(|net| net.prefix_len() == 0);
+        server_allowed_ips.retain(|net| net.prefix_len() != 0);
+
+        Ok(Self {
+            network_name,
+            internal_endpoint,
+            private_key,
+            address: address.ok_or_else(|| anyhow!("missing Address"))?,
+            listen_port,
+            obfuscation: parse_obfuscation(&obfuscation)?,
+            dns,
+            dns_search,
+            server_public_key: server_public_key.ok_or_else(|| anyhow!("missing PublicKey"))?,
+            server_endpoint: server_endpoint.ok_or_else(|| anyhow!("missing Endpoint"))?,
+            server_preshared_key,
+            // Without a record of what innernet generated, there's no telling what was edited.
+            custom_allowed_ips: generated_allowed_ips
+                .as_ref()
+                .filter(|generated| !full_tunnel && **generated != server_allowed_ips)
+                .map(|_| server_allowed_ips.clone()),
+            server_allowed_ips: generated_allowed_ips.unwrap_or(server_allowed_ips),
+            full_tunnel,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN_CONFIG: &str = "
+        [Interface]
+        PrivateKey = kJmzcgvEqcWnKgMDCkP+qMTWe5dZeg6ZR4L7SmWdr1k=
+        Address = 10.42.1.2/16
+        ListenPort = 51821
+        DNS = 10.42.0.1
+
+        [Peer]
+        PublicKey = J3Jv1OTl46Ne5jY4ylU+xoh7jB2UOxvMm0eWSjF1zHc=
+        Endpoint = vpn.example.com:51820
+        AllowedIPs = 10.42.0.0/16
+        PersistentKeepalive = 25
+    ";
+
+    #[test]
+    fn test_plain_config() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        assert_eq!(config.network_name(), None);
+        assert_eq!(config.internal_endpoint(), None);
+        assert!(config.to_interface_config().is_err());
+
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let interface_config = config.to_interface_config()?;
+        assert_eq!(interface_config.interface.network_name, "evilcorp");
+        assert_eq!(interface_config.interface.address, "10.42.1.2/16".parse()?);
+        assert_eq!(interface_config.interface.listen_port, Some(51821));
+        assert_eq!(
+            interface_config.server.internal_endpoint,
+            "10.42.0.1:51820".parse()?
+        );
+        assert_eq!(
+            interface_config.server.external_endpoint.to_string(),
+            "vpn.example.com:51820"
+        );
+        assert_eq!(interface_config.server.preshared_key, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_comments() -> Result<(), Error> {
+        let config: VanillaConfig = format!(
+            "# !network_name = evilcorp\n# !internal_endpoint = 10.42.0.1:51820\n{}",
+            PLAIN_CONFIG
+        )
+        .parse()?;
+        assert_eq!(config.network_name(), Some("evilcorp"));
+        assert_eq!(config.internal_endpoint(), Some("10.42.0.1:51820".parse()?));
+        assert!(config.to_interface_config().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_private_key() -> Result<(), Error> {
+        let mut config: VanillaConfig = format!(
+            "# !network_name = evilcorp\n# !internal_endpoint = 10.42.0.1:51820\n{}",
+            PLAIN_CONFIG
+        )
+        .parse()?;
+        config.remove_private_key();
+        let exported = config.to_string();
+        assert!(!exported.contains("kJmzcgvEqcWnKgMDCkP"));
+        assert!(exported.contains("# PrivateKey = "));
+
+        let mut reparsed: VanillaConfig = exported.parse()?;
+        assert_eq!(reparsed.private_key(), None);
+        assert!(reparsed.to_interface_config().is_err());
+        reparsed.set_private_key("kJmzcgvEqcWnKgMDCkP+qMTWe5dZeg6ZR4L7SmWdr1k=".into());
+        assert_eq!(
+            reparsed.to_interface_config()?.interface.private_key,
+            "kJmzcgvEqcWnKgMDCkP+qMTWe5dZeg6ZR4L7SmWdr1k="
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ipv6_internal_endpoint() -> Result<(), Error> {
+        let config: VanillaConfig = format!(
+            "# !network_name = evilcorp\n# !internal_endpoint,[fd00::1]:5555\n{}",
+            PLAIN_CONFIG
+        )
+        .parse()?;
+        assert_eq!(config.internal_endpoint(), Some("[fd00::1]:5555".parse()?));
+        let interface_config = config.to_interface_config()?;
+        assert_eq!(
+            interface_config.server.internal_endpoint,
+            "[fd00::1]:5555".parse()?
+        );
+
+        let exported = VanillaConfig::try_from(&interface_config)?;
+        let reparsed: VanillaConfig = exported.to_string().parse()?;
+        assert_eq!(
+            reparsed.internal_endpoint(),
+            Some("[fd00::1]:5555".parse()?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_round_trip() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let interface_config = config.to_interface_config()?;
+
+        let exported = VanillaConfig::try_from(&interface_config)?.to_string();
+        // Standard networks don't get any AmneziaWG parameters.
+        assert!(!exported.contains("Jc"));
+        assert_eq!(exported.parse::<VanillaConfig>()?, config);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dns_round_trip() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG
+            .replace(
+                "DNS = 10.42.0.1",
+                "DNS = 10.42.0.1, fd00::53, mynet, corp.example",
+            )
+            .parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let mut interface_config = config.to_interface_config()?;
+        assert_eq!(
+            interface_config.interface.dns,
+            vec!["10.42.0.1".parse::<IpAddr>()?, "fd00::53".parse()?]
+        );
+        assert_eq!(
+            interface_config.interface.dns_search,
+            ["mynet", "corp.example"]
+        );
+
+        let exported = VanillaConfig::try_from(&interface_config)?.to_string();
+        assert!(exported.contains("DNS = 10.42.0.1, fd00::53, mynet, corp.example\n"));
+        assert_eq!(exported.parse::<VanillaConfig>()?, config);
+
+        // Without any DNS servers the line is left out, and search domains alone are refused.
+        interface_config.interface.dns.clear();
+        assert!(VanillaConfig::try_from(&interface_config).is_err());
+        interface_config.interface.dns_search.clear();
+        let exported = VanillaConfig::try_from(&interface_config)?.to_string();
+        assert!(!exported.contains("DNS"));
+        assert!(PLAIN_CONFIG
+            .replace("DNS = 10.42.0.1", "DNS = mynet")
+            .parse::<VanillaConfig>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_allowed_ips_round_trip() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let exported = VanillaConfig::try_from(&config.to_interface_config()?)?.to_string();
+        assert!(exported.contains("# !generated_allowed_ips = 10.42.0.0/16\n"));
+
+        // Unedited, nothing is stored.
+        let imported: VanillaConfig = exported.parse()?;
+        assert_eq!(imported.to_interface_config()?.server.allowed_ips, None);
+
+        let edited = exported.replace(
+            "AllowedIPs = 10.42.0.0/16",
+            "AllowedIPs = 10.42.0.0/16, 192.168.1.0/24",
+        );
+        let interface_config = edited.parse::<VanillaConfig>()?.to_interface_config()?;
+        assert_eq!(
+            interface_config.server.allowed_ips,
+            Some(vec!["10.42.0.0/16".parse()?, "192.168.1.0/24".parse()?])
+        );
+
+        let reexported = VanillaConfig::try_from(&interface_config)?;
+        assert_eq!(reexported.to_string(), edited);
+        assert!(reexported
+            .to_strict_string()?
+            .contains("AllowedIPs = 10.42.0.0/16, 192.168.1.0/24\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_tunnel_round_trip() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        config.set_full_tunnel();
+        let exported = config.to_string();
+        assert!(exported.contains("AllowedIPs = 0.0.0.0/0, ::/0\n"));
+        assert!(exported.contains("PostUp = iptables -I OUTPUT ! -o %i"));
+        assert!(exported.contains("&& ip6tables -D OUTPUT ! -o %i"));
+
+        // The hooks are innernet's own, and the generated allowed-IPs stay as they were.
+        let imported: VanillaConfig = exported.parse()?;
+        assert_eq!(imported, config);
+        let interface_config = imported.to_interface_config()?;
+        assert!(interface_config.interface.full_tunnel);
+        assert_eq!(interface_config.server.allowed_ips, None);
+        assert_eq!(VanillaConfig::try_from(&interface_config)?, config);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_export() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+
+        let strict = config.to_strict_string()?;
+        assert!(!strict.contains('#'));
+        assert!(strict.starts_with("[Interface]\n"));
+        assert!(strict.ends_with("PersistentKeepalive = 25\n"));
+        // Without the metadata, the network can't be set up from it again.
+        assert!(strict
+            .parse::<VanillaConfig>()?
+            .to_interface_config()
+            .is_err());
+
+        // wg-quick needs root, even just to strip a config.
+        if unsafe { libc::getuid() } == 0 {
+            let dir = std::env::temp_dir().join(format!("innernet-strict-{}", std::process::id()));
+            fs::create_dir_all(&dir)?;
+            let path = dir.join("evilcorp.conf");
+            fs::write(&path, &strict)?;
+            let output = std::process::Command::new("wg-quick")
+                .arg("strip")
+                .arg(&path)
+                .output();
+            fs::remove_dir_all(&dir)?;
+            match output {
+                Ok(output) => {
+                    assert!(
+                        output.status.success(),
+                        "wg-quick rejected the config: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                    let stripped = String::from_utf8(output.stdout)?;
+                    assert!(stripped.contains("PrivateKey = "));
+                    assert!(!stripped.contains("Address"));
+                },
+                Err(e) => println!("skipping wg-quick validation: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_obfuscation_round_trip() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_network_name("evilcorp".into());
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let mut interface_config = config.to_interface_config()?;
+        let params = ObfuscationParams {
+            jc: 4,
+            jmin: 40,
+            jmax: 70,
+            s1: 15,
+            s2: 61,
+            h1: 1_234_567_891,
+            h2: 1_234_567_892,
+            h3: 1_234_567_893,
+            h4: 1_234_567_894,
+        };
+        interface_config.interface.obfuscation = Some(params);
+
+        // INI
+        let exported = VanillaConfig::try_from(&interface_config)?.to_string();
+        assert!(exported.contains("Jc = 4\n"));
+        assert!(exported.contains("H4 = 1234567894\n"));
+        let imported: VanillaConfig = exported.parse()?;
+        assert!(imported.to_strict_string().is_err());
+        assert_eq!(
+            imported.to_interface_config()?.interface.obfuscation,
+            Some(params)
+        );
+
+        // TOML
+        let toml = toml::to_string(&interface_config)?;
+        let parsed: InterfaceConfig = toml::from_str(&toml)?;
+        assert_eq!(parsed.interface.obfuscation, Some(params));
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_routes() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.add_server_routes(&["10.42.0.0/16".parse()?, "192.168.1.0/24".parse()?]);
+        let exported = config.to_string();
+        assert!(exported.contains("AllowedIPs = 10.42.0.0/16, 192.168.1.0/24\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restrict_server_routes() -> Result<(), Error> {
+        let mut config: VanillaConfig = PLAIN_CONFIG.parse()?;
+        config.set_internal_endpoint("10.42.0.1:51820".parse()?);
+        let cidr = |name: &str, cidr: &str| Cidr {
+            id: 0,
+            contents: crate::CidrContents {
+                name: name.into(),
+                cidr: cidr.parse().unwrap(),
+                parent: None,
+                allocation_range: None,
+                default_keepalive: None,
+                default_mtu: None,
+                max_peers: None,
+            },
+        };
+        let (servers, humans, lab) = (
+            cidr("servers", "10.42.8.0/24"),
+            cidr("humans", "10.42.64.0/24"),
+            cidr("lab", "10.42.9.0/24"),
+        );
+        // Two servers and a gateway routing a LAN behind the servers CIDR, plus a human.
+        let permitted = [
+            "10.42.8.1/32".parse()?,
+            "10.42.8.2/32".parse()?,
+            "10.42.8.128/25".parse()?,
+            "10.42.64.5/32".parse()?,
+        ];
+
+        config.restrict_server_routes(&[&servers], &permitted)?;
+        assert!(config
+            .to_string()
+            .contains("AllowedIPs = 10.42.0.1/32, 10.42.8.1/32, 10.42.8.2/32, 10.42.8.128/25\n"));
+
+        // A CIDR inside a permitted route is narrowed to the CIDR, never widened.
+        let gateway_half = cidr("gateway-half", "10.42.8.192/26");
+        config.restrict_server_routes(&[&gateway_half, &humans], &permitted)?;
+        assert!(config
+            .to_string()
+            .contains("AllowedIPs = 10.42.0.1/32, 10.42.8.192/26, 10.42.64.5/32\n"));
+
+        let err = config
+            .restrict_server_routes(&[&servers, &lab], &permitted)
+            .unwrap_err();
+        assert!(err.to_string().contains("lab"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_incomplete_obfuscation() {
+        let config = PLAIN_CONFIG.replace("ListenPort = 51821", "ListenPort = 51821\nJc = 4");
+        assert!(config.parse::<VanillaConfig>().is_err());
+    }
+
+    #[test]
+    fn test_multiple_peers() {
+        let config = format!("{PLAIN_CONFIG}\n[Peer]\nPublicKey = abc\n");
+        assert!(config.parse::<VanillaConfig>().is_err());
+    }
+}
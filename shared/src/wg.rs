@@ -1,9 +1,16 @@
-use crate::{Error, IoErrorContext, NetworkOpts, Peer, PeerDiff};
+use crate::{
+    interface_config::InterfaceInfo, Endpoint, Error, IoErrorContext, NetworkOpts, Peer, PeerDiff,
+};
+use anyhow::{anyhow, bail};
 use ipnet::IpNet;
+use serde::Serialize;
 use std::{
+    collections::HashMap,
     io,
-    net::{IpAddr, SocketAddr},
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
 };
 use wireguard_control::{
     Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
@@ -56,6 +63,10 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
     }
 }
 
+/// The MTU interfaces are brought up with when neither the command line nor the peer's CIDR
+/// sets one.
+pub const DEFAULT_MTU: u32 = 1280;
+
 #[cfg(target_os = "macos")]
 pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
     let real_interface = wireguard_control::backends::userspace::resolve_tun(interface)?;
@@ -69,42 +80,263 @@ pub use super::netlink::set_addr;
 #[cfg(target_os = "linux")]
 pub use super::netlink::set_up;
 
+/// A network namespace (as created by `ip netns add`) that the process runs in. Interfaces
+/// brought up in it are created in the namespace the process started in, so that their
+/// WireGuard sockets can still reach the outside, and only then moved into it.
+#[derive(Debug)]
+pub struct Netns {
+    pub name: String,
+    origin: std::fs::File,
+    target: std::fs::File,
+}
+
+impl Netns {
+    /// Move this thread into the namespace named `name`, remembering the one it came from.
+    /// Namespaces are per thread, so this has to happen before any threads are spawned for
+    /// them to inherit it.
+    #[cfg(target_os = "linux")]
+    pub fn enter(name: &str) -> Result<Self, Error> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            bail!("invalid network namespace name '{}'", name);
+        }
+        let open = |path: std::path::PathBuf| std::fs::File::open(&path).with_path(&path);
+        let netns = Self {
+            name: name.to_string(),
+            origin: open("/proc/thread-self/ns/net".into())?,
+            target: open(std::path::Path::new("/run/netns").join(name))?,
+        };
+        netns.switch(&netns.target)?;
+        log::debug!("entered network namespace {}", name);
+        Ok(netns)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enter(_name: &str) -> Result<Self, Error> {
+        bail!("network namespaces are only supported on Linux.")
+    }
+
+    /// Run `f` back in the namespace the process started in.
+    fn outside<T>(&self, f: impl FnOnce() -> Result<T, io::Error>) -> Result<T, io::Error> {
+        self.switch(&self.origin)?;
+        let result = f();
+        self.switch(&self.target)?;
+        result
+    }
+
+    #[cfg(target_os = "linux")]
+    fn switch(&self, namespace: &std::fs::File) -> Result<(), io::Error> {
+        use std::os::fd::AsRawFd;
+        if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn switch(&self, _namespace: &std::fs::File) -> Result<(), io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "network namespaces are only supported on Linux",
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn move_in(&self, interface: &InterfaceName) -> Result<(), io::Error> {
+        use std::os::fd::AsRawFd;
+        super::netlink::set_netns(interface, self.target.as_raw_fd())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn move_in(&self, _interface: &InterfaceName) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Everything bringing up an interface does, worked out before doing any of it so that
+/// `innernet up --dry-run` can show exactly what the real bring-up would do.
+#[derive(Debug)]
+pub struct UpPlan {
+    pub private_key: Key,
+    pub address: IpNet,
+    pub listen_port: Option<u16>,
+    pub mtu: u32,
+    pub peers: Vec<PeerConfigBuilder>,
+    /// The route to the network through the interface, unless routing is left alone.
+    pub route: Option<IpNet>,
+    /// Whether all traffic goes through the server, see [`UpPlan::set_full_tunnel`].
+    pub full_tunnel: bool,
+    /// The routing table to put `route` in instead of the main one (Linux only), for hosts
+    /// whose own policy rules decide what reaches it.
+    pub route_table: Option<u32>,
+    /// The fwmark on the interface's own packets (Linux only), so policy rules can keep
+    /// them out of the tunnel.
+    pub fwmark: Option<u32>,
+    /// The network namespace the process runs in, if any, for the interface to be moved
+    /// into once it's created.
+    pub netns: Option<Arc<Netns>>,
+}
+
+impl UpPlan {
+    pub fn new(
+        private_key: &str,
+        address: IpNet,
+        listen_port: Option<u16>,
+        peer: Option<(&str, IpAddr, SocketAddr, Option<&str>)>,
+        network: NetworkOpts,
+    ) -> Result<Self, io::Error> {
+        let parse_key = |key: &str, what: &str| {
+            Key::from_base64(key).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("failed to parse base64 {what}"),
+                )
+            })
+        };
+        let mut peers = vec![];
+        if let Some((public_key, address, endpoint, preshared_key)) = peer {
+            let prefix = if address.is_ipv4() { 32 } else { 128 };
+            let mut peer_config = PeerConfigBuilder::new(&parse_key(public_key, "public key")?)
+                .add_allowed_ip(address, prefix)
+                .set_persistent_keepalive_interval(25)
+                .set_endpoint(endpoint);
+            if let Some(preshared_key) = preshared_key {
+                peer_config =
+                    peer_config.set_preshared_key(parse_key(preshared_key, "preshared key")?);
+            }
+            peers.push(peer_config);
+        }
+        Ok(Self {
+            private_key: parse_key(private_key, "private key")?,
+            address,
+            listen_port,
+            mtu: network.mtu.unwrap_or(DEFAULT_MTU),
+            peers,
+            route: (!network.no_routing).then_some(address),
+            full_tunnel: false,
+            route_table: None,
+            fwmark: None,
+            netns: None,
+        })
+    }
+
+    /// Send all traffic through the server (the first peer), the way wg-quick does: the
+    /// interface marks its own packets with [`FULL_TUNNEL_TABLE`], and everything else is
+    /// routed through it by policy rules. Routing is still left alone with `--no-routing`.
+    pub fn set_full_tunnel(&mut self) {
+        if let Some(server) = self.peers.first_mut() {
+            *server = server
+                .clone()
+                .add_allowed_ip(Ipv4Addr::UNSPECIFIED.into(), 0)
+                .add_allowed_ip(Ipv6Addr::UNSPECIFIED.into(), 0);
+        }
+        self.full_tunnel = true;
+    }
+
+    fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+            .or_else(|| self.full_tunnel.then_some(FULL_TUNNEL_TABLE))
+    }
+
+    /// Add peers on top of the ones already planned, replacing any with the same key.
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = PeerConfigBuilder>) {
+        for peer in peers {
+            self.peers
+                .retain(|planned| planned.public_key() != peer.public_key());
+            self.peers.push(peer);
+        }
+    }
+
+    pub fn apply(self, interface: &InterfaceName, backend: Backend) -> Result<(), io::Error> {
+        let mut device = DeviceUpdate::new().add_peers(&self.peers);
+        if let Some(listen_port) = self.listen_port {
+            device = device.set_listen_port(listen_port);
+        }
+        if let Some(fwmark) = self.fwmark() {
+            device = device.set_fwmark(fwmark);
+        }
+        let backend = backend.resolve(interface);
+        log::info!("bringing up {} with the {} backend.", interface, backend);
+        let device = device.set_private_key(self.private_key);
+        match &self.netns {
+            Some(netns) => netns.outside(|| {
+                device.apply(interface, backend)?;
+                netns.move_in(interface)
+            })?,
+            None => device.apply(interface, backend)?,
+        }
+        set_addr(interface, self.address)?;
+        set_up(interface, self.mtu)?;
+        if let Some(route) = self.route {
+            add_route(interface, route, self.route_table)?;
+            if self.full_tunnel {
+                add_full_tunnel_routes(interface, FULL_TUNNEL_TABLE)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The plan in wg-quick's format, with the private and preshared keys redacted.
+impl std::fmt::Display for UpPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "[Interface]")?;
+        writeln!(f, "PrivateKey = (redacted)")?;
+        writeln!(f, "Address = {}", self.address)?;
+        match self.listen_port {
+            Some(port) => writeln!(f, "ListenPort = {port}")?,
+            None => writeln!(f, "# ListenPort is picked at random")?,
+        }
+        writeln!(f, "MTU = {}", self.mtu)?;
+        if let Some(table) = self.route_table {
+            writeln!(f, "Table = {table}")?;
+        }
+        if let Some(fwmark) = self.fwmark() {
+            writeln!(f, "FwMark = {fwmark}")?;
+        }
+        for peer in &self.peers {
+            let peer = peer.clone().into_peer_config();
+            writeln!(f, "\n[Peer]")?;
+            writeln!(f, "PublicKey = {}", peer.public_key.to_base64())?;
+            if peer.preshared_key.is_some() {
+                writeln!(f, "PresharedKey = (redacted)")?;
+            }
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(f, "Endpoint = {endpoint}")?;
+            }
+            let allowed_ips = peer
+                .allowed_ips
+                .iter()
+                .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+                .collect::<Vec<_>>();
+            writeln!(f, "AllowedIPs = {}", allowed_ips.join(", "))?;
+            if let Some(keepalive) = peer.persistent_keepalive_interval.filter(|&k| k > 0) {
+                writeln!(f, "PersistentKeepalive = {keepalive}")?;
+            }
+        }
+        match self.route {
+            Some(route) if self.full_tunnel => write!(
+                f,
+                "\n# Route: {}, and everything else through table {FULL_TUNNEL_TABLE}",
+                route.trunc()
+            ),
+            Some(route) => match self.route_table {
+                Some(table) => write!(f, "\n# Route: {} in table {table}", route.trunc()),
+                None => write!(f, "\n# Route: {}", route.trunc()),
+            },
+            None => write!(f, "\n# No routes (--no-routing)"),
+        }
+    }
+}
+
 pub fn up(
     interface: &InterfaceName,
     private_key: &str,
     address: IpNet,
     listen_port: Option<u16>,
-    peer: Option<(&str, IpAddr, SocketAddr)>,
+    peer: Option<(&str, IpAddr, SocketAddr, Option<&str>)>,
     network: NetworkOpts,
 ) -> Result<(), io::Error> {
-    let mut device = DeviceUpdate::new();
-    if let Some((public_key, address, endpoint)) = peer {
-        let prefix = if address.is_ipv4() { 32 } else { 128 };
-        let peer_config = PeerConfigBuilder::new(
-            &wireguard_control::Key::from_base64(public_key).map_err(|_| {
-                io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "failed to parse base64 public key",
-                )
-            })?,
-        )
-        .add_allowed_ip(address, prefix)
-        .set_persistent_keepalive_interval(25)
-        .set_endpoint(endpoint);
-        device = device.add_peer(peer_config);
-    }
-    if let Some(listen_port) = listen_port {
-        device = device.set_listen_port(listen_port);
-    }
-    device
-        .set_private_key(wireguard_control::Key::from_base64(private_key).unwrap())
-        .apply(interface, network.backend)?;
-    set_addr(interface, address)?;
-    set_up(interface, network.mtu.unwrap_or(1280))?;
-    if !network.no_routing {
-        add_route(interface, address)?;
-    }
-    Ok(())
+    UpPlan::new(private_key, address, listen_port, peer, network)?.apply(interface, network.backend)
 }
 
 pub fn set_listen_port(
@@ -123,6 +355,77 @@ pub fn set_listen_port(
     Ok(())
 }
 
+/// Whether another socket already holds the UDP port, on either the IPv4 or IPv6 wildcard
+/// address. This binds the port instead of asking the OS for a socket table, so it works the
+/// same everywhere.
+pub fn is_udp_port_in_use(port: u16) -> bool {
+    [
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    ]
+    .into_iter()
+    .any(|ip| {
+        matches!(
+            UdpSocket::bind((ip, port)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse
+        )
+    })
+}
+
+/// Ask the OS for a UDP port that's currently free.
+pub fn free_udp_port() -> Result<u16, io::Error> {
+    loop {
+        let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+            .local_addr()?
+            .port();
+        if !is_udp_port_in_use(port) {
+            return Ok(port);
+        }
+    }
+}
+
+/// Make sure the interface's listen port can be bound before bringing it up.
+///
+/// A port that's taken is an error if the user chose it, but an automatically picked port
+/// (or none at all) is replaced with a free one, from `listen_port_range` if it's set.
+/// Returns whether `interface.listen_port` changed and needs to be saved.
+pub fn ensure_listen_port_available(interface: &mut InterfaceInfo) -> Result<bool, Error> {
+    let range = interface.listen_port_range;
+    let in_range = |port: u16| match range {
+        Some(range) => range.contains(port),
+        None => true,
+    };
+    match interface.listen_port {
+        Some(port)
+            if !is_udp_port_in_use(port) && (!interface.listen_port_auto || in_range(port)) =>
+        {
+            return Ok(false)
+        },
+        Some(port) if !interface.listen_port_auto => bail!(
+            "listen port {} is already in use by another program. Stop it, or choose another \
+             port with 'innernet set-listen-port'.",
+            port
+        ),
+        Some(port) if in_range(port) => {
+            log::warn!("listen port {} is already in use, picking another.", port)
+        },
+        Some(port) => log::info!("listen port {} is outside of the allowed range.", port),
+        None => {},
+    }
+
+    let port = match range {
+        Some(range) => range
+            .ports()
+            .find(|port| !is_udp_port_in_use(*port))
+            .ok_or_else(|| anyhow!("every port in the listen port range {} is in use.", range))?,
+        None => free_udp_port()?,
+    };
+    log::info!("using listen port {}.", port);
+    interface.listen_port = Some(port);
+    interface.listen_port_auto = true;
+    Ok(true)
+}
+
 pub fn down(interface: &InterfaceName, backend: Backend) -> Result<(), Error> {
     Ok(Device::get(interface, backend)
         .with_str(interface.as_str_lossy())?
@@ -132,9 +435,20 @@ pub fn down(interface: &InterfaceName, backend: Backend) -> Result<(), Error> {
 
 /// Add a route in the OS's routing table to get traffic flowing through this interface.
 /// Returns an error if the process doesn't exit successfully, otherwise returns
-/// true if the route was changed, false if the route already exists.
+/// true if the route was changed, false if the route already exists. Custom routing tables
+/// are Linux-only, so `table` is ignored.
 #[cfg(target_os = "macos")]
-pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+pub fn add_route(
+    interface: &InterfaceName,
+    cidr: IpNet,
+    table: Option<u32>,
+) -> Result<bool, io::Error> {
+    if let Some(table) = table {
+        log::warn!(
+            "routing tables are only supported on Linux, adding the route to the main table instead of {}.",
+            table
+        );
+    }
     let real_interface = wireguard_control::backends::userspace::resolve_tun(interface)?;
     let output = cmd(
         "route",
@@ -168,6 +482,26 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
 #[cfg(target_os = "linux")]
 pub use super::netlink::add_route;
 
+/// The routing table full-tunnel interfaces put their default routes in, which is also the
+/// fwmark on their own packets. It's the same one wg-quick uses by default.
+pub const FULL_TUNNEL_TABLE: u32 = 51820;
+
+#[cfg(target_os = "linux")]
+pub use super::netlink::{add_full_tunnel_routes, remove_full_tunnel_routes};
+
+#[cfg(target_os = "macos")]
+pub fn add_full_tunnel_routes(_interface: &InterfaceName, _table: u32) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "full-tunnel mode isn't supported on macOS yet",
+    ))
+}
+
+#[cfg(target_os = "macos")]
+pub fn remove_full_tunnel_routes(_table: u32) -> Result<(), io::Error> {
+    Ok(())
+}
+
 pub trait DeviceExt {
     /// Diff the output of a wgctrl device with a list of server-reported peers.
     fn diff<'a>(&'a self, peers: &'a [Peer]) -> Vec<PeerDiff<'a>>;
@@ -176,6 +510,73 @@ pub trait DeviceExt {
     fn get_peer(&self, public_key: &str) -> Option<&PeerInfo>;
 }
 
+/// Whether `peer` belongs on the interface whose own public key is `interface_public_key`:
+/// it isn't disabled, and isn't the interface itself.
+pub fn is_wanted(peer: &Peer, interface_public_key: &str) -> bool {
+    !peer.is_disabled && peer.public_key != interface_public_key
+}
+
+/// How many peer endpoints are resolved at once.
+const RESOLVE_CONCURRENCY: usize = 8;
+
+/// How long a single endpoint gets to resolve before it's skipped.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolve endpoints (keyed by anything, i.e. a peer's public key) with up to `concurrency`
+/// lookups in flight at a time.
+///
+/// Endpoints that fail to resolve, or take longer than `timeout`, are left out of the
+/// result. A lookup that times out can't be cancelled, so its thread is left to finish on
+/// its own.
+pub fn resolve_endpoints<F>(
+    endpoints: Vec<(String, Endpoint)>,
+    resolve: F,
+    concurrency: usize,
+    timeout: Duration,
+) -> HashMap<String, SocketAddr>
+where
+    F: Fn(&Endpoint) -> Result<SocketAddr, io::Error> + Send + Sync + 'static,
+{
+    let resolve = Arc::new(resolve);
+    let workers = concurrency.min(endpoints.len());
+    let queue = Arc::new(Mutex::new(endpoints.into_iter()));
+    let (results_tx, results_rx) = mpsc::channel();
+
+    let workers = (0..workers)
+        .map(|_| {
+            let queue = queue.clone();
+            let resolve = resolve.clone();
+            let results_tx = results_tx.clone();
+            thread::spawn(move || loop {
+                let Some((key, endpoint)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let (tx, rx) = mpsc::channel();
+                thread::spawn({
+                    let resolve = resolve.clone();
+                    let endpoint = endpoint.clone();
+                    move || {
+                        let _ = tx.send(resolve(&endpoint));
+                    }
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(Ok(addr)) => {
+                        let _ = results_tx.send((key, addr));
+                    },
+                    Ok(Err(e)) => log::warn!("failed to resolve {}: {}", endpoint, e),
+                    Err(_) => log::warn!("timed out resolving {}", endpoint),
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(results_tx);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    results_rx.into_iter().collect()
+}
+
 impl DeviceExt for Device {
     fn diff<'a>(&'a self, peers: &'a [Peer]) -> Vec<PeerDiff<'a>> {
         let interface_public_key = self
@@ -184,16 +585,37 @@ impl DeviceExt for Device {
             .map(|k| k.to_base64())
             .unwrap_or_default();
         let existing_peers = &self.peers;
+        let existing_peer = |peer: &Peer| {
+            existing_peers
+                .iter()
+                .find(|p| p.config.public_key.to_base64() == peer.public_key)
+        };
+        let is_wanted = |peer: &Peer| is_wanted(peer, &interface_public_key);
+
+        // Resolve all the endpoints that might be needed up front, since hostname lookups
+        // one after the other can take a while.
+        let endpoints = peers
+            .iter()
+            .filter(|peer| is_wanted(peer))
+            .filter(|peer| !existing_peer(peer).is_some_and(|info| info.is_recently_connected()))
+            .filter_map(|peer| Some((peer.public_key.clone(), peer.endpoint.clone()?)))
+            .collect::<Vec<_>>();
+        let resolved = resolve_endpoints(
+            endpoints,
+            Endpoint::resolve,
+            RESOLVE_CONCURRENCY,
+            RESOLVE_TIMEOUT,
+        );
 
         // Match existing peers (by pubkey) to new peer information from the server.
         let modifications = peers.iter().filter_map(|peer| {
-            if peer.is_disabled || peer.public_key == interface_public_key {
+            if !is_wanted(peer) {
                 None
             } else {
-                let existing_peer = existing_peers
-                    .iter()
-                    .find(|p| p.config.public_key.to_base64() == peer.public_key);
-                PeerDiff::new(existing_peer, Some(peer)).unwrap()
+                PeerDiff::with_resolver(existing_peer(peer), Some(peer), |_| {
+                    resolved.get(&peer.public_key).copied()
+                })
+                .unwrap()
             }
         });
 
@@ -217,21 +639,299 @@ impl DeviceExt for Device {
     }
 }
 
+/// WireGuard initiates a new handshake after REKEY_AFTER_TIME as long as there's traffic
+/// (which includes persistent keepalives) going to the peer.
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// WireGuard rejects any communication after REJECT_AFTER_TIME without a new handshake.
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// A coarse indicator of how healthy a peer's connection is, based on how recently
+/// the last handshake happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HandshakeHealth {
+    /// The handshake is as recent as it would be with keepalives flowing.
+    Healthy,
+    /// A handshake is overdue, but the session hasn't expired yet.
+    Stale,
+    /// No handshake has happened, or the session has expired.
+    Down,
+}
+
+impl HandshakeHealth {
+    pub fn from_handshake_age(age: Option<Duration>, keepalive_interval: Option<u16>) -> Self {
+        let keepalive = Duration::from_secs(keepalive_interval.unwrap_or_default().into());
+        match age {
+            Some(age) if age <= REKEY_AFTER_TIME + keepalive => Self::Healthy,
+            Some(age) if age <= REJECT_AFTER_TIME => Self::Stale,
+            _ => Self::Down,
+        }
+    }
+}
+
+/// How long before `now` a handshake at `last_handshake` happened, or `None` if there's never
+/// been one.
+///
+/// Devices report "never" either as no timestamp or as the UNIX epoch, so both count as never
+/// rather than as a handshake decades ago. A handshake that seems to be in the future (ex.
+/// the clock was set back since) counts as having just happened.
+pub fn handshake_age(last_handshake: Option<SystemTime>, now: SystemTime) -> Option<Duration> {
+    let last_handshake = last_handshake.filter(|&time| time != SystemTime::UNIX_EPOCH)?;
+    Some(now.duration_since(last_handshake).unwrap_or_default())
+}
+
 pub trait PeerInfoExt {
+    /// How long ago the last handshake with the peer was, if there's been one. See
+    /// [`handshake_age`].
+    fn handshake_age(&self) -> Option<Duration>;
+
     /// WireGuard rejects any communication after REJECT_AFTER_TIME, so we can use this
     /// as a heuristic for "currentness" without relying on heavier things like ICMP.
     fn is_recently_connected(&self) -> bool;
+
+    /// Judge the health of the connection based on the last handshake compared to the
+    /// peer's persistent keepalive interval.
+    fn handshake_health(&self) -> HandshakeHealth;
 }
 impl PeerInfoExt for PeerInfo {
+    fn handshake_age(&self) -> Option<Duration> {
+        handshake_age(self.stats.last_handshake_time, SystemTime::now())
+    }
+
+    fn handshake_health(&self) -> HandshakeHealth {
+        HandshakeHealth::from_handshake_age(
+            self.handshake_age(),
+            self.config.persistent_keepalive_interval,
+        )
+    }
+
     fn is_recently_connected(&self) -> bool {
-        const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+        self.handshake_age()
+            .is_some_and(|age| age <= REJECT_AFTER_TIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FamilyPreference, PortRange};
+
+    fn interface_info(listen_port: Option<u16>, listen_port_auto: bool) -> InterfaceInfo {
+        InterfaceInfo {
+            network_name: "test".into(),
+            address: "10.0.0.2/16".parse().unwrap(),
+            private_key: Key::generate_private().to_base64(),
+            private_key_keyring: None,
+            listen_port,
+            listen_port_auto,
+            listen_port_range: None,
+            metered: false,
+            pinned_endpoint: None,
+            obfuscation: None,
+            dns: vec![],
+            dns_search: vec![],
+            prefer_family: FamilyPreference::Auto,
+            full_tunnel: false,
+            report_description: false,
+            removal_interval: None,
+            route_table: None,
+            fwmark: None,
+        }
+    }
+
+    #[test]
+    fn test_listen_port_in_use() -> Result<(), Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let port = socket.local_addr()?.port();
+        assert!(is_udp_port_in_use(port));
+
+        // A port the user chose explicitly is never swapped out from under them.
+        let mut explicit = interface_info(Some(port), false);
+        let err = ensure_listen_port_available(&mut explicit).unwrap_err();
+        assert!(err.to_string().contains(&port.to_string()));
+        assert_eq!(explicit.listen_port, Some(port));
+
+        // An automatically picked one is replaced with a free port.
+        let mut auto = interface_info(Some(port), true);
+        assert!(ensure_listen_port_available(&mut auto)?);
+        assert_ne!(auto.listen_port, Some(port));
+        assert!(!is_udp_port_in_use(auto.listen_port.unwrap()));
+
+        drop(socket);
+        let mut explicit = interface_info(Some(port), false);
+        assert!(!ensure_listen_port_available(&mut explicit)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_port_unset() -> Result<(), Error> {
+        let mut info = interface_info(None, false);
+        assert!(ensure_listen_port_available(&mut info)?);
+        assert!(info.listen_port.is_some());
+        assert!(info.listen_port_auto);
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_port_range() -> Result<(), Error> {
+        // Find two free ports next to each other to use as the range.
+        let (first, socket) = loop {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            let port = socket.local_addr()?.port();
+            if port < u16::MAX && !is_udp_port_in_use(port + 1) {
+                break (port, socket);
+            }
+        };
+        let range = PortRange {
+            start: first,
+            end: first + 1,
+        };
+
+        // A free port outside of the range gets swapped for one inside it.
+        let mut info = interface_info(None, true);
+        info.listen_port_range = Some(range);
+        assert!(ensure_listen_port_available(&mut info)?);
+        assert_eq!(info.listen_port, Some(first + 1));
+        assert!(!ensure_listen_port_available(&mut info)?);
+
+        // Once the whole range is taken, it's an error rather than any other port.
+        let _other = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, first + 1))?;
+        let err = ensure_listen_port_available(&mut info).unwrap_err();
+        assert!(err.to_string().contains(&range.to_string()));
+
+        drop(socket);
+        assert!(ensure_listen_port_available(&mut info)?);
+        assert_eq!(info.listen_port, Some(first));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_endpoints() {
+        let endpoints = (0..6)
+            .map(|i| (format!("peer{i}"), format!("host{i}.example.com:51820")))
+            .chain([
+                ("slow".to_string(), "slow.example.com:51820".to_string()),
+                ("broken".to_string(), "broken.example.com:51820".to_string()),
+            ])
+            .map(|(key, endpoint)| (key, endpoint.parse().unwrap()))
+            .collect::<Vec<(String, Endpoint)>>();
+
+        let start = std::time::Instant::now();
+        let resolved = resolve_endpoints(
+            endpoints,
+            |endpoint| match endpoint.to_string().as_str() {
+                "slow.example.com:51820" => {
+                    thread::sleep(Duration::from_secs(5));
+                    Ok("10.0.0.100:51820".parse().unwrap())
+                },
+                "broken.example.com:51820" => Err(io::ErrorKind::NotFound.into()),
+                _ => {
+                    thread::sleep(Duration::from_millis(100));
+                    Ok("10.0.0.1:51820".parse().unwrap())
+                },
+            },
+            4,
+            Duration::from_millis(500),
+        );
+
+        // Failed and timed out lookups are skipped instead of failing everything.
+        assert_eq!(resolved.len(), 6);
+        assert!(!resolved.contains_key("slow"));
+        assert!(!resolved.contains_key("broken"));
+        // Six 100ms lookups with four at a time, and one 500ms timeout, finish well before
+        // they would one after another.
+        assert!(start.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_handshake_health() {
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(
+            HandshakeHealth::from_handshake_age(None, Some(25)),
+            HandshakeHealth::Down
+        );
+        assert_eq!(
+            HandshakeHealth::from_handshake_age(secs(10), Some(25)),
+            HandshakeHealth::Healthy
+        );
+        assert_eq!(
+            HandshakeHealth::from_handshake_age(secs(140), Some(25)),
+            HandshakeHealth::Healthy
+        );
+        assert_eq!(
+            HandshakeHealth::from_handshake_age(secs(140), None),
+            HandshakeHealth::Stale
+        );
+        assert_eq!(
+            HandshakeHealth::from_handshake_age(secs(181), Some(25)),
+            HandshakeHealth::Down
+        );
+    }
+
+    #[test]
+    fn test_handshake_age() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            handshake_age(Some(now - Duration::from_secs(30)), now),
+            Some(Duration::from_secs(30))
+        );
+        // A handshake long ago is still a handshake...
+        assert_eq!(
+            handshake_age(Some(now - Duration::from_secs(86_400)), now),
+            Some(Duration::from_secs(86_400))
+        );
+        // ...but the zero timestamp means there's never been one.
+        assert_eq!(handshake_age(Some(SystemTime::UNIX_EPOCH), now), None);
+        assert_eq!(handshake_age(None, now), None);
+        // A handshake from the future (the local clock is behind) just happened.
+        assert_eq!(
+            handshake_age(Some(now + Duration::from_secs(90)), now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_up_plan_display() -> Result<(), io::Error> {
+        let private_key = Key::generate_private().to_base64();
+        let server_key = Key::generate_private().get_public().to_base64();
+        let preshared_key = Key::generate_preshared().to_base64();
+        let network = NetworkOpts {
+            no_routing: false,
+            backend: Backend::default(),
+            mtu: None,
+        };
+        let plan = UpPlan::new(
+            &private_key,
+            "10.0.0.2/16".parse().unwrap(),
+            Some(51820),
+            Some((
+                &server_key,
+                "10.0.0.1".parse().unwrap(),
+                "203.0.113.1:51820".parse().unwrap(),
+                Some(&preshared_key),
+            )),
+            network,
+        )?;
+        let shown = plan.to_string();
+        assert!(!shown.contains(&private_key) && !shown.contains(&preshared_key));
+        assert!(shown.contains(&format!("PublicKey = {server_key}")));
+        assert!(shown.contains("AllowedIPs = 10.0.0.1/32"));
+        assert!(shown.contains(&format!("MTU = {DEFAULT_MTU}")));
+        assert!(shown.ends_with("# Route: 10.0.0.0/16"));
 
-        let last_handshake = self
-            .stats
-            .last_handshake_time
-            .and_then(|t| t.elapsed().ok())
-            .unwrap_or(Duration::MAX);
+        let mut plan = plan;
+        plan.set_full_tunnel();
+        let shown = plan.to_string();
+        assert!(shown.contains("AllowedIPs = 10.0.0.1/32, 0.0.0.0/0, ::/0"));
+        assert!(shown.contains(&format!("FwMark = {FULL_TUNNEL_TABLE}")));
 
-        last_handshake <= REJECT_AFTER_TIME
+        plan.full_tunnel = false;
+        plan.route_table = Some(1000);
+        plan.fwmark = Some(0x1000);
+        let shown = plan.to_string();
+        assert!(shown.contains("Table = 1000\nFwMark = 4096\n"));
+        assert!(shown.ends_with("# Route: 10.0.0.0/16 in table 1000"));
+        Ok(())
     }
 }
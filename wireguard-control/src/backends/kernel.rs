@@ -5,7 +5,10 @@ use crate::{
 use netlink_packet_core::{
     NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
 };
-use netlink_packet_generic::GenlMessage;
+use netlink_packet_generic::{
+    ctrl::{nlas::GenlCtrlAttrs, GenlCtrl, GenlCtrlCmd},
+    GenlMessage,
+};
 use netlink_packet_route::{
     constants::*,
     link::{
@@ -164,6 +167,22 @@ impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
     }
 }
 
+/// Whether the running kernel supports WireGuard, i.e. it has registered (or can load)
+/// the wireguard generic netlink family.
+pub fn is_available() -> bool {
+    let genlmsg: GenlMessage<GenlCtrl> = GenlMessage::from_payload(GenlCtrl {
+        cmd: GenlCtrlCmd::GetFamily,
+        nlas: vec![GenlCtrlAttrs::FamilyName("wireguard".to_string())],
+    });
+    match netlink_request_genl::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK)) {
+        Ok(_) => true,
+        Err(e) => {
+            log::debug!("kernel WireGuard support not found: {}", e);
+            false
+        },
+    }
+}
+
 pub fn enumerate() -> Result<Vec<InterfaceName>, io::Error> {
     let link_responses = netlink_request_rtnl(
         RtnlMessage::GetLink(LinkMessage::default()),
@@ -366,8 +385,7 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
         responses.len()
     );
 
-    let nlas = responses.into_iter().fold(Ok(vec![]), |nlas_res, nlmsg| {
-        let mut nlas = nlas_res?;
+    let nlas = responses.into_iter().try_fold(vec![], |mut nlas, nlmsg| {
         let mut message = match nlmsg {
             NetlinkMessage {
                 payload: NetlinkPayload::InnerMessage(message),
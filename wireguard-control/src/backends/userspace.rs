@@ -7,11 +7,12 @@ use std::{
     os::unix::net::UnixStream,
     path::{Path, PathBuf},
     process::{Command, Output},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 static VAR_RUN_PATH: &str = "/var/run/wireguard";
 static RUN_PATH: &str = "/run/wireguard";
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn get_base_folder() -> io::Result<PathBuf> {
     if Path::new(VAR_RUN_PATH).exists() {
@@ -50,10 +51,30 @@ pub fn resolve_tun(name: &InterfaceName) -> io::Result<String> {
         .to_string())
 }
 
+/// Whether a userspace implementation is currently serving this interface.
+pub fn is_running(name: &InterfaceName) -> bool {
+    open_socket(name).is_ok()
+}
+
+/// Removing the UAPI socket signals the userspace implementation to shut down, which then
+/// removes its tun device. Wait briefly for that so a following `up` doesn't race it.
 pub fn delete_interface(name: &InterfaceName) -> io::Result<()> {
+    let tun = resolve_tun(name).unwrap_or_else(|_| name.to_string());
     fs::remove_file(get_socketfile(name)?).ok();
     fs::remove_file(get_namefile(name)?).ok();
 
+    if cfg!(target_os = "linux") {
+        let device_path = Path::new("/sys/class/net").join(&tun);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while device_path.exists() {
+            if Instant::now() > deadline {
+                log::warn!("userspace WireGuard for {} didn't shut down in time.", name);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
     Ok(())
 }
 
@@ -278,7 +299,7 @@ fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
         command.args(&[iface.to_string()]).output()?
     } else {
         command
-            .env("WG_TUN_NAME_FILE", &format!("{VAR_RUN_PATH}/{iface}.name"))
+            .env("WG_TUN_NAME_FILE", format!("{VAR_RUN_PATH}/{iface}.name"))
             .args(["utun"])
             .output()?
     };
@@ -46,6 +46,7 @@ impl std::str::FromStr for AllowedIp {
 ///
 /// These are the attributes that don't change over time and are part of the configuration.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[allow(clippy::manual_non_exhaustive)]
 pub struct PeerConfig {
     /// The public key of the peer.
     pub public_key: Key,
@@ -74,6 +75,15 @@ pub struct PeerStats {
     pub tx_bytes: u64,
 }
 
+/// The statistics of one peer on a device, as returned by [`Device::stats`](Device::stats).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DevicePeerStats {
+    /// The public key of the peer.
+    pub public_key: Key,
+    /// The peer's transfer counters and last handshake time.
+    pub stats: PeerStats,
+}
+
 /// Represents the complete status of a peer.
 ///
 /// This struct simply combines [`PeerInfo`](PeerInfo) and [`PeerStats`](PeerStats)
@@ -91,6 +101,7 @@ pub struct PeerInfo {
 /// The peer statistics are retrieved once at construction time,
 /// and need to be updated manually by calling [`get_by_name`](DeviceInfo::get_by_name).
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[allow(clippy::manual_non_exhaustive)]
 pub struct Device {
     /// The interface name of this device
     pub name: InterfaceName,
@@ -222,6 +233,20 @@ impl Device {
     /// detailed information on each interface.
     pub fn list(backend: Backend) -> Result<Vec<InterfaceName>, std::io::Error> {
         match backend {
+            Backend::Auto => {
+                let mut interfaces = vec![];
+                #[cfg(target_os = "linux")]
+                if backends::kernel::is_available() {
+                    interfaces.extend(backends::kernel::enumerate()?);
+                }
+                match backends::userspace::enumerate() {
+                    Ok(userspace) => interfaces.extend(userspace),
+                    // No userspace interfaces have ever been started.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => return Err(e),
+                }
+                Ok(interfaces)
+            },
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::enumerate(),
             Backend::Userspace => backends::userspace::enumerate(),
@@ -229,22 +254,166 @@ impl Device {
     }
 
     pub fn get(name: &InterfaceName, backend: Backend) -> Result<Self, std::io::Error> {
-        match backend {
+        // `resolve` never returns `Auto`.
+        match backend.resolve(name) {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::get_by_name(name),
-            Backend::Userspace => backends::userspace::get_by_name(name),
+            Backend::Userspace | Backend::Auto => backends::userspace::get_by_name(name),
         }
     }
 
     pub fn delete(self) -> Result<(), std::io::Error> {
-        match self.backend {
+        match self.backend.resolve(&self.name) {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::delete_interface(&self.name),
-            Backend::Userspace => backends::userspace::delete_interface(&self.name),
+            Backend::Userspace | Backend::Auto => backends::userspace::delete_interface(&self.name),
+        }
+    }
+
+    /// The transfer counters and last handshake time of each peer, as of when the
+    /// device was read.
+    pub fn stats(&self) -> Vec<DevicePeerStats> {
+        self.peers
+            .iter()
+            .map(|peer| DevicePeerStats {
+                public_key: peer.config.public_key.clone(),
+                stats: peer.stats.clone(),
+            })
+            .collect()
+    }
+
+    /// Reads the current per-peer statistics of an interface.
+    pub fn get_stats(
+        name: &InterfaceName,
+        backend: Backend,
+    ) -> Result<Vec<DevicePeerStats>, std::io::Error> {
+        Ok(Self::get(name, backend)?.stats())
+    }
+
+    /// Removes a single peer from an interface, leaving all other peers untouched.
+    ///
+    /// Removing a peer that isn't on the interface is a no-op.
+    pub fn remove_peer(
+        name: &InterfaceName,
+        backend: Backend,
+        public_key: &Key,
+    ) -> Result<(), std::io::Error> {
+        let device = Self::get(name, backend)?;
+        if !device
+            .peers
+            .iter()
+            .any(|peer| &peer.config.public_key == public_key)
+        {
+            return Ok(());
         }
+        DeviceUpdate::new()
+            .remove_peer_by_key(public_key)
+            .apply(name, device.backend)
+    }
+
+    /// Builds an update that brings this device's peers in line with `desired`.
+    ///
+    /// Peers that aren't in `desired` are removed, new peers are added, and peers whose
+    /// configuration changed get only the changed attributes. Peers that already match are
+    /// left out of the update entirely so their sessions aren't disturbed. A desired peer
+    /// without an endpoint keeps whatever endpoint it has roamed to.
+    pub fn peer_diff(&self, desired: &[PeerConfig]) -> DeviceUpdate {
+        let mut update = DeviceUpdate::new();
+
+        for existing in &self.peers {
+            if !desired
+                .iter()
+                .any(|peer| peer.public_key == existing.config.public_key)
+            {
+                update = update.remove_peer_by_key(&existing.config.public_key);
+            }
+        }
+
+        for peer in desired {
+            let existing = self
+                .peers
+                .iter()
+                .find(|existing| existing.config.public_key == peer.public_key);
+            let builder = match existing {
+                Some(existing) => modify_peer(&existing.config, peer),
+                None => Some(PeerConfigBuilder::from_peer_config(peer.clone())),
+            };
+            if let Some(builder) = builder {
+                update = update.add_peer(builder);
+            }
+        }
+
+        update
+    }
+
+    /// Reconfigures the peers of a WireGuard interface to match `desired` in a single
+    /// update, so the interface never sees a partially applied peer list.
+    ///
+    /// See [`peer_diff`](Device::peer_diff) for how the live and desired peers are compared.
+    /// This is for other users of the crate: innernet itself diffs its peers with
+    /// `shared::PeerDiff`, which also tracks what changed for its fetch output.
+    pub fn apply_peer_diff(
+        iface: &InterfaceName,
+        backend: Backend,
+        desired: &[PeerConfig],
+    ) -> io::Result<()> {
+        let update = Self::get(iface, backend)?.peer_diff(desired);
+        if update.peers.is_empty() {
+            return Ok(());
+        }
+        update.apply(iface, backend)
     }
 }
 
+/// The minimal builder to turn `existing` into `desired`, or `None` if nothing changed.
+fn modify_peer(existing: &PeerConfig, desired: &PeerConfig) -> Option<PeerConfigBuilder> {
+    let mut builder = PeerConfigBuilder::new(&desired.public_key);
+    let mut changed = false;
+
+    if existing.preshared_key != desired.preshared_key {
+        builder = match &desired.preshared_key {
+            Some(key) => builder.set_preshared_key(key.clone()),
+            None => builder.unset_preshared_key(),
+        };
+        changed = true;
+    }
+
+    if let Some(endpoint) = desired.endpoint {
+        if existing.endpoint != Some(endpoint) {
+            builder = builder.set_endpoint(endpoint);
+            changed = true;
+        }
+    }
+
+    // Devices report a disabled keepalive as an interval of 0.
+    let keepalive = |config: &PeerConfig| {
+        config
+            .persistent_keepalive_interval
+            .filter(|&secs| secs != 0)
+    };
+    if keepalive(existing) != keepalive(desired) {
+        builder = match keepalive(desired) {
+            Some(interval) => builder.set_persistent_keepalive_interval(interval),
+            None => builder.unset_persistent_keepalive(),
+        };
+        changed = true;
+    }
+
+    let same_allowed_ips = existing.allowed_ips.len() == desired.allowed_ips.len()
+        && desired
+            .allowed_ips
+            .iter()
+            .all(|ip| existing.allowed_ips.contains(ip));
+    if !same_allowed_ips {
+        builder = builder
+            .replace_allowed_ips()
+            .add_allowed_ips(&desired.allowed_ips);
+        changed = true;
+    }
+
+    changed.then_some(builder)
+}
+
 /// Builds and represents a configuration that can be applied to a WireGuard interface.
 ///
 /// This is the primary way of changing the settings of an interface.
@@ -422,10 +591,10 @@ impl DeviceUpdate {
     ///
     /// An interface with the provided name will be created if one does not exist already.
     pub fn apply(self, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
-        match backend {
+        match backend.resolve(iface) {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::apply(&self, iface),
-            Backend::Userspace => backends::userspace::apply(&self, iface),
+            Backend::Userspace | Backend::Auto => backends::userspace::apply(&self, iface),
         }
     }
 }
@@ -469,6 +638,183 @@ mod tests {
         device.delete().unwrap();
     }
 
+    fn peer_info(config: PeerConfig) -> PeerInfo {
+        PeerInfo {
+            config,
+            stats: PeerStats::default(),
+        }
+    }
+
+    fn device_with_peers(peers: Vec<PeerInfo>) -> Device {
+        Device {
+            name: TEST_INTERFACE.parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers,
+            linked_name: None,
+            backend: Backend::Userspace,
+            __cant_construct_me: (),
+        }
+    }
+
+    fn peer_config(key: &Key, ip: &str) -> PeerConfig {
+        PeerConfigBuilder::new(key)
+            .add_allowed_ip(ip.parse().unwrap(), 32)
+            .into_peer_config()
+    }
+
+    #[test]
+    fn test_peer_diff() {
+        let (unchanged, changed, removed, added) = (
+            KeyPair::generate().public,
+            KeyPair::generate().public,
+            KeyPair::generate().public,
+            KeyPair::generate().public,
+        );
+        let device = device_with_peers(vec![
+            peer_info(peer_config(&unchanged, "10.0.0.1")),
+            peer_info(peer_config(&changed, "10.0.0.2")),
+            peer_info(peer_config(&removed, "10.0.0.3")),
+        ]);
+        let mut changed_config = peer_config(&changed, "10.0.0.2");
+        changed_config.persistent_keepalive_interval = Some(25);
+
+        let update = device.peer_diff(&[
+            peer_config(&unchanged, "10.0.0.1"),
+            changed_config,
+            peer_config(&added, "10.0.0.4"),
+        ]);
+
+        assert!(!update.peers.iter().any(|p| p.public_key == unchanged));
+        assert!(update
+            .peers
+            .iter()
+            .any(|p| p.public_key == removed && p.remove_me));
+
+        let changed_peer = update
+            .peers
+            .iter()
+            .find(|p| p.public_key == changed)
+            .unwrap();
+        assert_eq!(changed_peer.persistent_keepalive_interval, Some(25));
+        // Only the keepalive changed, so the allowed IPs are left alone.
+        assert!(!changed_peer.replace_allowed_ips);
+        assert!(changed_peer.allowed_ips.is_empty());
+
+        let added_peer = update.peers.iter().find(|p| p.public_key == added).unwrap();
+        assert!(added_peer.replace_allowed_ips);
+        assert_eq!(added_peer.allowed_ips.len(), 1);
+        assert_eq!(update.peers.len(), 3);
+    }
+
+    #[test]
+    fn test_peer_diff_unchanged() {
+        let key = KeyPair::generate().public;
+        let mut existing = peer_config(&key, "10.0.0.1");
+        existing.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        let device = device_with_peers(vec![peer_info(existing)]);
+
+        // A missing endpoint keeps the roamed one rather than counting as a change.
+        let update = device.peer_diff(&[peer_config(&key, "10.0.0.1")]);
+        assert!(update.peers.is_empty());
+
+        // Nor does a disabled keepalive, which the device reports as 0.
+        let mut existing = peer_config(&key, "10.0.0.1");
+        existing.persistent_keepalive_interval = Some(0);
+        let device = device_with_peers(vec![peer_info(existing)]);
+        let update = device.peer_diff(&[peer_config(&key, "10.0.0.1")]);
+        assert!(update.peers.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+
+        let backends = [
+            #[cfg(target_os = "linux")]
+            Backend::Kernel,
+            Backend::Userspace,
+        ];
+        let interface = "wgctrl-stats".parse().unwrap();
+        let keypair = KeyPair::generate();
+        for backend in backends {
+            let update = DeviceUpdate::new().add_peer(
+                PeerConfigBuilder::new(&keypair.public)
+                    .add_allowed_ip("10.99.0.2".parse().unwrap(), 32),
+            );
+            if let Err(e) = update.apply(&interface, backend) {
+                eprintln!(
+                    "skipping {} backend, couldn't create interface: {}",
+                    backend, e
+                );
+                continue;
+            }
+
+            let stats = Device::get_stats(&interface, backend).unwrap();
+            Device::get(&interface, backend).unwrap().delete().unwrap();
+
+            assert_eq!(
+                stats,
+                vec![DevicePeerStats {
+                    public_key: keypair.public.clone(),
+                    stats: PeerStats::default(),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+
+        let interface = "wgctrl-remove".parse().unwrap();
+        let (kept, removed) = (KeyPair::generate().public, KeyPair::generate().public);
+        let update = DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&kept))
+            .add_peer(PeerConfigBuilder::new(&removed));
+        if let Err(e) = update.apply(&interface, Backend::Auto) {
+            eprintln!("skipping, couldn't create interface: {}", e);
+            return;
+        }
+
+        Device::remove_peer(&interface, Backend::Auto, &removed).unwrap();
+        // Removing an absent peer succeeds.
+        Device::remove_peer(&interface, Backend::Auto, &removed).unwrap();
+
+        let device = Device::get(&interface, Backend::Auto).unwrap();
+        let keys: Vec<_> = device.peers.iter().map(|p| &p.config.public_key).collect();
+        assert_eq!(keys, vec![&kept]);
+        device.delete().unwrap();
+    }
+
+    #[test]
+    fn test_stats_from_peers() {
+        let key = KeyPair::generate().public;
+        let stats = PeerStats {
+            last_handshake_time: Some(SystemTime::UNIX_EPOCH),
+            rx_bytes: 1024,
+            tx_bytes: 2048,
+        };
+        let device = device_with_peers(vec![PeerInfo {
+            config: peer_config(&key, "10.0.0.1"),
+            stats: stats.clone(),
+        }]);
+
+        assert_eq!(
+            device.stats(),
+            vec![DevicePeerStats {
+                public_key: key,
+                stats,
+            }]
+        );
+    }
+
     #[test]
     fn test_interface_names() {
         assert_eq!(
@@ -10,30 +10,20 @@ use std::{
 
 pub use crate::{config::*, device::*, key::*};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Backend {
+    /// Use the kernel module if it's available, and a userspace implementation otherwise.
+    #[default]
+    Auto,
     #[cfg(target_os = "linux")]
     Kernel,
     Userspace,
 }
 
-impl Default for Backend {
-    fn default() -> Self {
-        #[cfg(target_os = "linux")]
-        {
-            Self::Kernel
-        }
-
-        #[cfg(not(target_os = "linux"))]
-        {
-            Self::Userspace
-        }
-    }
-}
-
 impl Display for Backend {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Auto => write!(f, "auto"),
             #[cfg(target_os = "linux")]
             Self::Kernel => write!(f, "kernel"),
             Self::Userspace => write!(f, "userspace"),
@@ -46,6 +36,7 @@ impl FromStr for Backend {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
             #[cfg(target_os = "linux")]
             "kernel" => Ok(Self::Kernel),
             "userspace" => Ok(Self::Userspace),
@@ -58,12 +49,25 @@ impl Backend {
     pub fn variants() -> &'static [&'static str] {
         #[cfg(target_os = "linux")]
         {
-            &["kernel", "userspace"]
+            &["auto", "kernel", "userspace"]
         }
 
         #[cfg(not(target_os = "linux"))]
         {
-            &["userspace"]
+            &["auto", "userspace"]
+        }
+    }
+
+    /// Pick the concrete backend to use for an interface. `Auto` keeps an interface that's
+    /// already served by a userspace implementation there, and otherwise prefers the kernel
+    /// module when it's available.
+    pub fn resolve(self, iface: &InterfaceName) -> Self {
+        match self {
+            Self::Auto if backends::userspace::is_running(iface) => Self::Userspace,
+            #[cfg(target_os = "linux")]
+            Self::Auto if backends::kernel::is_available() => Self::Kernel,
+            Self::Auto => Self::Userspace,
+            backend => backend,
         }
     }
 }